@@ -0,0 +1,167 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use serde_json::{json, Value};
+
+use crate::config;
+use crate::error;
+use crate::ipc;
+use crate::update;
+use crate::update::marker::Marker;
+use crate::update::Updater;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Default address the API is served on (localhost only).
+const DEFAULT_LISTEN: &str = "127.0.0.1:8642";
+
+/// Serves the local HTTP API, if enabled (`ORM_API`): `GET /status`, `/version`, `/history`
+/// and `/metrics` (Prometheus text format),
+/// and `POST /check-now`; Each request must be authenticated with the `ORM_API_TOKEN`
+/// as bearer token.
+pub async fn serve(updater: Updater, local_prefix: PathBuf) -> Result<(), Error> {
+    if !config::parse_or("ORM_API", setting!("ORM_API"), false) {
+        return Ok(());
+    }
+
+    let token = match setting!("ORM_API_TOKEN") {
+        Some(token) if !token.is_empty() => token,
+        _ => return Err(format_error!("Missing ORM_API_TOKEN for {}", "ORM_API")),
+    };
+
+    let listen = setting!("ORM_API_LISTEN").unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+    let addr = listen
+        .parse::<SocketAddr>()
+        .map_err(|err| format_error!("Invalid API listen address {}: {}", listen, err))?;
+
+    let make_svc = make_service_fn(move |_conn| {
+        let token = token.clone();
+        let local_prefix = local_prefix.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let token = token.clone();
+                let local_prefix = local_prefix.clone();
+
+                async move { Ok::<_, Infallible>(respond(updater, &local_prefix, &token, req)) }
+            }))
+        }
+    });
+
+    info!("Serving the API on {}", addr);
+
+    Server::try_bind(&addr)?
+        .serve(make_svc)
+        .await
+        .map_err(Error::from)
+}
+
+fn respond(
+    updater: Updater,
+    local_prefix: &Path,
+    token: &str,
+    req: Request<Body>,
+) -> Response<Body> {
+    debug!("API request: {} {}", req.method(), req.uri());
+
+    if !authorized(&req, token) {
+        return reply(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }));
+    }
+
+    let app_dir = local_prefix.join(updater.app_name);
+    let version = || Marker::load(&app_dir).ok().flatten().map(|m| m.version);
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/status") => reply(
+            StatusCode::OK,
+            update::status::current(updater.app_name, local_prefix),
+        ),
+        (&Method::GET, "/version") => reply(StatusCode::OK, json!({ "version": version() })),
+        (&Method::GET, "/history") => match update::backup::history(local_prefix) {
+            Ok(history) => reply(StatusCode::OK, json!(history)),
+            Err(cause) => reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": cause.to_string() }),
+            ),
+        },
+        (&Method::GET, "/metrics") => {
+            let mut resp =
+                Response::new(Body::from(update::metrics::load(local_prefix).exposition()));
+
+            resp.headers_mut()
+                .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+
+            resp
+        }
+        (&Method::POST, "/check-now") => match ipc::request_check(updater, local_prefix) {
+            Ok(_) => reply(StatusCode::ACCEPTED, json!({ "check": "started" })),
+            Err(cause) => reply(StatusCode::CONFLICT, json!({ "error": cause })),
+        },
+        (_, "/status" | "/version" | "/history" | "/metrics" | "/check-now") => reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "method not allowed" }),
+        ),
+        _ => reply(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    }
+}
+
+/// Whether the request is authenticated by the token (`Authorization: Bearer {token}`).
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // Constant time comparison
+    bearer.len() == token.len()
+        && bearer
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body.to_string()));
+
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+
+    resp
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        let req = |auth: Option<&str>| {
+            let mut req = Request::builder().uri("/status");
+
+            if let Some(auth) = auth {
+                req = req.header(AUTHORIZATION, auth);
+            }
+
+            req.body(Body::empty()).unwrap()
+        };
+
+        assert!(authorized(&req(Some("Bearer secret")), "secret"));
+        assert!(!authorized(&req(Some("Bearer secreT")), "secret"));
+        assert!(!authorized(&req(Some("Bearer secret2")), "secret"));
+        assert!(!authorized(&req(Some("secret")), "secret"));
+        assert!(!authorized(&req(None), "secret"));
+    }
+}
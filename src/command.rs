@@ -0,0 +1,87 @@
+use crate::error;
+use crate::format_error;
+use error::Error;
+
+/// Command of the executable (first argument).
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Updates the application if required, then runs it (default).
+    Run,
+
+    /// Clears the failed versions (`clear-failed [version]`), all by default.
+    ClearFailed(Option<semver::Version>),
+
+    /// Prunes the local prefix (`prune`): leftovers, then backups over the disk budget.
+    Prune,
+
+    /// Lists the local backups (`backups`).
+    Backups,
+
+    /// Restores the named backup (`restore {name}`) as the application directory.
+    Restore(String),
+}
+
+impl Command {
+    /// Parses the command from the arguments (without the executable name).
+    pub fn parse<I>(args: I) -> Result<Command, Error>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+
+        let command = match args.next().as_deref() {
+            None | Some("run") => Command::Run,
+            Some("clear-failed") => Command::ClearFailed(
+                args.next()
+                    .map(|v| semver::Version::parse(&v))
+                    .transpose()?,
+            ),
+            Some("prune") => Command::Prune,
+            Some("backups") => Command::Backups,
+            Some("restore") => match args.next() {
+                Some(name) => Command::Restore(name),
+                None => return Err(Error::new("Missing backup name".to_string())),
+            },
+            Some(other) => return Err(format_error!("Unknown command: {}", other)),
+        };
+
+        match args.next() {
+            Some(extra) => Err(format_error!("Unexpected argument: {}", extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, Error> {
+        Command::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[]).unwrap(), Command::Run);
+        assert_eq!(
+            parse(&["clear-failed"]).unwrap(),
+            Command::ClearFailed(None)
+        );
+        assert_eq!(
+            parse(&["clear-failed", "1.2.0"]).unwrap(),
+            Command::ClearFailed(Some(semver::Version::new(1, 2, 0)))
+        );
+        assert!(parse(&["clear-failed", "foo"]).is_err());
+        assert_eq!(parse(&["prune"]).unwrap(), Command::Prune);
+        assert!(parse(&["prune", "all"]).is_err());
+        assert_eq!(parse(&["backups"]).unwrap(), Command::Backups);
+        assert_eq!(
+            parse(&["restore", "foo-20260101000000.tar.zst"]).unwrap(),
+            Command::Restore("foo-20260101000000.tar.zst".to_string())
+        );
+        assert!(parse(&["restore"]).is_err());
+        assert!(parse(&["upgrade"]).is_err());
+    }
+}
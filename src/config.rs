@@ -0,0 +1,46 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::warn;
+
+/// Usage: `setting!("NAME")`
+///
+/// Resolves an optional setting, first at compile-time,
+/// then from the runtime environment.
+#[macro_export]
+macro_rules! setting {
+    ($name:expr) => {
+        option_env!($name)
+            .map(|s| s.to_string())
+            .or_else(|| std::env::var($name).ok())
+    };
+}
+
+/// Returns the directory of the mutable state (`ORM_STATE_DIR`, default: the local prefix):
+/// journal, records, keys, caches and backups.
+pub fn state_dir(local_prefix: &Path) -> PathBuf {
+    setting!("ORM_STATE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| local_prefix.to_path_buf())
+}
+
+/// Parses the value of the named setting,
+/// or returns the default one if undefined or invalid.
+pub fn parse_or<T>(name: &str, value: Option<String>, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match value {
+        Some(repr) => match repr.trim().parse::<T>() {
+            Ok(v) => v,
+            Err(cause) => {
+                warn!("Invalid setting {} = {}: {}", name, repr, cause);
+
+                default
+            }
+        },
+        None => default,
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use log::{info, warn};
+
+use serde_json::Value as Json;
+
+use zbus::fdo;
+use zbus::message::Header;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::Value;
+
+use crate::config;
+use crate::error;
+use crate::io::group_id;
+use crate::ipc;
+use crate::update::{hold, status, Updater};
+use crate::{format_error, setting};
+use error::Error;
+
+/// Well-known name of the service.
+const BUS_NAME: &str = "org.orm.Updater";
+
+/// Path of the updater object.
+const OBJECT_PATH: &str = "/org/orm/Updater";
+
+/// Connection of the service, once registered.
+static CONNECTION: OnceLock<zbus::Connection> = OnceLock::new();
+
+/// Updater exposed on D-Bus, as `org.orm.Updater1` interface.
+struct Service {
+    updater: Updater,
+    local_prefix: PathBuf,
+
+    /// Group whose members are also allowed to hold the updates and roll back.
+    admin_gid: Option<u32>,
+}
+
+#[zbus::interface(name = "org.orm.Updater1")]
+impl Service {
+    /// Checks for update in the background (see `ipc::request_check`).
+    fn check_now(&self) -> fdo::Result<()> {
+        ipc::request_check(self.updater, &self.local_prefix)
+            .map_err(|cause| fdo::Error::Failed(cause.to_string()))
+    }
+
+    /// Returns the current status (see `status::current`), without the undefined fields.
+    fn get_status(&self) -> HashMap<String, Value<'static>> {
+        variants(status::current(self.updater.app_name, &self.local_prefix))
+    }
+
+    /// Holds the updates, or releases them (if the caller is allowed, see `authorize`).
+    async fn hold(
+        &self,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+        hold: bool,
+    ) -> fdo::Result<()> {
+        self.authorize(connection, &header).await?;
+
+        match hold {
+            true => hold::hold(&self.local_prefix),
+            false => hold::release(&self.local_prefix),
+        }
+        .map_err(|cause| fdo::Error::Failed(cause.to_string()))
+    }
+
+    /// Rolls back the current version (see `ipc::request_rollback`),
+    /// if the caller is allowed (see `authorize`).
+    async fn rollback(
+        &self,
+        #[zbus(connection)] connection: &zbus::Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> fdo::Result<()> {
+        self.authorize(connection, &header).await?;
+
+        ipc::request_rollback().map_err(|cause| fdo::Error::Failed(cause.to_string()))
+    }
+
+    /// Emitted once a new version is about to be installed.
+    #[zbus(signal)]
+    async fn update_available(emitter: &SignalEmitter<'_>, version: &str) -> zbus::Result<()>;
+
+    /// Emitted once the updated version is committed.
+    #[zbus(signal)]
+    async fn update_applied(emitter: &SignalEmitter<'_>, version: &str) -> zbus::Result<()>;
+}
+
+impl Service {
+    /// Checks the caller is root, runs as orm, or is a member of the `ORM_DBUS_ADMIN_GROUP`
+    /// (according its credentials from the bus).
+    async fn authorize(
+        &self,
+        connection: &zbus::Connection,
+        header: &Header<'_>,
+    ) -> fdo::Result<()> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::AccessDenied("Unknown caller".to_string()))?;
+
+        let credentials = fdo::DBusProxy::new(connection)
+            .await?
+            .get_connection_credentials(sender.clone().into())
+            .await?;
+
+        let uid = credentials.unix_user_id();
+        let gids = credentials.unix_group_ids().map(Vec::as_slice);
+
+        if allowed(uid, gids, self.admin_gid) {
+            Ok(())
+        } else {
+            warn!("D-Bus caller {} (uid = {:?}) not allowed", sender, uid);
+
+            Err(fdo::Error::AccessDenied(format!(
+                "Caller {} not allowed",
+                sender
+            )))
+        }
+    }
+}
+
+/// Returns whether the caller with the user and groups is allowed:
+/// either root, the user of orm, or a member of the admin group.
+fn allowed(uid: Option<u32>, gids: Option<&[u32]>, admin_gid: Option<u32>) -> bool {
+    let euid = unsafe { libc::geteuid() };
+
+    match (uid, gids, admin_gid) {
+        (Some(uid), _, _) if uid == 0 || uid == euid => true,
+        (_, Some(gids), Some(admin_gid)) => gids.contains(&admin_gid),
+        _ => false,
+    }
+}
+
+/// Converts the JSON fields to D-Bus variants (`a{sv}`), without the undefined
+/// (or structured) ones.
+fn variants(document: Json) -> HashMap<String, Value<'static>> {
+    match document {
+        Json::Object(fields) => fields
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Json::String(s) => Some((key, Value::from(s))),
+                Json::Bool(b) => Some((key, Value::from(b))),
+                _ => None,
+            })
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+/// Registers the D-Bus service (if `ORM_DBUS`) on the system bus,
+/// or the session one (`ORM_DBUS_BUS`).
+pub async fn serve(updater: Updater, local_prefix: PathBuf) -> Result<(), Error> {
+    if !config::parse_or("ORM_DBUS", setting!("ORM_DBUS"), false) {
+        return Ok(());
+    }
+
+    let builder = match setting!("ORM_DBUS_BUS").as_deref() {
+        None | Some("system") => zbus::connection::Builder::system()?,
+        Some("session") => zbus::connection::Builder::session()?,
+        Some(bus) => return Err(format_error!("Unsupported D-Bus bus: {}", bus)),
+    };
+
+    let admin_gid = match setting!("ORM_DBUS_ADMIN_GROUP") {
+        Some(group) => Some(group_id(&group)?),
+        None => None,
+    };
+
+    let service = Service {
+        updater,
+        local_prefix,
+        admin_gid,
+    };
+
+    let connection = builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, service)?
+        .build()
+        .await?;
+
+    info!("Serving {} on D-Bus", BUS_NAME);
+
+    let _ = CONNECTION.set(connection);
+
+    Ok(())
+}
+
+/// Signals the new version is available, about to be installed.
+pub async fn update_available(version: &str) {
+    if let Some(emitter) = emitter() {
+        if let Err(cause) = Service::update_available(&emitter, version).await {
+            warn!("Fails to signal the available update: {}", cause);
+        }
+    }
+}
+
+/// Signals the updated version is applied.
+pub async fn update_applied(version: &str) {
+    if let Some(emitter) = emitter() {
+        if let Err(cause) = Service::update_applied(&emitter, version).await {
+            warn!("Fails to signal the applied update: {}", cause);
+        }
+    }
+}
+
+fn emitter() -> Option<SignalEmitter<'static>> {
+    SignalEmitter::new(CONNECTION.get()?, OBJECT_PATH).ok()
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_variants() {
+        let status = variants(json!({
+            "version": "1.2.3",
+            "pending_version": null,
+            "running": true,
+            "metrics": { "attempts": 1 },
+        }));
+
+        assert_eq!(status.len(), 2);
+        assert_eq!(status["version"], Value::from("1.2.3"));
+        assert_eq!(status["running"], Value::from(true));
+
+        assert!(variants(json!("1.2.3")).is_empty());
+    }
+
+    #[test]
+    fn test_allowed() {
+        let euid = unsafe { libc::geteuid() };
+
+        assert!(allowed(Some(0), None, None));
+        assert!(allowed(Some(euid), None, None));
+
+        let other = euid.wrapping_add(4242).max(1);
+
+        assert!(!allowed(Some(other), Some(&[100, 200]), None));
+        assert!(!allowed(Some(other), Some(&[100, 200]), Some(300)));
+        assert!(allowed(Some(other), Some(&[100, 200]), Some(200)));
+        assert!(!allowed(None, None, Some(200)));
+    }
+}
@@ -13,7 +13,7 @@ pub struct Error {
 
 impl Error {
     pub fn new(message: String) -> Error {
-        Error { message: message }
+        Error { message }
     }
 }
 
@@ -59,18 +59,36 @@ impl From<serde_yaml::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(jsonerr: serde_json::Error) -> Error {
+        Error::new(format!("JSON error: {}", jsonerr))
+    }
+}
+
 impl From<semver::Error> for Error {
     fn from(verr: semver::Error) -> Error {
         Error::new(format!("Version error: {}", verr))
     }
 }
 
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(sslerr: openssl::error::ErrorStack) -> Error {
+        Error::new(format!("SSL error: {}", sslerr))
+    }
+}
+
 impl From<regex::Error> for Error {
     fn from(rerr: regex::Error) -> Error {
         Error::new(format!("Regex error: {}", rerr))
     }
 }
 
+impl From<zbus::Error> for Error {
+    fn from(dberr: zbus::Error) -> Error {
+        Error::new(format!("D-Bus error: {}", dberr))
+    }
+}
+
 /// Usage: `boxed_error!("Msg format: {}", details)`
 #[macro_export]
 macro_rules! boxed_error {
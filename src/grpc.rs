@@ -0,0 +1,371 @@
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response};
+
+use openssl::ssl::{AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
+
+use prost::Message;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio_openssl::SslStream;
+
+use crate::error;
+use crate::ipc;
+use crate::update::{hold, status, Updater};
+use crate::{format_error, setting};
+use error::Error;
+
+/// Path prefix of the methods of the `orm.v1.Updater` service (see `proto/updater.proto`).
+const SERVICE_PATH: &str = "/orm.v1.Updater/";
+
+/// Interval the status is checked for change, when watched.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// gRPC status codes
+const CODE_OK: u8 = 0;
+const CODE_INVALID_ARGUMENT: u8 = 3;
+const CODE_FAILED_PRECONDITION: u8 = 9;
+const CODE_UNIMPLEMENTED: u8 = 12;
+const CODE_INTERNAL: u8 = 13;
+
+/// Status of the updater (see `status::current`).
+#[derive(Clone, PartialEq, Message)]
+pub struct Status {
+    #[prost(string, tag = "1")]
+    pub version: String,
+
+    #[prost(string, tag = "2")]
+    pub pending_version: String,
+
+    #[prost(bool, tag = "3")]
+    pub running: bool,
+
+    #[prost(bool, tag = "4")]
+    pub updating: bool,
+
+    #[prost(string, tag = "5")]
+    pub update_step: String,
+
+    #[prost(string, tag = "6")]
+    pub held: String,
+
+    #[prost(string, tag = "7")]
+    pub run_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HoldRequest {
+    #[prost(bool, tag = "1")]
+    hold: bool,
+}
+
+/// Empty message (e.g. `CommandReply`).
+#[derive(Clone, PartialEq, Message)]
+struct Empty {}
+
+/// Serves the `orm.v1.Updater` gRPC service on `ORM_GRPC_LISTEN` (if defined),
+/// over HTTP/2 with mutual TLS: the clients must present a certificate
+/// issued by the `ORM_GRPC_CLIENT_CA`.
+pub async fn serve(updater: Updater, local_prefix: PathBuf) -> Result<(), Error> {
+    let listen = match setting!("ORM_GRPC_LISTEN") {
+        Some(listen) => listen,
+        None => return Ok(()),
+    };
+
+    let addr = listen
+        .parse::<SocketAddr>()
+        .map_err(|err| format_error!("Invalid gRPC listen address {}: {}", listen, err))?;
+
+    let acceptor = acceptor()?;
+    let listener = TcpListener::bind(addr).await?;
+
+    info!("Serving gRPC on {}", addr);
+
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let ssl = Ssl::new(acceptor.context())?;
+        let local_prefix = local_prefix.clone();
+
+        tokio::spawn(async move {
+            if let Err(cause) = connection(ssl, tcp, updater, local_prefix).await {
+                warn!("Fails to serve gRPC client {}: {}", peer, cause);
+            }
+        });
+    }
+}
+
+/// Returns the TLS acceptor, verifying the client certificates and negotiating HTTP/2.
+fn acceptor() -> Result<SslAcceptor, Error> {
+    let required = |name: &str, value: Option<String>| {
+        value.ok_or_else(|| format_error!("Missing {} for the gRPC server", name))
+    };
+
+    let cert = required("ORM_GRPC_CERT", setting!("ORM_GRPC_CERT"))?;
+    let key = required("ORM_GRPC_KEY", setting!("ORM_GRPC_KEY"))?;
+    let client_ca = required("ORM_GRPC_CLIENT_CA", setting!("ORM_GRPC_CLIENT_CA"))?;
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+
+    builder.set_certificate_chain_file(&cert)?;
+    builder.set_private_key_file(&key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_ca_file(&client_ca)?;
+    builder.set_client_ca_list(X509Name::load_client_ca_file(&client_ca)?);
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    builder.set_alpn_select_callback(|_, client| {
+        openssl::ssl::select_next_proto(b"\x02h2", client).ok_or(AlpnError::NOACK)
+    });
+
+    Ok(builder.build())
+}
+
+async fn connection(
+    ssl: Ssl,
+    tcp: TcpStream,
+    updater: Updater,
+    local_prefix: PathBuf,
+) -> Result<(), Error> {
+    let mut stream = SslStream::new(ssl, tcp)?;
+
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .map_err(|err| format_error!("TLS error: {}", err))?;
+
+    if let Some(cert) = stream.ssl().peer_certificate() {
+        let subject: Vec<String> = cert
+            .subject_name()
+            .entries()
+            .filter_map(|entry| entry.data().to_string().ok())
+            .collect();
+
+        debug!("gRPC client: {}", subject.join(", "));
+    }
+
+    let service = service_fn(move |req| {
+        let local_prefix = local_prefix.clone();
+
+        async move { Ok::<_, Infallible>(respond(updater, &local_prefix, req).await) }
+    });
+
+    Http::new()
+        .http2_only(true)
+        .serve_connection(stream, service)
+        .await
+        .map_err(Error::from)
+}
+
+async fn respond(updater: Updater, local_prefix: &Path, req: Request<Body>) -> Response<Body> {
+    debug!("gRPC request: {}", req.uri());
+
+    let method = match req.uri().path().strip_prefix(SERVICE_PATH) {
+        Some(method) if req.method() == Method::POST => method.to_string(),
+        _ => return trailers_only(CODE_UNIMPLEMENTED, "Unknown service"),
+    };
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(cause) => return trailers_only(CODE_INTERNAL, &cause.to_string()),
+    };
+
+    let message = match unframe(&body) {
+        Ok(message) => message,
+        Err(cause) => return trailers_only(CODE_INVALID_ARGUMENT, cause),
+    };
+
+    match method.as_str() {
+        "GetStatus" => unary(current(updater, local_prefix)),
+        "WatchStatus" => watch(updater, local_prefix.to_path_buf()),
+        "CheckNow" => command(ipc::request_check(updater, local_prefix)),
+        "Hold" => match HoldRequest::decode(message) {
+            Ok(HoldRequest { hold: true }) => command(hold::hold(local_prefix)),
+            Ok(HoldRequest { hold: false }) => command(hold::release(local_prefix)),
+            Err(cause) => trailers_only(CODE_INVALID_ARGUMENT, &cause.to_string()),
+        },
+        "Rollback" => command(ipc::request_rollback()),
+        _ => trailers_only(CODE_UNIMPLEMENTED, &format!("Unknown method {}", method)),
+    }
+}
+
+/// Returns the current status (see `status::current`).
+fn current(updater: Updater, local_prefix: &Path) -> Status {
+    let current = status::current(updater.app_name, local_prefix);
+    let text = |key: &str| current[key].as_str().unwrap_or_default().to_string();
+
+    Status {
+        version: text("version"),
+        pending_version: text("pending_version"),
+        running: current["running"].as_bool().unwrap_or_default(),
+        updating: current["updating"].as_bool().unwrap_or_default(),
+        update_step: text("update_step"),
+        held: text("held"),
+        run_id: text("run_id"),
+    }
+}
+
+/// Replies the result of the command, refused with the `FAILED_PRECONDITION` status.
+fn command<E: Display>(result: Result<(), E>) -> Response<Body> {
+    match result {
+        Ok(_) => unary(Empty {}),
+        Err(cause) => trailers_only(CODE_FAILED_PRECONDITION, &cause.to_string()),
+    }
+}
+
+fn unary<M: Message + 'static>(message: M) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let data = frame(&message);
+
+    tokio::spawn(async move {
+        if sender.send_data(data).await.is_ok() {
+            let _ = sender.send_trailers(trailers(CODE_OK, None)).await;
+        }
+    });
+
+    response(body, HeaderMap::new())
+}
+
+/// Streams the status once, then each time it changes, until the client is gone.
+fn watch(updater: Updater, local_prefix: PathBuf) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut last = None;
+
+        loop {
+            let closed = std::future::poll_fn(|cx| {
+                Poll::Ready(matches!(sender.poll_ready(cx), Poll::Ready(Err(_))))
+            })
+            .await;
+
+            if closed {
+                return;
+            }
+
+            let status = current(updater, &local_prefix);
+
+            if last.as_ref() != Some(&status) {
+                if sender.send_data(frame(&status)).await.is_err() {
+                    return;
+                }
+
+                last = Some(status);
+            }
+
+            tokio::time::sleep(WATCH_INTERVAL).await;
+        }
+    });
+
+    response(body, HeaderMap::new())
+}
+
+/// Replies the failure status (as headers), without message.
+fn trailers_only(code: u8, message: &str) -> Response<Body> {
+    debug!("gRPC status {}: {}", code, message);
+
+    response(Body::empty(), trailers(code, Some(message)))
+}
+
+fn response(body: Body, headers: HeaderMap) -> Response<Body> {
+    let mut resp = Response::new(body);
+
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    resp.headers_mut().extend(headers);
+
+    resp
+}
+
+fn trailers(code: u8, message: Option<&str>) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+
+    trailers.insert("grpc-status", HeaderValue::from(code as u16));
+
+    if let Some(message) = message {
+        // Percent-encoded, as required
+        let encoded: String = message
+            .bytes()
+            .map(|b| match b {
+                b' '..=b'~' if b != b'%' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+
+        if let Ok(value) = HeaderValue::from_str(&encoded) {
+            trailers.insert("grpc-message", value);
+        }
+    }
+
+    trailers
+}
+
+/// Returns the message as length-prefixed (uncompressed) gRPC frame.
+fn frame<M: Message>(message: &M) -> Bytes {
+    let encoded = message.encode_to_vec();
+    let mut frame = Vec::with_capacity(5 + encoded.len());
+
+    frame.push(0);
+    frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&encoded);
+
+    Bytes::from(frame)
+}
+
+/// Returns the message of the (single, uncompressed) request frame.
+fn unframe(body: &[u8]) -> Result<&[u8], &'static str> {
+    if body.len() < 5 {
+        return Err("Incomplete message");
+    }
+
+    if body[0] != 0 {
+        return Err("Compressed message not supported");
+    }
+
+    let length = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+
+    body.get(5..5 + length).ok_or("Incomplete message")
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        let status = Status {
+            version: "1.0.0".to_string(),
+            running: true,
+            ..Status::default()
+        };
+
+        let frame = frame(&status);
+
+        assert_eq!(
+            &frame[..],
+            &[0, 0, 0, 0, 9, 0x0a, 5, b'1', b'.', b'0', b'.', b'0', 0x18, 1]
+        );
+
+        let message = unframe(&frame).unwrap();
+
+        assert_eq!(Status::decode(message).unwrap(), status);
+
+        assert!(unframe(&frame[..8]).is_err());
+        assert!(unframe(&[1, 0, 0, 0, 0]).is_err());
+        assert_eq!(unframe(&[0, 0, 0, 0, 0]), Ok(&[][..]));
+        assert!(HoldRequest::decode(&[0x08, 1][..]).unwrap().hold);
+    }
+}
@@ -1,7 +1,15 @@
-use std::io::{BufRead, BufReader, Error};
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, Read, Write};
 
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
 use std::path::Path;
 
+use log::warn;
+
+use sha2::{Digest, Sha256};
+
 /// List the file names in the specified directory,
 /// using the given filter.
 pub fn list_file_names<'x, F>(dir_path: &'x Path, filter: F) -> Result<Vec<String>, Error>
@@ -16,29 +24,266 @@ where
             let nme = file.file_name();
             let osn = nme.to_str().map(|s| s.to_string());
 
-            osn.filter(|n| filter(&n))
+            osn.filter(|n| filter(n))
         })
         .collect::<Vec<String>>();
 
     Ok(names)
 }
 
-/// Finds a text line in file at given path.
-pub fn find_line<'x, F>(path: &'x Path, accepts: F) -> Result<Option<String>, Error>
-where
-    F: Fn(&String) -> bool,
-{
-    let file = std::fs::File::open(path)?;
-    let reader = BufReader::new(file);
-    let lines = reader.lines();
+/// Returns the total size in bytes of the files at given path (recursively).
+pub fn disk_usage(path: &Path) -> Result<u64, Error> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        size += disk_usage(&entry?.path())?;
+    }
+
+    Ok(size)
+}
+
+/// Writes the file atomically: the content is written and synced
+/// to a temporary file next to it, then renamed over it.
+pub fn write_atomic<'x>(path: &'x Path, content: &'x [u8]) -> Result<(), Error> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    let partial = path.with_file_name(format!(".{}.partial", name));
+
+    let mut file = fs::File::create(&partial)?;
+
+    file.write_all(content)?;
+    file.sync_all()?;
+
+    fs::rename(&partial, path)?;
+
+    // Sync the directory entry
+    match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) => fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Appends a line to the file, atomically rewritten.
+pub fn append_line<'x>(path: &'x Path, line: &'x str) -> Result<(), Error> {
+    let mut content = match fs::read(path) {
+        Ok(content) => content,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(cause) => return Err(cause),
+    };
+
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        content.push(b'\n');
+    }
+
+    content.extend_from_slice(line.as_bytes());
+    content.push(b'\n');
+
+    write_atomic(path, &content)
+}
+
+/// Resolves the group ID, either numeric or from the group name.
+pub fn group_id(group: &str) -> Result<u32, Error> {
+    if let Ok(id) = group.parse::<u32>() {
+        return Ok(id);
+    }
+
+    let name =
+        CString::new(group).map_err(|err| Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+
+    if gr.is_null() {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Unknown group: {}", group),
+        ));
+    }
+
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+/// Lower case hexadecimal representation.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Computes the SHA-256 digest (hexadecimal) of the content.
+pub fn sha256_hex<R: Read>(reader: &mut R) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+
+    std::io::copy(reader, &mut hasher)?;
+
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Moves the directory or file, by renaming it if possible,
+/// otherwise by copying it (e.g. from another filesystem)
+/// then removing the source.
+pub fn move_path<'x>(from: &'x Path, to: &'x Path) -> Result<(), Error> {
+    match fs::rename(from, to) {
+        Err(cause) if cause.raw_os_error() == Some(libc::EXDEV) => {
+            warn!(
+                "Cannot rename {:?} across filesystems; Fallback to copy",
+                from
+            );
+
+            if fs::symlink_metadata(from)?.is_dir() {
+                copy_dir(from, to)?;
 
-    for ln in lines {
-        let line = ln?;
+                fs::remove_dir_all(from)
+            } else {
+                fs::copy(from, to)?;
+                fs::File::open(to)?.sync_all()?;
 
-        if accepts(&line) {
-            return Ok(Some(line));
+                fs::remove_file(from)
+            }
         }
+        res => res,
     }
+}
+
+/// Atomically exchanges the two paths (`renameat2` with `RENAME_EXCHANGE`),
+/// failing with `EINVAL` or `ENOSYS` if not supported by the filesystem/kernel.
+pub fn exchange<'x>(a: &'x Path, b: &'x Path) -> Result<(), Error> {
+    let c_path = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|err| Error::new(std::io::ErrorKind::InvalidInput, err))
+    };
+
+    let (a, b) = (c_path(a)?, c_path(b)?);
+
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+/// Recursively syncs the files and directories to the storage.
+pub fn sync_tree(path: &Path) -> Result<(), Error> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            sync_tree(&entry.path())?;
+        } else if file_type.is_file() {
+            fs::File::open(entry.path())?.sync_all()?;
+        }
+    }
+
+    fs::File::open(path)?.sync_all()
+}
+
+/// Recursively copies the directory, keeping the modes and symlinks,
+/// synced to the storage.
+fn copy_dir<'x>(from: &'x Path, to: &'x Path) -> Result<(), Error> {
+    fs::create_dir(to)?;
+
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+
+        if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+            fs::File::open(&target)?.sync_all()?;
+        }
+    }
+
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+
+    fs::File::open(to)?.sync_all()
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_append_line() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".orm_failed");
+
+        append_line(&path, "1.0.0").unwrap();
+        append_line(&path, "1.1.0").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1.0.0\n1.1.0\n");
+        assert_eq!(list_file_names(tmp.path(), |_| true).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_exchange() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+
+        fs::create_dir(&a).unwrap();
+        fs::write(a.join("version"), "1").unwrap();
+        fs::create_dir(&b).unwrap();
+        fs::write(b.join("version"), "2").unwrap();
 
-    Ok(None)
+        match exchange(&a, &b) {
+            Err(cause) if cause.raw_os_error() == Some(libc::EINVAL) => (), // Unsupported
+            res => {
+                res.unwrap();
+
+                assert_eq!(fs::read_to_string(a.join("version")).unwrap(), "2");
+                assert_eq!(fs::read_to_string(b.join("version")).unwrap(), "1");
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let from = tmp.path().join("from");
+
+        fs::create_dir_all(from.join("lib")).unwrap();
+        fs::write(from.join("run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(from.join("run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        symlink("../run.sh", from.join("lib/run")).unwrap();
+
+        let to = tmp.path().join("to");
+
+        copy_dir(&from, &to).unwrap();
+
+        let mode = fs::metadata(to.join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+
+        assert_eq!(mode & 0o777, 0o750);
+        assert_eq!(
+            fs::read_link(to.join("lib/run")).unwrap(),
+            Path::new("../run.sh")
+        );
+        assert_eq!(fs::read_to_string(to.join("lib/run")).unwrap(), "#!/bin/sh");
+    }
 }
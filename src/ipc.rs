@@ -0,0 +1,435 @@
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config;
+use crate::error;
+use crate::format_error;
+use crate::io::group_id;
+use crate::logging;
+use crate::process;
+use crate::setting;
+use crate::update;
+use crate::update::marker::Marker;
+use crate::update::{hold, status, Updater};
+use error::Error;
+
+/// Default timeout (in seconds) waiting for the answer of the application.
+const DEFAULT_TIMEOUT: u64 = 5;
+
+/// Whether the application requested to be restarted into the latest version.
+static UPDATE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the application acknowledged its reload.
+static RELOADED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the rollback of the current version is requested.
+static ROLLBACK: AtomicBool = AtomicBool::new(false);
+
+/// Requests the application to be stopped, then restarted into the latest version.
+pub fn request_restart() {
+    UPDATE.store(true, Ordering::SeqCst);
+
+    tokio::spawn(process::terminate_all());
+}
+
+/// Returns (and resets) whether the application acknowledged its reload.
+pub fn take_reloaded() -> bool {
+    RELOADED.swap(false, Ordering::SeqCst)
+}
+
+/// Returns (and resets) whether the application requested to be restarted
+/// into the latest version.
+pub fn take_update() -> bool {
+    UPDATE.swap(false, Ordering::SeqCst)
+}
+
+/// Returns whether the application requested to be restarted into the latest version.
+pub fn update_requested() -> bool {
+    UPDATE.load(Ordering::SeqCst)
+}
+
+/// Returns (and resets) whether the rollback of the current version is requested,
+/// once the application is stopped.
+pub fn take_rollback() -> bool {
+    ROLLBACK.swap(false, Ordering::SeqCst)
+}
+
+/// Returns whether the rollback of the current version is requested.
+pub fn rollback_requested() -> bool {
+    ROLLBACK.load(Ordering::SeqCst)
+}
+
+/// Checks for update in the background, as periodically while the application runs
+/// (see `update::watch`); Refused while an update is in progress, or the application is not
+/// running.
+pub fn request_check(updater: Updater, local_prefix: &Path) -> Result<(), &'static str> {
+    if update::updating() {
+        return Err("update in progress");
+    }
+
+    if !process::is_running() {
+        return Err("application not running");
+    }
+
+    info!("Checking for update, as requested");
+
+    let local_prefix = local_prefix.to_path_buf();
+
+    tokio::spawn(async move {
+        update::check(
+            updater.manifest_url,
+            updater.object_type,
+            updater.app_name,
+            &local_prefix,
+        )
+        .await
+    });
+
+    Ok(())
+}
+
+/// Requests the rollback of the current version, once the application is stopped
+/// (see `update::revert`); Refused while an update is in progress, or the application is not
+/// running.
+pub fn request_rollback() -> Result<(), &'static str> {
+    if update::updating() {
+        return Err("update in progress");
+    }
+
+    if !process::is_running() {
+        return Err("application not running");
+    }
+
+    info!("Rolling back the current version, as requested");
+
+    ROLLBACK.store(true, Ordering::SeqCst);
+
+    tokio::spawn(process::terminate_all());
+
+    Ok(())
+}
+
+/// Returns the path of the control socket of orm (`ORM_CONTROL_SOCKET`), if enabled.
+pub fn control_socket() -> Option<PathBuf> {
+    setting!("ORM_CONTROL_SOCKET").map(PathBuf::from)
+}
+
+/// Serves the requests of the application (or of the local tooling) on the control socket,
+/// if enabled: `restart` (into the latest version, once checked for update), `reloaded`,
+/// `version`, `status` (answered as JSON document, see `status::current`), `check`
+/// (for update, in the background), `hold` and `unhold` the updates, `rollback`
+/// (the current version, marked as failed), and `log_level` (overridden if followed
+/// by a level, e.g. `log_level debug`, or restored with `log_level reset`).
+pub async fn serve(updater: Updater, local_prefix: PathBuf) -> std::io::Result<()> {
+    let path = match control_socket() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    remove_stale(&path)?;
+
+    let listener = UnixListener::bind(&path)?;
+
+    // Only for the user of orm (and the members of the `ORM_CONTROL_SOCKET_GROUP`)
+    let mode = match setting!("ORM_CONTROL_SOCKET_GROUP") {
+        Some(group) => {
+            std::os::unix::fs::chown(&path, None, Some(group_id(&group)?))?;
+
+            0o660
+        }
+        None => 0o600,
+    };
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+
+    info!("Serving the application requests on {:?}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let local_prefix = local_prefix.clone();
+
+        tokio::spawn(async move {
+            if let Err(cause) = handle(stream, updater, &local_prefix).await {
+                warn!("Fails to handle the application request: {}", cause);
+            }
+        });
+    }
+}
+
+/// Removes the socket left at the path (e.g. after a crash), refusing any other file.
+fn remove_stale(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("Not a socket: {:?}", path),
+        )),
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(cause) => Err(cause),
+    }
+}
+
+async fn handle(stream: UnixStream, updater: Updater, local_prefix: &Path) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let app_dir = local_prefix.join(updater.app_name);
+
+    let answer_of = |res: Result<(), Error>| match res {
+        Ok(_) => "ok".to_string(),
+        Err(cause) => format!("error {}", cause),
+    };
+
+    while let Some(line) = lines.next_line().await? {
+        debug!("Application request: {}", line);
+
+        let answer = match line.trim() {
+            "restart" => {
+                info!("Restarting the application into the latest version, as requested");
+
+                request_restart();
+
+                "ok".to_string()
+            }
+            "reloaded" => {
+                RELOADED.store(true, Ordering::SeqCst);
+
+                "ok".to_string()
+            }
+            "version" => match Marker::load(&app_dir) {
+                Ok(Some(marker)) => format!("ok {}", marker.version),
+                Ok(None) => "error unknown version".to_string(),
+                Err(cause) => format!("error {}", cause),
+            },
+            "status" => format!("ok {}", status::current(updater.app_name, local_prefix)),
+            "check" => {
+                answer_of(request_check(updater, local_prefix).map_err(|e| format_error!("{}", e)))
+            }
+            "hold" => answer_of(hold::hold(local_prefix)),
+            "unhold" => answer_of(hold::release(local_prefix)),
+            "rollback" => answer_of(request_rollback().map_err(|e| format_error!("{}", e))),
+            "log_level" => format!(
+                "ok {}",
+                logging::level_override().map_or("default".to_string(), |l| l.to_string())
+            ),
+            "log_level reset" => {
+                logging::override_level(None);
+
+                "ok".to_string()
+            }
+            request if request.starts_with("log_level ") => {
+                match request["log_level ".len()..].trim().parse() {
+                    Ok(level) => {
+                        logging::override_level(Some(level));
+
+                        "ok".to_string()
+                    }
+                    Err(cause) => format!("error {}", cause),
+                }
+            }
+            request => format!("error unsupported request: {}", request),
+        };
+
+        writer.write_all(format!("{}\n", answer).as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends the request to the application listening on `ORM_APP_SOCKET`
+/// (relative to its directory), returning its answer, if any (not listening).
+async fn request(app_dir: &Path, request: &str) -> std::io::Result<Option<String>> {
+    let path = match setting!("ORM_APP_SOCKET") {
+        Some(path) => app_dir.join(path),
+        None => return Ok(None),
+    };
+
+    let mut stream = match UnixStream::connect(&path).await {
+        Ok(stream) => stream,
+        Err(cause) => {
+            debug!("Application not listening on {:?}: {}", path, cause);
+
+            return Ok(None);
+        }
+    };
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_APP_SOCKET_TIMEOUT",
+        setting!("ORM_APP_SOCKET_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    debug!("Requesting the application: {}", request);
+
+    let exchange = async {
+        stream
+            .write_all(format!("{}\n", request).as_bytes())
+            .await?;
+
+        let mut answer = String::new();
+
+        BufReader::new(&mut stream).read_line(&mut answer).await?;
+
+        Ok::<_, std::io::Error>(answer.trim().to_string())
+    };
+
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(answer) => answer.map(Some),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("No answer from the application within {:?}", timeout),
+        )),
+    }
+}
+
+/// Asks the running application whether it's safe to update to the version,
+/// returning the reason if not (`no [reason]` answered, or no answer).
+pub async fn can_update(app_dir: &Path, version: &str) -> Option<String> {
+    match request(app_dir, &format!("can_update {}", version)).await {
+        Ok(Some(answer)) => match answer.strip_prefix("no") {
+            Some(reason) if reason.is_empty() || reason.starts_with(' ') => Some(
+                Some(reason.trim())
+                    .filter(|r| !r.is_empty())
+                    .unwrap_or("not safe to update")
+                    .to_string(),
+            ),
+            _ => None,
+        },
+        Ok(None) => None,
+        Err(cause) => Some(cause.to_string()),
+    }
+}
+
+/// Announces to the running application the version is staged, about to replace it.
+pub async fn staged(app_dir: &Path, version: &str) {
+    if let Err(cause) = request(app_dir, &format!("staged {}", version)).await {
+        warn!("Fails to announce the staged version: {}", cause);
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_can_update() {
+        let tmp = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(tmp.path().join("app.sock")).unwrap();
+
+        // Disabled
+        assert_eq!(can_update(tmp.path(), "2.0.0").await, None);
+
+        std::env::set_var("ORM_APP_SOCKET", "app.sock");
+
+        let answers = ["yes", "no measuring", "no"];
+
+        tokio::spawn(async move {
+            for answer in answers {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut line = String::new();
+
+                BufReader::new(reader).read_line(&mut line).await.unwrap();
+
+                assert_eq!(line, "can_update 2.0.0\n");
+
+                writer
+                    .write_all(format!("{}\n", answer).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        assert_eq!(can_update(tmp.path(), "2.0.0").await, None);
+        assert_eq!(
+            can_update(tmp.path(), "2.0.0").await,
+            Some("measuring".to_string())
+        );
+        assert_eq!(
+            can_update(tmp.path(), "2.0.0").await,
+            Some("not safe to update".to_string())
+        );
+
+        std::env::remove_var("ORM_APP_SOCKET");
+    }
+
+    #[test]
+    fn test_remove_stale() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("orm.sock");
+
+        remove_stale(&path).unwrap(); // None
+
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        remove_stale(&path).unwrap();
+
+        assert!(!path.exists());
+
+        fs::write(&path, "data").unwrap();
+
+        assert!(remove_stale(&path).is_err());
+        assert!(path.exists());
+    }
+
+    /// Sends the request, returning the answer line.
+    async fn exchange(client: &mut BufReader<UnixStream>, request: &str) -> String {
+        let mut answer = String::new();
+
+        client
+            .get_mut()
+            .write_all(format!("{}\n", request).as_bytes())
+            .await
+            .unwrap();
+
+        client.read_line(&mut answer).await.unwrap();
+
+        answer.trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_handle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let local_prefix = tmp.path().to_path_buf();
+        let updater = Updater {
+            manifest_url: "http://localhost/manifest.yaml",
+            object_type: "OBJ_TPE",
+            app_name: "foo",
+        };
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let served = tokio::spawn(async move { handle(server, updater, &local_prefix).await });
+        let mut client = BufReader::new(client);
+        let c = &mut client;
+
+        assert_eq!(exchange(c, "version").await, "error unknown version");
+        assert_eq!(exchange(c, " hold ").await, "ok");
+
+        let status = exchange(c, "status").await;
+        let status: serde_json::Value = serde_json::from_str(&status["ok ".len()..]).unwrap();
+
+        assert!(status["held"].is_string());
+        assert_eq!(status["running"], false);
+
+        assert_eq!(exchange(c, "unhold").await, "ok");
+        assert_eq!(exchange(c, "check").await, "error application not running");
+        assert_eq!(
+            exchange(c, "log_level foo").await,
+            "error attempted to convert a string that doesn't match an existing log level"
+        );
+        assert_eq!(exchange(c, "bar").await, "error unsupported request: bar");
+
+        drop(client);
+
+        served.await.unwrap().unwrap();
+        assert!(hold::since(tmp.path()).is_none());
+    }
+}
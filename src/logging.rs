@@ -1,10 +1,28 @@
 use std::env::var;
+use std::fmt::Display;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, LevelFilter, Log, Metadata, Record};
 
 use datadog_logs::config::{DataDogConfig, DataDogHttpConfig};
 use datadog_logs::error::DataDogLoggerError;
-use datadog_logs::logger::DataDogLogger;
 
 use crate::error::Error;
+use crate::outbox::Outbox;
+use crate::{config, setting};
+use datadog::DataDogLogger;
+use file::FileLogger;
+use journald::JournaldLogger;
+use syslog::SyslogLogger;
+
+mod datadog;
+mod file;
+mod journald;
+mod syslog;
 
 /// Compile-time DataDog API URL
 const DATADOG_API_URL: Option<&'static str> = option_env!("DATADOG_API_URL");
@@ -21,67 +39,489 @@ const DATADOG_SERVICE: Option<&'static str> = option_env!("DATADOG_SERVICE");
 /// Compile-time DataDog source
 const DATADOG_SOURCE: Option<&'static str> = option_env!("DATADOG_SOURCE");
 
-/// Set up logging.
-pub fn setup() -> Result<(), Error> {
+/// Compile-time DataDog log level
+const DATADOG_LOG_LEVEL: Option<&'static str> = option_env!("DATADOG_LOG_LEVEL");
+
+/// Default duration (in seconds) the logs have to be sent before exit.
+const DEFAULT_FLUSH_TIMEOUT: u64 = 5;
+
+/// Crockford's Base32 alphabet, as used by the ULIDs.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// ID of the current update run, to correlate the logs (and records) of an attempt.
+static RUN_ID: RwLock<String> = RwLock::new(String::new());
+
+/// Level overriding the ones of the sinks (if any), and whether it's set by the manifest.
+static LEVEL_OVERRIDE: RwLock<Option<(LevelFilter, bool)>> = RwLock::new(None);
+
+/// Maximum level of the sinks, as configured.
+static CONFIGURED_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+/// Context of the device (e.g. its `thing_id`), attached as fields to the log records.
+static CONTEXT: RwLock<Vec<(&'static str, String)>> = RwLock::new(Vec::new());
+
+/// Attaches the field (e.g. `version`) to the subsequent log records, replacing its value if any.
+pub fn enrich<V: Display>(key: &'static str, value: V) {
+    if let Ok(mut context) = CONTEXT.write() {
+        let value = value.to_string();
+
+        match context.iter_mut().find(|(k, _)| *k == key) {
+            Some(field) => field.1 = value,
+            None => context.push((key, value)),
+        }
+    }
+}
+
+/// Detaches the field from the subsequent log records.
+pub fn forget(key: &str) {
+    if let Ok(mut context) = CONTEXT.write() {
+        context.retain(|(k, _)| *k != key);
+    }
+}
+
+/// Returns the fields attached to the log records (see `enrich`).
+fn context() -> Vec<(&'static str, String)> {
+    CONTEXT.read().map(|c| c.clone()).unwrap_or_default()
+}
+
+/// Returns the context as ` key=value` pairs, to be appended to a text header.
+fn context_text() -> String {
+    context()
+        .iter()
+        .map(|(key, value)| format!(" {}={}", key, value))
+        .collect()
+}
+
+/// Overrides the level of all the sinks (e.g. `debug` to troubleshoot the device),
+/// or restores the configured ones if `None`.
+pub fn override_level(level: Option<LevelFilter>) {
+    set_override(|_| Some(level.map(|level| (level, false))));
+}
+
+/// Overrides the level of all the sinks as declared by the manifest (if any),
+/// otherwise restores the configured ones if the manifest overrode them;
+/// An override from the control socket (see `override_level`) takes precedence.
+pub fn manifest_level(level: Option<LevelFilter>) {
+    set_override(|current| manifest_override(level, current));
+}
+
+/// Returns the override once the manifest level applied to the current one,
+/// or `None` if unchanged.
+fn manifest_override(
+    level: Option<LevelFilter>,
+    current: Option<(LevelFilter, bool)>,
+) -> Option<Option<(LevelFilter, bool)>> {
+    match (level, current) {
+        (_, Some((_, false))) => None, // Overridden from the control socket
+        (Some(level), _) => Some(Some((level, true))),
+        (None, Some((_, true))) => Some(None),
+        (None, None) => None,
+    }
+}
+
+/// Updates the level override (if changed by `f`), and the maximum level accordingly.
+fn set_override<F>(f: F)
+where
+    F: FnOnce(Option<(LevelFilter, bool)>) -> Option<Option<(LevelFilter, bool)>>,
+{
+    let changed = match LEVEL_OVERRIDE.write() {
+        Ok(mut current) => match f(*current) {
+            Some(value) if value.map(|v| v.0) != current.map(|c| c.0) => {
+                *current = value;
+                Some(value.map(|v| v.0))
+            }
+            Some(value) => {
+                *current = value;
+                None
+            }
+            None => None,
+        },
+        Err(_) => None,
+    };
+
+    if let Some(level) = changed {
+        let configured = CONFIGURED_LEVEL.get().copied().unwrap_or(LevelFilter::Info);
+
+        log::set_max_level(level.unwrap_or(configured));
+
+        match level {
+            Some(level) => info!("Log level overridden: {}", level),
+            None => info!("Log levels restored"),
+        }
+    }
+}
+
+/// Returns the level overriding the ones of the sinks, if any.
+pub fn level_override() -> Option<LevelFilter> {
+    LEVEL_OVERRIDE
+        .read()
+        .ok()
+        .and_then(|current| current.map(|c| c.0))
+}
+
+/// Returns the ID of the current update run (empty if none yet).
+pub fn run_id() -> String {
+    RUN_ID.read().map(|id| id.clone()).unwrap_or_default()
+}
+
+/// Starts a new update run, with a new ULID as ID.
+pub fn new_run_id() -> String {
+    let id = ulid();
+
+    if let Ok(mut current) = RUN_ID.write() {
+        *current = id.clone();
+    }
+
+    id
+}
+
+/// Generates a [ULID](https://github.com/ulid/spec):
+/// 48 bits of timestamp (milliseconds) and 80 random bits, in Crockford's Base32.
+fn ulid() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+
+    let mut random = [0u8; 16];
+
+    openssl::rand::rand_bytes(&mut random[6..]).unwrap_or_default();
+
+    let value = (millis & 0xFFFF_FFFF_FFFF) << 80 | u128::from_be_bytes(random);
+
+    (0..26)
+        .map(|i| CROCKFORD[((value >> (125 - 5 * i)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+/// Logger prefixing the messages with the run ID (if any).
+struct RunLogger<L: Log>(L);
+
+impl<L: Log> Log for RunLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let id = run_id();
+
+        if id.is_empty() {
+            return self.0.log(record);
+        }
+
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("[{}] {}", id, record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        )
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Returns the DataDog API key, if defined.
+pub fn datadog_api_key() -> Option<String> {
+    DATADOG_API_KEY
+        .map(|s| s.to_string())
+        .or_else(|| var("DATADOG_API_KEY").ok())
+}
+
+/// Returns the DataDog tags (comma separated), if defined.
+pub fn datadog_tags() -> Option<String> {
+    DATADOG_TAGS
+        .map(|s| s.to_string())
+        .or_else(|| var("DATADOG_TAGS").ok())
+}
+
+/// Sink of the records up to its level.
+struct Filtered {
+    logger: Box<dyn Log>,
+    level: LevelFilter,
+}
+
+impl Filtered {
+    fn new<L: Log + 'static>(logger: L, level: LevelFilter) -> Filtered {
+        Filtered {
+            logger: Box::new(logger),
+            level,
+        }
+    }
+}
+
+/// Logger dispatching the records to several sinks, each filtered at its own level.
+struct Sinks(Vec<Filtered>);
+
+impl Sinks {
+    /// Whether any sink accepts the records, up to the overriding level if any.
+    fn accepts(&self, metadata: &Metadata, level: Option<LevelFilter>) -> bool {
+        self.0.iter().any(|sink| {
+            metadata.level() <= level.unwrap_or(sink.level) && sink.logger.enabled(metadata)
+        })
+    }
+
+    /// Sends the record to the sinks accepting it, up to the overriding level if any.
+    fn dispatch(&self, record: &Record, level: Option<LevelFilter>) {
+        for sink in &self.0 {
+            if record.level() <= level.unwrap_or(sink.level)
+                && sink.logger.enabled(record.metadata())
+            {
+                sink.logger.log(record);
+            }
+        }
+    }
+}
+
+impl Log for Sinks {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.accepts(metadata, level_override())
+    }
+
+    fn log(&self, record: &Record) {
+        self.dispatch(record, level_override())
+    }
+
+    fn flush(&self) {
+        for sink in &self.0 {
+            sink.logger.flush();
+        }
+    }
+}
+
+/// Returns the DataDog logger, with the future sending its records, if configured.
+fn datadog(
+    local_prefix: &Path,
+) -> Result<Option<(DataDogLogger, impl Future<Output = ()>)>, Error> {
     let datadog_api_url = DATADOG_API_URL
         .map(|s| s.to_string())
         .or_else(|| var("DATADOG_API_URL").ok());
 
-    let datadog_api_key = DATADOG_API_KEY
+    let (url, api_key) = match datadog_api_url.zip(datadog_api_key()) {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+
+    let http_config = DataDogHttpConfig { url };
+    let tags = datadog_tags();
+    let service = DATADOG_SERVICE
         .map(|s| s.to_string())
-        .or_else(|| var("DATADOG_API_KEY").ok());
+        .or_else(|| var("DATADOG_SERVICE").ok());
 
-    match datadog_api_url.zip(datadog_api_key) {
-        Some((url, api_key)) => {
-            let http_config = DataDogHttpConfig { url: url };
-            let tags = DATADOG_TAGS
-                .map(|s| s.to_string())
-                .or_else(|| var("DATADOG_TAGS").ok());
-            let service = DATADOG_SERVICE
-                .map(|s| s.to_string())
-                .or_else(|| var("DATADOG_SERVICE").ok());
+    let source = DATADOG_SOURCE
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| var("DATADOG_SOURCE").unwrap_or_else(|_| "orm".to_string()));
 
-            let source = DATADOG_SOURCE
-                .map(|s| s.to_string())
-                .unwrap_or_else(|| var("DATADOG_SOURCE").unwrap_or_else(|_| "orm".to_string()));
+    let config: DataDogConfig = DataDogConfig {
+        apikey: api_key,
+        tags,
+        service,
+        source,
+        hostname: var("HOSTNAME").ok(),
+        http_config,
+        ..DataDogConfig::default()
+    };
 
-            let config: DataDogConfig = DataDogConfig {
-                apikey: api_key,
-                tags: tags,
-                service: service,
-                source: source,
-                hostname: var("HOSTNAME").ok(),
-                http_config: http_config,
-                ..DataDogConfig::default()
-            };
+    println!("DataDog config = {:#?}", config);
 
-            println!("DataDog config = {:#?}", config);
+    let client = datadog_logs::client::HttpDataDogClient::new(&config)?;
 
-            let client = datadog_logs::client::HttpDataDogClient::new(&config)?;
-            let nonblocking =
-                DataDogLogger::set_nonblocking_logger(client, config, log::LevelFilter::Info)?;
+    Ok(Some(DataDogLogger::new(
+        client,
+        config,
+        Outbox::from_settings(local_prefix),
+    )))
+}
 
-            tokio::spawn(nonblocking);
+/// Console sink, filtered with `RUST_LOG` (if defined) unless the level is overridden.
+struct Console {
+    filtered: env_logger::Logger,
+    unfiltered: env_logger::Logger,
+}
 
-            Ok(())
+impl Console {
+    fn logger(&self) -> &env_logger::Logger {
+        match level_override() {
+            Some(_) => &self.unfiltered,
+            None => &self.filtered,
         }
+    }
+}
 
-        None => {
-            if var("RUST_LOG").map_or_else(|_| false, |_| true) {
-                env_logger::init()
-            } else if cfg!(debug_assertions) {
-                env_logger::Builder::new()
-                    .filter_level(log::LevelFilter::Debug)
-                    .init();
-            } else {
-                env_logger::Builder::new()
-                    .filter_level(log::LevelFilter::Info)
-                    .init();
-            }
+impl Log for Console {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.logger().log(record)
+    }
+
+    fn flush(&self) {
+        self.logger().flush()
+    }
+}
+
+/// Returns the console logger, filtered with `RUST_LOG` if defined
+/// (otherwise up to the level, see `Sinks`); The context is appended to the header.
+fn console(level: LevelFilter) -> Filtered {
+    let rust_log = var("RUST_LOG").is_ok();
+    let filtered = env_logger(rust_log);
+    let level = if rust_log { filtered.filter() } else { level };
+
+    Filtered::new(
+        Console {
+            filtered,
+            unfiltered: env_logger(false),
+        },
+        level,
+    )
+}
+
+/// Returns the `env_logger`, filtered with `RUST_LOG` if `rust_log`
+/// (otherwise accepting all the records).
+fn env_logger(rust_log: bool) -> env_logger::Logger {
+    let mut builder = if rust_log {
+        env_logger::Builder::from_default_env()
+    } else {
+        let mut builder = env_logger::Builder::new();
+
+        builder.filter_level(LevelFilter::Trace);
+        builder
+    };
+
+    builder
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "[{} {:<5} {}{}] {}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target(),
+                context_text(),
+                record.args()
+            )
+        })
+        .build()
+}
+
+/// Set up logging to the active sinks, each up to its own level (default: `ORM_LOG_LEVEL`):
+/// DataDog if configured (`DATADOG_LOG_LEVEL`), the `ORM_LOG_BACKEND` ones (comma separated,
+/// default: `console` unless DataDog is configured): `console` (`ORM_LOG_CONSOLE_LEVEL`),
+/// `syslog` (`ORM_SYSLOG_LEVEL`, see `SyslogLogger`), `journald` (`ORM_JOURNALD_LEVEL`,
+/// see `JournaldLogger`), and the local file if enabled (`ORM_LOG_FILE_LEVEL`,
+/// see `FileLogger`).
+pub fn setup(local_prefix: &Path) -> Result<(), Error> {
+    let default_level = config::parse_or(
+        "ORM_LOG_LEVEL",
+        setting!("ORM_LOG_LEVEL"),
+        if cfg!(debug_assertions) {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        },
+    );
+
+    let mut sinks = Vec::new();
+    let datadog = datadog(local_prefix)?;
 
-            Ok(())
+    let backends = setting!("ORM_LOG_BACKEND").unwrap_or_else(|| match datadog {
+        Some(_) => String::new(),
+        None => "console".to_string(),
+    });
+
+    let nonblocking = datadog.map(|(logger, nonblocking)| {
+        let level = config::parse_or(
+            "DATADOG_LOG_LEVEL",
+            DATADOG_LOG_LEVEL
+                .map(|s| s.to_string())
+                .or_else(|| var("DATADOG_LOG_LEVEL").ok()),
+            LevelFilter::Info,
+        );
+
+        sinks.push(Filtered::new(logger, level));
+
+        nonblocking
+    });
+
+    for backend in backends.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+        match backend {
+            "console" => sinks.push(console(config::parse_or(
+                "ORM_LOG_CONSOLE_LEVEL",
+                setting!("ORM_LOG_CONSOLE_LEVEL"),
+                default_level,
+            ))),
+            "syslog" => sinks.push(Filtered::new(
+                SyslogLogger::from_settings(),
+                config::parse_or(
+                    "ORM_SYSLOG_LEVEL",
+                    setting!("ORM_SYSLOG_LEVEL"),
+                    default_level,
+                ),
+            )),
+            "journald" => sinks.push(Filtered::new(
+                JournaldLogger::from_settings()?,
+                config::parse_or(
+                    "ORM_JOURNALD_LEVEL",
+                    setting!("ORM_JOURNALD_LEVEL"),
+                    default_level,
+                ),
+            )),
+            other => return Err(Error::new(format!("Unsupported log backend: {}", other))),
         }
     }
+
+    if let Some(file) = FileLogger::from_settings(local_prefix) {
+        sinks.push(Filtered::new(
+            file,
+            config::parse_or(
+                "ORM_LOG_FILE_LEVEL",
+                setting!("ORM_LOG_FILE_LEVEL"),
+                default_level,
+            ),
+        ));
+    }
+
+    let level = sinks
+        .iter()
+        .map(|sink| sink.level)
+        .max()
+        .unwrap_or(LevelFilter::Off);
+
+    log::set_boxed_logger(Box::new(RunLogger(Sinks(sinks))))
+        .map_err(|err| Error::new(format!("Logger error: {}", err)))?;
+    log::set_max_level(level);
+
+    let _ = CONFIGURED_LEVEL.set(level);
+
+    if let Some(nonblocking) = nonblocking {
+        tokio::spawn(nonblocking);
+    }
+
+    Ok(())
+}
+
+/// Flushes the logs before exit, waiting up to `ORM_LOG_FLUSH_TIMEOUT` (seconds)
+/// for the ones not yet sent to DataDog.
+pub async fn shutdown() {
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_LOG_FLUSH_TIMEOUT",
+        setting!("ORM_LOG_FLUSH_TIMEOUT"),
+        DEFAULT_FLUSH_TIMEOUT,
+    ));
+
+    if tokio::time::timeout(timeout, datadog::flush())
+        .await
+        .is_err()
+    {
+        eprintln!("Fails to send the logs to DataDog within {:?}", timeout);
+    }
+
+    log::logger().flush();
 }
 
 impl From<DataDogLoggerError> for Error {
@@ -89,3 +529,121 @@ impl From<DataDogLoggerError> for Error {
         Error::new(format!("Datadog error: {}", dderr))
     }
 }
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use log::Level;
+
+    use super::*;
+
+    /// Sink capturing the records as `LEVEL message`.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Capture {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn log(sinks: &Sinks, level: Level, message: &str, overridden: Option<LevelFilter>) {
+        sinks.dispatch(
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{}", message))
+                .build(),
+            overridden,
+        );
+    }
+
+    #[test]
+    fn test_sinks() {
+        let (info, debug) = (Capture::default(), Capture::default());
+        let sinks = Sinks(vec![
+            Filtered::new(info.clone(), LevelFilter::Info),
+            Filtered::new(debug.clone(), LevelFilter::Debug),
+        ]);
+
+        log(&sinks, Level::Warn, "foo", None);
+        log(&sinks, Level::Debug, "bar", None);
+        log(&sinks, Level::Trace, "lorem", None);
+
+        assert_eq!(info.take(), vec!["WARN foo"]);
+        assert_eq!(debug.take(), vec!["WARN foo", "DEBUG bar"]);
+
+        let trace = Metadata::builder().level(Level::Trace).build();
+
+        assert!(sinks.accepts(&Metadata::builder().level(Level::Debug).build(), None));
+        assert!(!sinks.accepts(&trace, None));
+        assert!(sinks.accepts(&trace, Some(LevelFilter::Trace)));
+
+        // Overridden
+        log(&sinks, Level::Trace, "ipsum", Some(LevelFilter::Trace));
+        log(&sinks, Level::Info, "dolor", Some(LevelFilter::Warn));
+
+        assert_eq!(info.take(), vec!["TRACE ipsum"]);
+        assert_eq!(debug.take(), vec!["TRACE ipsum"]);
+    }
+
+    #[test]
+    fn test_manifest_override() {
+        let debug = Some(LevelFilter::Debug);
+        assert_eq!(
+            manifest_override(debug, None),
+            Some(Some((LevelFilter::Debug, true)))
+        );
+        assert_eq!(
+            manifest_override(debug, Some((LevelFilter::Trace, true))),
+            Some(Some((LevelFilter::Debug, true)))
+        );
+        assert_eq!(
+            manifest_override(None, Some((LevelFilter::Trace, true))),
+            Some(None)
+        );
+        assert_eq!(manifest_override(None, None), None);
+
+        // Control socket override neither replaced nor cleared
+        assert_eq!(
+            manifest_override(debug, Some((LevelFilter::Trace, false))),
+            None
+        );
+        assert_eq!(
+            manifest_override(None, Some((LevelFilter::Trace, false))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ulid() {
+        let first = ulid();
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        let second = new_run_id();
+
+        assert_eq!(first.len(), 26);
+        assert!(first.chars().all(|c| CROCKFORD.contains(&(c as u8))));
+        assert!(second > first); // Sortable
+        assert_ne!(first[10..], second[10..]);
+        assert_eq!(run_id(), second);
+    }
+}
@@ -0,0 +1,223 @@
+use std::future::Future;
+use std::sync::OnceLock;
+
+use log::{Level, Log, Metadata, Record};
+
+use datadog_logs::client::{AsyncDataDogClient, HttpDataDogClient};
+use datadog_logs::config::DataDogConfig;
+use datadog_logs::logger::{DataDogLog, DataDogLogLevel};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use crate::error::Error;
+use crate::outbox::{self, Outbox};
+
+/// Maximum number of logs sent at once.
+const BATCH_SIZE: usize = 50;
+
+/// Kind of the log batches queued in the outbox.
+const KIND: &str = "logs";
+
+/// Sender to the task of the logger (if any), to flush it (see `flush`).
+static SENDER: OnceLock<UnboundedSender<Message>> = OnceLock::new();
+
+enum Message {
+    Log(DataDogLog),
+
+    /// Request to be notified once the previous logs are sent (or queued).
+    Flush(oneshot::Sender<()>),
+}
+
+/// Logger sending the records to DataDog, tagged with the context (see `logging::enrich`)
+/// as it's when they are logged.
+pub struct DataDogLogger {
+    config: DataDogConfig,
+    sender: UnboundedSender<Message>,
+}
+
+impl DataDogLogger {
+    /// Returns the logger, with the future sending its records (to be spawned);
+    /// The batches failing to be sent are queued in the outbox (if any).
+    pub fn new(
+        client: HttpDataDogClient,
+        config: DataDogConfig,
+        outbox: Option<Outbox>,
+    ) -> (DataDogLogger, impl Future<Output = ()>) {
+        let (sender, receiver) = unbounded_channel();
+
+        let _ = SENDER.set(sender.clone());
+
+        (
+            DataDogLogger { config, sender },
+            send(client, receiver, outbox),
+        )
+    }
+}
+
+impl Log for DataDogLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let level = match record.level() {
+            Level::Error => DataDogLogLevel::Error,
+            Level::Warn => DataDogLogLevel::Warning,
+            Level::Info => DataDogLogLevel::Informational,
+            Level::Debug | Level::Trace => DataDogLogLevel::Debug,
+        };
+
+        let _ = self.sender.send(Message::Log(DataDogLog {
+            message: record.args().to_string(),
+            ddtags: tags(self.config.tags.as_deref(), &super::context()),
+            ddsource: self.config.source.clone(),
+            host: self.config.hostname.clone().unwrap_or_default(),
+            service: self.config.service.clone().unwrap_or_default(),
+            level: level.to_string(),
+        }));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Waits until the logs already emitted are sent (if the logger is set up).
+pub async fn flush() {
+    if let Some(sender) = SENDER.get() {
+        let (flushed, done) = oneshot::channel();
+
+        if sender.send(Message::Flush(flushed)).is_ok() {
+            let _ = done.await;
+        }
+    }
+}
+
+/// Sends the logs by batch, as soon as they are received, after the queued ones
+/// (also periodically forwarded).
+async fn send(
+    mut client: HttpDataDogClient,
+    mut receiver: UnboundedReceiver<Message>,
+    outbox: Option<Outbox>,
+) {
+    let mut batch = Vec::new();
+    let mut flushed = Vec::new();
+    let mut retry = tokio::time::interval(outbox::retry_interval());
+
+    loop {
+        let message = tokio::select! {
+            message = receiver.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = retry.tick() => {
+                if let Some(outbox) = &outbox {
+                    let _ = forward(&mut client, outbox).await; // Still offline otherwise
+                }
+
+                continue;
+            }
+        };
+
+        let mut next = Some(message);
+
+        while let Some(message) = next {
+            match message {
+                Message::Log(log) => batch.push(log),
+                Message::Flush(notify) => flushed.push(notify),
+            }
+
+            next = match batch.len() < BATCH_SIZE {
+                true => receiver.try_recv().ok(),
+                false => None,
+            };
+        }
+
+        if !batch.is_empty() {
+            deliver(&mut client, outbox.as_ref(), &batch).await;
+            batch.clear();
+        }
+
+        for notify in flushed.drain(..) {
+            let _ = notify.send(());
+        }
+    }
+}
+
+/// Sends the batch after the queued ones, or queues it in the outbox (if any).
+async fn deliver(client: &mut HttpDataDogClient, outbox: Option<&Outbox>, batch: &[DataDogLog]) {
+    let outbox = match outbox {
+        Some(outbox) => outbox,
+        None => {
+            if let Err(cause) = client.send_async(batch).await {
+                eprintln!("Fails to send the logs to DataDog: {}", cause);
+            }
+
+            return;
+        }
+    };
+
+    let sent = match forward(client, outbox).await {
+        Ok(()) => client.send_async(batch).await.map_err(Error::from),
+        Err(cause) => Err(cause),
+    };
+
+    if sent.is_err() {
+        let queued = serde_json::to_vec(batch)
+            .map_err(Error::from)
+            .and_then(|payload| outbox.push(KIND, &payload));
+
+        if let Err(cause) = queued {
+            eprintln!("Fails to queue the logs: {}", cause);
+        }
+    }
+}
+
+/// Sends the queued batches, oldest first, until one fails.
+async fn forward(client: &mut HttpDataDogClient, outbox: &Outbox) -> Result<(), Error> {
+    while let Some(entry) = outbox.next(KIND)? {
+        let logs: Vec<DataDogLog> = serde_json::from_slice(&entry.payload)?;
+
+        client.send_async(&logs).await?;
+        outbox.remove(entry)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the configured tags (comma separated), with the context as `key:value` ones.
+fn tags(configured: Option<&str>, context: &[(&str, String)]) -> Option<String> {
+    let tags: Vec<String> = configured
+        .into_iter()
+        .filter(|tags| !tags.is_empty())
+        .map(str::to_string)
+        .chain(
+            context
+                .iter()
+                .map(|(key, value)| format!("{}:{}", key, value)),
+        )
+        .collect();
+
+    Some(tags.join(",")).filter(|tags| !tags.is_empty())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        let context = [
+            ("thing_id", "dev1".to_string()),
+            ("version", "1.0.0".to_string()),
+        ];
+
+        assert_eq!(
+            tags(Some("env:prod"), &context).as_deref(),
+            Some("env:prod,thing_id:dev1,version:1.0.0")
+        );
+        assert_eq!(tags(None, &context[..1]).as_deref(), Some("thing_id:dev1"));
+        assert_eq!(tags(Some(""), &[]), None);
+    }
+}
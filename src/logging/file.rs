@@ -0,0 +1,282 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use log::{Log, Metadata, Record};
+
+use crate::config;
+use crate::setting;
+
+/// Name of the current log file, in the `logs` directory of the state.
+const FILE_NAME: &str = "orm.log";
+
+/// Default size (in bytes) the log file is rotated at.
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Default number of rotated files kept.
+const DEFAULT_RETENTION: usize = 7;
+
+/// Period the log file is rotated at, whatever its size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+
+    #[default]
+    Daily,
+
+    Never,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Rotation, String> {
+        match repr {
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            "never" => Ok(Rotation::Never),
+            _ => Err(format!("Unsupported log rotation: {}", repr)),
+        }
+    }
+}
+
+impl Rotation {
+    /// Returns the period of the time (e.g. its day), if rotated.
+    fn period(&self, time: &DateTime<Utc>) -> Option<String> {
+        match self {
+            Rotation::Hourly => Some(time.format("%Y%m%d%H").to_string()),
+            Rotation::Daily => Some(time.format("%Y%m%d").to_string()),
+            Rotation::Never => None,
+        }
+    }
+}
+
+/// Logger appending the records (with the context in their header) to a local file,
+/// rotated by size and period, with the most recent rotated files kept.
+pub struct FileLogger {
+    appender: Mutex<Appender>,
+}
+
+struct Appender {
+    dir: PathBuf,
+    max_size: u64,
+    rotation: Rotation,
+    retention: usize,
+
+    file: Option<File>,
+    size: u64,
+
+    /// Period of the current file (see `Rotation::period`).
+    period: Option<String>,
+}
+
+impl FileLogger {
+    /// Returns the file logger (if `ORM_LOG_FILE`), appending to `{ORM_STATE_DIR}/logs/orm.log`;
+    /// Rotated once over `ORM_LOG_FILE_MAX_SIZE` or at the `ORM_LOG_FILE_ROTATION` period,
+    /// keeping `ORM_LOG_FILE_RETENTION` files.
+    pub fn from_settings(local_prefix: &Path) -> Option<FileLogger> {
+        if !config::parse_or("ORM_LOG_FILE", setting!("ORM_LOG_FILE"), false) {
+            return None;
+        }
+
+        Some(FileLogger::new(
+            &config::state_dir(local_prefix).join("logs"),
+            config::parse_or(
+                "ORM_LOG_FILE_MAX_SIZE",
+                setting!("ORM_LOG_FILE_MAX_SIZE"),
+                DEFAULT_MAX_SIZE,
+            ),
+            config::parse_or(
+                "ORM_LOG_FILE_ROTATION",
+                setting!("ORM_LOG_FILE_ROTATION"),
+                Rotation::default(),
+            ),
+            config::parse_or(
+                "ORM_LOG_FILE_RETENTION",
+                setting!("ORM_LOG_FILE_RETENTION"),
+                DEFAULT_RETENTION,
+            ),
+        ))
+    }
+
+    fn new(dir: &Path, max_size: u64, rotation: Rotation, retention: usize) -> FileLogger {
+        FileLogger {
+            appender: Mutex::new(Appender {
+                dir: dir.to_path_buf(),
+                max_size,
+                rotation,
+                retention,
+                file: None,
+                size: 0,
+                period: None,
+            }),
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let now = Utc::now();
+        let line = format!(
+            "[{} {:<5} {}{}] {}\n",
+            now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            record.level(),
+            record.target(),
+            super::context_text(),
+            record.args()
+        );
+
+        if let Ok(mut appender) = self.appender.lock() {
+            if let Err(cause) = appender.append(&now, line.as_bytes()) {
+                eprintln!("Fails to write the log file: {}", cause);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut appender) = self.appender.lock() {
+            if let Some(file) = appender.file.as_mut() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+impl Appender {
+    fn path(&self) -> PathBuf {
+        self.dir.join(FILE_NAME)
+    }
+
+    fn append(&mut self, now: &DateTime<Utc>, line: &[u8]) -> std::io::Result<()> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let period = self.rotation.period(now);
+        let oversized = self.max_size > 0 && self.size + line.len() as u64 > self.max_size;
+
+        if self.size > 0 && (oversized || period != self.period) {
+            self.rotate(now)?;
+        }
+
+        self.period = period;
+
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line)?;
+            self.size += line.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Opens the current file, resuming its period from its modification time.
+    fn open(&mut self) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())?;
+        let metadata = file.metadata()?;
+
+        self.size = metadata.len();
+        self.period = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| self.rotation.period(&DateTime::<Utc>::from(modified)));
+        self.file = Some(file);
+
+        Ok(())
+    }
+
+    /// Renames the current file with the rotation time, then removes the oldest rotated ones
+    /// over the retention.
+    fn rotate(&mut self, now: &DateTime<Utc>) -> std::io::Result<()> {
+        self.file = None;
+
+        let rotated = self
+            .dir
+            .join(format!("{}.{}", FILE_NAME, now.format("%Y%m%dT%H%M%S%.3f")));
+
+        fs::rename(self.path(), rotated)?;
+
+        let prefix = format!("{}.", FILE_NAME);
+        let mut rotated: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(&prefix))
+            })
+            .collect();
+
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.retention);
+
+        for path in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+
+        self.open()
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_append() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("logs");
+        let logger = FileLogger::new(&dir, 20, Rotation::Daily, 2);
+        let mut appender = logger.appender.lock().unwrap();
+        let day = |d, s| Utc.with_ymd_and_hms(2026, 10, d, 0, 0, s).unwrap();
+
+        appender.append(&day(1, 0), b"first line\n").unwrap();
+        appender.append(&day(1, 1), b"second\n").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join(FILE_NAME)).unwrap(),
+            "first line\nsecond\n"
+        );
+
+        appender.append(&day(1, 2), b"third line\n").unwrap(); // Over the size
+        appender.append(&day(2, 3), b"next day\n").unwrap();
+        appender.append(&day(2, 4), b"still\n").unwrap();
+        appender.append(&day(3, 5), b"last\n").unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+
+        names.sort();
+
+        assert_eq!(
+            names,
+            vec![
+                "orm.log",
+                "orm.log.20261002T000003.000",
+                "orm.log.20261003T000005.000"
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("orm.log.20261003T000005.000")).unwrap(),
+            "next day\nstill\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join(FILE_NAME)).unwrap(), "last\n");
+    }
+}
@@ -0,0 +1,115 @@
+use std::os::unix::net::UnixDatagram;
+
+use log::{Level, Log, Metadata, Record};
+
+use crate::setting;
+
+/// Default socket of the journal (native protocol).
+const DEFAULT_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identifier of the entries (`SYSLOG_IDENTIFIER`).
+const IDENTIFIER: &str = "orm";
+
+/// Logger sending the records to the systemd journal, with their fields
+/// (`PRIORITY`, `CODE_FILE`, `CODE_LINE`, the `ORM_TARGET` module, and the context
+/// as `ORM_` ones, e.g. `ORM_THING_ID`).
+pub struct JournaldLogger {
+    path: String,
+    socket: UnixDatagram,
+}
+
+impl JournaldLogger {
+    /// Returns the logger sending the records to the journal
+    /// (`ORM_JOURNALD_SOCKET`, default: `/run/systemd/journal/socket`).
+    pub fn from_settings() -> std::io::Result<JournaldLogger> {
+        Ok(JournaldLogger {
+            path: setting!("ORM_JOURNALD_SOCKET").unwrap_or_else(|| DEFAULT_SOCKET.to_string()),
+            socket: UnixDatagram::unbound()?,
+        })
+    }
+}
+
+impl Log for JournaldLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+
+        let mut fields = vec![
+            ("PRIORITY".to_string(), priority.to_string()),
+            ("MESSAGE".to_string(), record.args().to_string()),
+            ("SYSLOG_IDENTIFIER".to_string(), IDENTIFIER.to_string()),
+            ("SYSLOG_PID".to_string(), std::process::id().to_string()),
+            ("ORM_TARGET".to_string(), record.target().to_string()),
+        ];
+
+        if let Some(file) = record.file() {
+            fields.push(("CODE_FILE".to_string(), file.to_string()));
+        }
+
+        if let Some(line) = record.line() {
+            fields.push(("CODE_LINE".to_string(), line.to_string()));
+        }
+
+        for (key, value) in super::context() {
+            fields.push((format!("ORM_{}", key.to_uppercase()), value));
+        }
+
+        if let Err(cause) = self.socket.send_to(&entry(&fields), &self.path) {
+            eprintln!("Fails to send the log to the journal: {}", cause);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the entry in the journal native format: `KEY=value` lines,
+/// or the length-prefixed value if it spans several lines.
+fn entry<K: AsRef<str>>(fields: &[(K, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+
+    for (key, value) in fields {
+        entry.extend_from_slice(key.as_ref().as_bytes());
+
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+
+    entry
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let entry = entry(&[
+            ("PRIORITY", "6".to_string()),
+            ("MESSAGE", "two\nlines".to_string()),
+        ]);
+
+        let mut expected = b"PRIORITY=6\nMESSAGE\n".to_vec();
+
+        expected.extend_from_slice(&[9, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"two\nlines\n");
+
+        assert_eq!(entry, expected);
+    }
+}
@@ -0,0 +1,281 @@
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use log::{Level, Log, Metadata, Record};
+
+use crate::config;
+use crate::setting;
+
+/// Default address of the system logger (local socket).
+const DEFAULT_ADDRESS: &str = "/dev/log";
+
+/// Application name of the messages.
+const APP_NAME: &str = "orm";
+
+/// ID of the structured data element of the context (private enterprise number of the RFC 5612).
+const SD_ID: &str = "orm@32473";
+
+/// Facility of the messages (see RFC 5424, section 6.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facility(u8);
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Facility, String> {
+        match repr {
+            "user" => Ok(Facility(1)),
+            "daemon" => Ok(Facility(3)),
+            _ => match repr
+                .strip_prefix("local")
+                .and_then(|n| n.parse::<u8>().ok())
+            {
+                Some(n) if n <= 7 => Ok(Facility(16 + n)),
+                _ => Err(format!("Unsupported syslog facility: {}", repr)),
+            },
+        }
+    }
+}
+
+/// Transport of the messages to the system logger.
+enum Transport {
+    /// Local socket (e.g. `/dev/log`).
+    Unix(String, Option<UnixDatagram>),
+
+    Udp(String, Option<UdpSocket>),
+
+    /// Remote syslog over TCP, with octet counting (see RFC 6587).
+    Tcp(String, Option<TcpStream>),
+}
+
+/// Logger sending the records to the system logger, as RFC 5424 messages
+/// (with the context as structured data).
+pub struct SyslogLogger {
+    facility: Facility,
+    hostname: String,
+    transport: Mutex<Transport>,
+}
+
+impl SyslogLogger {
+    /// Returns the logger sending the records to `ORM_SYSLOG_ADDRESS`
+    /// (either a local socket path, `udp://host:port` or `tcp://host:port`),
+    /// with the `ORM_SYSLOG_FACILITY`.
+    pub fn from_settings() -> SyslogLogger {
+        let address = setting!("ORM_SYSLOG_ADDRESS").unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+
+        let transport = if let Some(addr) = address.strip_prefix("udp://") {
+            Transport::Udp(addr.to_string(), None)
+        } else if let Some(addr) = address.strip_prefix("tcp://") {
+            Transport::Tcp(addr.to_string(), None)
+        } else {
+            Transport::Unix(address, None)
+        };
+
+        SyslogLogger {
+            facility: config::parse_or(
+                "ORM_SYSLOG_FACILITY",
+                setting!("ORM_SYSLOG_FACILITY"),
+                Facility(3),
+            ),
+            hostname: hostname(),
+            transport: Mutex::new(transport),
+        }
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = message(
+            self.facility,
+            record.level(),
+            &Utc::now(),
+            &self.hostname,
+            std::process::id(),
+            &super::context(),
+            &record.args().to_string(),
+        );
+
+        if let Ok(mut transport) = self.transport.lock() {
+            if let Err(cause) = transport.send(message.as_bytes()) {
+                eprintln!("Fails to send the log to syslog: {}", cause);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Transport {
+    /// Sends the message, (re)connecting if needed.
+    fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        let sent = self.try_send(message);
+
+        if sent.is_err() {
+            self.disconnect();
+
+            return self.try_send(message);
+        }
+
+        sent
+    }
+
+    fn try_send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        match self {
+            Transport::Unix(path, socket) => {
+                if socket.is_none() {
+                    let unix = UnixDatagram::unbound()?;
+
+                    unix.connect(&*path)?;
+                    *socket = Some(unix);
+                }
+
+                socket
+                    .as_ref()
+                    .map_or(Ok(()), |s| s.send(message).map(|_| ()))
+            }
+            Transport::Udp(addr, socket) => {
+                if socket.is_none() {
+                    let udp = UdpSocket::bind("0.0.0.0:0")?;
+
+                    udp.connect(&*addr)?;
+                    *socket = Some(udp);
+                }
+
+                socket
+                    .as_ref()
+                    .map_or(Ok(()), |s| s.send(message).map(|_| ()))
+            }
+            Transport::Tcp(addr, stream) => {
+                if stream.is_none() {
+                    *stream = Some(TcpStream::connect(&*addr)?);
+                }
+
+                match stream.as_mut() {
+                    Some(stream) => {
+                        let mut frame = format!("{} ", message.len()).into_bytes();
+
+                        frame.extend_from_slice(message);
+                        stream.write_all(&frame)
+                    }
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        match self {
+            Transport::Unix(_, socket) => *socket = None,
+            Transport::Udp(_, socket) => *socket = None,
+            Transport::Tcp(_, stream) => *stream = None,
+        }
+    }
+}
+
+/// Returns the RFC 5424 message, with the context as structured data (if any).
+fn message(
+    facility: Facility,
+    level: Level,
+    timestamp: &DateTime<Utc>,
+    hostname: &str,
+    pid: u32,
+    context: &[(&str, String)],
+    msg: &str,
+) -> String {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+
+    let data = if context.is_empty() {
+        "-".to_string()
+    } else {
+        let params: String = context
+            .iter()
+            .map(|(key, value)| {
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace(']', "\\]");
+
+                format!(" {}=\"{}\"", key, value)
+            })
+            .collect();
+
+        format!("[{}{}]", SD_ID, params)
+    };
+
+    format!(
+        "<{}>1 {} {} {} {} - {} {}",
+        facility.0 * 8 + severity,
+        timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+        hostname,
+        APP_NAME,
+        pid,
+        data,
+        msg
+    )
+}
+
+/// Returns the hostname of the device (or the nil value).
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+
+    // Safety: the buffer is large enough for the hostname (and its terminating NUL)
+    let res = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+
+    let len = name.iter().position(|&b| b == 0).unwrap_or(0);
+
+    match std::str::from_utf8(&name[..len]) {
+        Ok(name) if res == 0 && !name.is_empty() => name.to_string(),
+        _ => "-".to_string(),
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_message() {
+        let timestamp = Utc.with_ymd_and_hms(2026, 10, 17, 4, 5, 6).unwrap()
+            + chrono::Duration::microseconds(7);
+
+        assert_eq!(
+            message(
+                "local3".parse().unwrap(),
+                Level::Warn,
+                &timestamp,
+                "dev1",
+                42,
+                &[
+                    ("thing_id", "dev1".to_string()),
+                    ("version", "1.0.0\"]".to_string())
+                ],
+                "Fails to update"
+            ),
+            "<156>1 2026-10-17T04:05:06.000007Z dev1 orm 42 - [orm@32473 thing_id=\"dev1\" version=\"1.0.0\\\"\\]\"] Fails to update"
+        );
+
+        assert_eq!(
+            message(Facility(3), Level::Debug, &timestamp, "-", 1, &[], "x"),
+            "<31>1 2026-10-17T04:05:06.000007Z - orm 1 - - x"
+        );
+
+        assert!("local8".parse::<Facility>().is_err());
+    }
+}
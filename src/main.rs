@@ -2,40 +2,187 @@ use std::error::Error;
 use std::str;
 
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use log::{debug, info, warn};
 
+mod api;
+mod command;
+mod config;
+mod dbus;
 mod error;
+mod grpc;
 mod io;
+mod ipc;
 mod logging;
+mod mqtt;
+mod outbox;
+mod process;
 mod update;
 
+use process::restart::Decision;
 use update::ExecutionStatus as UpdateStatus;
 
 /// The type of IoT object; Must correspond to the object type on IoT Core.
-const OBJECT_TYPE: &'static str = env!("OBJECT_TYPE");
+const OBJECT_TYPE: &str = env!("OBJECT_TYPE");
 
 /// The URL to fetch/GET the YAML manifest.
-const YAML_MANIFEST_URL: &'static str = env!("YAML_MANIFEST_URL");
+const YAML_MANIFEST_URL: &str = env!("YAML_MANIFEST_URL");
 
 /// The name of the managed application.
-const APPLICATION_NAME: &'static str = env!("APPLICATION_NAME");
+const APPLICATION_NAME: &str = env!("APPLICATION_NAME");
 
 /// The local prefix path.
-const LOCAL_PREFIX: &'static str = env!("LOCAL_PREFIX");
+const LOCAL_PREFIX: &str = env!("LOCAL_PREFIX");
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    logging::setup()?;
+    let local_prefix = Path::new(LOCAL_PREFIX);
 
-    info!("Software management for {}.", OBJECT_TYPE);
+    logging::setup(local_prefix)?;
 
-    let local_prefix = Path::new(LOCAL_PREFIX);
+    let managed = manage(local_prefix).await;
+
+    if let Err(cause) = &managed {
+        log::error!(
+            "Fails to manage the software for {}: {}",
+            OBJECT_TYPE,
+            cause
+        );
+    }
+
+    logging::shutdown().await;
+
+    managed
+}
+
+/// Executes the command, or updates then runs the application.
+async fn manage(local_prefix: &'static Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Software management for {}.", OBJECT_TYPE);
 
     if !local_prefix.is_dir() {
         return boxed_error!("Local prefix is not a valid directory: {}", LOCAL_PREFIX);
     }
 
+    let command = command::Command::parse(std::env::args().skip(1))?;
+
+    debug!("Command = {:?}", command);
+
+    match command {
+        command::Command::ClearFailed(version) => {
+            let cleared = update::quarantine::clear(
+                &config::state_dir(local_prefix).join(".orm_failed"),
+                version.as_ref(),
+            )?;
+
+            info!("{} failed version record(s) cleared", cleared);
+
+            return Ok(());
+        }
+        command::Command::Prune => {
+            let freed =
+                update::prune::prune(local_prefix, APPLICATION_NAME, update::prune::budget())?;
+
+            info!("{} bytes freed", freed);
+
+            return Ok(());
+        }
+        command::Command::Backups => {
+            for backup in update::backup::list(local_prefix, APPLICATION_NAME)? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    backup.name,
+                    backup
+                        .timestamp
+                        .map(|ts| ts.to_rfc3339())
+                        .unwrap_or_else(|| "-".to_string()),
+                    backup.version.as_deref().unwrap_or("-"),
+                    backup.size
+                );
+            }
+
+            return Ok(());
+        }
+        command::Command::Restore(name) => {
+            let app_dir = local_prefix.join(APPLICATION_NAME);
+            let version = update::restore(APPLICATION_NAME, local_prefix, &app_dir, &name)?;
+
+            info!("Backup {} restored (version {})", name, version);
+
+            return Ok(());
+        }
+        command::Command::Run => (),
+    }
+
+    process::handle_signals()?;
+
+    tokio::spawn(async move {
+        if let Err(cause) = update::peer::serve(local_prefix.to_path_buf()).await {
+            warn!("Fails to serve archives to peers: {}", cause);
+        }
+    });
+
+    tokio::spawn(update::watch(
+        YAML_MANIFEST_URL,
+        OBJECT_TYPE,
+        APPLICATION_NAME,
+        local_prefix.to_path_buf(),
+    ));
+
+    tokio::spawn(update::report::forward(local_prefix.to_path_buf()));
+
+    let updater = update::Updater {
+        manifest_url: YAML_MANIFEST_URL,
+        object_type: OBJECT_TYPE,
+        app_name: APPLICATION_NAME,
+    };
+
+    tokio::spawn(update::subscribe(updater, local_prefix.to_path_buf()));
+
+    tokio::spawn(async move {
+        if let Err(cause) = api::serve(updater, local_prefix.to_path_buf()).await {
+            warn!("Fails to serve the API: {}", cause);
+        }
+    });
+
+    tokio::spawn(async move {
+        if let Err(cause) = grpc::serve(updater, local_prefix.to_path_buf()).await {
+            warn!("Fails to serve gRPC: {}", cause);
+        }
+    });
+
+    if let Err(cause) = dbus::serve(updater, local_prefix.to_path_buf()).await {
+        warn!("Fails to register the D-Bus service: {}", cause);
+    }
+
+    tokio::spawn(async move {
+        if let Err(cause) = ipc::serve(updater, local_prefix.to_path_buf()).await {
+            warn!("Fails to serve the application requests: {}", cause);
+        }
+    });
+
+    if let Err(cause) = update::journal::recover(local_prefix, APPLICATION_NAME) {
+        warn!("Fails to recover interrupted update: {}", cause);
+    }
+
+    if let Err(cause) = update::confirm::boot(
+        APPLICATION_NAME,
+        local_prefix,
+        &local_prefix.join(APPLICATION_NAME),
+    ) {
+        warn!("Fails to check update pending confirmation: {}", cause);
+    }
+
+    if update::slots::layout() == update::slots::Layout::Slots {
+        update::slots::ensure(&local_prefix.join(APPLICATION_NAME))?;
+    }
+
+    match update::prune::prune(local_prefix, APPLICATION_NAME, update::prune::budget()) {
+        Ok(0) => (),
+        Ok(freed) => info!("Reclaimed {} bytes from the local prefix", freed),
+        Err(cause) => warn!("Fails to prune local prefix: {}", cause),
+    }
+
     // ---
 
     let app_dir = local_prefix.join(APPLICATION_NAME);
@@ -46,98 +193,225 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         return boxed_error!("Application directory is not a valid one: {:?}", app_dir);
     }
 
+    update::status::enable_lifecycle(&app_dir);
+
     // ---
 
-    let current_version = resolve_version(&app_dir)?;
+    let run = || async {
+        if process::shutdown().is_some() {
+            return Ok(None);
+        }
 
-    info!("Current version is {}", current_version);
+        let started = Instant::now();
+        let run_status = match run_app(local_prefix, &app_dir).await? {
+            Some(status) => status,
+            None => return Ok(None), // Timed out
+        };
+        let uptime = started.elapsed();
 
-    // ---
+        info!("Exited with status: {:?}", run_status);
 
-    let update_status = update::execute(
-        YAML_MANIFEST_URL,
-        OBJECT_TYPE,
-        APPLICATION_NAME,
-        &local_prefix,
-        &app_dir,
-        current_version,
-    )
-    .await
-    .or_else(|up_err| Err(Box::new(up_err))?);
-
-    debug!("Update status: {:?}", update_status);
-
-    let run = || -> Result<(), Box<dyn Error + Send + Sync>> {
-        run_app(&app_dir)
-            .or_else(|run_err| Err(Box::new(run_err))?)
-            .map(|run_status| info!("Exited with status: {:?}", run_status))
+        if process::shutdown().is_some() {
+            return Ok(None); // Not a crash
+        }
+
+        if process::monitor::restart_requested()
+            || ipc::update_requested()
+            || ipc::rollback_requested()
+        {
+            return Ok(Some((run_status, uptime))); // Not a crash either
+        }
+
+        if let Err(cause) =
+            update::safe_mode::check(APPLICATION_NAME, local_prefix, &app_dir, uptime)
+        {
+            warn!("Fails to check crash loop: {}", cause);
+        }
+
+        Ok::<_, Box<dyn Error + Send + Sync>>(Some((run_status, uptime)))
+    };
+
+    // Updates (if any) then runs the application
+    let update_run = || async {
+        let current_version = resolve_version(&app_dir)?;
+
+        info!("Current version is {}", current_version);
+
+        let update_status = update::execute(
+            YAML_MANIFEST_URL,
+            OBJECT_TYPE,
+            APPLICATION_NAME,
+            local_prefix,
+            &app_dir,
+            current_version,
+        )
+        .await
+        .or_else(|up_err| Err(Box::new(up_err))?);
+
+        debug!("Update status: {:?}", update_status);
+
+        let update_result = match update_status {
+            Ok(UpdateStatus::NoUpdate(msg))
+            | Ok(UpdateStatus::RestartRequired(msg))
+            | Ok(UpdateStatus::RolledBack(msg)) => {
+                info!("No update: {}", msg);
+                info!("Executing the current version ...");
+
+                run().await
+            }
+            Ok(UpdateStatus::AppTerminated(status)) => {
+                info!("Updated application successfully terminated: {}", status);
+
+                match process::shutdown() {
+                    Some(_) => Ok(None),
+                    None => Ok(Some((status, Duration::ZERO))),
+                }
+            }
+            Ok(UpdateStatus::Rebooting) => {
+                info!("Rebooting to complete the update ...");
+
+                Ok(None)
+            }
+            Err(up_err) => Err(up_err),
+        };
+
+        match update_result {
+            Ok(exited) => Ok(exited),
+            Err(up_err) => {
+                warn!("Fails to update software for {}: {}", OBJECT_TYPE, up_err);
+
+                run().await
+            }
+        }
     };
 
-    let update_result = update_status.and_then(|status| match status {
-        UpdateStatus::NoUpdate(msg) => {
-            info!("No update: {}", msg);
-            info!("Executing the current version ...");
+    let mut exited = update_run().await?;
 
-            run()
+    let mut supervisor = process::restart::Supervisor::new(local_prefix);
+
+    while let Some((status, uptime)) = exited {
+        if !status.success() {
+            update::status::lifecycle(
+                "app_crashed",
+                "warning",
+                format!("Application exited after {:?}: {}", uptime, status),
+            );
+        }
+
+        if ipc::take_update() {
+            exited = update_run().await?; // Restarted into the latest version
+            continue;
+        }
+
+        if ipc::take_rollback() {
+            match update::revert(APPLICATION_NAME, local_prefix, &app_dir) {
+                Ok(restored) => warn!("Rolled back to version {}, as requested", restored),
+                Err(cause) => warn!("Fails to roll back the current version: {}", cause),
+            }
+
+            exited = run().await?; // Restored (or current) version restarted
+            continue;
+        }
+
+        match supervisor.exited(&status, uptime) {
+            Decision::Restart(delay) => tokio::time::sleep(delay).await,
+            Decision::Stop => break,
+            Decision::CrashLoop(exits) => {
+                let event = update::status::Event::new(
+                    &app_dir,
+                    "crash_loop",
+                    "critical",
+                    format!("Application exited {} times; No longer restarted", exits),
+                );
+
+                update::status::report(&app_dir, &event).await;
+
+                match update::safe_mode::crash_loop(APPLICATION_NAME, local_prefix, &app_dir) {
+                    Ok(true) => supervisor.reset(), // Restored version restarted
+                    Ok(false) => break,
+                    Err(cause) => {
+                        warn!(
+                            "Fails to roll back the crash looping application: {}",
+                            cause
+                        );
+
+                        break;
+                    }
+                }
+            }
+        }
+
+        if update::pending::exists(APPLICATION_NAME, local_prefix) {
+            exited = update_run().await?; // Pending update applied
+            continue;
         }
-        UpdateStatus::AppTerminated(status) => Ok(info!(
-            "Updated application successfully terminated: {}",
-            status
-        )),
-    });
 
-    update_result.or_else(|up_err| {
-        warn!("Fails to update software for {}: {}", OBJECT_TYPE, up_err);
+        exited = run().await?;
+    }
 
-        run()
-    })
+    Ok(())
 }
 
 /// Resolves the version for the specified application directory.
 fn resolve_version(app_dir: &Path) -> Result<semver::Version, error::Error> {
     let lowest_version = semver::Version::new(0, 0, 0);
-    let version_path = app_dir.join(".orm_version");
 
-    if !version_path.is_file() {
-        warn!(
-            "Missing ORM version marker {:?}; Fallback to 0",
-            version_path
-        );
+    let marker = match update::marker::Marker::load_verified(app_dir) {
+        Ok(Some(marker)) => marker,
+        Ok(None) => {
+            warn!(
+                "Missing ORM version marker {:?}; Fallback to 0",
+                app_dir.join(".orm_version")
+            );
+
+            return Ok(lowest_version);
+        }
+        Err(cause) => {
+            warn!("Invalid ORM version marker (fallback to 0): {}", cause);
 
-        Ok(lowest_version)
-    } else {
-        let version_content = std::fs::read_to_string(version_path)?;
-        let version_repr = version_content.trim();
-        let parsed = semver::Version::parse(&version_repr);
+            return Ok(lowest_version);
+        }
+    };
 
-        if parsed.is_err() {
+    match semver::Version::parse(&marker.version) {
+        Ok(version) => Ok(version),
+        Err(cause) => {
             warn!(
                 "Invalid ORM_version {} (fallback to 0): {}",
-                version_repr,
-                parsed.unwrap_err()
+                marker.version, cause
             );
 
             Ok(lowest_version)
-        } else {
-            Ok(parsed.unwrap())
         }
     }
 }
 
-use std::process::{Command, ExitStatus};
+use std::process::ExitStatus;
 
 /// Runs current version of the application
-fn run_app(app_dir: &Path) -> Result<ExitStatus, Box<error::Error>> {
-    let run_script = app_dir.join("run.sh");
+/// (`None` if killed once the run timeout is over)
+async fn run_app(
+    local_prefix: &Path,
+    app_dir: &Path,
+) -> Result<Option<ExitStatus>, Box<error::Error>> {
+    let marker = update::marker::Marker::load_runnable(app_dir);
+
+    let commands = process::commands(app_dir, marker.as_ref())
+        .map_err(|err| Box::new(error::Error::from(err)))?;
 
-    debug!("Run script: {:?}", run_script);
+    let mut app = process::start(app_dir, commands)
+        .await
+        .map_err(|err| Box::new(error::Error::from(err)))?;
 
-    Command::new(run_script)
-        .spawn()
-        .and_then(|mut child| {
-            info!("Successfully started {:?} ...", app_dir);
+    info!("Successfully started {:?} ...", app_dir);
+
+    let _monitor = process::monitor::watch(app.id(), app_dir.to_path_buf());
+
+    if let Err(cause) = update::confirm::confirm(local_prefix, app_dir, &mut app).await {
+        warn!("Fails to confirm the updated version: {}", cause);
+    }
 
-            child.wait()
-        })
-        .or_else(|err| Err(Box::new(error::Error::from(err)))?)
+    process::wait_timeout(&mut app, process::run_timeout())
+        .await
+        .map_err(|err| Box::new(error::Error::from(err)))
 }
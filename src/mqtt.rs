@@ -0,0 +1,404 @@
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use hyper::Uri;
+
+use rumqttc::{
+    AsyncClient, ClientError, ConnectionError, Event, MqttOptions, Packet, Publish, QoS, Transport,
+};
+
+use crate::config;
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Default timeout (in seconds) waiting for a MQTT message.
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Delay before reconnecting a subscription (see `subscribe`).
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Returns the MQTT options from the `ORM_MQTT_URL` setting, if defined.
+pub fn options(client_id: &str) -> Result<Option<MqttOptions>, Error> {
+    let url = match setting!("ORM_MQTT_URL") {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|err| format_error!("Invalid MQTT URL {}: {}", url, err))?;
+
+    let secure = match uri.scheme_str() {
+        Some("mqtt") => false,
+        Some("mqtts") => true,
+        _ => return Err(format_error!("Unsupported MQTT URL: {}", url)),
+    };
+
+    let host = uri
+        .host()
+        .ok_or_else(|| format_error!("Missing MQTT host: {}", url))?;
+
+    let port = uri.port_u16().unwrap_or(if secure { 8883 } else { 1883 });
+
+    let mut options = MqttOptions::new(client_id, host, port);
+
+    options.set_keep_alive(Duration::from_secs(30));
+
+    if let Some(username) = setting!("ORM_MQTT_USERNAME") {
+        options.set_credentials(username, setting!("ORM_MQTT_PASSWORD").unwrap_or_default());
+    }
+
+    if secure {
+        let transport = match setting!("ORM_MQTT_CA") {
+            Some(ca_path) => {
+                let ca = std::fs::read(ca_path)?;
+                let client_auth = match (setting!("ORM_MQTT_CERT"), setting!("ORM_MQTT_KEY")) {
+                    (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+                    _ => None,
+                };
+
+                Transport::tls(ca, client_auth, None)
+            }
+            None => Transport::tls_with_default_config(),
+        };
+
+        options.set_transport(transport);
+    }
+
+    Ok(Some(options))
+}
+
+/// Returns the topic setting, with the `{thing_id}` placeholder resolved.
+pub fn topic(value: &str, thing_id: &str) -> String {
+    value.replace("{thing_id}", thing_id)
+}
+
+/// Returns whether the topic matches the filter, possibly with `+` (single level)
+/// or `#` (remaining levels) wildcards.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return !topic.starts_with('$') || !filter.starts_with('#'),
+            ("+", Some(level)) => {
+                if level.starts_with('$') && filter.starts_with('+') {
+                    return false; // System topic, not matched by a leading wildcard
+                }
+            }
+            (pattern, Some(level)) if pattern == level => (),
+            _ => return false,
+        }
+    }
+
+    levels.next().is_none()
+}
+
+/// Keeps subscribed to the topic filter (resubscribing once reconnected),
+/// calling the handler with each message it matches.
+pub async fn subscribe<F: FnMut(Publish)>(options: MqttOptions, filter: &str, mut handler: F) {
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                debug!("Subscribing to MQTT topic '{}' ...", filter);
+
+                if let Err(cause) = client.try_subscribe(filter, QoS::AtLeastOnce) {
+                    warn!("Fails to subscribe to MQTT topic '{}': {}", filter, cause);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if matches(filter, &publish.topic) => {
+                debug!(
+                    "Received message on '{}' (retain = {})",
+                    publish.topic, publish.retain
+                );
+
+                handler(publish);
+            }
+            Ok(event) => debug!("MQTT event: {:?}", event),
+            Err(cause) => {
+                warn!(
+                    "MQTT subscription to '{}' interrupted; Reconnecting in {:?}: {}",
+                    filter, RECONNECT_DELAY, cause
+                );
+
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Long-lived connection the messages are published over, without waiting for them
+/// (reconnected by its event loop, polled in a spawned task).
+pub struct Publisher {
+    client: AsyncClient,
+}
+
+impl Publisher {
+    pub fn connect(options: MqttOptions) -> Publisher {
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(event) => debug!("MQTT event: {:?}", event),
+                    Err(ConnectionError::RequestsDone) => break, // Publisher dropped
+                    Err(cause) => {
+                        warn!(
+                            "MQTT connection interrupted; Reconnecting in {:?}: {}",
+                            RECONNECT_DELAY, cause
+                        );
+
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Publisher { client }
+    }
+
+    /// Queues the payload to be published on the topic.
+    pub async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), Error> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .map_err(Error::from)
+    }
+}
+
+/// Subscribes to the topic, and returns the payload of its retained message
+/// (if any received before `ORM_MQTT_TIMEOUT`).
+pub async fn retained(options: MqttOptions, topic: &str) -> Result<Option<Vec<u8>>, Error> {
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_MQTT_TIMEOUT",
+        setting!("ORM_MQTT_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    client.subscribe(topic, QoS::AtLeastOnce).await?;
+
+    info!("Waiting for retained message on MQTT topic '{}' ...", topic);
+
+    let received = tokio::time::timeout(timeout, async {
+        loop {
+            match eventloop.poll().await? {
+                Event::Incoming(Packet::Publish(publish)) if matches(topic, &publish.topic) => {
+                    debug!(
+                        "Received message on '{}' (retain = {})",
+                        publish.topic, publish.retain
+                    );
+
+                    return Ok::<Vec<u8>, Error>(publish.payload.to_vec());
+                }
+                event => debug!("MQTT event: {:?}", event),
+            }
+        }
+    })
+    .await;
+
+    let _ = client.disconnect().await;
+
+    match received {
+        Ok(payload) => payload.map(Some),
+        Err(_) => {
+            info!("No retained message on MQTT topic '{}'", topic);
+
+            Ok(None)
+        }
+    }
+}
+
+/// Publishes the payload on the topic, waiting for its acknowledgement
+/// (until `ORM_MQTT_TIMEOUT`).
+pub async fn publish(options: MqttOptions, topic: &str, payload: Vec<u8>) -> Result<(), Error> {
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_MQTT_TIMEOUT",
+        setting!("ORM_MQTT_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    client
+        .publish(topic, QoS::AtLeastOnce, false, payload)
+        .await?;
+
+    let acknowledged = tokio::time::timeout(timeout, async {
+        loop {
+            match eventloop.poll().await? {
+                Event::Incoming(Packet::PubAck(_)) => return Ok::<(), Error>(()),
+                event => debug!("MQTT event: {:?}", event),
+            }
+        }
+    })
+    .await;
+
+    let _ = client.disconnect().await;
+
+    match acknowledged {
+        Ok(res) => res,
+        Err(_) => Err(format_error!(
+            "Message on MQTT topic '{}' not acknowledged within {:?}",
+            topic,
+            timeout
+        )),
+    }
+}
+
+/// Publishes the request on the topic (connected as the client), once subscribed to its
+/// `accepted` and `rejected` response topics (as the AWS IoT reserved topics),
+/// returning the accepted response payload (received before `ORM_MQTT_TIMEOUT`).
+pub async fn request(client_id: &str, topic: &str, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let options = match options(client_id)? {
+        Some(options) => options,
+        None => return Err(format_error!("Missing ORM_MQTT_URL for topic {}", topic)),
+    };
+
+    let (accepted, rejected) = (format!("{}/accepted", topic), format!("{}/rejected", topic));
+    let filter = format!("{}/+", topic);
+
+    let (response_topic, response) = exchange(options, &filter, topic, payload, |t| {
+        t == accepted || t == rejected
+    })
+    .await?;
+
+    match response_topic == accepted {
+        true => Ok(response),
+        false => Err(format_error!(
+            "Request on MQTT topic '{}' rejected: {}",
+            topic,
+            String::from_utf8_lossy(&response)
+        )),
+    }
+}
+
+/// Publishes the payload on the topic, once subscribed to the response filter,
+/// returning the topic and payload of the first response it matches
+/// (received before `ORM_MQTT_TIMEOUT`).
+pub async fn exchange<F: Fn(&str) -> bool>(
+    options: MqttOptions,
+    filter: &str,
+    topic: &str,
+    payload: Vec<u8>,
+    matches: F,
+) -> Result<(String, Vec<u8>), Error> {
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_MQTT_TIMEOUT",
+        setting!("ORM_MQTT_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    client.subscribe(filter, QoS::AtLeastOnce).await?;
+
+    let response = tokio::time::timeout(timeout, async {
+        loop {
+            match eventloop.poll().await? {
+                Event::Incoming(Packet::SubAck(_)) => {
+                    debug!("Requesting on MQTT topic '{}'", topic);
+
+                    client
+                        .publish(topic, QoS::AtLeastOnce, false, payload.clone())
+                        .await?;
+                }
+                Event::Incoming(Packet::Publish(publish)) if matches(&publish.topic) => {
+                    return Ok::<_, Error>((publish.topic, publish.payload.to_vec()));
+                }
+                event => debug!("MQTT event: {:?}", event),
+            }
+        }
+    })
+    .await;
+
+    let _ = client.disconnect().await;
+
+    match response {
+        Ok(res) => res,
+        Err(_) => Err(format_error!(
+            "No response to MQTT topic '{}' within {:?}",
+            topic,
+            timeout
+        )),
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(cerr: ClientError) -> Error {
+        Error::new(format!("MQTT client error: {}", cerr))
+    }
+}
+
+impl From<ConnectionError> for Error {
+    fn from(cerr: ConnectionError) -> Error {
+        Error::new(format!("MQTT connection error: {}", cerr))
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publisher() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let publisher = Publisher::connect(MqttOptions::new("dev1-status", "127.0.0.1", port));
+
+        // Queued before the broker even accepts the connection
+        publisher
+            .publish("things/dev1/status", b"{}".to_vec())
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 256];
+            let read = stream.read(&mut buf).await.unwrap();
+
+            assert!(read > 0 && buf[0] == 0x10); // CONNECT
+
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap(); // CONNACK
+
+            let read = stream.read(&mut buf).await.unwrap();
+
+            buf[..read].to_vec()
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(received[0] & 0xf0, 0x30); // PUBLISH
+        assert!(received.ends_with(b"things/dev1/status\x00\x01{}"));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("things/dev1/manifest", "things/dev1/manifest"));
+        assert!(!matches("things/dev1/manifest", "things/dev2/manifest"));
+        assert!(!matches("things/dev1", "things/dev1/manifest"));
+        assert!(!matches("things/dev1/manifest", "things/dev1"));
+
+        assert!(matches("things/+/manifest", "things/dev1/manifest"));
+        assert!(matches("things/+/manifest", "things//manifest"));
+        assert!(!matches("things/+/manifest", "things/dev1/status"));
+        assert!(!matches("things/+", "things/dev1/manifest"));
+
+        assert!(matches("things/#", "things/dev1/manifest"));
+        assert!(matches("things/#", "things"));
+        assert!(matches("#", "things/dev1"));
+
+        assert!(!matches("#", "$aws/things/dev1"));
+        assert!(!matches("+/things/dev1", "$aws/things/dev1"));
+        assert!(matches("$aws/things/+", "$aws/things/dev1"));
+    }
+}
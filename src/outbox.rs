@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::error::Error;
+use crate::io::write_atomic;
+use crate::setting;
+
+/// Default size (in bytes) of the queue, over which the oldest entries are dropped.
+const DEFAULT_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Default duration (in seconds) between the attempts to forward the queued entries.
+const DEFAULT_RETRY_INTERVAL: u64 = 60;
+
+/// Sequence of the entries, to keep their order within the same millisecond.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Serializes the changes of the queue.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Bounded on-disk queue of the payloads (e.g. status reports) which cannot be delivered
+/// while offline, to be forwarded once the network returns.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// Queued payload.
+#[derive(Debug)]
+pub struct Entry {
+    path: PathBuf,
+    pub payload: Vec<u8>,
+}
+
+impl Outbox {
+    /// Returns the outbox (if `ORM_OUTBOX`) in `{ORM_STATE_DIR}/outbox`,
+    /// bounded to `ORM_OUTBOX_MAX_SIZE` bytes.
+    pub fn from_settings(local_prefix: &Path) -> Option<Outbox> {
+        if !config::parse_or("ORM_OUTBOX", setting!("ORM_OUTBOX"), false) {
+            return None;
+        }
+
+        Some(Outbox {
+            dir: config::state_dir(local_prefix).join("outbox"),
+            max_size: config::parse_or(
+                "ORM_OUTBOX_MAX_SIZE",
+                setting!("ORM_OUTBOX_MAX_SIZE"),
+                DEFAULT_MAX_SIZE,
+            ),
+        })
+    }
+
+    /// Queues the payload of the kind (e.g. `report`), then drops the oldest entries
+    /// over the maximum size; Returns how many are dropped.
+    pub fn push(&self, kind: &str, payload: &[u8]) -> Result<usize, Error> {
+        let _lock = LOCK.lock();
+
+        fs::create_dir_all(&self.dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let seq = SEQUENCE.fetch_add(1, Ordering::SeqCst) % 1_000_000;
+
+        write_atomic(
+            &self.dir.join(format!("{:013}-{:06}.{}", millis, seq, kind)),
+            payload,
+        )?;
+
+        let mut sizes = Vec::new();
+
+        for path in self.entries(None)? {
+            sizes.push((path.metadata()?.len(), path));
+        }
+
+        let mut size: u64 = sizes.iter().map(|(len, _)| len).sum();
+        let mut dropped = 0;
+
+        for (len, path) in sizes {
+            if size <= self.max_size {
+                break;
+            }
+
+            fs::remove_file(path)?;
+            size -= len;
+            dropped += 1;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Returns the oldest entry of the kind, if any.
+    pub fn next(&self, kind: &str) -> Result<Option<Entry>, Error> {
+        for path in self.entries(Some(kind))? {
+            match fs::read(&path) {
+                Ok(payload) => return Ok(Some(Entry { path, payload })),
+                Err(cause) if cause.kind() == ErrorKind::NotFound => continue, // Dropped meanwhile
+                Err(cause) => return Err(cause.into()),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Removes the entry, once forwarded.
+    pub fn remove(&self, entry: Entry) -> Result<(), Error> {
+        let _lock = LOCK.lock();
+
+        match fs::remove_file(&entry.path) {
+            Err(cause) if cause.kind() != ErrorKind::NotFound => Err(cause.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the paths of the entries (of the kind if specified), oldest first.
+    fn entries(&self, kind: Option<&str>) -> Result<Vec<PathBuf>, Error> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(cause) if cause.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(cause) => return Err(cause.into()),
+        };
+
+        let mut paths = Vec::new();
+
+        for entry in dir {
+            let path = entry?.path();
+            let queued = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+                !n.starts_with('.') && kind.is_none_or(|k| n.ends_with(&format!(".{}", k)))
+            });
+
+            if queued {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+}
+
+/// Returns the duration between the attempts to forward the queued entries
+/// (`ORM_OUTBOX_RETRY_INTERVAL`, in seconds).
+pub fn retry_interval() -> Duration {
+    Duration::from_secs(config::parse_or(
+        "ORM_OUTBOX_RETRY_INTERVAL",
+        setting!("ORM_OUTBOX_RETRY_INTERVAL"),
+        DEFAULT_RETRY_INTERVAL,
+    ))
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outbox = Outbox {
+            dir: tmp.path().join("outbox"),
+            max_size: 10,
+        };
+
+        assert!(outbox.next("report").unwrap().is_none());
+
+        assert_eq!(outbox.push("report", b"r1").unwrap(), 0);
+        assert_eq!(outbox.push("logs", b"l1").unwrap(), 0);
+        assert_eq!(outbox.push("report", b"r2").unwrap(), 0);
+
+        let entry = outbox.next("report").unwrap().unwrap();
+
+        assert_eq!(entry.payload, b"r1");
+
+        outbox.remove(entry).unwrap();
+
+        assert_eq!(outbox.next("report").unwrap().unwrap().payload, b"r2");
+
+        assert_eq!(outbox.push("logs", b"l2-bytes!").unwrap(), 2); // l1 & r2 dropped
+        assert!(outbox.next("report").unwrap().is_none());
+        assert_eq!(outbox.next("logs").unwrap().unwrap().payload, b"l2-bytes!");
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use tokio::process::Command;
+
+use crate::setting;
+
+/// Resource limits of the application cgroup, as the cgroup v2 interface files.
+#[derive(Debug, Default, PartialEq)]
+struct Limits {
+    /// `memory.max` (`ORM_MEMORY_MAX`), e.g. `268435456` or `256M`.
+    memory: Option<String>,
+
+    /// `cpu.max` (`ORM_CPU_MAX`), e.g. `50000 100000` for half a CPU.
+    cpu: Option<String>,
+
+    /// `pids.max` (`ORM_PIDS_MAX`).
+    pids: Option<String>,
+}
+
+impl Limits {
+    fn from_settings() -> Limits {
+        Limits {
+            memory: setting!("ORM_MEMORY_MAX"),
+            cpu: setting!("ORM_CPU_MAX"),
+            pids: setting!("ORM_PIDS_MAX"),
+        }
+    }
+
+    fn files(&self) -> Vec<(&'static str, &'static str, &String)> {
+        [
+            ("memory", "memory.max", &self.memory),
+            ("cpu", "cpu.max", &self.cpu),
+            ("pids", "pids.max", &self.pids),
+        ]
+        .into_iter()
+        .filter_map(|(controller, file, value)| value.as_ref().map(|v| (controller, file, v)))
+        .collect()
+    }
+}
+
+/// Places the command into the dedicated cgroup (`ORM_CGROUP`), if any,
+/// created with the configured limits; The returned file must be kept until it's spawned.
+pub fn place(command: &mut Command) -> Option<File> {
+    let dir = PathBuf::from(setting!("ORM_CGROUP")?);
+
+    let procs = match setup(&dir, &Limits::from_settings()) {
+        Ok(procs) => procs,
+        Err(cause) => {
+            warn!(
+                "Fails to setup the cgroup {:?}; Not limited: {}",
+                dir, cause
+            );
+
+            return None;
+        }
+    };
+
+    let fd = procs.as_raw_fd();
+
+    // Moves the process into the cgroup before exec, so it's limited from its start
+    unsafe {
+        command.pre_exec(move || {
+            if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    Some(procs)
+}
+
+/// Creates the cgroup (if not yet) and writes its limits,
+/// returning its opened `cgroup.procs` file.
+fn setup(dir: &Path, limits: &Limits) -> std::io::Result<File> {
+    fs::create_dir_all(dir)?;
+
+    let files = limits.files();
+
+    if let Some(parent) = dir.parent() {
+        let controllers = files
+            .iter()
+            .map(|(controller, _, _)| format!("+{}", controller))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        if !controllers.is_empty() {
+            // Possibly already enabled (or delegated)
+            if let Err(cause) = fs::write(parent.join("cgroup.subtree_control"), &controllers) {
+                debug!("Fails to enable the controllers {}: {}", controllers, cause);
+            }
+        }
+    }
+
+    for (_, file, value) in files {
+        debug!("Cgroup limit {} = {}", file, value);
+
+        fs::write(dir.join(file), value)?;
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .open(dir.join("cgroup.procs"))
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("app");
+        let limits = Limits {
+            memory: Some("256M".to_string()),
+            pids: Some("64".to_string()),
+            ..Limits::default()
+        };
+
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("cgroup.procs"), "").unwrap();
+
+        assert!(setup(&dir, &limits).is_ok());
+
+        assert_eq!(fs::read_to_string(dir.join("memory.max")).unwrap(), "256M");
+        assert_eq!(fs::read_to_string(dir.join("pids.max")).unwrap(), "64");
+        assert!(!dir.join("cpu.max").exists());
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("cgroup.subtree_control")).unwrap(),
+            "+memory +pids"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::path::Path;
+
+use log::debug;
+
+use crate::setting;
+
+/// Loads the variables of the environment file of the application
+/// (`ORM_ENV_FILE`, relative to the application directory; default: `.env`, if any).
+pub fn load(app_dir: &Path) -> std::io::Result<Vec<(String, String)>> {
+    let path = match setting!("ORM_ENV_FILE") {
+        Some(file) => app_dir.join(file),
+        None => app_dir.join(".env"),
+    };
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(cause) => return Err(cause),
+    };
+
+    debug!("Loading environment file {:?}", path);
+
+    parse(&content).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid environment file {:?}: {}", path, err),
+        )
+    })
+}
+
+/// Parses the `KEY=value` lines (optionally `export`ed, with the value possibly quoted),
+/// skipping the blank ones and the `#` comments.
+fn parse(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Missing '=' at line {}", i + 1))?;
+
+        let key = key.trim();
+
+        if key.is_empty()
+            || key.starts_with(|c: char| c.is_ascii_digit())
+            || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("Invalid name '{}' at line {}", key, i + 1));
+        }
+
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) if value.len() >= 2 && value.ends_with(quote) => {
+                &value[1..value.len() - 1]
+            }
+            _ => value,
+        };
+
+        vars.push((key.to_string(), value.to_string()));
+    }
+
+    Ok(vars)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content =
+            "# Settings\n\nFOO=bar\nexport BAR = \"lorem ipsum\"\nEMPTY=\nURL='http://x?a=b'\n";
+
+        assert_eq!(
+            parse(content).unwrap(),
+            vec![
+                ("FOO".to_string(), "bar".to_string()),
+                ("BAR".to_string(), "lorem ipsum".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+                ("URL".to_string(), "http://x?a=b".to_string()),
+            ]
+        );
+
+        assert!(parse("FOO").is_err());
+        assert!(parse("1FOO=bar").is_err());
+        assert!(parse("FOO BAR=baz").is_err());
+    }
+}
@@ -0,0 +1,46 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use log::debug;
+
+use tokio::process::Command;
+
+use crate::config;
+use crate::setting;
+
+/// Default data directory of the application (relative to its directory), kept writable.
+const DEFAULT_DATA_DIR: &str = "data";
+
+/// Script run in the new namespaces given the application and data directories,
+/// then the command: the application directory is bind-mounted read-only over itself
+/// (except the data directory, created if missing), before the command is run
+/// (not as init, so it's signaled as usual).
+const MOUNT_SCRIPT: &str = r#"app="$1"; data="$2"; shift 2
+mkdir -p "$data" || exit 1
+mount --bind "$app" "$app" && mount -o remount,bind,ro "$app" || exit 1
+mount --bind "$data" "$data" && mount -o remount,bind,rw "$data" || exit 1
+"$@""#;
+
+/// Returns the command running the program, in new mount and PID namespaces
+/// if the application is isolated (`ORM_ISOLATE`).
+pub fn command<S: AsRef<OsStr>>(app_dir: &Path, program: S) -> Command {
+    if !config::parse_or("ORM_ISOLATE", setting!("ORM_ISOLATE"), false) {
+        return Command::new(program);
+    }
+
+    let data =
+        app_dir.join(setting!("ORM_ISOLATE_DATA").unwrap_or_else(|| DEFAULT_DATA_DIR.to_string()));
+
+    debug!("Isolating {:?} (writable {:?})", app_dir, data);
+
+    let mut command = Command::new("unshare");
+
+    command
+        .args(["--mount", "--pid", "--fork", "--mount-proc"])
+        .args(["/bin/sh", "-c", MOUNT_SCRIPT, "sh"])
+        .arg(app_dir)
+        .arg(data)
+        .arg(program);
+
+    command
+}
@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+
+use log::debug;
+
+use tokio::process::Command;
+
+use crate::io::write_atomic;
+use crate::setting;
+
+/// Default launchd domain of the service.
+const DEFAULT_DOMAIN: &str = "system";
+
+/// Default directory of the property list of the service.
+const DEFAULT_DIR: &str = "/Library/LaunchDaemons";
+
+/// Script (re)starting the service given its domain, label and property list,
+/// then watching it as long as it's running: it's booted out once exited (or this script
+/// terminated), with its last exit code.
+const WATCH_SCRIPT: &str = r#"target="$1/$2"
+launchctl bootout "$target" 2>/dev/null
+launchctl bootstrap "$1" "$3" || exit 1
+trap 'launchctl bootout "$target"; exit 143' TERM INT
+launchctl kickstart -k "$target" || exit 1
+while launchctl print "$target" | grep -q 'state = running'; do sleep 1; done
+code=$(launchctl print "$target" | sed -n 's/.*last exit code = \([0-9]*\).*/\1/p')
+launchctl bootout "$target" 2>/dev/null
+exit "${code:-0}""#;
+
+/// Returns the label of the launchd service the application is run as
+/// (`ORM_LAUNCHD_LABEL`), if enabled.
+pub fn label() -> Option<String> {
+    setting!("ORM_LAUNCHD_LABEL")
+}
+
+/// Installs (or updates) the property list of the service running the command
+/// (in `ORM_LAUNCHD_DIR`), returning the command bouncing then watching the service
+/// (in `ORM_LAUNCHD_DOMAIN`).
+pub fn service(label: &str, command: &Command) -> std::io::Result<Command> {
+    let domain = setting!("ORM_LAUNCHD_DOMAIN").unwrap_or_else(|| DEFAULT_DOMAIN.to_string());
+    let path = setting!("ORM_LAUNCHD_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR))
+        .join(format!("{}.plist", label));
+
+    debug!("Installing launchd service {:?}", path);
+
+    write_atomic(&path, plist(label, command).as_bytes())?;
+
+    let mut watch = Command::new("/bin/sh");
+
+    watch
+        .arg("-c")
+        .arg(WATCH_SCRIPT)
+        .arg("sh")
+        .arg(&domain)
+        .arg(label)
+        .arg(&path);
+
+    Ok(watch)
+}
+
+/// Returns the property list of the service, as the command
+/// (program, arguments, working directory and environment).
+fn plist(label: &str, command: &Command) -> String {
+    let command = command.as_std();
+    let string =
+        |value: &std::ffi::OsStr| format!("<string>{}</string>", escape(&value.to_string_lossy()));
+
+    let mut plist = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+        "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n<dict>\n"
+    ));
+
+    plist.push_str(&format!(
+        "  <key>Label</key>\n  <string>{}</string>\n",
+        escape(label)
+    ));
+
+    plist.push_str("  <key>ProgramArguments</key>\n  <array>\n");
+    plist.push_str(&format!("    {}\n", string(command.get_program())));
+
+    for arg in command.get_args() {
+        plist.push_str(&format!("    {}\n", string(arg)));
+    }
+
+    plist.push_str("  </array>\n");
+
+    if let Some(dir) = command.get_current_dir() {
+        plist.push_str(&format!(
+            "  <key>WorkingDirectory</key>\n  {}\n",
+            string(dir.as_os_str())
+        ));
+    }
+
+    plist.push_str("  <key>EnvironmentVariables</key>\n  <dict>\n");
+
+    for (name, value) in command.get_envs() {
+        if let Some(value) = value {
+            plist.push_str(&format!(
+                "    <key>{}</key>\n    {}\n",
+                escape(&name.to_string_lossy()),
+                string(value)
+            ));
+        }
+    }
+
+    // Restarted by orm, according its policy
+    plist.push_str(concat!(
+        "  </dict>\n",
+        "  <key>RunAtLoad</key>\n  <false/>\n",
+        "  <key>KeepAlive</key>\n  <false/>\n",
+        "</dict>\n</plist>\n"
+    ));
+
+    plist
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plist() {
+        let mut command = Command::new("/opt/foo/bin/server");
+
+        command
+            .arg("--name=a&b")
+            .current_dir("/opt/foo")
+            .env("ORM_APP_VERSION", "1.2.3");
+
+        let plist = plist("com.example.foo", &command);
+
+        assert!(plist.contains("<key>Label</key>\n  <string>com.example.foo</string>"));
+        assert!(plist.contains(concat!(
+            "<array>\n    <string>/opt/foo/bin/server</string>\n",
+            "    <string>--name=a&amp;b</string>\n  </array>"
+        )));
+        assert!(plist.contains("<key>WorkingDirectory</key>\n  <string>/opt/foo</string>"));
+        assert!(plist.contains("<key>ORM_APP_VERSION</key>\n    <string>1.2.3</string>"));
+    }
+}
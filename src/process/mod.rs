@@ -0,0 +1,790 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path};
+use std::process::{ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+
+mod cgroup;
+mod env;
+mod isolate;
+mod launchd;
+pub mod monitor;
+mod output;
+pub mod procfile;
+pub mod restart;
+mod sandbox;
+
+use crate::config;
+use crate::ipc;
+use crate::logging;
+use crate::setting;
+use crate::update::health;
+use crate::update::marker::Marker;
+
+/// Default entrypoint of the application, relative to its directory.
+pub const DEFAULT_ENTRYPOINT: &str = "run.sh";
+
+/// Default duration (in seconds) a stopped application has to terminate, before being killed.
+const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+/// Process groups of the supervised applications.
+static GROUPS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+/// Signal requesting the shutdown (`0` if none).
+static SHUTDOWN: AtomicI32 = AtomicI32::new(0);
+
+/// Duration a stopped application has to terminate (`ORM_STOP_TIMEOUT`).
+fn stop_timeout() -> Duration {
+    Duration::from_secs(config::parse_or(
+        "ORM_STOP_TIMEOUT",
+        setting!("ORM_STOP_TIMEOUT"),
+        DEFAULT_STOP_TIMEOUT,
+    ))
+}
+
+/// Maximum runtime of the application in one-shot mode (`ORM_RUN_TIMEOUT`),
+/// if any (not in daemon mode, with a restart policy).
+pub fn run_timeout() -> Option<Duration> {
+    if restart::Policy::from_settings() != restart::Policy::Never {
+        return None;
+    }
+
+    let timeout = config::parse_or("ORM_RUN_TIMEOUT", setting!("ORM_RUN_TIMEOUT"), 0);
+
+    Some(Duration::from_secs(timeout)).filter(|t| !t.is_zero())
+}
+
+/// Returns the signal the shutdown has been requested with, if any.
+pub fn shutdown() -> Option<i32> {
+    match SHUTDOWN.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Handles `SIGTERM` and `SIGINT`, forwarding them to the supervised applications,
+/// killed if not terminated within `ORM_STOP_TIMEOUT`;
+/// Without any application running, orm exits right away.
+pub fn handle_signals() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    tokio::spawn(async move {
+        let signal = tokio::select! {
+            _ = terminate.recv() => libc::SIGTERM,
+            _ = interrupt.recv() => libc::SIGINT,
+        };
+
+        SHUTDOWN.store(signal, Ordering::SeqCst);
+
+        let groups = running();
+
+        if groups.is_empty() {
+            info!("Shutting down on signal {}", signal);
+
+            logging::shutdown().await;
+            std::process::exit(128 + signal);
+        }
+
+        info!(
+            "Forwarding signal {} to the application (process groups {:?})",
+            signal, groups
+        );
+
+        for pgid in &groups {
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+
+        tokio::time::sleep(stop_timeout()).await;
+
+        for pgid in running() {
+            warn!("Killing process group {} not terminated", pgid);
+
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Default variables kept from the environment of orm, when cleared (`ORM_ENV_CLEAR`).
+const DEFAULT_ENV_ALLOWLIST: &str = "PATH,HOME,USER,LANG,TZ";
+
+/// Returns the entrypoint of the application, as the program and its arguments: either declared
+/// in the artifact metadata (as recorded in its version marker), or configured (`ORM_ENTRYPOINT`,
+/// whitespace separated), or else its `run.sh` script.
+pub fn entrypoint(marker: Option<&Marker>) -> Vec<String> {
+    let declared = marker
+        .and_then(|m| m.entrypoint.clone())
+        .filter(|e| !e.is_empty());
+
+    declared
+        .or_else(|| {
+            setting!("ORM_ENTRYPOINT")
+                .map(|e| {
+                    e.split_whitespace()
+                        .map(String::from)
+                        .collect::<Vec<String>>()
+                })
+                .filter(|e| !e.is_empty())
+        })
+        .unwrap_or_else(|| vec![DEFAULT_ENTRYPOINT.to_string()])
+}
+
+/// Returns whether the program of a declared entrypoint (see `Marker`) is inside
+/// the application directory: relative, without `..`.
+pub fn contained(program: &str) -> bool {
+    !program.is_empty()
+        && Path::new(program)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Returns whether the application can be started from the directory:
+/// either its `Procfile` or its entrypoint is there.
+pub fn runnable(app_dir: &Path, marker: Option<&Marker>) -> bool {
+    app_dir.join(procfile::PROCFILE).is_file() || app_dir.join(&entrypoint(marker)[0]).is_file()
+}
+
+/// Returns the commands running the application, by process name: either the ones declared in
+/// its `Procfile` (executed with `sh -c` from the application directory, given `ORM_PROCESS`),
+/// or else its entrypoint (relative to its directory unless absolute), possibly run as
+/// a launchd service (see `launchd`).
+pub fn commands(
+    app_dir: &Path,
+    marker: Option<&Marker>,
+) -> std::io::Result<Vec<(String, Command)>> {
+    if let Some(label) = launchd::label() {
+        let service = launchd::service(&label, &command(app_dir, marker)?)?;
+
+        return Ok(vec![("app".to_string(), service)]);
+    }
+
+    let processes = match procfile::load(app_dir)? {
+        Some(processes) => processes,
+        None => return Ok(vec![("app".to_string(), command(app_dir, marker)?)]),
+    };
+
+    processes
+        .into_iter()
+        .map(|(name, line)| {
+            debug!("Process {} of {:?}: {}", name, app_dir, line);
+
+            let mut command = isolate::command(app_dir, "/bin/sh");
+
+            command.arg("-c").arg(line);
+
+            configure(&mut command, app_dir, marker)?;
+
+            command.current_dir(app_dir).env("ORM_PROCESS", &name);
+
+            Ok((name, command))
+        })
+        .collect()
+}
+
+fn command(app_dir: &Path, marker: Option<&Marker>) -> std::io::Result<Command> {
+    let entrypoint = entrypoint(marker);
+
+    debug!("Entrypoint of {:?}: {:?}", app_dir, entrypoint);
+
+    let declared = marker.and_then(|m| m.entrypoint.as_ref());
+
+    if let Some(program) = declared.and_then(|e| e.first()) {
+        if !contained(program) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Entrypoint outside of the application directory: {}",
+                    program
+                ),
+            ));
+        }
+    }
+
+    let mut command = isolate::command(app_dir, app_dir.join(&entrypoint[0]));
+
+    command.args(&entrypoint[1..]);
+
+    configure(&mut command, app_dir, marker)?;
+
+    Ok(command)
+}
+
+/// Configures the command with the variables `ORM_APP_DIR`, `ORM_APP_VERSION` (if known),
+/// `ORM_RUN_ID` and `ORM_CONTROL_SOCKET` (if enabled), then the ones of the environment file (if any); The environment of orm
+/// is inherited, unless cleared (`ORM_ENV_CLEAR`) but the allowed variables
+/// (`ORM_ENV_ALLOWLIST`), and the working directory too, unless it's the application directory
+/// (`ORM_APP_WORKDIR`).
+fn configure(
+    command: &mut Command,
+    app_dir: &Path,
+    marker: Option<&Marker>,
+) -> std::io::Result<()> {
+    if config::parse_or("ORM_ENV_CLEAR", setting!("ORM_ENV_CLEAR"), false) {
+        let allowlist = setting!("ORM_ENV_ALLOWLIST").unwrap_or(DEFAULT_ENV_ALLOWLIST.to_string());
+
+        command.env_clear();
+
+        for name in allowlist.split(',').map(str::trim) {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
+    if config::parse_or("ORM_APP_WORKDIR", setting!("ORM_APP_WORKDIR"), false) {
+        command.current_dir(app_dir);
+    }
+
+    command
+        .env("ORM_APP_DIR", app_dir)
+        .env("ORM_RUN_ID", logging::run_id());
+
+    if let Some(path) = ipc::control_socket() {
+        command.env("ORM_CONTROL_SOCKET", path);
+    }
+
+    if let Some(marker) = marker {
+        command.env("ORM_APP_VERSION", &marker.version);
+    }
+
+    command.envs(env::load(app_dir)?);
+
+    Ok(())
+}
+
+/// Started application: its processes, all in the process group of the first one.
+#[derive(Debug)]
+pub struct Application {
+    pgid: Option<i32>,
+    processes: Vec<(String, Child)>,
+
+    /// Whether the processes are stopped one by one, in the reverse order of their start
+    /// (as they have dependencies).
+    ordered: bool,
+}
+
+impl Application {
+    /// Returns the process group of the application.
+    pub fn id(&self) -> Option<u32> {
+        self.pgid.map(|pgid| pgid as u32)
+    }
+
+    /// Returns the exit status of the first exited process, if any.
+    pub fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        for (name, child) in self.processes.iter_mut() {
+            if let Some(status) = child.try_wait()? {
+                debug!("Process {} exited: {}", name, status);
+
+                return Ok(Some(status));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Waits the first exited process.
+    async fn first_exit(&mut self) -> std::io::Result<(String, ExitStatus)> {
+        let mut waits = self
+            .processes
+            .iter_mut()
+            .map(|(name, child)| (name.clone(), Box::pin(child.wait())))
+            .collect::<Vec<_>>();
+
+        std::future::poll_fn(|cx| {
+            for (name, wait) in waits.iter_mut() {
+                if let Poll::Ready(res) = wait.as_mut().poll(cx) {
+                    return Poll::Ready(res.map(|status| (name.clone(), status)));
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Waits all the processes.
+    async fn reap(&mut self) -> std::io::Result<()> {
+        for (_, child) in self.processes.iter_mut() {
+            child.wait().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops the running processes, terminated then killed after `ORM_STOP_TIMEOUT`.
+    async fn stop_all(&mut self) -> std::io::Result<()> {
+        if self.ordered {
+            for (name, child) in self.processes.iter_mut().rev() {
+                if child.try_wait()?.is_some() {
+                    continue;
+                }
+
+                let pid = match child.id() {
+                    Some(pid) => pid as i32,
+                    None => continue,
+                };
+
+                debug!("Terminating process {} ({})", name, pid);
+
+                unsafe {
+                    libc::kill(pid, libc::SIGTERM);
+                }
+
+                if tokio::time::timeout(stop_timeout(), child.wait())
+                    .await
+                    .is_err()
+                {
+                    warn!("Killing process {} not terminated", name);
+
+                    unsafe {
+                        libc::kill(pid, libc::SIGKILL);
+                    }
+
+                    child.wait().await?;
+                }
+            }
+        }
+
+        // Whole process group, with the possible descendants
+        if !self.exited()? {
+            self.signal(libc::SIGTERM);
+
+            if tokio::time::timeout(stop_timeout(), self.reap())
+                .await
+                .is_err()
+            {
+                warn!("Killing process group {:?} not terminated", self.pgid);
+
+                self.signal(libc::SIGKILL);
+                self.reap().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn exited(&mut self) -> std::io::Result<bool> {
+        for (_, child) in self.processes.iter_mut() {
+            if child.try_wait()?.is_none() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn signal(&self, signal: i32) {
+        if let Some(pgid) = self.pgid {
+            unsafe {
+                libc::kill(-pgid, signal);
+            }
+        }
+    }
+
+    fn release(&self) {
+        if let Some(pgid) = self.pgid {
+            release(pgid);
+        }
+    }
+}
+
+/// Starts the application processes in their own process group (and cgroup if any),
+/// sandboxed if enabled (`ORM_SANDBOX`), supervised until waited (their output being captured if enabled);
+/// A process with dependencies (`ORM_PROCESS_DEPENDENCIES`) is only started once they are ready,
+/// passing their probe (`healthcheck-{name}.sh` script, if any).
+pub async fn start(
+    app_dir: &Path,
+    commands: Vec<(String, Command)>,
+) -> std::io::Result<Application> {
+    let named = commands.len() > 1;
+    let dependencies = if named {
+        procfile::dependencies().map_err(std::io::Error::other)?
+    } else {
+        HashMap::new()
+    };
+
+    let mut app = Application {
+        pgid: None,
+        processes: Vec::new(),
+        ordered: !dependencies.is_empty(),
+    };
+
+    let mut ready: Vec<&str> = Vec::new();
+
+    for (name, mut command) in commands {
+        for dependency in dependencies.get(&name).into_iter().flatten() {
+            if ready.contains(&dependency.as_str()) {
+                continue;
+            }
+
+            if let Some(probe) = health::process_probe(app_dir, dependency) {
+                if let Err(cause) = health::ready(&probe, app_dir, dependency, &mut app).await {
+                    let _ = kill(&mut app).await;
+
+                    return Err(cause);
+                }
+            }
+
+            ready.push(dependency);
+        }
+
+        output::pipe(&mut command);
+
+        let cgroup = cgroup::place(&mut command);
+        let spawned = sandbox::confine(&mut command, app_dir).and_then(|sandbox| {
+            let spawned = command.process_group(app.pgid.unwrap_or(0)).spawn();
+
+            drop(sandbox);
+            spawned
+        });
+
+        drop(cgroup);
+
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(cause) => {
+                // Not left partially started
+                app.signal(libc::SIGKILL);
+                app.release();
+
+                return Err(std::io::Error::new(
+                    cause.kind(),
+                    format!("Fails to start process {}: {}", name, cause),
+                ));
+            }
+        };
+
+        output::capture(&mut child, Some(name.as_str()).filter(|_| named));
+
+        if app.pgid.is_none() {
+            app.pgid = child.id().map(|pid| pid as i32);
+
+            if let (Some(pgid), Ok(mut groups)) = (app.pgid, GROUPS.lock()) {
+                groups.push(pgid);
+            }
+        }
+
+        if named {
+            info!("Process {} started: {:?}", name, child.id());
+        }
+
+        app.processes.push((name, child));
+    }
+
+    Ok(app)
+}
+
+/// Waits the termination of the supervised application: once a process exits,
+/// the other ones are stopped (terminated then killed after `ORM_STOP_TIMEOUT`).
+pub async fn wait(app: &mut Application) -> std::io::Result<ExitStatus> {
+    let (name, status) = app.first_exit().await?;
+
+    if !app.exited()? {
+        info!(
+            "Process {} exited ({}); Stopping the other ones",
+            name, status
+        );
+
+        app.stop_all().await?;
+    }
+
+    app.release();
+
+    if let Some(signal) = shutdown() {
+        info!("Application stopped on signal {}: {}", signal, status);
+    }
+
+    Ok(status)
+}
+
+/// Waits the termination of the supervised application,
+/// killed if still running after the timeout (if any), then returning `None`.
+pub async fn wait_timeout(
+    app: &mut Application,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return wait(app).await.map(Some),
+    };
+
+    if let Ok(status) = tokio::time::timeout(timeout, wait(app)).await {
+        return status.map(Some);
+    }
+
+    error!("Killing the application still running after {:?}", timeout);
+
+    kill(app).await.map(|_| None)
+}
+
+/// Executes the command, collecting its output (as `std::process::Command::output`),
+/// but killed with its process group if not terminated within the timeout.
+pub fn output_timeout(
+    command: &mut std::process::Command,
+    timeout: Duration,
+) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+
+    while child.try_wait()?.is_none() {
+        if Instant::now() >= deadline {
+            unsafe {
+                libc::kill(-(child.id() as i32), libc::SIGKILL);
+            }
+
+            let _ = child.wait();
+
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("Not terminated within {:?}", timeout),
+            ));
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let join = |reader: std::thread::JoinHandle<std::io::Result<Vec<u8>>>| {
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("Output reader panicked")))
+    };
+
+    Ok(Output {
+        status: child.wait()?,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Reads the pipe (if any) to its end on a thread,
+/// so the child isn't blocked once the pipe buffer is full.
+fn drain<R: Read + Send + 'static>(
+    pipe: Option<R>,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut output)?;
+        }
+
+        Ok(output)
+    })
+}
+
+/// Stops the supervised application, terminated then killed after `ORM_STOP_TIMEOUT`.
+pub async fn stop(app: &mut Application) -> std::io::Result<()> {
+    if app.exited()? {
+        return Ok(());
+    }
+
+    debug!("Stopping process group {:?}", app.pgid);
+
+    app.stop_all().await?;
+    app.release();
+
+    Ok(())
+}
+
+/// Terminates the process group, killed if still running after `ORM_STOP_TIMEOUT`.
+async fn terminate(pgid: i32) {
+    debug!("Terminating process group {}", pgid);
+
+    unsafe {
+        libc::kill(-pgid, libc::SIGTERM);
+    }
+
+    tokio::time::sleep(stop_timeout()).await;
+
+    if unsafe { libc::kill(-pgid, 0) } == 0 {
+        warn!("Killing process group {} not terminated", pgid);
+
+        unsafe {
+            libc::kill(-pgid, libc::SIGKILL);
+        }
+    }
+}
+
+/// Returns whether an application is running.
+pub fn is_running() -> bool {
+    !running().is_empty()
+}
+
+/// Sends the signal to the running applications.
+pub fn signal_all(signal: i32) {
+    for pgid in running() {
+        unsafe {
+            libc::kill(-pgid, signal);
+        }
+    }
+}
+
+/// Terminates the running applications (e.g. to be restarted).
+pub async fn terminate_all() {
+    for pgid in running() {
+        terminate(pgid).await;
+    }
+}
+
+/// Kills the supervised application (its whole process group).
+pub async fn kill(app: &mut Application) -> std::io::Result<()> {
+    if !app.exited()? {
+        app.signal(libc::SIGKILL);
+    }
+
+    app.reap().await?;
+    app.release();
+
+    Ok(())
+}
+
+/// Returns the process groups still running (the exited ones being released).
+fn running() -> Vec<i32> {
+    let mut groups = match GROUPS.lock() {
+        Ok(groups) => groups,
+        Err(_) => return Vec::new(),
+    };
+
+    groups.retain(|pgid| unsafe { libc::kill(-pgid, 0) } == 0);
+
+    groups.clone()
+}
+
+fn release(pgid: i32) {
+    if let Ok(mut groups) = GROUPS.lock() {
+        groups.retain(|g| *g != pgid);
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        std::fs::write(tmp.path().join(".env"), "FOO=bar\n").unwrap();
+
+        let mut marker = Marker {
+            version: "1.2.0".to_string(),
+            ..Marker::default()
+        };
+
+        let command = command(tmp.path(), Some(&marker)).unwrap();
+        let envs = command
+            .as_std()
+            .get_envs()
+            .filter_map(|(k, v)| Some((k.to_str()?, v?.to_str()?)))
+            .collect::<Vec<(&str, &str)>>();
+
+        assert_eq!(command.as_std().get_program(), tmp.path().join("run.sh"));
+        assert!(envs.contains(&("ORM_APP_VERSION", "1.2.0")));
+        assert!(envs.contains(&("ORM_APP_DIR", tmp.path().to_str().unwrap())));
+        assert!(envs.contains(&("FOO", "bar")));
+
+        // Declared entrypoint
+        marker.entrypoint = Some(vec!["bin/app".to_string(), "--port=8080".to_string()]);
+
+        let declared = super::command(tmp.path(), Some(&marker)).unwrap();
+
+        assert_eq!(declared.as_std().get_program(), tmp.path().join("bin/app"));
+        assert_eq!(
+            declared.as_std().get_args().collect::<Vec<_>>(),
+            vec!["--port=8080"]
+        );
+
+        marker.entrypoint = Some(vec!["/bin/sh".to_string(), "-c".to_string()]);
+
+        assert!(super::command(tmp.path(), Some(&marker)).is_err());
+    }
+
+    #[test]
+    fn test_contained() {
+        assert!(contained("run.sh"));
+        assert!(contained("./bin/app"));
+
+        assert!(!contained(""));
+        assert!(!contained("/bin/sh"));
+        assert!(!contained("../bin/sh"));
+        assert!(!contained("bin/../../sh"));
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        std::fs::write(
+            tmp.path().join("Procfile"),
+            "web: sleep 30\nworker: exit 3\n",
+        )
+        .unwrap();
+
+        let mut app = start(tmp.path(), commands(tmp.path(), None).unwrap())
+            .await
+            .unwrap();
+        let started = Instant::now();
+
+        // Other processes stopped once one exits
+        assert_eq!(wait(&mut app).await.unwrap().code(), Some(3));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(app.exited().unwrap());
+    }
+
+    #[test]
+    fn test_output_timeout() {
+        let timeout = Duration::from_secs(1);
+
+        let output =
+            output_timeout(std::process::Command::new("echo").arg("foo"), timeout).unwrap();
+
+        assert_eq!(output.stdout, b"foo\n");
+
+        // More than the pipe buffer
+        let large = output_timeout(
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg("head -c 1048576 /dev/zero; echo bar >&2"),
+            timeout,
+        )
+        .unwrap();
+
+        assert!(large.status.success());
+        assert_eq!(large.stdout.len(), 1048576);
+        assert_eq!(large.stderr, b"bar\n");
+
+        let hung = output_timeout(std::process::Command::new("sleep").arg("5"), timeout);
+
+        assert_eq!(
+            hung.map_err(|err| err.kind()).unwrap_err(),
+            std::io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use serde::Serialize;
+
+use tokio::task::JoinHandle;
+
+use crate::config;
+use crate::setting;
+use crate::update::status;
+
+/// Default duration (in seconds) a threshold must be exceeded for the application to be restarted.
+const DEFAULT_THRESHOLD_DURATION: u64 = 60;
+
+/// Whether a restart of the application has been requested, over a threshold.
+static RESTART: AtomicBool = AtomicBool::new(false);
+
+/// Resource usage of the application (its whole process group).
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Usage {
+    /// CPU usage since the previous sample, in percent of a CPU.
+    pub cpu: f64,
+
+    /// Resident memory, in bytes.
+    pub rss: u64,
+
+    /// Opened file descriptors.
+    pub fds: usize,
+
+    pub processes: usize,
+}
+
+/// Monitoring of the application, stopped once dropped.
+#[derive(Debug)]
+pub struct Monitor {
+    task: JoinHandle<()>,
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Thresholds of the resource usage the application is restarted over, for a duration
+/// (`ORM_RESTART_THRESHOLD_DURATION`); `0` for none.
+#[derive(Debug, Default)]
+struct Thresholds {
+    /// `ORM_RESTART_RSS_MAX` (bytes)
+    rss: u64,
+
+    /// `ORM_RESTART_CPU_MAX` (percent)
+    cpu: f64,
+
+    /// `ORM_RESTART_FDS_MAX`
+    fds: usize,
+
+    duration: Duration,
+}
+
+impl Thresholds {
+    fn from_settings() -> Thresholds {
+        Thresholds {
+            rss: config::parse_or("ORM_RESTART_RSS_MAX", setting!("ORM_RESTART_RSS_MAX"), 0),
+            cpu: config::parse_or("ORM_RESTART_CPU_MAX", setting!("ORM_RESTART_CPU_MAX"), 0.0),
+            fds: config::parse_or("ORM_RESTART_FDS_MAX", setting!("ORM_RESTART_FDS_MAX"), 0),
+            duration: Duration::from_secs(config::parse_or(
+                "ORM_RESTART_THRESHOLD_DURATION",
+                setting!("ORM_RESTART_THRESHOLD_DURATION"),
+                DEFAULT_THRESHOLD_DURATION,
+            )),
+        }
+    }
+
+    /// Returns the description of the exceeded thresholds, if any.
+    fn exceeded(&self, usage: &Usage) -> Option<String> {
+        let mut exceeded = Vec::new();
+
+        if self.rss > 0 && usage.rss > self.rss {
+            exceeded.push(format!("RSS {} > {} bytes", usage.rss, self.rss));
+        }
+
+        if self.cpu > 0.0 && usage.cpu > self.cpu {
+            exceeded.push(format!("CPU {:.1}% > {}%", usage.cpu, self.cpu));
+        }
+
+        if self.fds > 0 && usage.fds > self.fds {
+            exceeded.push(format!("{} > {} file descriptors", usage.fds, self.fds));
+        }
+
+        Some(exceeded.join(", ")).filter(|e| !e.is_empty())
+    }
+}
+
+/// Returns (and resets) whether a restart of the application has been requested.
+pub fn take_restart() -> bool {
+    RESTART.swap(false, Ordering::SeqCst)
+}
+
+/// Returns whether a restart of the application has been requested.
+pub fn restart_requested() -> bool {
+    RESTART.load(Ordering::SeqCst)
+}
+
+/// Interval between the samples of the resource usage (`ORM_MONITOR_INTERVAL`), if enabled.
+fn interval() -> Option<Duration> {
+    let secs = config::parse_or("ORM_MONITOR_INTERVAL", setting!("ORM_MONITOR_INTERVAL"), 0);
+
+    Some(Duration::from_secs(secs)).filter(|i| !i.is_zero())
+}
+
+/// Periodically samples the resource usage of the application started with the process group,
+/// logged and reported as `usage` status event (if `ORM_MONITOR_REPORT`);
+/// Over a threshold for its duration, the application is stopped to be restarted.
+pub fn watch(pgid: Option<u32>, app_dir: PathBuf) -> Option<Monitor> {
+    let interval = interval()?;
+    let pgid = pgid? as i32;
+    let report = config::parse_or("ORM_MONITOR_REPORT", setting!("ORM_MONITOR_REPORT"), false);
+    let thresholds = Thresholds::from_settings();
+
+    let task = tokio::spawn(async move {
+        let mut sampler = Sampler::new(pgid);
+        let mut exceeded_since: Option<Instant> = None;
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let usage = sampler.sample();
+
+            if usage.processes == 0 {
+                return;
+            }
+
+            info!(
+                "Application usage: CPU {:.1}%, RSS {} bytes, {} file descriptors, {} processes",
+                usage.cpu, usage.rss, usage.fds, usage.processes
+            );
+
+            let exceeded = thresholds.exceeded(&usage);
+
+            if report {
+                let event =
+                    status::Event::new(&app_dir, "usage", "info", "Resource usage".to_string())
+                        .with_usage(usage.clone());
+
+                status::report(&app_dir, &event).await;
+            }
+
+            let exceeded = match exceeded {
+                Some(exceeded) => exceeded,
+                None => {
+                    exceeded_since = None;
+                    continue;
+                }
+            };
+
+            let since = *exceeded_since.get_or_insert_with(Instant::now);
+
+            if since.elapsed() < thresholds.duration {
+                debug!(
+                    "Threshold exceeded since {:?}: {}",
+                    since.elapsed(),
+                    exceeded
+                );
+                continue;
+            }
+
+            warn!(
+                "Restarting the application over threshold for {:?}: {}",
+                thresholds.duration, exceeded
+            );
+
+            let event =
+                status::Event::new(&app_dir, "threshold", "warning", exceeded).with_usage(usage);
+
+            status::report(&app_dir, &event).await;
+
+            RESTART.store(true, Ordering::SeqCst);
+
+            super::terminate(pgid).await;
+
+            return;
+        }
+    });
+
+    Some(Monitor { task })
+}
+
+/// Sampler of the resource usage of a process group, from `/proc`.
+#[derive(Debug)]
+struct Sampler {
+    pgid: i32,
+    ticks_per_sec: f64,
+    page_size: u64,
+
+    /// CPU ticks and time of the previous sample.
+    previous: Option<(u64, Instant)>,
+}
+
+impl Sampler {
+    fn new(pgid: i32) -> Sampler {
+        let (ticks, page_size) = unsafe {
+            (
+                libc::sysconf(libc::_SC_CLK_TCK),
+                libc::sysconf(libc::_SC_PAGESIZE),
+            )
+        };
+
+        Sampler {
+            pgid,
+            ticks_per_sec: ticks.max(1) as f64,
+            page_size: page_size.max(1) as u64,
+            previous: None,
+        }
+    }
+
+    fn sample(&mut self) -> Usage {
+        let mut usage = Usage::default();
+        let mut ticks = 0;
+
+        let pids = fs::read_dir("/proc")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok());
+
+        for pid in pids {
+            let stat = match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+                Ok(stat) => stat,
+                Err(_) => continue, // Exited meanwhile
+            };
+
+            // Fields after the command name (which can contain spaces), from the state (3rd)
+            let fields = match stat.rfind(')') {
+                Some(end) => stat[end + 1..].split_whitespace().collect::<Vec<&str>>(),
+                None => continue,
+            };
+
+            let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+
+            if field(5) != Some(self.pgid as u64) {
+                continue;
+            }
+
+            usage.processes += 1;
+            ticks += field(14).unwrap_or(0) + field(15).unwrap_or(0);
+            usage.rss += field(24).unwrap_or(0) * self.page_size;
+            usage.fds += fs::read_dir(format!("/proc/{}/fd", pid))
+                .map(|entries| entries.count())
+                .unwrap_or(0);
+        }
+
+        let now = Instant::now();
+
+        if let Some((previous, at)) = self.previous {
+            let elapsed = now.duration_since(at).as_secs_f64();
+
+            if elapsed > 0.0 {
+                usage.cpu =
+                    ticks.saturating_sub(previous) as f64 / self.ticks_per_sec / elapsed * 100.0;
+            }
+        }
+
+        self.previous = Some((ticks, now));
+
+        debug!("Usage of process group {} = {:?}", self.pgid, usage);
+
+        usage
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        let mut sampler = Sampler::new(unsafe { libc::getpgrp() });
+        let usage = sampler.sample();
+
+        assert!(usage.processes >= 1);
+        assert!(usage.rss > 0);
+        assert!(usage.fds > 0);
+
+        let mut missing = Sampler::new(i32::MAX);
+
+        assert_eq!(missing.sample(), Usage::default());
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let thresholds = Thresholds {
+            rss: 1000,
+            fds: 10,
+            ..Thresholds::default()
+        };
+
+        let usage = Usage {
+            cpu: 99.0,
+            rss: 500,
+            fds: 10,
+            processes: 1,
+        };
+
+        assert_eq!(thresholds.exceeded(&usage), None);
+        assert_eq!(
+            thresholds.exceeded(&Usage {
+                rss: 2000,
+                fds: 11,
+                ..usage
+            }),
+            Some("RSS 2000 > 1000 bytes, 11 > 10 file descriptors".to_string())
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use log::{info, warn};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+
+use crate::config;
+use crate::setting;
+
+/// Default size (in bytes) of the output file, before it's rotated.
+const DEFAULT_FILE_SIZE: u64 = 1048576;
+
+/// Output file shared by the captured streams (`ORM_OUTPUT_FILE`).
+static RING: Mutex<Option<Ring>> = Mutex::new(None);
+
+/// Whether the output of the application is captured (`ORM_CAPTURE_OUTPUT`),
+/// rather than inherited from orm.
+pub fn enabled() -> bool {
+    config::parse_or("ORM_CAPTURE_OUTPUT", setting!("ORM_CAPTURE_OUTPUT"), false)
+}
+
+/// Pipes the output streams of the command, if captured.
+pub fn pipe(command: &mut Command) {
+    if enabled() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+}
+
+/// Forwards the piped output of the application to the logger
+/// (with `app::stdout` or `app::stderr` as target), prefixed with the process name if any.
+pub fn capture(child: &mut Child, process: Option<&str>) {
+    let prefix = process
+        .map(|name| format!("[{}] ", name))
+        .unwrap_or_default();
+
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(forward(stdout, "app::stdout", prefix.clone()));
+    }
+
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(forward(stderr, "app::stderr", prefix));
+    }
+}
+
+async fn forward<R: AsyncRead + Unpin>(stream: R, target: &'static str, prefix: String) {
+    let mut reader = BufReader::new(stream);
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) => return,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                let line = format!("{}{}", prefix, line.trim_end_matches(['\r', '\n']));
+
+                info!(target: target, "{}", line);
+
+                if let Err(cause) = append(target, &line) {
+                    warn!("Fails to write the application output: {}", cause);
+                }
+            }
+            Err(cause) => {
+                warn!("Fails to read the application output: {}", cause);
+
+                return;
+            }
+        }
+    }
+}
+
+/// Appends the line to the output file, if any (`ORM_OUTPUT_FILE`).
+fn append(stream: &str, line: &str) -> std::io::Result<()> {
+    let mut ring = RING
+        .lock()
+        .map_err(|_| std::io::Error::other("Poisoned lock"))?;
+
+    if ring.is_none() {
+        let path = match setting!("ORM_OUTPUT_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(()),
+        };
+
+        let max_size = config::parse_or(
+            "ORM_OUTPUT_FILE_SIZE",
+            setting!("ORM_OUTPUT_FILE_SIZE"),
+            DEFAULT_FILE_SIZE,
+        );
+
+        *ring = Some(Ring::open(path, max_size)?);
+    }
+
+    match ring.as_mut() {
+        Some(ring) => ring.append(stream, line),
+        None => Ok(()),
+    }
+}
+
+/// Output file, rotated as `{path}.1` once over its maximum size
+/// (so at most twice this size is used).
+struct Ring {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl Ring {
+    fn open(path: PathBuf, max_size: u64) -> std::io::Result<Ring> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Ring {
+            path,
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    fn append(&mut self, stream: &str, line: &str) -> std::io::Result<()> {
+        if self.size >= self.max_size {
+            let mut rotated = self.path.clone().into_os_string();
+
+            rotated.push(".1");
+
+            fs::rename(&self.path, rotated)?;
+
+            *self = Ring::open(self.path.clone(), self.max_size)?;
+        }
+
+        let entry = format!("{}\t{}\t{}\n", Utc::now().to_rfc3339(), stream, line);
+
+        self.file.write_all(entry.as_bytes())?;
+        self.size += entry.len() as u64;
+
+        Ok(())
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("app.log");
+
+        let mut ring = Ring::open(path.clone(), 64).unwrap();
+
+        ring.append("app::stdout", "first").unwrap();
+        ring.append("app::stderr", "second").unwrap();
+
+        assert!(!tmp.path().join("app.log.1").exists());
+
+        ring.append("app::stdout", "third").unwrap(); // Rotated
+
+        let rotated = fs::read_to_string(tmp.path().join("app.log.1")).unwrap();
+
+        assert_eq!(rotated.lines().count(), 2);
+        assert!(rotated.contains("\tapp::stderr\tsecond"));
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("\tapp::stdout\tthird\n"));
+    }
+}
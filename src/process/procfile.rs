@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use log::debug;
+
+use crate::setting;
+
+/// Name of the file declaring the processes of the application, at the root of its directory.
+pub const PROCFILE: &str = "Procfile";
+
+/// Loads the processes declared in the `Procfile` of the application, if any,
+/// as their names and shell commands, ordered according their dependencies.
+pub fn load(app_dir: &Path) -> std::io::Result<Option<Vec<(String, String)>>> {
+    let path = app_dir.join(PROCFILE);
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(cause) => return Err(cause),
+    };
+
+    debug!("Loading processes from {:?}", path);
+
+    parse(&content)
+        .and_then(|processes| order(processes, &dependencies()?))
+        .map(Some)
+        .map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid {:?}: {}", path, err),
+            )
+        })
+}
+
+/// Returns the dependencies between the processes (`ORM_PROCESS_DEPENDENCIES`),
+/// as `name=dependency,...` entries (whitespace separated).
+pub fn dependencies() -> Result<HashMap<String, Vec<String>>, String> {
+    let declared = match setting!("ORM_PROCESS_DEPENDENCIES") {
+        Some(declared) => declared,
+        None => return Ok(HashMap::new()),
+    };
+
+    declared
+        .split_whitespace()
+        .map(|entry| {
+            let (name, after) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid process dependencies '{}'", entry))?;
+
+            Ok((
+                name.to_string(),
+                after
+                    .split(',')
+                    .filter(|d| !d.is_empty())
+                    .map(String::from)
+                    .collect(),
+            ))
+        })
+        .collect()
+}
+
+/// Orders the processes so each one comes after its dependencies (otherwise as declared).
+fn order(
+    mut pending: Vec<(String, String)>,
+    dependencies: &HashMap<String, Vec<String>>,
+) -> Result<Vec<(String, String)>, String> {
+    for (name, after) in dependencies.iter() {
+        for dependency in after.iter().chain(std::iter::once(name)) {
+            if !pending.iter().any(|(n, _)| n == dependency) {
+                return Err(format!("Unknown process '{}' in dependencies", dependency));
+            }
+        }
+    }
+
+    let mut ordered: Vec<(String, String)> = Vec::new();
+
+    while !pending.is_empty() {
+        let next = pending.iter().position(|(name, _)| {
+            dependencies
+                .get(name)
+                .is_none_or(|after| after.iter().all(|d| ordered.iter().any(|(n, _)| n == d)))
+        });
+
+        match next {
+            Some(i) => ordered.push(pending.remove(i)),
+            None => {
+                return Err(format!(
+                    "Dependency cycle between the processes {:?}",
+                    pending.iter().map(|(n, _)| n).collect::<Vec<&String>>()
+                ))
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
+/// Parses the `name: command` lines, skipping the blank ones and the `#` comments.
+fn parse(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut processes: Vec<(String, String)> = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, command) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Missing ':' at line {}", i + 1))?;
+
+        let (name, command) = (name.trim(), command.trim());
+
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("Invalid process name '{}' at line {}", name, i + 1));
+        }
+
+        if command.is_empty() {
+            return Err(format!("Missing command at line {}", i + 1));
+        }
+
+        if processes.iter().any(|(n, _)| n == name) {
+            return Err(format!("Duplicate process '{}' at line {}", name, i + 1));
+        }
+
+        processes.push((name.to_string(), command.to_string()));
+    }
+
+    if processes.is_empty() {
+        return Err("No process declared".to_string());
+    }
+
+    Ok(processes)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = "# Processes\nweb: bin/server --port=$PORT\n\nworker:bin/worker -q 'a:b'\n";
+
+        assert_eq!(
+            parse(content).unwrap(),
+            vec![
+                ("web".to_string(), "bin/server --port=$PORT".to_string()),
+                ("worker".to_string(), "bin/worker -q 'a:b'".to_string()),
+            ]
+        );
+
+        assert!(parse("web bin/server").is_err());
+        assert!(parse("web:").is_err());
+        assert!(parse("web app: bin/server").is_err());
+        assert!(parse("web: a\nweb: b").is_err());
+        assert!(parse("# None\n").is_err());
+    }
+
+    #[test]
+    fn test_order() {
+        let processes =
+            parse("analytics: bin/analytics\nweb: bin/web\nbroker: bin/broker\n").unwrap();
+        let names = |ordered: Vec<(String, String)>| {
+            ordered.into_iter().map(|(n, _)| n).collect::<Vec<String>>()
+        };
+        let mut dependencies = HashMap::new();
+
+        dependencies.insert("analytics".to_string(), vec!["broker".to_string()]);
+
+        assert_eq!(
+            names(order(processes.clone(), &dependencies).unwrap()),
+            vec!["web", "broker", "analytics"]
+        );
+
+        dependencies.insert("broker".to_string(), vec!["analytics".to_string()]);
+
+        assert!(order(processes.clone(), &dependencies).is_err());
+
+        dependencies.clear();
+        dependencies.insert("web".to_string(), vec!["db".to_string()]);
+
+        assert!(order(processes, &dependencies).is_err());
+    }
+}
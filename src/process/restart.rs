@@ -0,0 +1,281 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use log::{debug, error, info, warn};
+
+use serde::{Deserialize, Serialize};
+
+use super::monitor;
+use crate::config;
+use crate::io::write_atomic;
+use crate::setting;
+
+/// Default delay (in seconds) before the first restart.
+const DEFAULT_DELAY: u64 = 1;
+
+/// Default maximum delay (in seconds) between the restarts.
+const DEFAULT_MAX_DELAY: u64 = 300;
+
+/// Default window (in seconds) the successive exits are considered as a crash loop.
+const DEFAULT_LOOP_WINDOW: u64 = 300;
+
+/// Restart policy of the application, once exited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Orm exits with the application.
+    #[default]
+    Never,
+
+    /// Restarted if it exits with a failure (or killed by a signal).
+    OnFailure,
+
+    /// Always restarted.
+    Always,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Policy, String> {
+        match repr {
+            "never" => Ok(Policy::Never),
+            "on-failure" => Ok(Policy::OnFailure),
+            "always" => Ok(Policy::Always),
+            _ => Err(format!("Unsupported restart policy: {}", repr)),
+        }
+    }
+}
+
+impl Policy {
+    /// Returns the restart policy (`ORM_RESTART`).
+    pub fn from_settings() -> Policy {
+        config::parse_or("ORM_RESTART", setting!("ORM_RESTART"), Policy::default())
+    }
+
+    fn restarts(&self, status: &ExitStatus) -> bool {
+        match self {
+            Policy::Never => false,
+            Policy::OnFailure => !status.success(),
+            Policy::Always => true,
+        }
+    }
+}
+
+/// Decision once the application exited.
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// Restarted after the delay.
+    Restart(Duration),
+
+    /// Not restarted, according the policy.
+    Stop,
+
+    /// Not restarted, as crash looping (with the number of exits within the window).
+    CrashLoop(usize),
+}
+
+/// Counters of the supervised application (`.orm_status`).
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Counters {
+    restarts: u32,
+
+    /// Exits with a failure (or killed by a signal).
+    failures: u32,
+
+    last_exit: Option<String>,
+    last_exit_at: Option<String>,
+}
+
+/// Supervisor restarting the application according the policy (or over a threshold),
+/// with an exponential backoff (`ORM_RESTART_DELAY` doubled up to `ORM_RESTART_MAX_DELAY`).
+#[derive(Debug)]
+pub struct Supervisor {
+    policy: Policy,
+    delay: Duration,
+    max_delay: Duration,
+
+    /// Consecutive restarts, reset once the application ran longer than the maximum delay.
+    attempts: u32,
+
+    /// Exits a crash loop is detected at (`ORM_RESTART_LOOP_EXITS`, `0` to disable),
+    /// within the window (`ORM_RESTART_LOOP_WINDOW`).
+    loop_exits: usize,
+    loop_window: Duration,
+    exits: Vec<Instant>,
+
+    counters: Counters,
+    status_path: PathBuf,
+}
+
+impl Supervisor {
+    pub fn new(local_prefix: &Path) -> Supervisor {
+        Supervisor {
+            policy: Policy::from_settings(),
+            delay: Duration::from_secs(config::parse_or(
+                "ORM_RESTART_DELAY",
+                setting!("ORM_RESTART_DELAY"),
+                DEFAULT_DELAY,
+            )),
+            max_delay: Duration::from_secs(config::parse_or(
+                "ORM_RESTART_MAX_DELAY",
+                setting!("ORM_RESTART_MAX_DELAY"),
+                DEFAULT_MAX_DELAY,
+            )),
+            attempts: 0,
+            loop_exits: config::parse_or(
+                "ORM_RESTART_LOOP_EXITS",
+                setting!("ORM_RESTART_LOOP_EXITS"),
+                0,
+            ),
+            loop_window: Duration::from_secs(config::parse_or(
+                "ORM_RESTART_LOOP_WINDOW",
+                setting!("ORM_RESTART_LOOP_WINDOW"),
+                DEFAULT_LOOP_WINDOW,
+            )),
+            exits: Vec::new(),
+            counters: Counters::default(),
+            status_path: config::state_dir(local_prefix).join(".orm_status"),
+        }
+    }
+
+    /// Records the exit of the application (after the given uptime),
+    /// deciding whether it's restarted.
+    pub fn exited(&mut self, status: &ExitStatus, uptime: Duration) -> Decision {
+        let decision = self.decide(status, uptime);
+
+        if !status.success() {
+            self.counters.failures += 1;
+        }
+
+        if let Decision::Restart(delay) = decision {
+            self.counters.restarts += 1;
+
+            info!(
+                "Restarting the application in {:?} (restart {})",
+                delay, self.counters.restarts
+            );
+        }
+
+        self.counters.last_exit = Some(status.to_string());
+        self.counters.last_exit_at = Some(Utc::now().to_rfc3339());
+
+        debug!("Application counters = {:?}", self.counters);
+
+        if let Err(cause) = self.save() {
+            warn!("Fails to save the application counters: {}", cause);
+        }
+
+        decision
+    }
+
+    fn decide(&mut self, status: &ExitStatus, uptime: Duration) -> Decision {
+        if !monitor::take_restart() && !self.policy.restarts(status) {
+            return Decision::Stop;
+        }
+
+        let now = Instant::now();
+
+        self.exits
+            .retain(|exit| now.duration_since(*exit) < self.loop_window);
+        self.exits.push(now);
+
+        if self.loop_exits > 0 && self.exits.len() >= self.loop_exits {
+            error!(
+                "Application crash looping ({} exits within {:?}); No longer restarted",
+                self.exits.len(),
+                self.loop_window
+            );
+
+            return Decision::CrashLoop(self.exits.len());
+        }
+
+        if uptime >= self.max_delay {
+            self.attempts = 0;
+        }
+
+        let delay = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max_delay);
+
+        self.attempts += 1;
+
+        Decision::Restart(delay)
+    }
+
+    /// Resets the backoff and the crash loop detection (e.g. once rolled back).
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.exits.clear();
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        write_atomic(&self.status_path, &serde_json::to_vec(&self.counters)?)
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_exited() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut supervisor = Supervisor::new(tmp.path());
+        let success = ExitStatus::from_raw(0);
+        let failure = ExitStatus::from_raw(256);
+        let crash = Duration::from_secs(1);
+
+        supervisor.policy = Policy::OnFailure;
+        supervisor.max_delay = Duration::from_secs(3);
+
+        assert_eq!(supervisor.exited(&success, crash), Decision::Stop);
+        assert_eq!(
+            supervisor.exited(&failure, crash),
+            Decision::Restart(Duration::from_secs(1))
+        );
+        assert_eq!(
+            supervisor.exited(&failure, crash),
+            Decision::Restart(Duration::from_secs(2))
+        );
+        assert_eq!(
+            supervisor.exited(&failure, crash),
+            Decision::Restart(Duration::from_secs(3))
+        );
+
+        // Reset once stable
+        assert_eq!(
+            supervisor.exited(&failure, Duration::from_secs(60)),
+            Decision::Restart(Duration::from_secs(1))
+        );
+
+        let saved: Counters =
+            serde_json::from_slice(&std::fs::read(tmp.path().join(".orm_status")).unwrap())
+                .unwrap();
+
+        assert_eq!(saved.restarts, 4);
+        assert_eq!(saved.failures, 4);
+        assert_eq!(saved.last_exit, Some("exit status: 1".to_string()));
+
+        // Crash loop
+        supervisor.loop_exits = 3;
+        supervisor.reset();
+
+        assert!(matches!(
+            supervisor.exited(&failure, crash),
+            Decision::Restart(_)
+        ));
+        assert!(matches!(
+            supervisor.exited(&failure, crash),
+            Decision::Restart(_)
+        ));
+        assert_eq!(supervisor.exited(&failure, crash), Decision::CrashLoop(3));
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs::{self, OpenOptions};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use tokio::process::Command;
+
+use crate::config;
+use crate::setting;
+
+/// Default paths the application can only read and execute.
+const DEFAULT_READ_PATHS: &str = "/usr,/lib,/lib64,/bin,/sbin,/etc,/opt,/proc,/sys";
+
+/// Default paths the application has full access to (relative to its directory unless absolute).
+const DEFAULT_WRITE_PATHS: &str = "data,/tmp,/dev";
+
+/// Default seccomp profile shipped in the application (relative to its directory).
+const DEFAULT_SECCOMP_PROFILE: &str = "seccomp.bpf";
+
+// Landlock filesystem access rights (ABI v1)
+const ACCESS_EXECUTE: u64 = 1 << 0;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_ALL: u64 = (1 << 13) - 1;
+const ACCESS_READ: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Confinement of the application, to be kept until it's spawned.
+#[derive(Debug)]
+pub struct Sandbox {
+    /// Landlock ruleset restricting the filesystem access.
+    ruleset: Option<OwnedFd>,
+
+    /// Seccomp filter (BPF program) restricting the system calls.
+    filter: Vec<libc::sock_filter>,
+}
+
+/// Confines the command (if `ORM_SANDBOX`): its filesystem access is restricted with Landlock
+/// to its directory (read-only, except the `ORM_SANDBOX_WRITE` paths) and the `ORM_SANDBOX_READ`
+/// ones, and its system calls filtered by the seccomp profile it ships (`ORM_SECCOMP_PROFILE`),
+/// if any; The returned sandbox must be kept until it's spawned.
+pub fn confine(command: &mut Command, app_dir: &Path) -> std::io::Result<Option<Sandbox>> {
+    if !config::parse_or("ORM_SANDBOX", setting!("ORM_SANDBOX"), false) {
+        return Ok(None);
+    }
+
+    let ruleset = match ruleset(app_dir) {
+        Ok(ruleset) => Some(ruleset),
+        Err(cause) if matches!(cause.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) => {
+            warn!(
+                "Landlock not supported; Filesystem not restricted: {}",
+                cause
+            );
+
+            None
+        }
+        Err(cause) => return Err(cause),
+    };
+
+    let filter = seccomp_filter(app_dir)?;
+    let sandbox = Sandbox { ruleset, filter };
+
+    let ruleset_fd = sandbox.ruleset.as_ref().map(|fd| fd.as_raw_fd());
+
+    // The filter is kept allocated by the sandbox until spawned
+    let filter = (
+        sandbox.filter.len() as libc::c_ushort,
+        sandbox.filter.as_ptr() as usize,
+    );
+
+    // Applied right before exec, with async-signal-safe calls only
+    unsafe {
+        command.pre_exec(move || {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            if let Some(fd) = ruleset_fd {
+                if libc::syscall(libc::SYS_landlock_restrict_self, fd, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+
+            if filter.0 > 0 {
+                let fprog = libc::sock_fprog {
+                    len: filter.0,
+                    filter: filter.1 as *mut libc::sock_filter,
+                };
+
+                if libc::syscall(
+                    libc::SYS_seccomp,
+                    libc::SECCOMP_SET_MODE_FILTER,
+                    0,
+                    &fprog as *const libc::sock_fprog,
+                ) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+    Ok(Some(sandbox))
+}
+
+/// Returns the paths the application can access, with their access rights.
+fn paths(app_dir: &Path) -> Vec<(PathBuf, u64)> {
+    let list = |setting: Option<String>, default: &str| {
+        setting
+            .unwrap_or_else(|| default.to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|p| app_dir.join(p))
+            .collect::<Vec<PathBuf>>()
+    };
+
+    let mut paths = vec![(app_dir.to_path_buf(), ACCESS_READ)];
+
+    paths.extend(
+        list(setting!("ORM_SANDBOX_READ"), DEFAULT_READ_PATHS)
+            .into_iter()
+            .map(|p| (p, ACCESS_READ)),
+    );
+    paths.extend(
+        list(setting!("ORM_SANDBOX_WRITE"), DEFAULT_WRITE_PATHS)
+            .into_iter()
+            .map(|p| (p, ACCESS_ALL)),
+    );
+
+    paths
+}
+
+/// Creates the Landlock ruleset allowing the access to the paths (the missing ones skipped).
+fn ruleset(app_dir: &Path) -> std::io::Result<OwnedFd> {
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_ALL,
+    };
+
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    for (path, access) in paths(app_dir) {
+        let file = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(cause) => return Err(cause),
+        };
+
+        // Only the file rights for a file (e.g. `/dev/null`)
+        let access = match file.metadata()?.is_dir() {
+            true => access,
+            false => access & ACCESS_FILE,
+        };
+
+        debug!("Sandboxed access to {:?} = {:#x}", path, access);
+
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(ruleset)
+}
+
+/// Loads the seccomp filter from the profile of the application, if any:
+/// a BPF program (e.g. exported with `seccomp_export_bpf`), as `sock_filter` instructions.
+fn seccomp_filter(app_dir: &Path) -> std::io::Result<Vec<libc::sock_filter>> {
+    let path = app_dir.join(
+        setting!("ORM_SECCOMP_PROFILE").unwrap_or_else(|| DEFAULT_SECCOMP_PROFILE.to_string()),
+    );
+
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(cause) => return Err(cause),
+    };
+
+    if bytes.is_empty() || bytes.len() % 8 != 0 || bytes.len() / 8 > u16::MAX as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid seccomp profile {:?}", path),
+        ));
+    }
+
+    debug!("Loading seccomp profile {:?}", path);
+
+    Ok(bytes
+        .chunks_exact(8)
+        .map(|i| libc::sock_filter {
+            code: u16::from_ne_bytes([i[0], i[1]]),
+            jt: i[2],
+            jf: i[3],
+            k: u32::from_ne_bytes([i[4], i[5], i[6], i[7]]),
+        })
+        .collect())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seccomp_filter() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        assert!(seccomp_filter(tmp.path()).unwrap().is_empty());
+
+        // BPF_RET | BPF_K, SECCOMP_RET_ALLOW
+        let allow = [
+            &0x06u16.to_ne_bytes()[..],
+            &[0, 0],
+            &0x7fff0000u32.to_ne_bytes(),
+        ]
+        .concat();
+
+        fs::write(tmp.path().join("seccomp.bpf"), allow).unwrap();
+
+        let filter = seccomp_filter(tmp.path()).unwrap();
+
+        assert_eq!(filter.len(), 1);
+        assert_eq!((filter[0].code, filter[0].k), (0x06, 0x7fff0000));
+
+        fs::write(tmp.path().join("seccomp.bpf"), [0u8; 5]).unwrap();
+
+        assert!(seccomp_filter(tmp.path()).is_err());
+    }
+}
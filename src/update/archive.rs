@@ -0,0 +1,1579 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use log::{debug, warn};
+
+use regex::Regex;
+
+use sha2::{Digest, Sha256};
+
+use flate2::read::GzDecoder;
+use tar::{Archive, EntryType};
+use xz2::read::XzDecoder;
+use zip::result::ZipError;
+use zip::ZipArchive;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use super::manifest::{Format, Permission};
+use super::progress::Progress;
+use super::storage::Usage;
+use crate::config;
+use crate::error;
+use crate::io::hex;
+use crate::process::procfile::PROCFILE;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Default maximum size (in bytes) of the extracted archive.
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Default maximum size (in bytes) of an extracted file.
+const DEFAULT_MAX_FILE_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Default maximum number of entries in the archive.
+const DEFAULT_MAX_ENTRIES: usize = 10000;
+
+/// Resources consumed by the extraction, against the configured limits.
+struct Budget {
+    max_size: u64,
+    max_file_size: u64,
+    max_entries: usize,
+    size: u64,
+    entries: usize,
+}
+
+impl Budget {
+    fn from_settings() -> Budget {
+        Budget {
+            max_size: config::parse_or(
+                "ORM_ARCHIVE_MAX_SIZE",
+                setting!("ORM_ARCHIVE_MAX_SIZE"),
+                DEFAULT_MAX_SIZE,
+            ),
+            max_file_size: config::parse_or(
+                "ORM_ARCHIVE_MAX_FILE_SIZE",
+                setting!("ORM_ARCHIVE_MAX_FILE_SIZE"),
+                DEFAULT_MAX_FILE_SIZE,
+            ),
+            max_entries: config::parse_or(
+                "ORM_ARCHIVE_MAX_ENTRIES",
+                setting!("ORM_ARCHIVE_MAX_ENTRIES"),
+                DEFAULT_MAX_ENTRIES,
+            ),
+            size: 0,
+            entries: 0,
+        }
+    }
+
+    /// Returns the size still allowed for the next file.
+    fn allowance(&self) -> u64 {
+        self.max_file_size.min(self.max_size - self.size)
+    }
+
+    /// Accounts for an entry of the given size,
+    /// failing if any limit is exceeded.
+    fn consume(&mut self, path: &Path, size: u64) -> Result<(), Error> {
+        self.entries += 1;
+
+        if self.entries > self.max_entries {
+            return Err(format_error!(
+                "Invalid archive; More than {} entries",
+                self.max_entries
+            ));
+        }
+
+        if size > self.max_file_size {
+            return Err(format_error!(
+                "Invalid archive; Entry {:?} exceeds {} bytes",
+                path,
+                self.max_file_size
+            ));
+        }
+
+        if size > self.max_size - self.size {
+            return Err(format_error!(
+                "Invalid archive; Extracted size exceeds {} bytes",
+                self.max_size
+            ));
+        }
+
+        self.size += size;
+
+        Ok(())
+    }
+}
+
+/// Policy for the entries other than plain files and directories.
+struct Policy {
+    /// Whether symlinks pointing outside the application directory are allowed.
+    external_symlinks: bool,
+
+    /// Whether hard links (inside the archive) are allowed.
+    hardlinks: bool,
+
+    /// Whether device nodes and FIFOs are allowed.
+    special_files: bool,
+
+    /// Whether the setuid/setgid bits are kept (otherwise cleared).
+    setuid: bool,
+
+    /// Capabilities the artifact metadata can set (e.g. `cap_net_bind_service`).
+    capabilities: Vec<String>,
+
+    /// Whether the owner from the archive is kept
+    /// (otherwise the extracted files are owned by the current user).
+    preserve_ownership: bool,
+
+    /// Whether the extended attributes from the archive are kept
+    /// (e.g. the `security.selinux` context).
+    xattrs: bool,
+
+    /// User and optional group the extracted files are given to.
+    owner: Option<(u32, Option<u32>)>,
+
+    /// Permission bits cleared from the archive modes.
+    umask: u32,
+
+    /// Entries to skip or to require.
+    filter: Filter,
+}
+
+impl Policy {
+    fn from_settings() -> Result<Policy, Error> {
+        let allow = |name: &str, value: Option<String>| config::parse_or(name, value, false);
+
+        let owner = match setting!("ORM_ARCHIVE_OWNER") {
+            Some(spec) => Some(resolve_owner(&spec)?),
+            None => None,
+        };
+
+        let umask = match setting!("ORM_ARCHIVE_UMASK") {
+            Some(repr) => u32::from_str_radix(repr.trim(), 8)
+                .map_err(|err| format_error!("Invalid ORM_ARCHIVE_UMASK {}: {}", repr, err))?,
+            None => 0,
+        };
+
+        Ok(Policy {
+            external_symlinks: allow(
+                "ORM_ARCHIVE_ALLOW_EXTERNAL_SYMLINKS",
+                setting!("ORM_ARCHIVE_ALLOW_EXTERNAL_SYMLINKS"),
+            ),
+            hardlinks: allow(
+                "ORM_ARCHIVE_ALLOW_HARDLINKS",
+                setting!("ORM_ARCHIVE_ALLOW_HARDLINKS"),
+            ),
+            special_files: allow(
+                "ORM_ARCHIVE_ALLOW_SPECIAL_FILES",
+                setting!("ORM_ARCHIVE_ALLOW_SPECIAL_FILES"),
+            ),
+            setuid: allow(
+                "ORM_ARCHIVE_ALLOW_SETUID",
+                setting!("ORM_ARCHIVE_ALLOW_SETUID"),
+            ),
+            capabilities: setting!("ORM_ALLOWED_CAPABILITIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|c| c.trim().to_lowercase())
+                .filter(|c| !c.is_empty())
+                .collect(),
+            preserve_ownership: allow(
+                "ORM_ARCHIVE_PRESERVE_OWNERSHIP",
+                setting!("ORM_ARCHIVE_PRESERVE_OWNERSHIP"),
+            ),
+            xattrs: allow(
+                "ORM_ARCHIVE_PRESERVE_XATTRS",
+                setting!("ORM_ARCHIVE_PRESERVE_XATTRS"),
+            ),
+            owner,
+            umask: umask & 0o7777,
+            filter: Filter::from_settings()?,
+        })
+    }
+
+    /// Checks the symlink from the given entry is allowed.
+    fn check_symlink(
+        &self,
+        extracted_path: &Path,
+        prefix: &Path,
+        path: &Path,
+        target: &Path,
+    ) -> Result<(), Error> {
+        if self.external_symlinks || symlink_inside(extracted_path, prefix, path, target) {
+            Ok(())
+        } else {
+            Err(format_error!(
+                "Invalid archive; Symlink {:?} points outside application: {:?}",
+                path,
+                target
+            ))
+        }
+    }
+}
+
+/// Glob patterns of the entries to skip or to require,
+/// relative to the application directory.
+struct Filter {
+    exclude: Vec<Regex>,
+    require: Vec<(String, Regex)>,
+}
+
+impl Filter {
+    fn from_settings() -> Result<Filter, Error> {
+        let globs = |value: Option<String>| -> Result<Vec<(String, Regex)>, Error> {
+            value
+                .unwrap_or_default()
+                .split(',')
+                .map(|g| g.trim())
+                .filter(|g| !g.is_empty())
+                .map(|g| glob_regex(g).map(|re| (g.to_string(), re)))
+                .collect()
+        };
+
+        Ok(Filter {
+            exclude: globs(setting!("ORM_ARCHIVE_EXCLUDE"))?
+                .into_iter()
+                .map(|(_, re)| re)
+                .collect(),
+            require: globs(setting!("ORM_ARCHIVE_REQUIRE"))?,
+        })
+    }
+
+    /// Checks whether the entry is excluded (a directory also matching `dir/`).
+    fn excludes(&self, prefix: &Path, path: &Path, is_dir: bool) -> bool {
+        let relative = match normalize(path).strip_prefix(prefix) {
+            Ok(relative) => relative.to_string_lossy().to_string(),
+            Err(_) => return false,
+        };
+
+        self.exclude
+            .iter()
+            .any(|re| re.is_match(&relative) || (is_dir && re.is_match(&format!("{}/", relative))))
+    }
+
+    /// Checks each required pattern matches at least one extracted entry.
+    fn check_required(&self, prefix: &Path, entries: &[PathBuf]) -> Result<(), Error> {
+        let relatives: Vec<String> = entries
+            .iter()
+            .filter_map(|p| {
+                normalize(p)
+                    .strip_prefix(prefix)
+                    .ok()
+                    .map(|r| r.to_string_lossy().to_string())
+            })
+            .collect();
+
+        for (glob, re) in self.require.iter() {
+            if !relatives.iter().any(|r| re.is_match(r)) {
+                return Err(format_error!("Invalid archive; No entry matching {}", glob));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts the glob pattern to a regular expression:
+/// `*` and `?` don't match `/`, `**` matches any path.
+fn glob_regex(glob: &str) -> Result<Regex, Error> {
+    let mut re = String::from("^");
+    let mut chars = glob.trim_start_matches("./").chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    re.push('$');
+
+    Ok(Regex::new(&re)?)
+}
+
+/// Expected SHA-256 digests of the archive files, from the artifact metadata;
+/// They protect against tampering only once the metadata signature is verified
+/// (if a public key is provisioned), otherwise only against corruption.
+#[derive(Default)]
+pub struct Digests {
+    expected: HashMap<PathBuf, String>,
+
+    /// Files already verified, not to be overwritten by a later entry.
+    verified: HashSet<PathBuf>,
+}
+
+impl Digests {
+    pub fn new(files: &HashMap<String, String>) -> Digests {
+        Digests {
+            expected: files
+                .iter()
+                .map(|(p, d)| (normalize(Path::new(p)), d.trim().to_lowercase()))
+                .collect(),
+            verified: HashSet::new(),
+        }
+    }
+
+    /// Takes the expected digest for the entry (then verified), if any.
+    fn take(&mut self, path: &Path) -> Option<String> {
+        let path = normalize(path);
+        let expected = self.expected.remove(&path);
+
+        if expected.is_some() {
+            self.verified.insert(path);
+        }
+
+        expected
+    }
+
+    /// Discards the expected digest for the excluded entry, if any.
+    fn discard(&mut self, path: &Path) {
+        self.expected.remove(&normalize(path));
+    }
+
+    /// Checks the entry doesn't repeat the path of an already verified file.
+    fn check_unverified(&self, path: &Path) -> Result<(), Error> {
+        if self.verified.contains(&normalize(path)) {
+            Err(format_error!(
+                "Invalid archive; Duplicate verified file: {:?}",
+                path
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks all the expected files have been found in the archive.
+    fn check_exhausted(&self) -> Result<(), Error> {
+        if self.expected.is_empty() {
+            Ok(())
+        } else {
+            Err(format_error!(
+                "Invalid archive; Missing file(s): {:?}",
+                self.expected.keys().collect::<Vec<_>>()
+            ))
+        }
+    }
+}
+
+/// Writer computing the SHA-256 digest of the written data.
+struct DigestWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Unpacks the file content, verifying its digest in the same pass.
+fn unpack_verified<R: Read>(
+    reader: &mut R,
+    target: &Path,
+    path: &Path,
+    expected: &str,
+) -> Result<u64, Error> {
+    let mut writer = DigestWriter {
+        inner: File::create(target)?,
+        hasher: Sha256::new(),
+    };
+
+    let size = std::io::copy(reader, &mut writer)?;
+    let digest = hex(&writer.hasher.finalize());
+
+    if digest != expected {
+        return Err(format_error!(
+            "Invalid archive; Digest mismatch for {:?}: {} != {}",
+            path,
+            digest,
+            expected
+        ));
+    }
+
+    debug!("Verified digest of {:?}", path);
+
+    Ok(size)
+}
+
+/// Sets the extended attributes of the extracted file,
+/// from the PAX headers of the entry (as `tar --xattrs` does).
+fn set_xattrs<R: Read>(entry: &mut tar::Entry<R>, target: &Path) -> Result<(), Error> {
+    let extensions = match entry.pax_extensions()? {
+        Some(extensions) => extensions,
+        None => return Ok(()),
+    };
+
+    let c_path = CString::new(target.as_os_str().as_bytes())
+        .map_err(|err| format_error!("Invalid path {:?}: {}", target, err))?;
+
+    for extension in extensions {
+        let extension = extension?;
+
+        let name = match extension.key()?.strip_prefix("SCHILY.xattr.") {
+            Some(name) => CString::new(name)
+                .map_err(|err| format_error!("Invalid xattr {}: {}", name, err))?,
+            None => continue,
+        };
+
+        let value = extension.value_bytes();
+
+        debug!("Setting xattr {:?} on {:?}", name, target);
+
+        let res = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+
+        if res != 0 {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the directory of the application inside the archive,
+/// either the configured `root` or the application name.
+pub fn app_root(root: Option<&str>, app_name: &str) -> Result<PathBuf, Error> {
+    let root = Path::new(root.unwrap_or(app_name));
+
+    if !root
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format_error!("Invalid archive root: {:?}", root));
+    }
+
+    Ok(normalize(root))
+}
+
+/// Removes the `.` components from the relative path.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| c != &Component::CurDir)
+        .collect()
+}
+
+/// Extracts the application archive,
+/// checking it contains the required executables for the application:
+/// its ID script (`ORM_ID_SCRIPT`) and its entrypoint (if given and not absolute),
+/// unless it declares its processes in a `Procfile`.
+pub fn extract<'x>(
+    prefix: &'x Path,
+    ar_file: &'x File,
+    extracted_path: &'x Path,
+    declared: Format,
+    mut digests: Digests,
+    entrypoint: Option<&'x str>,
+) -> Result<usize, Error> {
+    let format = detect_format(ar_file, declared)?;
+
+    debug!("Archive format = {:?}", format);
+
+    let mut budget = Budget::from_settings();
+    let policy = Policy::from_settings()?;
+    let progress = Progress::new("Extracting".to_string(), "entries", None);
+
+    let entries = match format {
+        Format::TarGz => extract_tar(
+            GzDecoder::new(ar_file),
+            prefix,
+            extracted_path,
+            &mut budget,
+            &policy,
+            &mut digests,
+            &progress,
+        )?,
+        Format::Tar => extract_tar(
+            ar_file,
+            prefix,
+            extracted_path,
+            &mut budget,
+            &policy,
+            &mut digests,
+            &progress,
+        )?,
+        Format::TarZst => extract_tar(
+            ZstdDecoder::new(ar_file)?,
+            prefix,
+            extracted_path,
+            &mut budget,
+            &policy,
+            &mut digests,
+            &progress,
+        )?,
+        Format::TarXz => extract_tar(
+            XzDecoder::new(ar_file),
+            prefix,
+            extracted_path,
+            &mut budget,
+            &policy,
+            &mut digests,
+            &progress,
+        )?,
+        Format::Zip => extract_zip(
+            ar_file,
+            prefix,
+            extracted_path,
+            &mut budget,
+            &policy,
+            &mut digests,
+            &progress,
+        )?,
+    };
+
+    digests.check_exhausted()?;
+    policy.filter.check_required(prefix, &entries)?;
+
+    debug!(
+        "Extracted {} entries ({} bytes)",
+        budget.entries, budget.size
+    );
+
+    let procfile = prefix.join(PROCFILE);
+    let id_script = super::id_script();
+    let executables: Vec<PathBuf> = entrypoint
+        .map(Path::new)
+        .filter(|p| p.is_relative() && !entries.iter().any(|e| normalize(e) == procfile))
+        .into_iter()
+        .chain(std::iter::once(Path::new(&id_script)))
+        .map(|p| prefix.join(normalize(p)))
+        .collect();
+
+    let missing: Vec<&PathBuf> = executables
+        .iter()
+        .filter(|e| !entries.iter().any(|p| &normalize(p) == *e))
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(format_error!(
+            "Invalid archive; Missing executable(s): {:?}",
+            missing
+        ));
+    }
+
+    if let Some((uid, gid)) = policy.owner {
+        debug!("Changing owner of extracted files to {}:{:?}", uid, gid);
+
+        chown_all(extracted_path, uid, gid)?;
+    }
+
+    for executable in executables.iter() {
+        ensure_executable(&extracted_path.join(executable))?;
+    }
+
+    Ok(executables.len())
+}
+
+/// Applies the permissions declared in the artifact metadata to the extracted files
+/// (after any owner change, which would clear the capabilities).
+pub fn apply_permissions<'x>(
+    extracted_path: &'x Path,
+    permissions: &'x HashMap<String, Permission>,
+) -> Result<(), Error> {
+    let policy = Policy::from_settings()?;
+
+    for (name, permission) in permissions.iter() {
+        let path = normalize(Path::new(name));
+
+        match fs::symlink_metadata(extracted_path.join(&path)) {
+            Ok(metadata) if !metadata.file_type().is_symlink() => (),
+            _ => {
+                return Err(format_error!(
+                    "Invalid archive; Missing file for permissions: {:?}",
+                    path
+                ))
+            }
+        }
+
+        let target = entry_target(extracted_path, &path)?;
+
+        if let Some(repr) = &permission.mode {
+            let mode = u32::from_str_radix(repr.trim(), 8)
+                .map_err(|err| format_error!("Invalid mode for {:?}: {}", path, err))?;
+
+            if mode & 0o6000 != 0 && !policy.setuid {
+                return Err(format_error!(
+                    "Setuid/setgid mode {:o} not allowed for {:?}",
+                    mode,
+                    path
+                ));
+            }
+
+            debug!("Setting mode {:o} of {:?}", mode, path);
+
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+
+        if let Some(capabilities) = &permission.capabilities {
+            check_capabilities(capabilities, &policy.capabilities)
+                .map_err(|err| format_error!("{} for {:?}", err, path))?;
+
+            debug!("Setting capabilities {} of {:?}", capabilities, path);
+
+            // Not an option once checked (`setcap` doesn't support `--`)
+            let status = Command::new("setcap")
+                .arg(capabilities)
+                .arg(&target)
+                .status()?;
+
+            if !status.success() {
+                return Err(format_error!(
+                    "Fails to set capabilities {} of {:?}: {}",
+                    capabilities,
+                    path,
+                    status
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the capabilities (as accepted by `setcap`, e.g. `cap_net_bind_service+ep`)
+/// are all allowed; `all` (or none named, e.g. `=ep`) only if allowed as such.
+fn check_capabilities(repr: &str, allowed: &[String]) -> Result<(), Error> {
+    if repr.trim().is_empty() || repr.trim_start().starts_with('-') {
+        return Err(format_error!("Invalid capabilities {:?}", repr));
+    }
+
+    for clause in repr.split_whitespace() {
+        let names = &clause[..clause.find(['+', '-', '=']).unwrap_or(clause.len())];
+        let names = if names.is_empty() { "all" } else { names };
+
+        for name in names.split(',') {
+            if !allowed.iter().any(|a| a.eq_ignore_ascii_case(name.trim())) {
+                return Err(format_error!("Capability {} not allowed", name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the owner specified as `user[:group]`, either names or IDs.
+fn resolve_owner(spec: &str) -> Result<(u32, Option<u32>), Error> {
+    let (user, group) = match spec.trim().split_once(':') {
+        Some((u, g)) => (u, Some(g)),
+        None => (spec.trim(), None),
+    };
+
+    let uid = match user.parse::<u32>() {
+        Ok(id) => id,
+        Err(_) => {
+            let name = std::ffi::CString::new(user)
+                .map_err(|err| format_error!("Invalid user {}: {}", user, err))?;
+
+            // Resolved once, before any concurrent lookup
+            let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+
+            if pw.is_null() {
+                return Err(format_error!("Unknown user: {}", user));
+            }
+
+            unsafe { (*pw).pw_uid }
+        }
+    };
+
+    let gid = match group.filter(|g| !g.is_empty()) {
+        None => None,
+        Some(g) => Some(match g.parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => {
+                let name = std::ffi::CString::new(g)
+                    .map_err(|err| format_error!("Invalid group {}: {}", g, err))?;
+
+                let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+
+                if gr.is_null() {
+                    return Err(format_error!("Unknown group: {}", g));
+                }
+
+                unsafe { (*gr).gr_gid }
+            }
+        }),
+    };
+
+    Ok((uid, gid))
+}
+
+/// Changes the owner of the whole extracted tree (without following the symlinks).
+fn chown_all(path: &Path, uid: u32, gid: Option<u32>) -> Result<(), Error> {
+    std::os::unix::fs::lchown(path, Some(uid), gid)?;
+
+    if fs::symlink_metadata(path)?.is_dir() {
+        for child in fs::read_dir(path)? {
+            chown_all(&child?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensures the extracted script (or entrypoint) is an executable file,
+/// as the mode is often lost for the archives created on Windows.
+fn ensure_executable(path: &Path) -> Result<(), Error> {
+    let metadata = fs::metadata(path)?;
+
+    if !metadata.is_file() {
+        return Err(format_error!(
+            "Invalid archive; Executable is not a file: {:?}",
+            path
+        ));
+    }
+
+    let mode = metadata.permissions().mode();
+
+    if mode & 0o111 == 0 {
+        warn!("File {:?} is not executable; Fixing mode {:o}", path, mode);
+
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o111))?;
+    }
+
+    Ok(())
+}
+
+/// Scans the archive entries, without extracting them,
+/// to estimate the storage required by the extraction.
+pub fn scan(ar_file: &File, declared: Format) -> Result<Usage, Error> {
+    let mut budget = Budget::from_settings();
+
+    match detect_format(ar_file, declared)? {
+        Format::TarGz => scan_tar(GzDecoder::new(ar_file), &mut budget)?,
+        Format::Tar => scan_tar(ar_file, &mut budget)?,
+        Format::TarZst => scan_tar(ZstdDecoder::new(ar_file)?, &mut budget)?,
+        Format::TarXz => scan_tar(XzDecoder::new(ar_file), &mut budget)?,
+        Format::Zip => {
+            let mut app_archive = ZipArchive::new(ar_file)?;
+
+            for i in 0..app_archive.len() {
+                let entry = app_archive.by_index_raw(i)?;
+
+                budget.consume(Path::new(entry.name()), entry.size())?;
+            }
+        }
+    }
+
+    let mut reader = ar_file;
+
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(Usage {
+        entries: budget.entries as u64,
+        size: budget.size,
+    })
+}
+
+fn scan_tar<R: Read>(tar: R, budget: &mut Budget) -> Result<(), Error> {
+    for entry in Archive::new(tar).entries()? {
+        let entry = entry?;
+
+        budget.consume(&entry.path()?, entry.header().size()?)?;
+    }
+
+    Ok(())
+}
+
+/// Detects the archive format from its magic bytes,
+/// otherwise assumes the declared one.
+fn detect_format(ar_file: &File, declared: Format) -> Result<Format, Error> {
+    let mut reader = ar_file;
+    let mut magic = Vec::with_capacity(262);
+
+    reader.take(262).read_to_end(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(match magic.as_slice() {
+        [0x1f, 0x8b, ..] => Format::TarGz,
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Format::TarZst,
+        [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Format::TarXz,
+        [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => Format::Zip,
+        m if m.len() >= 262 && &m[257..262] == b"ustar" => Format::Tar,
+        _ => declared,
+    })
+}
+
+/// Extracts a tarball, from the decompressed stream.
+fn extract_tar<'x, R: Read>(
+    tar: R,
+    prefix: &'x Path,
+    extracted_path: &'x Path,
+    budget: &'x mut Budget,
+    policy: &'x Policy,
+    digests: &'x mut Digests,
+    progress: &'x Progress,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut app_archive = Archive::new(tar);
+    let mut entries = Vec::new();
+
+    app_archive.set_preserve_ownerships(policy.preserve_ownership);
+    app_archive.set_unpack_xattrs(policy.xattrs);
+
+    for entry in app_archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+
+        digests.check_unverified(&path)?;
+
+        if policy
+            .filter
+            .excludes(prefix, &path, entry.header().entry_type().is_dir())
+        {
+            debug!("Excluded entry = {:?}", path);
+
+            digests.discard(&path);
+            continue;
+        }
+
+        budget.consume(&path, entry.header().size()?)?;
+        progress.advance(1);
+
+        let extracted_entry = entry_target(extracted_path, &path)?;
+
+        debug!("Extracted entry = {:?}", extracted_entry);
+
+        match entry.header().entry_type() {
+            EntryType::Symlink => {
+                let target = entry.link_name()?.unwrap_or_default();
+
+                policy.check_symlink(extracted_path, prefix, &path, &target)?;
+            }
+
+            EntryType::Link => {
+                if !policy.hardlinks {
+                    return Err(format_error!("Invalid archive; Hard link: {:?}", path));
+                }
+
+                // Resolved against the extraction directory (not the working one)
+                let source = entry.link_name()?.unwrap_or_default();
+
+                fs::hard_link(entry_target(extracted_path, &source)?, extracted_entry)?;
+                entries.push(path);
+
+                continue;
+            }
+
+            EntryType::Regular | EntryType::Continuous => {
+                if let Some(expected) = digests.take(&path) {
+                    unpack_verified(&mut entry, &extracted_entry, &path, &expected)?;
+
+                    if policy.xattrs {
+                        set_xattrs(&mut entry, &extracted_entry)?;
+                    }
+
+                    let header = entry.header();
+                    let mode = header.mode()? & if policy.setuid { 0o7777 } else { 0o777 };
+
+                    fs::set_permissions(
+                        &extracted_entry,
+                        fs::Permissions::from_mode(mode & !policy.umask),
+                    )?;
+
+                    if policy.preserve_ownership {
+                        std::os::unix::fs::lchown(
+                            &extracted_entry,
+                            Some(header.uid()? as u32),
+                            Some(header.gid()? as u32),
+                        )?;
+                    }
+
+                    entries.push(path);
+
+                    continue;
+                }
+            }
+
+            EntryType::Block | EntryType::Char | EntryType::Fifo if !policy.special_files => {
+                return Err(format_error!("Invalid archive; Special file: {:?}", path));
+            }
+
+            _ => (),
+        }
+
+        entry.set_preserve_permissions(policy.setuid);
+        entry.set_mask(policy.umask);
+        entry.unpack(extracted_entry)?;
+        entries.push(path);
+    }
+
+    Ok(entries)
+}
+
+/// Extracts a zip archive.
+fn extract_zip<'x>(
+    ar_file: &'x File,
+    prefix: &'x Path,
+    extracted_path: &'x Path,
+    budget: &'x mut Budget,
+    policy: &'x Policy,
+    digests: &'x mut Digests,
+    progress: &'x Progress,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut app_archive = ZipArchive::new(ar_file)?;
+    let mut entries = Vec::with_capacity(app_archive.len());
+
+    for i in 0..app_archive.len() {
+        let mut entry = app_archive.by_index(i)?;
+
+        progress.advance(1);
+
+        let path = PathBuf::from(entry.name());
+
+        digests.check_unverified(&path)?;
+
+        if policy.filter.excludes(prefix, &path, entry.is_dir()) {
+            debug!("Excluded entry = {:?}", path);
+
+            digests.discard(&path);
+            continue;
+        }
+
+        let extracted_entry = entry_target(extracted_path, &path)?;
+
+        debug!("Extracted entry = {:?}", extracted_entry);
+
+        if entry.is_dir() {
+            budget.consume(&path, 0)?;
+            fs::create_dir_all(&extracted_entry)?;
+        } else {
+            let mut mode = entry.unix_mode().unwrap_or(0o644);
+
+            // The declared size is not trusted
+            let mut limited = (&mut entry).take(budget.allowance() + 1);
+
+            if mode & 0o170000 == 0o120000 {
+                let mut target = String::new();
+
+                budget.consume(&path, limited.read_to_string(&mut target)? as u64)?;
+                policy.check_symlink(extracted_path, prefix, &path, Path::new(&target))?;
+
+                std::os::unix::fs::symlink(&target, &extracted_entry)?;
+                entries.push(path);
+
+                continue;
+            }
+
+            let size = match digests.take(&path) {
+                Some(expected) => {
+                    unpack_verified(&mut limited, &extracted_entry, &path, &expected)?
+                }
+                None => std::io::copy(&mut limited, &mut File::create(&extracted_entry)?)?,
+            };
+
+            budget.consume(&path, size)?;
+
+            if !policy.setuid {
+                mode &= !0o6000;
+            }
+
+            fs::set_permissions(
+                &extracted_entry,
+                fs::Permissions::from_mode(mode & 0o7777 & !policy.umask),
+            )?;
+        }
+
+        entries.push(path);
+    }
+
+    Ok(entries)
+}
+
+/// Checks whether the symlink target, resolved from the entry path,
+/// is inside the application directory.
+///
+/// A `..` after a target component is only resolved if this component is
+/// a directory already extracted (not a symlink, which `..` could escape through).
+fn symlink_inside(extracted_path: &Path, prefix: &Path, path: &Path, target: &Path) -> bool {
+    let mut resolved: Vec<Component> = path
+        .parent()
+        .map(|p| p.components().filter(|c| c != &Component::CurDir).collect())
+        .unwrap_or_default();
+
+    // Resolved components from the target, rather than the (checked) entry path
+    let mut from_target = 0;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => {
+                resolved.push(component);
+                from_target += 1;
+            }
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if from_target > 0 {
+                    let dir = extracted_path.join(resolved.iter().collect::<PathBuf>());
+
+                    if !fs::symlink_metadata(dir).is_ok_and(|m| m.is_dir()) {
+                        return false;
+                    }
+
+                    from_target -= 1;
+                }
+
+                if resolved.pop().is_none() {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+
+    resolved.iter().collect::<PathBuf>().starts_with(prefix)
+}
+
+/// Resolves where the archive entry is to be extracted,
+/// refusing any path that could escape from the extraction directory:
+/// absolute path, `..` component, or existing symlink along the path.
+fn entry_target<'x>(extracted_path: &'x Path, path: &'x Path) -> Result<PathBuf, Error> {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format_error!(
+            "Invalid archive; Unsafe entry path: {:?}",
+            path
+        ));
+    }
+
+    let relative = normalize(path);
+    let target = extracted_path.join(&relative);
+    let mut ancestor = extracted_path.to_path_buf();
+
+    for component in relative.components() {
+        ancestor.push(component);
+
+        if fs::symlink_metadata(&ancestor).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(format_error!(
+                "Invalid archive; Entry path through symlink: {:?}",
+                path
+            ));
+        }
+    }
+
+    let parent = match relative.parent() {
+        Some(_) => target.parent().unwrap_or(extracted_path),
+        None => extracted_path, // Archive root (e.g. `./`)
+    };
+
+    fs::create_dir_all(parent)?;
+
+    if !parent
+        .canonicalize()?
+        .starts_with(extracted_path.canonicalize()?)
+    {
+        return Err(format_error!(
+            "Invalid archive; Entry path outside extraction directory: {:?}",
+            path
+        ));
+    }
+
+    Ok(target)
+}
+
+impl From<ZipError> for Error {
+    fn from(zerr: ZipError) -> Error {
+        Error::new(format!("Zip error: {}", zerr))
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    const SCRIPT: &[u8] = b"#!/bin/sh\n";
+
+    fn zip_file(names: &[&str]) -> File {
+        let mut ar_file = tempfile::tempfile().unwrap();
+
+        {
+            let mut writer = ZipWriter::new(&mut ar_file);
+
+            for name in names {
+                let options = FileOptions::default().unix_permissions(0o644);
+
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(SCRIPT).unwrap();
+            }
+
+            writer.finish().unwrap();
+        }
+
+        ar_file.seek(SeekFrom::Start(0)).unwrap();
+
+        ar_file
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let ar_file = zip_file(&["foo/run.sh", "foo/id.sh", "foo/lib/data.txt"]);
+        let extracted = tempfile::tempdir().unwrap();
+        let prefix = Path::new("foo");
+
+        // Detected from magic bytes, whatever the declared format
+        assert_eq!(
+            extract(
+                prefix,
+                &ar_file,
+                extracted.path(),
+                Format::TarGz,
+                Digests::default(),
+                Some("run.sh")
+            )
+            .unwrap(),
+            2
+        );
+
+        assert!(extracted.path().join("foo/lib/data.txt").is_file());
+
+        // Scripts made executable
+        let run_mode = fs::metadata(extracted.path().join("foo/run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+
+        assert_eq!(run_mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_extract_entrypoint() {
+        let extract_with = |entrypoint| {
+            let ar_file = zip_file(&["foo/bin/app", "foo/id.sh"]);
+            let extracted = tempfile::tempdir().unwrap();
+
+            extract(
+                Path::new("foo"),
+                &ar_file,
+                extracted.path(),
+                Format::Zip,
+                Digests::default(),
+                Some(entrypoint),
+            )
+            .map(|_| {
+                fs::metadata(extracted.path().join("foo/bin/app"))
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o111
+                    != 0
+            })
+        };
+
+        assert!(extract_with("./bin/app").unwrap());
+        assert!(!extract_with("/usr/bin/java").unwrap());
+
+        let err = extract_with("run.sh").unwrap_err().to_string();
+
+        assert!(err.contains("Missing executable"), "{}", err);
+
+        // Processes declared in a Procfile
+        let ar_file = zip_file(&["foo/Procfile", "foo/id.sh"]);
+        let extracted = tempfile::tempdir().unwrap();
+
+        assert_eq!(
+            extract(
+                Path::new("foo"),
+                &ar_file,
+                extracted.path(),
+                Format::Zip,
+                Digests::default(),
+                Some("run.sh"),
+            )
+            .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_extract_digests() {
+        let digest = crate::io::sha256_hex(&mut &SCRIPT[..]).unwrap();
+        let files = |d: &str| {
+            let mut files = HashMap::new();
+
+            files.insert("./foo/run.sh".to_string(), digest.clone());
+            files.insert("foo/id.sh".to_string(), d.to_string());
+
+            Digests::new(&files)
+        };
+
+        let extract_with = |digests| {
+            let ar_file = zip_file(&["foo/run.sh", "foo/id.sh"]);
+            let extracted = tempfile::tempdir().unwrap();
+
+            extract(
+                Path::new("foo"),
+                &ar_file,
+                extracted.path(),
+                Format::Zip,
+                digests,
+                Some("run.sh"),
+            )
+        };
+
+        assert!(extract_with(files(&digest.to_uppercase())).is_ok());
+
+        let err = extract_with(files("0badc0de")).unwrap_err().to_string();
+
+        assert!(err.contains("Digest mismatch"), "{}", err);
+
+        let mut missing = HashMap::new();
+
+        missing.insert("foo/lib.sh".to_string(), digest.clone());
+
+        assert!(extract_with(Digests::new(&missing)).is_err());
+    }
+
+    #[test]
+    fn test_extract_duplicate_verified() {
+        let digest = crate::io::sha256_hex(&mut &SCRIPT[..]).unwrap();
+        let mut ar_file = tempfile::tempfile().unwrap();
+
+        {
+            let enc = flate2::write::GzEncoder::new(&mut ar_file, flate2::Compression::fast());
+            let mut tar = tar::Builder::new(enc);
+
+            for (name, data) in [
+                ("foo/run.sh", SCRIPT),
+                ("foo/id.sh", SCRIPT),
+                ("./foo/run.sh", &b"#!/bin/sh\nrm -rf /\n"[..]),
+            ] {
+                let mut header = tar::Header::new_gnu();
+
+                header.set_size(data.len() as u64);
+                header.set_mode(0o755);
+                header.set_cksum();
+
+                tar.append_data(&mut header, name, data).unwrap();
+            }
+
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        ar_file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut files = HashMap::new();
+
+        files.insert("foo/run.sh".to_string(), digest);
+
+        let extracted = tempfile::tempdir().unwrap();
+        let err = extract(
+            Path::new("foo"),
+            &ar_file,
+            extracted.path(),
+            Format::TarGz,
+            Digests::new(&files),
+            Some("run.sh"),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.contains("Duplicate verified file"), "{}", err);
+    }
+
+    #[test]
+    fn test_apply_permissions() {
+        let extracted = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(extracted.path().join("foo/shared")).unwrap();
+        fs::write(extracted.path().join("foo/run.sh"), SCRIPT).unwrap();
+
+        let permission = |mode: &str| Permission {
+            mode: Some(mode.to_string()),
+            capabilities: None,
+        };
+
+        let mut permissions = HashMap::new();
+
+        permissions.insert("./foo/shared".to_string(), permission("1777"));
+
+        apply_permissions(extracted.path(), &permissions).unwrap();
+
+        let mode = fs::metadata(extracted.path().join("foo/shared"))
+            .unwrap()
+            .permissions()
+            .mode();
+
+        assert_eq!(mode & 0o7777, 0o1777);
+
+        // Setuid not allowed by default
+        permissions.insert("foo/run.sh".to_string(), permission("4755"));
+
+        assert!(apply_permissions(extracted.path(), &permissions).is_err());
+
+        let mut missing = HashMap::new();
+
+        missing.insert("foo/bin/server".to_string(), permission("0750"));
+
+        assert!(apply_permissions(extracted.path(), &missing).is_err());
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        let allowed = vec![
+            "cap_net_bind_service".to_string(),
+            "cap_net_raw".to_string(),
+        ];
+
+        assert!(check_capabilities("cap_net_bind_service+ep", &allowed).is_ok());
+        assert!(check_capabilities("CAP_NET_RAW,cap_net_bind_service=ep", &allowed).is_ok());
+        assert!(check_capabilities("cap_net_raw+p cap_net_bind_service+ei", &allowed).is_ok());
+
+        assert!(check_capabilities("cap_sys_admin+ep", &allowed).is_err());
+        assert!(check_capabilities("cap_net_raw,cap_sys_admin+ep", &allowed).is_err());
+        assert!(check_capabilities("=ep", &allowed).is_err());
+        assert!(check_capabilities("all+ep", &allowed).is_err());
+        assert!(check_capabilities("-r", &allowed).is_err());
+        assert!(check_capabilities(" ", &allowed).is_err());
+        assert!(check_capabilities("cap_net_raw+ep", &[]).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter {
+            exclude: vec![
+                glob_regex("docs/**").unwrap(),
+                glob_regex("**/*.md").unwrap(),
+            ],
+            require: vec![("bin/*".to_string(), glob_regex("bin/*").unwrap())],
+        };
+
+        let prefix = Path::new("foo");
+        let excludes = |p: &str, is_dir| filter.excludes(prefix, Path::new(p), is_dir);
+
+        assert!(excludes("./foo/docs/", true));
+        assert!(excludes("foo/docs/api/index.html", false));
+        assert!(excludes("foo/README.md", false));
+        assert!(excludes("foo/lib/README.md", false));
+        assert!(!excludes("foo/run.sh", false));
+        assert!(!excludes("foo/documentation.txt", false));
+        assert!(!excludes("docs/index.html", false));
+
+        let entries = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert!(filter
+            .check_required(prefix, &entries(&["foo/run.sh", "foo/bin/server"]))
+            .is_ok());
+
+        assert!(filter
+            .check_required(prefix, &entries(&["foo/run.sh", "foo/bin/lib/x.so"]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = || Budget {
+            max_size: 15,
+            max_file_size: 10,
+            max_entries: 2,
+            size: 0,
+            entries: 0,
+        };
+
+        let path = Path::new("foo/data");
+
+        let mut b = budget();
+        assert!(b.consume(path, 11).is_err());
+
+        let mut b = budget();
+        assert!(b.consume(path, 10).is_ok());
+        assert_eq!(b.allowance(), 5);
+        assert!(b.consume(path, 6).is_err());
+
+        let mut b = budget();
+        assert!(b.consume(path, 0).is_ok());
+        assert!(b.consume(path, 0).is_ok());
+        assert!(b.consume(path, 0).is_err());
+    }
+
+    #[test]
+    fn test_resolve_owner() {
+        assert_eq!(resolve_owner("1000").unwrap(), (1000, None));
+        assert_eq!(resolve_owner("1000:100").unwrap(), (1000, Some(100)));
+        assert_eq!(resolve_owner("root:root").unwrap(), (0, Some(0)));
+        assert!(resolve_owner("no-such-user").is_err());
+    }
+
+    #[test]
+    fn test_symlink_inside() {
+        let extracted = tempfile::tempdir().unwrap();
+        let ex = extracted.path();
+        let prefix = Path::new("foo");
+        let path = Path::new("foo/lib/current");
+
+        assert!(symlink_inside(ex, prefix, path, Path::new("v1")));
+        assert!(symlink_inside(ex, prefix, path, Path::new("../bin/run")));
+        assert!(symlink_inside(
+            ex,
+            prefix,
+            Path::new("./foo/x"),
+            Path::new("y")
+        ));
+
+        assert!(!symlink_inside(ex, prefix, path, Path::new("../../bar")));
+        assert!(!symlink_inside(ex, prefix, path, Path::new("../../../etc")));
+        assert!(!symlink_inside(ex, prefix, path, Path::new("/etc/passwd")));
+
+        // foo/sub/x -> .. then foo/y -> sub/x/../.. (i.e. the parent of foo)
+        let chained = Path::new("sub/x/../..");
+        let y = Path::new("foo/y");
+
+        fs::create_dir_all(ex.join("foo/sub")).unwrap();
+
+        assert!(!symlink_inside(ex, prefix, y, chained)); // x not yet extracted
+
+        assert!(symlink_inside(
+            ex,
+            prefix,
+            Path::new("foo/sub/x"),
+            Path::new("..")
+        ));
+        std::os::unix::fs::symlink("..", ex.join("foo/sub/x")).unwrap();
+
+        assert!(!symlink_inside(ex, prefix, y, chained));
+        assert!(symlink_inside(ex, prefix, y, Path::new("sub/../sub/x")));
+    }
+
+    #[test]
+    fn test_extract_tar_hardlink() {
+        let mut ar_file = tempfile::tempfile().unwrap();
+
+        {
+            let enc = flate2::write::GzEncoder::new(&mut ar_file, flate2::Compression::fast());
+            let mut tar = tar::Builder::new(enc);
+            let mut link = tar::Header::new_gnu();
+
+            link.set_entry_type(EntryType::Link);
+            link.set_size(0);
+
+            tar.append_link(&mut link, "foo/run.sh", "/etc/passwd")
+                .unwrap();
+
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        ar_file.seek(SeekFrom::Start(0)).unwrap();
+
+        let extracted = tempfile::tempdir().unwrap();
+        let res = extract(
+            Path::new("foo"),
+            &ar_file,
+            extracted.path(),
+            Format::TarGz,
+            Digests::default(),
+            Some("run.sh"),
+        );
+
+        assert!(res.unwrap_err().to_string().contains("Hard link"));
+    }
+
+    #[test]
+    fn test_entry_target() {
+        let extracted = tempfile::tempdir().unwrap();
+        let root = extracted.path();
+
+        assert_eq!(
+            entry_target(root, Path::new("foo/run.sh")).unwrap(),
+            root.join("foo/run.sh")
+        );
+
+        assert_eq!(entry_target(root, Path::new("./")).unwrap(), root);
+        assert_eq!(
+            entry_target(root, Path::new("./run.sh")).unwrap(),
+            root.join("run.sh")
+        );
+
+        assert!(entry_target(root, Path::new("../evil.sh")).is_err());
+        assert!(entry_target(root, Path::new("foo/../../evil.sh")).is_err());
+        assert!(entry_target(root, Path::new("/etc/passwd")).is_err());
+
+        std::os::unix::fs::symlink("/tmp", root.join("foo/link")).unwrap();
+
+        assert!(entry_target(root, Path::new("foo/link/evil.sh")).is_err());
+        assert!(entry_target(root, Path::new("foo/link")).is_err());
+    }
+
+    #[test]
+    fn test_extract_tar_zst() {
+        let mut ar_file = tempfile::tempfile().unwrap();
+
+        {
+            let enc = zstd::stream::write::Encoder::new(&mut ar_file, 0).unwrap();
+            let mut tar = tar::Builder::new(enc.auto_finish());
+            let mut dir = tar::Header::new_gnu();
+
+            dir.set_entry_type(tar::EntryType::Directory);
+            dir.set_size(0);
+            dir.set_mode(0o755);
+            dir.set_cksum();
+
+            tar.append_data(&mut dir, "foo/", std::io::empty()).unwrap();
+
+            for name in ["foo/run.sh", "foo/id.sh"] {
+                let mut header = tar::Header::new_gnu();
+
+                header.set_size(10);
+                header.set_mode(0o755);
+                header.set_cksum();
+
+                tar.append_data(&mut header, name, &b"#!/bin/sh\n"[..])
+                    .unwrap();
+            }
+
+            tar.finish().unwrap();
+        }
+
+        ar_file.seek(SeekFrom::Start(0)).unwrap();
+
+        let extracted = tempfile::tempdir().unwrap();
+
+        assert_eq!(
+            extract(
+                Path::new("foo"),
+                &ar_file,
+                extracted.path(),
+                Format::TarGz,
+                Digests::default(),
+                Some("run.sh")
+            )
+            .unwrap(),
+            2
+        );
+    }
+}
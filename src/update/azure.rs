@@ -0,0 +1,207 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use log::{debug, info};
+
+use rumqttc::MqttOptions;
+
+use serde_json::{json, Value};
+
+use super::download::query_component;
+use super::manifest;
+use crate::config;
+use crate::error;
+use crate::mqtt;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Version of the IoT Hub MQTT API.
+const API_VERSION: &str = "2021-04-12";
+
+/// Default validity (in seconds) of the SAS token.
+const DEFAULT_SAS_TTL: u64 = 3600;
+
+/// Twin property (both desired and reported) of the update.
+const PROPERTY: &str = "orm";
+
+/// Whether the updates are intended by the Azure IoT Hub device twin (`ORM_AZURE_TWIN`),
+/// rather than by the manifest.
+pub fn enabled() -> bool {
+    config::parse_or("ORM_AZURE_TWIN", setting!("ORM_AZURE_TWIN"), false)
+}
+
+/// Returns the update intended by the desired `orm` property of the twin (if any),
+/// declared as a manifest device.
+pub async fn desired(device_id: &str) -> Result<Option<manifest::Device>, Error> {
+    let twin = twin_request(device_id, "GET/", Vec::new()).await?;
+    let twin = serde_json::from_slice::<Value>(&twin)?;
+
+    debug!("Device twin: {}", twin);
+
+    match twin.pointer(&format!("/desired/{}", PROPERTY)) {
+        Some(intent) if intent.is_object() => {
+            let device = manifest::Device::from_json(intent.clone())
+                .map_err(|err| format_error!("Invalid desired property {}: {}", PROPERTY, err))?;
+
+            info!("Desired version {}", device.version);
+
+            Ok(Some(device))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reports the state of the update, as the reported `orm` property of the twin.
+pub async fn report(device_id: &str, state: Value) -> Result<(), Error> {
+    let patch = json!({ PROPERTY: state });
+
+    debug!("Reporting twin properties: {}", patch);
+
+    twin_request(
+        device_id,
+        "PATCH/properties/reported/",
+        serde_json::to_vec(&patch)?,
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Sends the twin request (`$iothub/twin/{operation}`), returning the response payload.
+async fn twin_request(
+    device_id: &str,
+    operation: &str,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let rid = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .to_string();
+
+    let topic = format!("$iothub/twin/{}?$rid={}", operation, rid);
+
+    let (response_topic, response) = mqtt::exchange(
+        options(device_id)?,
+        "$iothub/twin/res/#",
+        &topic,
+        payload,
+        |t| response_rid(t) == Some(rid.as_str()),
+    )
+    .await?;
+
+    let status = response_topic
+        .trim_start_matches("$iothub/twin/res/")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+
+    match status {
+        "200" | "204" => Ok(response),
+        _ => Err(format_error!(
+            "Twin request {} failed: status = {} {}",
+            operation,
+            status,
+            String::from_utf8_lossy(&response)
+        )),
+    }
+}
+
+/// Returns the request ID of the response topic
+/// (e.g. `$iothub/twin/res/204/?$rid=1&$version=2`).
+fn response_rid(topic: &str) -> Option<&str> {
+    topic
+        .split_once('?')?
+        .1
+        .split('&')
+        .find_map(|param| param.strip_prefix("$rid="))
+}
+
+/// Returns the MQTT options of `ORM_MQTT_URL` (the IoT Hub), authenticated as the device
+/// with a SAS token signed by `ORM_AZURE_DEVICE_KEY` if defined
+/// (otherwise with the `ORM_MQTT_*` credentials or client certificate).
+fn options(device_id: &str) -> Result<MqttOptions, Error> {
+    let mut options = match mqtt::options(device_id)? {
+        Some(options) => options,
+        None => {
+            return Err(format_error!(
+                "Missing ORM_MQTT_URL for the twin of {}",
+                device_id
+            ))
+        }
+    };
+
+    if let Some(key) = setting!("ORM_AZURE_DEVICE_KEY") {
+        let host = options.broker_address().0;
+        let ttl = config::parse_or(
+            "ORM_AZURE_SAS_TTL",
+            setting!("ORM_AZURE_SAS_TTL"),
+            DEFAULT_SAS_TTL,
+        );
+        let expiry = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            + ttl;
+
+        let username = format!("{}/{}/?api-version={}", host, device_id, API_VERSION);
+        let token = sas_token(&format!("{}/devices/{}", host, device_id), &key, expiry)?;
+
+        options.set_credentials(username, token);
+    }
+
+    Ok(options)
+}
+
+/// Returns the SAS token for the resource, signed by the (base64) key until the expiry.
+fn sas_token(resource: &str, key: &str, expiry: u64) -> Result<String, Error> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let key = engine
+        .decode(key.trim())
+        .map_err(|err| format_error!("Invalid device key: {}", err))?;
+
+    let resource = query_component(resource);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts any key size");
+
+    mac.update(format!("{}\n{}", resource, expiry).as_bytes());
+
+    let signature = engine.encode(mac.finalize().into_bytes());
+
+    Ok(format!(
+        "SharedAccessSignature sr={}&sig={}&se={}",
+        resource,
+        query_component(&signature),
+        expiry
+    ))
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sas_token() {
+        let token =
+            sas_token("hub.azure-devices.net/devices/dev1", "c2VjcmV0", 1700000000).unwrap();
+
+        assert_eq!(
+            token,
+            concat!(
+                "SharedAccessSignature sr=hub.azure-devices.net%2Fdevices%2Fdev1",
+                "&sig=1B3%2BXY3G8q61balM41o3sUBbAqkr1FDlwRPtTRsaFq0%3D&se=1700000000"
+            )
+        );
+
+        assert_eq!(
+            response_rid("$iothub/twin/res/204/?$rid=12&$version=3"),
+            Some("12")
+        );
+        assert_eq!(response_rid("$iothub/twin/res/200/?$rid=7"), Some("7"));
+        assert_eq!(response_rid("$iothub/twin/res/200/"), None);
+    }
+}
@@ -0,0 +1,776 @@
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use log::{debug, info, warn};
+
+use hyper::{Body, Method, Request, Uri};
+
+use age::secrecy::ExposeSecret;
+use age::x25519;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use serde::Serialize;
+
+use super::client::{self, HttpsClient};
+use super::encryption;
+use super::manifest::Version;
+use super::marker::Marker;
+use super::s3;
+use crate::config;
+use crate::error;
+use crate::io::{list_file_names, sha256_hex};
+use crate::logging;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Compression of the backup archives.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+
+    #[default]
+    Zstd,
+
+    None,
+}
+
+impl Compression {
+    /// Returns the file extension of the backup archive.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "tar.gz",
+            Compression::Zstd => "tar.zst",
+            Compression::None => "tar",
+        }
+    }
+
+    fn default_level(&self) -> u32 {
+        match self {
+            Compression::Gzip => 6,
+            Compression::Zstd => 3,
+            Compression::None => 0,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Compression, String> {
+        match repr {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "none" => Ok(Compression::None),
+            _ => Err(format!("Unsupported compression: {}", repr)),
+        }
+    }
+}
+
+/// Whether the previous application directory is archived (`ORM_BACKUP`),
+/// otherwise it's just removed.
+pub fn enabled() -> bool {
+    config::parse_or("ORM_BACKUP", setting!("ORM_BACKUP"), true)
+}
+
+/// Whether the backup archives are encrypted (`ORM_BACKUP_ENCRYPTION`),
+/// with the device backup key.
+pub fn encrypted() -> bool {
+    config::parse_or(
+        "ORM_BACKUP_ENCRYPTION",
+        setting!("ORM_BACKUP_ENCRYPTION"),
+        false,
+    )
+}
+
+/// Loads the device backup key, as age identity
+/// (`ORM_BACKUP_KEY_FILE`, default: `{ORM_STATE_DIR}/.orm_backup_key`), generated on the first use.
+pub fn key(local_prefix: &Path) -> std::io::Result<x25519::Identity> {
+    let path = setting!("ORM_BACKUP_KEY_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config::state_dir(local_prefix).join(".orm_backup_key"));
+
+    match fs::read_to_string(&path) {
+        Ok(content) => content.trim().parse::<x25519::Identity>().map_err(|err| {
+            std::io::Error::other(format!("Invalid backup key {:?}: {}", path, err))
+        }),
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => {
+            info!("Generating backup key {:?}", path);
+
+            let identity = x25519::Identity::generate();
+
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?;
+
+            writeln!(file, "{}", identity.to_string().expose_secret())?;
+            file.sync_all()?;
+
+            Ok(identity)
+        }
+        Err(cause) => Err(cause),
+    }
+}
+
+/// Opens the backup archive, decrypted (to a temporary file) if encrypted.
+pub fn open(local_prefix: &Path, path: &Path) -> std::io::Result<File> {
+    let mut file = File::open(path)?;
+
+    if !path.to_string_lossy().ends_with(".age") {
+        return Ok(file);
+    }
+
+    let identities: Vec<Box<dyn age::Identity>> = vec![Box::new(key(local_prefix)?)];
+
+    encryption::decrypt(&mut file, &identities)
+        .map_err(|err| std::io::Error::other(format!("Fails to decrypt {:?}: {}", path, err)))
+}
+
+/// Records the update in the history (`.orm_history`),
+/// as `{timestamp}\t{previous version}\t{version}\t{backup name or -}\t{run ID}`.
+pub fn record<'x>(
+    local_prefix: &'x Path,
+    previous_dir: &'x Path,
+    version: &'x Version,
+    backup: Option<&'x Path>,
+) -> std::io::Result<()> {
+    let previous = Marker::load(previous_dir)
+        .ok()
+        .flatten()
+        .map(|m| m.version)
+        .unwrap_or_else(|| "0.0.0".to_string());
+
+    let name = backup
+        .and_then(|p| p.file_name())
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "-".to_string());
+
+    let mut history = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(config::state_dir(local_prefix).join(".orm_history"))?;
+
+    writeln!(
+        history,
+        "{}\t{}\t{}\t{}\t{}",
+        Utc::now().to_rfc3339(),
+        previous,
+        version,
+        name,
+        logging::run_id()
+    )
+}
+
+/// Update recorded in the history.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Record {
+    pub timestamp: String,
+    pub previous_version: String,
+    pub version: String,
+
+    /// Name of the backup of the previous version, if any.
+    pub backup: Option<String>,
+
+    pub run_id: Option<String>,
+}
+
+/// Returns the updates recorded in the history, oldest first.
+pub fn history(local_prefix: &Path) -> std::io::Result<Vec<Record>> {
+    let content = match fs::read_to_string(config::state_dir(local_prefix).join(".orm_history")) {
+        Ok(content) => content,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(cause) => return Err(cause),
+    };
+
+    let field = |f: Option<&str>| f.filter(|v| !v.is_empty() && *v != "-").map(String::from);
+
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+
+            Some(Record {
+                timestamp: fields.next()?.to_string(),
+                previous_version: fields.next()?.to_string(),
+                version: fields.next()?.to_string(),
+                backup: field(fields.next()),
+                run_id: field(fields.next()),
+            })
+        })
+        .collect())
+}
+
+/// Backup archive of a previous version, available locally.
+#[derive(Debug)]
+pub struct Backup {
+    pub name: String,
+
+    /// Time the previous version was replaced (from the archive name).
+    pub timestamp: Option<DateTime<Utc>>,
+
+    /// Version marker (`.orm_version`) embedded in the archive, if any.
+    pub version: Option<String>,
+
+    /// Size in bytes of the archive.
+    pub size: u64,
+}
+
+/// Lists the backups of the application (in the state directory), newest first.
+pub fn list(local_prefix: &Path, app_name: &str) -> std::io::Result<Vec<Backup>> {
+    let state_dir = config::state_dir(local_prefix);
+    let mut names = list_file_names(&state_dir, |n| is_backup(app_name, n))?;
+
+    names.sort_by_key(|n| std::cmp::Reverse(order_key(n)));
+
+    let mut backups = Vec::new();
+
+    for name in names {
+        let path = state_dir.join(&name);
+
+        let timestamp = name
+            .strip_prefix(app_name)
+            .and_then(|n| n.strip_prefix('-'))
+            .and_then(|n| n.get(0..14))
+            .and_then(|ts| NaiveDateTime::parse_from_str(ts, "%Y%m%d%H%M%S").ok())
+            .map(|ts| DateTime::<Utc>::from_utc(ts, Utc));
+
+        let version = embedded_version(local_prefix, app_name, &path).unwrap_or_else(|cause| {
+            warn!("Fails to read the version of backup {}: {}", name, cause);
+            None
+        });
+
+        backups.push(Backup {
+            timestamp,
+            version,
+            size: fs::metadata(&path)?.len(),
+            name,
+        });
+    }
+
+    Ok(backups)
+}
+
+/// Returns the name of the newest backup of the application (in the state directory), if any.
+pub fn latest(local_prefix: &Path, app_name: &str) -> std::io::Result<Option<String>> {
+    let names = list_file_names(&config::state_dir(local_prefix), |n| is_backup(app_name, n))?;
+
+    Ok(names.into_iter().max_by_key(|n| order_key(n)))
+}
+
+/// Reads the version marker from the backup archive.
+fn embedded_version(
+    local_prefix: &Path,
+    app_name: &str,
+    path: &Path,
+) -> std::io::Result<Option<String>> {
+    let file = open(local_prefix, path)?;
+    let path_repr = path.to_string_lossy();
+    let name = path_repr.trim_end_matches(".age");
+
+    let reader: Box<dyn Read> = if name.ends_with(Compression::Gzip.extension()) {
+        Box::new(GzDecoder::new(file))
+    } else if name.ends_with(Compression::Zstd.extension()) {
+        Box::new(zstd::stream::read::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    let marker = Path::new(app_name).join(".orm_version");
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if entry.path()? == marker {
+            let mut content = String::new();
+
+            entry.read_to_string(&mut content)?;
+
+            return Ok(Marker::parse(&content).ok().map(|m| m.version));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the ordering key of a backup name (`{app}-{timestamp}[-{n}].{extension}`):
+/// its stem without the collision suffix (`-{n}`, see `unique_base`), then `n`.
+pub fn order_key(name: &str) -> (String, u32) {
+    let unencrypted = name.trim_end_matches(".age");
+    let stem = [Compression::Gzip, Compression::Zstd, Compression::None]
+        .iter()
+        .find_map(|c| unencrypted.strip_suffix(&format!(".{}", c.extension())))
+        .unwrap_or(unencrypted);
+
+    let is_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+    match stem.rsplit_once('-') {
+        Some((base, n))
+            if n.len() < 14
+                && is_digits(n)
+                && base.len() >= 14
+                && is_digits(&base[base.len() - 14..]) =>
+        {
+            (base.to_string(), n.parse().unwrap_or(0))
+        }
+        _ => (stem.to_string(), 0),
+    }
+}
+
+/// Returns a base path `{app}-{timestamp}` for the previous version not used yet
+/// by a directory or a backup, suffixed by `-{n}` on collision (updates within a second).
+pub fn unique_base(
+    local_prefix: &Path,
+    app_name: &str,
+    now: DateTime<Utc>,
+) -> std::io::Result<PathBuf> {
+    let base = format!("{}-{}", app_name, now.format("%Y%m%d%H%M%S"));
+    let mut used = list_file_names(local_prefix, |n| n.starts_with(&base))
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+    let state_dir = config::state_dir(local_prefix);
+
+    if state_dir != local_prefix {
+        used.extend(
+            list_file_names(&state_dir, |n| n.starts_with(&base))
+                .map_err(|err| std::io::Error::other(err.to_string()))?,
+        );
+    }
+
+    let is_used = |candidate: &str| {
+        used.iter().any(|n| {
+            n == candidate
+                || n.strip_prefix(candidate)
+                    .is_some_and(|ext| ext.starts_with('.'))
+        })
+    };
+
+    let mut candidate = base.clone();
+    let mut n = 0;
+
+    while is_used(&candidate) {
+        n += 1;
+        candidate = format!("{}-{}", base, n);
+    }
+
+    Ok(local_prefix.join(candidate))
+}
+
+/// Checks whether the file name is a backup archive of the application (possibly encrypted).
+pub fn is_backup(app_name: &str, name: &str) -> bool {
+    let unencrypted = name.trim_end_matches(".age");
+
+    name.starts_with(app_name)
+        && [Compression::Gzip, Compression::Zstd, Compression::None]
+            .iter()
+            .any(|c| unencrypted.ends_with(&format!(".{}", c.extension())))
+}
+
+/// Removes the oldest backups of the application beyond the retention:
+/// at most `ORM_BACKUP_KEEP` archives (default: 1), and `ORM_BACKUP_MAX_SIZE` bytes in total;
+/// The newest backup is always kept.
+pub fn retain(local_prefix: &Path, app_name: &str) -> std::io::Result<()> {
+    let keep = config::parse_or("ORM_BACKUP_KEEP", setting!("ORM_BACKUP_KEEP"), 1usize);
+    let max_size =
+        config::parse_or::<u64>("ORM_BACKUP_MAX_SIZE", setting!("ORM_BACKUP_MAX_SIZE"), 0);
+
+    let state_dir = config::state_dir(local_prefix);
+    let mut backups = Vec::new();
+
+    for name in list_file_names(&state_dir, |n| is_backup(app_name, n))? {
+        let size = fs::metadata(state_dir.join(&name))?.len();
+
+        backups.push((name, size));
+    }
+
+    for name in expired(backups, keep, Some(max_size).filter(|s| *s > 0)) {
+        debug!("Cleaning previous archive: {}", name);
+
+        let path = state_dir.join(name);
+
+        fs::remove_file(&path)?;
+
+        match fs::remove_file(digest_path(&path)) {
+            Err(cause) if cause.kind() != std::io::ErrorKind::NotFound => return Err(cause),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the names of the backups beyond the retention, oldest first.
+fn expired(mut backups: Vec<(String, u64)>, keep: usize, max_size: Option<u64>) -> Vec<String> {
+    // Timestamped names, so the newest first
+    backups.sort_by_key(|b| std::cmp::Reverse(order_key(&b.0)));
+
+    let mut total = 0;
+    let mut expired = Vec::new();
+
+    for (i, (name, size)) in backups.into_iter().enumerate() {
+        total += size;
+
+        if i > 0 && (i >= keep || max_size.map(|max| total > max).unwrap_or(false)) {
+            expired.push(name);
+        }
+    }
+
+    expired.reverse();
+
+    expired
+}
+
+/// Archives the application directory as `{base}.{extension}`,
+/// compressed according `ORM_BACKUP_COMPRESSION` and `ORM_BACKUP_LEVEL`,
+/// and encrypted (`.age` suffix) according `ORM_BACKUP_ENCRYPTION`.
+pub fn create<'x>(app_name: &'x str, dir: &'x Path, base: &'x Path) -> std::io::Result<PathBuf> {
+    let compression: Compression = config::parse_or(
+        "ORM_BACKUP_COMPRESSION",
+        setting!("ORM_BACKUP_COMPRESSION"),
+        Compression::default(),
+    );
+
+    let level = config::parse_or(
+        "ORM_BACKUP_LEVEL",
+        setting!("ORM_BACKUP_LEVEL"),
+        compression.default_level(),
+    );
+
+    let path = if encrypted() {
+        base.with_extension(format!("{}.age", compression.extension()))
+    } else {
+        base.with_extension(compression.extension())
+    };
+
+    let partial = path.with_file_name(format!(
+        ".{}.partial",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let file = File::create(&partial)?;
+
+    debug!(
+        "Archiving {:?} to {:?} ({:?}, level {})",
+        dir, path, compression, level
+    );
+
+    let file = if encrypted() {
+        let recipient = key(base.parent().unwrap_or(Path::new(".")))?.to_public();
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .map_err(std::io::Error::other)?;
+
+        compress(
+            app_name,
+            dir,
+            compression,
+            level,
+            encryptor.wrap_output(file)?,
+        )?
+        .finish()?
+    } else {
+        compress(app_name, dir, compression, level, file)?
+    };
+
+    file.sync_all()?;
+
+    let digest = sha256_hex(&mut File::open(&partial)?)
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+    fs::write(digest_path(&path), format!("{}\n", digest))?;
+    fs::rename(&partial, &path)?;
+
+    Ok(path)
+}
+
+/// Returns the path of the SHA-256 digest recorded for the backup archive (`.sha256` suffix).
+fn digest_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+
+    name.push(".sha256");
+
+    PathBuf::from(name)
+}
+
+/// Checks the backup archive against the SHA-256 digest recorded once created
+/// (only warns if none, for the backups created before).
+pub fn verify(path: &Path) -> Result<(), Error> {
+    let expected = match fs::read_to_string(digest_path(path)) {
+        Ok(digest) => digest.trim().to_lowercase(),
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => {
+            warn!("No digest recorded for backup {:?}", path);
+
+            return Ok(());
+        }
+        Err(cause) => return Err(cause.into()),
+    };
+
+    let digest = sha256_hex(&mut File::open(path)?)?;
+
+    if digest != expected {
+        return Err(format_error!(
+            "Invalid backup {:?}; SHA-256 mismatch: {} != {}",
+            path,
+            digest,
+            expected
+        ));
+    }
+
+    Ok(())
+}
+
+/// Uploads the backup archive to `ORM_BACKUP_UPLOAD_URL` (if any),
+/// as `{url}/{thing ID}/{name}`; Only warns on failure, as the local backup is kept anyway.
+pub fn upload(local_prefix: &Path, app_name: &str, path: &Path) {
+    let base_url = match setting!("ORM_BACKUP_UPLOAD_URL") {
+        Some(url) => url,
+        None => return,
+    };
+
+    let res = super::resolve_id(&local_prefix.join(app_name)).and_then(|thing_id| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let url = format!("{}/{}/{}", base_url.trim_end_matches('/'), thing_id, name);
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|err| format_error!("Invalid URL {}: {}", url, err))?;
+        let client = client::new_client()?;
+
+        info!("Uploading backup {:?} to {}", path, url);
+
+        // The update is executed on the multi-threaded runtime
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(put(&client, &uri, path))
+        })
+    });
+
+    if let Err(cause) = res {
+        warn!("Fails to upload backup {:?}: {}", path, cause);
+    }
+}
+
+async fn put<'x>(client: &'x HttpsClient, uri: &'x Uri, path: &'x Path) -> Result<(), Error> {
+    let payload = tokio::fs::read(path).await?;
+
+    let builder = match uri.scheme_str() {
+        Some("s3") => {
+            let digest = sha256_hex(&mut payload.as_slice())?;
+
+            s3::request(client, Method::PUT, uri, None, Some(&digest)).await?
+        }
+        _ => Request::builder().method(Method::PUT).uri(uri.clone()),
+    };
+
+    let req = builder
+        .body(Body::from(payload))
+        .map_err(|err| format_error!("Invalid request for {}: {}", uri, err))?;
+
+    let resp = client.request(req).await?;
+
+    if !resp.status().is_success() {
+        return Err(format_error!(
+            "Fails to upload to {}: status = {}",
+            uri,
+            resp.status()
+        ));
+    }
+
+    Ok(())
+}
+
+fn compress<W: Write>(
+    app_name: &str,
+    dir: &Path,
+    compression: Compression,
+    level: u32,
+    writer: W,
+) -> std::io::Result<W> {
+    match compression {
+        Compression::Gzip => {
+            let enc = GzEncoder::new(writer, flate2::Compression::new(level.min(9)));
+
+            append(app_name, dir, enc)?.finish()
+        }
+        Compression::Zstd => {
+            let enc = zstd::stream::write::Encoder::new(writer, level as i32)?;
+
+            append(app_name, dir, enc)?.finish()
+        }
+        Compression::None => append(app_name, dir, writer),
+    }
+}
+
+fn append<W: Write>(app_name: &str, dir: &Path, writer: W) -> std::io::Result<W> {
+    let mut tar = tar::Builder::new(writer);
+
+    tar.append_dir_all(app_name, dir)?;
+    tar.into_inner()
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_expired() {
+        let backups = vec![
+            ("foo-20260101000000.tar.zst".to_string(), 300),
+            ("foo-20260301000000.tar.zst".to_string(), 100),
+            ("foo-20260201000000.tar.gz".to_string(), 200),
+        ];
+
+        assert_eq!(
+            expired(backups.clone(), 1, None),
+            vec!["foo-20260101000000.tar.zst", "foo-20260201000000.tar.gz"]
+        );
+        assert_eq!(
+            expired(backups.clone(), 3, Some(350)),
+            vec!["foo-20260101000000.tar.zst"]
+        );
+
+        // The newest is kept whatever its size
+        assert_eq!(expired(backups, 2, Some(50)).len(), 2);
+    }
+
+    #[test]
+    fn test_unique_base() {
+        let tmp = tempfile::tempdir().unwrap();
+        let now = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+
+        let first = unique_base(tmp.path(), "foo", now).unwrap();
+
+        assert_eq!(first, tmp.path().join("foo-20260101000000"));
+
+        fs::write(tmp.path().join("foo-20260101000000.tar.zst"), "").unwrap();
+
+        let second = unique_base(tmp.path(), "foo", now).unwrap();
+
+        assert_eq!(second, tmp.path().join("foo-20260101000000-1"));
+
+        fs::create_dir(&second).unwrap();
+
+        assert_eq!(
+            unique_base(tmp.path(), "foo", now).unwrap(),
+            tmp.path().join("foo-20260101000000-2")
+        );
+
+        let mut names = vec![
+            "foo-20260101000000-1.tar.zst",
+            "foo-20260101000001.tar.zst",
+            "foo-20260101000000-10.tar.zst.age",
+            "foo-20260101000000.tar.zst",
+        ];
+
+        names.sort_by_key(|n| order_key(n));
+
+        assert_eq!(
+            names,
+            vec![
+                "foo-20260101000000.tar.zst",
+                "foo-20260101000000-1.tar.zst",
+                "foo-20260101000000-10.tar.zst.age",
+                "foo-20260101000001.tar.zst",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("foo");
+
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("run.sh"), "#!/bin/sh").unwrap();
+        fs::write(dir.join(".orm_version"), "1.0.0").unwrap();
+
+        std::env::set_var("ORM_BACKUP_COMPRESSION", "gzip");
+
+        let gzip = create("foo", &dir, &tmp.path().join("foo-20260101000000")).unwrap();
+
+        std::env::remove_var("ORM_BACKUP_COMPRESSION");
+
+        let zstd = create("foo", &dir, &tmp.path().join("foo-20260102000000")).unwrap();
+
+        assert!(is_backup(
+            "foo",
+            &gzip.file_name().unwrap().to_string_lossy()
+        ));
+        assert!(zstd.to_string_lossy().ends_with(".tar.zst"));
+        assert!(verify(&zstd).is_ok());
+
+        let names = |archive: tar::Archive<Box<dyn std::io::Read>>| {
+            let mut archive = archive;
+
+            archive
+                .entries()
+                .unwrap()
+                .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        let gz = flate2::read::GzDecoder::new(File::open(&gzip).unwrap());
+
+        assert!(names(tar::Archive::new(Box::new(gz))).contains(&"foo/run.sh".to_string()));
+
+        let zst = zstd::stream::read::Decoder::new(File::open(&zstd).unwrap()).unwrap();
+
+        assert!(names(tar::Archive::new(Box::new(zst))).contains(&"foo/run.sh".to_string()));
+
+        let backups = list(tmp.path(), "foo").unwrap();
+
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].name, "foo-20260102000000.tar.zst");
+        assert_eq!(
+            backups[0].timestamp.map(|ts| ts.to_rfc3339()),
+            Some("2026-01-02T00:00:00+00:00".to_string())
+        );
+        assert_eq!(backups[1].version, Some("1.0.0".to_string()));
+
+        std::env::set_var("ORM_BACKUP_ENCRYPTION", "true");
+
+        let encrypted = create("foo", &dir, &tmp.path().join("foo-20260103000000")).unwrap();
+
+        std::env::remove_var("ORM_BACKUP_ENCRYPTION");
+
+        assert!(encrypted.to_string_lossy().ends_with(".tar.zst.age"));
+        assert!(tmp.path().join(".orm_backup_key").is_file());
+
+        let backups = list(tmp.path(), "foo").unwrap();
+
+        assert_eq!(backups[0].name, "foo-20260103000000.tar.zst.age");
+        assert_eq!(backups[0].version, Some("1.0.0".to_string()));
+
+        // Unreadable, still listed
+        fs::write(tmp.path().join("foo-20260104000000.tar.zst"), "truncated").unwrap();
+
+        let backups = list(tmp.path(), "foo").unwrap();
+
+        assert_eq!(backups.len(), 4);
+        assert_eq!(backups[0].version, None);
+        assert_eq!(
+            latest(tmp.path(), "foo").unwrap().as_deref(),
+            Some("foo-20260104000000.tar.zst")
+        );
+
+        // Corrupted
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&gzip)
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+
+        assert!(verify(&gzip).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use super::health;
+use super::manifest;
+use super::marker::Marker;
+use crate::config;
+use crate::process;
+use crate::setting;
+
+/// Default duration (in seconds) the canary must stay alive.
+const DEFAULT_WINDOW: u64 = 10;
+
+/// Whether the updated version is first run as a canary (`ORM_CANARY`).
+pub fn enabled() -> bool {
+    config::parse_or("ORM_CANARY", setting!("ORM_CANARY"), false)
+}
+
+/// Runs the staged application as a canary (with `ORM_CANARY=1`, from the staging directory),
+/// side by side with the current one: it must stay alive during the canary window
+/// (`ORM_CANARY_WINDOW`) and pass its health probe (if any);
+/// The current application is then stopped, so the update can be swapped.
+pub async fn run(
+    app_dir: &Path,
+    staged_app: &Path,
+    device: &manifest::Device,
+    staged_marker: Option<&Marker>,
+) -> std::io::Result<()> {
+    let window = Duration::from_secs(config::parse_or(
+        "ORM_CANARY_WINDOW",
+        setting!("ORM_CANARY_WINDOW"),
+        DEFAULT_WINDOW,
+    ));
+
+    let marker = Marker::load_runnable(app_dir);
+
+    let mut current = if process::runnable(app_dir, marker.as_ref()) {
+        info!("Keeping the current version running during the canary ...");
+
+        Some(process::start(app_dir, process::commands(app_dir, marker.as_ref())?).await?)
+    } else {
+        None
+    };
+
+    let spawned = match process::commands(staged_app, staged_marker) {
+        Ok(mut commands) => {
+            for (_, command) in commands.iter_mut() {
+                command.current_dir(staged_app).env("ORM_CANARY", "1");
+            }
+
+            process::start(staged_app, commands).await
+        }
+        Err(cause) => Err(cause),
+    };
+
+    let res = match spawned {
+        Ok(mut canary) => {
+            info!("Canary started from {:?} ...", staged_app);
+
+            let res = watch(
+                &mut canary,
+                staged_app,
+                device.healthcheck.as_deref(),
+                window,
+            )
+            .await;
+
+            process::stop(&mut canary).await?;
+
+            res
+        }
+        Err(cause) => Err(cause),
+    };
+
+    if let Some(current) = current.as_mut() {
+        if res.is_err() {
+            warn!("Canary failed; Stopping the current version to be restarted");
+        }
+
+        process::stop(current).await?;
+    }
+
+    res
+}
+
+async fn watch(
+    canary: &mut process::Application,
+    staged_app: &Path,
+    healthcheck: Option<&str>,
+    window: Duration,
+) -> std::io::Result<()> {
+    let deadline = Instant::now() + window;
+
+    if let Some(probe) = health::probe(healthcheck, staged_app) {
+        health::check(&probe, staged_app, canary).await?;
+    }
+
+    debug!("Watching the canary for {:?}", window);
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if let Some(status) = canary.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "Canary exited during its window: {}",
+                status
+            )));
+        }
+
+        tokio::time::sleep(remaining.min(Duration::from_millis(200))).await;
+    }
+
+    info!("Canary passed its window of {:?}", window);
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+use serde::Deserialize;
+
+use super::client::HttpsClient;
+use super::download::{self, Fetched, Location};
+use super::progress::Progress;
+use crate::config;
+use crate::error;
+use crate::format_error;
+use crate::io::{list_file_names, sha256_hex};
+use error::Error;
+
+/// Index of the chunks the application archive is made of, in order.
+#[derive(Debug, Deserialize)]
+pub struct ChunkIndex {
+    pub chunks: Vec<Chunk>,
+}
+
+/// Content addressed chunk of the application archive.
+#[derive(Debug, Deserialize)]
+pub struct Chunk {
+    /// SHA-256 digest (hexadecimal) of the chunk content.
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Returns the directory of the local chunk store.
+pub fn store_dir(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_chunks")
+}
+
+/// Reconstructs the application archive to the target file,
+/// from the chunks listed in the index at given location.
+///
+/// Only the chunks missing from the local store are downloaded,
+/// from the `chunks/{sha256}` files next to the index.
+pub async fn download_to<'x>(
+    client: &'x HttpsClient,
+    index_location: &'x Location,
+    local_prefix: &'x Path,
+    target: &'x mut File,
+) -> Result<u64, Error> {
+    let index: ChunkIndex = match download::fetch_with_hints(client, index_location, &[]).await? {
+        Fetched::Content(body, _) => serde_yaml::from_slice(&body)?,
+        Fetched::RetryAfter(status, _) => {
+            return Err(format_error!(
+                "Chunk index unavailable: status = {}",
+                status
+            ))
+        }
+    };
+
+    let store = store_dir(local_prefix);
+
+    fs::create_dir_all(&store)?;
+
+    let mut referenced = HashSet::new();
+    let mut downloaded = 0;
+    let progress = Progress::new(
+        "Checking chunks".to_string(),
+        "chunks",
+        Some(index.chunks.len() as u64),
+    );
+
+    for chunk in index.chunks.iter() {
+        progress.advance(1);
+
+        let name = chunk.sha256.to_lowercase();
+
+        if name.len() != 64 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format_error!("Invalid chunk digest: {}", chunk.sha256));
+        }
+
+        if referenced.insert(name.clone()) && !store.join(&name).is_file() {
+            let location = index_location.sibling(&format!("chunks/{}", name))?;
+
+            debug!("Downloading chunk {}", location);
+
+            fetch_chunk(client, &location, &store, &name, chunk.size).await?;
+
+            downloaded += 1;
+        }
+    }
+
+    info!(
+        "Downloaded {} chunks, {} reused from the local store",
+        downloaded,
+        referenced.len() - downloaded
+    );
+
+    let mut size = 0;
+
+    for chunk in index.chunks.iter() {
+        let mut source = File::open(store.join(chunk.sha256.to_lowercase()))?;
+
+        size += std::io::copy(&mut source, target)?;
+    }
+
+    // Only keep the chunks of the current archive
+    for name in list_file_names(&store, |n| !referenced.contains(n))? {
+        debug!("Removing obsolete chunk: {}", name);
+
+        fs::remove_file(store.join(name))?;
+    }
+
+    Ok(size)
+}
+
+async fn fetch_chunk<'x>(
+    client: &'x HttpsClient,
+    location: &'x Location,
+    store: &'x Path,
+    name: &'x str,
+    expected_size: u64,
+) -> Result<(), Error> {
+    let partial = store.join(format!(".{}", name));
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)?;
+
+    let size = download::download_to(client, location, &mut file).await?;
+
+    file.seek(SeekFrom::Start(0))?;
+
+    let digest = sha256_hex(&mut file)?;
+
+    if size != expected_size || digest != name {
+        fs::remove_file(&partial)?;
+
+        return Err(format_error!(
+            "Chunk mismatch: {} ({} bytes) != {} ({} bytes)",
+            digest,
+            size,
+            name,
+            expected_size
+        ));
+    }
+
+    fs::rename(&partial, store.join(name))?;
+
+    Ok(())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    use crate::io::hex;
+    use sha2::{Digest, Sha256};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_download_to() {
+        let origin = tempfile::tempdir().unwrap();
+        let local_prefix = tempfile::tempdir().unwrap();
+
+        fs::create_dir(origin.path().join("chunks")).unwrap();
+
+        let publish = |parts: &[&[u8]]| {
+            let index: Vec<String> = parts
+                .iter()
+                .map(|part| {
+                    let digest = hex(&Sha256::digest(part));
+
+                    fs::write(origin.path().join("chunks").join(&digest), part).unwrap();
+
+                    format!("  - sha256: {}\n    size: {}", digest, part.len())
+                })
+                .collect();
+
+            let path = origin.path().join("foo.caidx.yaml");
+
+            fs::write(&path, format!("chunks:\n{}\n", index.join("\n"))).unwrap();
+
+            Location::Local(path)
+        };
+
+        let client = crate::update::client::new_client().unwrap();
+
+        let reconstruct = |location: Location| {
+            let client = &client;
+            let local_prefix = local_prefix.path().to_path_buf();
+
+            async move {
+                let mut target = tempfile::tempfile().unwrap();
+
+                download_to(client, &location, &local_prefix, &mut target)
+                    .await
+                    .map(|_| {
+                        let mut content = String::new();
+
+                        target.seek(SeekFrom::Start(0)).unwrap();
+                        target.read_to_string(&mut content).unwrap();
+
+                        content
+                    })
+            }
+        };
+
+        let v1 = publish(&[b"lorem ", b"ipsum ", b"dolor"]);
+
+        assert_eq!(reconstruct(v1).await.unwrap(), "lorem ipsum dolor");
+
+        // Shared chunks are reused from the local store
+        fs::remove_dir_all(origin.path().join("chunks")).unwrap();
+        fs::create_dir(origin.path().join("chunks")).unwrap();
+
+        let v2 = publish(&[b"lorem ", b"ipsum ", b"sit amet"]);
+
+        fs::remove_file(
+            origin
+                .path()
+                .join("chunks")
+                .join(hex(&Sha256::digest(b"lorem "))),
+        )
+        .unwrap();
+
+        assert_eq!(
+            reconstruct(v2.clone()).await.unwrap(),
+            "lorem ipsum sit amet"
+        );
+
+        // Obsolete chunks are removed from the store
+        let stored = list_file_names(&store_dir(local_prefix.path()), |_| true).unwrap();
+
+        assert_eq!(stored.len(), 3);
+        assert!(!stored.contains(&hex(&Sha256::digest(b"dolor"))));
+
+        // Tampered chunk
+        let ipsum = hex(&Sha256::digest(b"ipsum "));
+
+        fs::remove_file(store_dir(local_prefix.path()).join(&ipsum)).unwrap();
+        File::create(origin.path().join("chunks").join(&ipsum))
+            .and_then(|mut f| f.write_all(b"IPSUM "))
+            .unwrap();
+
+        assert!(reconstruct(v2).await.is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::config;
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+pub type HttpsClient = Client<AlpnConnector>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Default duration (in seconds) an idle connection is kept in the pool.
+const DEFAULT_IDLE_TIMEOUT: u64 = 90;
+
+/// Default interval (in seconds) of the TCP and HTTP/2 keep-alive.
+const DEFAULT_KEEP_ALIVE: u64 = 30;
+
+/// Client shared by the whole process (see `new_client`).
+static CLIENT: OnceLock<HttpsClient> = OnceLock::new();
+
+/// Returns the client shared by all the requests (built once),
+/// so the connections (HTTP/1.1 keep-alive or HTTP/2 negotiated with ALPN)
+/// are reused for the requests to the same origin.
+pub fn new_client() -> Result<HttpsClient, Error> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client.clone());
+    }
+
+    let client = build_client()?;
+
+    Ok(CLIENT.get_or_init(|| client).clone())
+}
+
+/// Builds the client, with its pool and connector settings.
+fn build_client() -> Result<HttpsClient, Error> {
+    let idle_timeout = Duration::from_secs(config::parse_or(
+        "ORM_HTTP_IDLE_TIMEOUT",
+        setting!("ORM_HTTP_IDLE_TIMEOUT"),
+        DEFAULT_IDLE_TIMEOUT,
+    ));
+
+    let keep_alive = Duration::from_secs(config::parse_or(
+        "ORM_HTTP_KEEP_ALIVE",
+        setting!("ORM_HTTP_KEEP_ALIVE"),
+        DEFAULT_KEEP_ALIVE,
+    ));
+
+    let mut http = HttpConnector::new();
+
+    http.enforce_http(false);
+    http.set_keepalive(Some(keep_alive));
+    http.set_nodelay(true);
+
+    let tls = native_tls::TlsConnector::builder()
+        .request_alpns(&["h2", "http/1.1"])
+        .build()
+        .map_err(|err| Error::new(format!("TLS error: {}", err)))?;
+
+    let https = HttpsConnector::from((http, tls.into()));
+
+    Ok(Client::builder()
+        .pool_idle_timeout(idle_timeout)
+        .pool_max_idle_per_host(4)
+        .http2_keep_alive_interval(keep_alive)
+        .http2_keep_alive_while_idle(true)
+        .build::<_, hyper::Body>(AlpnConnector(https)))
+}
+
+/// Posts the JSON body to the URL, which has to accept it within the timeout.
+pub async fn post_json(url: &str, body: Vec<u8>, timeout: Duration) -> Result<(), Error> {
+    post_json_with(url, &[], body, timeout).await
+}
+
+/// Posts the JSON body to the URL with the additional headers (see `post_json`).
+pub async fn post_json_with(
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Vec<u8>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|err| format_error!("Invalid URL {}: {}", url, err))?;
+
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(CONTENT_TYPE, "application/json");
+
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+
+    let req = req
+        .body(Body::from(body))
+        .map_err(|err| format_error!("Invalid request for {}: {}", url, err))?;
+
+    let resp = tokio::time::timeout(timeout, new_client()?.request(req))
+        .await
+        .map_err(|_| format_error!("Timed out after {:?}", timeout))??;
+
+    if !resp.status().is_success() {
+        return Err(format_error!("Status = {}", resp.status()));
+    }
+
+    Ok(())
+}
+
+/// HTTPS connector, indicating to the client
+/// when HTTP/2 has been negotiated with ALPN.
+#[derive(Clone)]
+pub struct AlpnConnector(HttpsConnector<HttpConnector>);
+
+impl Service<Uri> for AlpnConnector {
+    type Response = AlpnStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<AlpnStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.0.call(uri);
+
+        Box::pin(async move { connecting.await.map(AlpnStream) })
+    }
+}
+
+/// Connection stream, either plain or TLS.
+pub struct AlpnStream(MaybeHttpsStream<TcpStream>);
+
+impl Connection for AlpnStream {
+    fn connected(&self) -> Connected {
+        let connected = self.0.connected();
+
+        match &self.0 {
+            MaybeHttpsStream::Https(tls) => {
+                let alpn = tls.get_ref().negotiated_alpn().ok().flatten();
+
+                if alpn.as_deref() == Some(b"h2") {
+                    debug!("HTTP/2 negotiated");
+
+                    connected.negotiated_h2()
+                } else {
+                    connected
+                }
+            }
+            MaybeHttpsStream::Http(_) => connected,
+        }
+    }
+}
+
+impl AsyncRead for AlpnStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AlpnStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
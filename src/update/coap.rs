@@ -0,0 +1,456 @@
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use coap_lite::block_handler::BlockValue;
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType, ResponseType};
+
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+use hyper::Uri;
+
+use crate::config;
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Default port for `coap://`.
+const COAP_PORT: u16 = 5683;
+
+/// Default port for `coaps://`.
+const COAPS_PORT: u16 = 5684;
+
+/// Initial timeout waiting for an acknowledgement (RFC 7252 `ACK_TIMEOUT`).
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of retransmissions (RFC 7252 `MAX_RETRANSMIT`).
+const MAX_RETRANSMIT: u32 = 4;
+
+/// Preferred block size (in bytes).
+const BLOCK_SIZE: usize = 1024;
+
+/// Default maximum size (in bytes) of a fetched resource.
+const DEFAULT_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Maximum number of blocks of a fetched resource.
+const MAX_BLOCKS: u16 = 16384;
+
+/// Datagram channel to the CoAP server, either plain or DTLS.
+trait Channel {
+    fn send(&mut self, datagram: &[u8]) -> std::io::Result<()>;
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
+}
+
+impl Channel for UdpSocket {
+    fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+        UdpSocket::send(self, datagram).map(|_| ())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        UdpSocket::recv(self, buf)
+    }
+}
+
+/// Connected UDP socket, as stream for the DTLS session.
+#[derive(Debug)]
+struct Datagrams(UdpSocket);
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Channel for SslStream<Datagrams> {
+    fn send(&mut self, datagram: &[u8]) -> std::io::Result<()> {
+        self.write_all(datagram)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read(buf)
+    }
+}
+
+/// Fetches the resource at a `coap://` or `coaps://` URI,
+/// using blockwise transfer (RFC 7959) for the larger ones.
+pub async fn get(uri: &Uri) -> Result<Vec<u8>, Error> {
+    let uri = uri.clone();
+
+    tokio::task::spawn_blocking(move || get_blocking(&uri))
+        .await
+        .map_err(|err| format_error!("CoAP request aborted: {}", err))?
+}
+
+fn get_blocking(uri: &Uri) -> Result<Vec<u8>, Error> {
+    let secure = uri.scheme_str() == Some("coaps");
+    let host = uri
+        .host()
+        .ok_or_else(|| format_error!("Missing CoAP host: {}", uri))?;
+
+    let port = uri
+        .port_u16()
+        .unwrap_or(if secure { COAPS_PORT } else { COAP_PORT });
+
+    let addr = (host.trim_matches(|c| c == '[' || c == ']'), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format_error!("Fails to resolve CoAP host: {}", host))?;
+
+    let socket = UdpSocket::bind(if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+
+    socket.connect(addr)?;
+
+    debug!("CoAP {} connected to {}", uri, addr);
+
+    let mut channel: Box<dyn Channel> = if secure {
+        Box::new(dtls_connect(host, socket)?)
+    } else {
+        socket.set_read_timeout(Some(ACK_TIMEOUT))?;
+
+        Box::new(socket)
+    };
+
+    let mut message_id: u16 = rand_u16();
+    let token = rand_u16().to_be_bytes().to_vec();
+
+    let max_size = config::parse_or(
+        "ORM_COAP_MAX_SIZE",
+        setting!("ORM_COAP_MAX_SIZE"),
+        DEFAULT_MAX_SIZE,
+    );
+
+    let mut payload = Vec::new();
+    let mut block = BlockValue::new(0, false, BLOCK_SIZE)
+        .map_err(|err| format_error!("Invalid CoAP block: {:?}", err))?;
+
+    loop {
+        let request = new_request(uri, host, message_id, &token, &block)?;
+        let response = exchange(channel.as_mut(), &request)?;
+
+        match response.header.code {
+            MessageClass::Response(ResponseType::Content) => (),
+            code => {
+                return Err(format_error!(
+                    "Fails to fetch {}: CoAP status = {}",
+                    uri,
+                    code
+                ));
+            }
+        }
+
+        payload.extend_from_slice(&response.payload);
+
+        if payload.len() as u64 > max_size {
+            return Err(format_error!(
+                "CoAP resource {} exceeds {} bytes",
+                uri,
+                max_size
+            ));
+        }
+
+        let received = response
+            .get_first_option(CoapOption::Block2)
+            .map(|v| BlockValue::try_from(v.clone()))
+            .transpose()
+            .map_err(|err| format_error!("Invalid CoAP block option: {:?}", err))?;
+
+        match received {
+            Some(b) if b.more => {
+                if b.num >= MAX_BLOCKS - 1 {
+                    return Err(format_error!(
+                        "CoAP resource {} exceeds {} blocks",
+                        uri,
+                        MAX_BLOCKS
+                    ));
+                }
+
+                block = BlockValue {
+                    num: b.num + 1,
+                    more: false,
+                    size_exponent: b.size_exponent,
+                };
+
+                message_id = message_id.wrapping_add(1);
+            }
+            _ => break,
+        }
+    }
+
+    debug!("CoAP {} received {} bytes", uri, payload.len());
+
+    Ok(payload)
+}
+
+/// Prepares a confirmable GET request for the given block.
+fn new_request(
+    uri: &Uri,
+    host: &str,
+    message_id: u16,
+    token: &[u8],
+    block: &BlockValue,
+) -> Result<Packet, Error> {
+    let mut request = Packet::new();
+
+    request.header.set_type(MessageType::Confirmable);
+    request.header.code = MessageClass::Request(RequestType::Get);
+    request.header.message_id = message_id;
+    request.set_token(token.to_vec());
+
+    // IPv6 literal bracketed in the URI
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+
+    if literal.parse::<IpAddr>().is_err() {
+        request.add_option(CoapOption::UriHost, host.as_bytes().to_vec());
+    }
+
+    for segment in uri.path().split('/').filter(|s| !s.is_empty()) {
+        request.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+    }
+
+    for param in uri.query().iter().flat_map(|q| q.split('&')) {
+        request.add_option(CoapOption::UriQuery, param.as_bytes().to_vec());
+    }
+
+    request.add_option(CoapOption::Block2, block.clone().into());
+
+    Ok(request)
+}
+
+/// Sends the confirmable request, retransmitted with exponential back-off,
+/// until the matching response is received.
+fn exchange(channel: &mut dyn Channel, request: &Packet) -> Result<Packet, Error> {
+    let datagram = request
+        .to_bytes()
+        .map_err(|err| format_error!("Invalid CoAP request: {:?}", err))?;
+
+    let mut buf = [0u8; Packet::MAX_SIZE];
+    let mut acknowledged = false;
+
+    for attempt in 0..=MAX_RETRANSMIT {
+        if !acknowledged {
+            if attempt > 0 {
+                debug!("CoAP retransmission #{}", attempt);
+            }
+
+            channel.send(&datagram)?;
+        }
+
+        let size = match channel.recv(&mut buf) {
+            Ok(size) => size,
+            Err(cause) if is_timeout(&cause) => {
+                std::thread::sleep(ACK_TIMEOUT * 2u32.pow(attempt) - ACK_TIMEOUT);
+                continue;
+            }
+            Err(cause) => return Err(Error::from(cause)),
+        };
+
+        let response = match Packet::from_bytes(&buf[..size]) {
+            Ok(packet) => packet,
+            Err(cause) => {
+                warn!("Ignoring invalid CoAP message: {:?}", cause);
+                continue;
+            }
+        };
+
+        match (response.header.get_type(), response.header.code) {
+            (MessageType::Reset, _) if response.header.message_id == request.header.message_id => {
+                return Err(Error::new("CoAP request reset by server".to_string()));
+            }
+
+            (MessageType::Acknowledgement, MessageClass::Empty)
+                if response.header.message_id == request.header.message_id =>
+            {
+                // Separate response will follow
+                acknowledged = true;
+            }
+
+            (MessageType::Acknowledgement, _)
+                if response.header.message_id == request.header.message_id =>
+            {
+                return Ok(response);
+            }
+
+            (MessageType::Confirmable, _) | (MessageType::NonConfirmable, _)
+                if response.get_token() == request.get_token() =>
+            {
+                if response.header.get_type() == MessageType::Confirmable {
+                    let mut ack = Packet::new();
+
+                    ack.header.set_type(MessageType::Acknowledgement);
+                    ack.header.message_id = response.header.message_id;
+
+                    if let Ok(bytes) = ack.to_bytes() {
+                        channel.send(&bytes)?;
+                    }
+                }
+
+                return Ok(response);
+            }
+
+            _ => debug!("Ignoring unrelated CoAP message"),
+        }
+    }
+
+    Err(format_error!(
+        "No CoAP response after {} retransmissions",
+        MAX_RETRANSMIT
+    ))
+}
+
+/// Establishes the DTLS session,
+/// with a pre-shared key if `ORM_COAPS_PSK_IDENTITY` is defined,
+/// otherwise with the server certificate checked against the system CAs.
+fn dtls_connect(host: &str, socket: UdpSocket) -> Result<SslStream<Datagrams>, Error> {
+    let mut builder = SslConnector::builder(SslMethod::dtls())?;
+
+    if let Some(identity) = setting!("ORM_COAPS_PSK_IDENTITY") {
+        let key = unhex(&setting!("ORM_COAPS_PSK_KEY").unwrap_or_default())?;
+
+        builder.set_psk_client_callback(move |_, _, identity_buf, psk_buf| {
+            let id = identity.as_bytes();
+
+            if id.len() >= identity_buf.len() || key.len() > psk_buf.len() {
+                return Err(openssl::error::ErrorStack::get());
+            }
+
+            identity_buf[..id.len()].copy_from_slice(id);
+            identity_buf[id.len()] = 0;
+            psk_buf[..key.len()].copy_from_slice(&key);
+
+            Ok(key.len())
+        });
+    }
+
+    let handshake_timeout = Duration::from_secs(config::parse_or(
+        "ORM_COAPS_HANDSHAKE_TIMEOUT",
+        setting!("ORM_COAPS_HANDSHAKE_TIMEOUT"),
+        30,
+    ));
+
+    socket.set_read_timeout(Some(handshake_timeout))?;
+
+    let stream = builder
+        .build()
+        .configure()?
+        .connect(host, Datagrams(socket))
+        .map_err(|err| format_error!("DTLS handshake failed with {}: {}", host, err))?;
+
+    stream.get_ref().0.set_read_timeout(Some(ACK_TIMEOUT))?;
+
+    Ok(stream)
+}
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+/// Decodes an hexadecimal string.
+fn unhex(repr: &str) -> Result<Vec<u8>, Error> {
+    let repr = repr.trim();
+
+    if !repr.is_ascii() || !repr.len().is_multiple_of(2) {
+        return Err(format_error!("Invalid hexadecimal: {}", repr));
+    }
+
+    (0..repr.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&repr[i..i + 2], 16)
+                .map_err(|err| format_error!("Invalid hexadecimal {}: {}", repr, err))
+        })
+        .collect()
+}
+
+/// Pseudo-random 16 bits, for the message ID and token.
+fn rand_u16() -> u16 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+
+    (nanos ^ std::process::id()) as u16
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unhex() {
+        assert_eq!(unhex(" 00ff7A\n").unwrap(), vec![0x00, 0xff, 0x7a]);
+        assert_eq!(unhex("").unwrap(), Vec::<u8>::new());
+
+        assert!(unhex("abc").is_err());
+        assert!(unhex("zz").is_err());
+        assert!(unhex("é0").is_err());
+    }
+
+    #[test]
+    fn test_new_request() {
+        let uri = "coap://example.org/fw/foo-1.0.0.tar.gz?v=1&beta"
+            .parse::<Uri>()
+            .unwrap();
+        let block = BlockValue::new(2, false, BLOCK_SIZE).unwrap();
+        let request = new_request(&uri, "example.org", 42, &[1, 2], &block).unwrap();
+
+        assert_eq!(request.header.get_type(), MessageType::Confirmable);
+        assert_eq!(request.header.code, MessageClass::Request(RequestType::Get));
+        assert_eq!(request.header.message_id, 42);
+        assert_eq!(request.get_token(), &[1, 2]);
+
+        let option = |tp| {
+            request
+                .get_option(tp)
+                .map(|values| values.iter().cloned().collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+
+        assert_eq!(option(CoapOption::UriHost), vec![b"example.org".to_vec()]);
+        assert_eq!(
+            option(CoapOption::UriPath),
+            vec![b"fw".to_vec(), b"foo-1.0.0.tar.gz".to_vec()]
+        );
+        assert_eq!(
+            option(CoapOption::UriQuery),
+            vec![b"v=1".to_vec(), b"beta".to_vec()]
+        );
+
+        let block2 = request.get_first_option(CoapOption::Block2).unwrap();
+
+        assert_eq!(BlockValue::try_from(block2.clone()).unwrap(), block);
+
+        // No Uri-Host for an IP address
+        let uri = "coap://[::1]/manifest.yaml".parse::<Uri>().unwrap();
+        let request = new_request(&uri, uri.host().unwrap(), 43, &[1, 2], &block).unwrap();
+
+        assert!(request.get_option(CoapOption::UriHost).is_none());
+
+        let uri = "coap://127.0.0.1/manifest.yaml".parse::<Uri>().unwrap();
+        let request = new_request(&uri, uri.host().unwrap(), 44, &[1, 2], &block).unwrap();
+
+        assert!(request.get_option(CoapOption::UriHost).is_none());
+    }
+}
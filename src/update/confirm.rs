@@ -0,0 +1,223 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info, warn};
+
+use serde::{Deserialize, Serialize};
+
+use super::health;
+use super::marker::Marker;
+use super::quarantine;
+use crate::config;
+use crate::error;
+use crate::io::write_atomic;
+use crate::process;
+use crate::setting;
+use error::Error;
+
+/// Update pending confirmation (`.orm_pending`), until the updated version is healthy
+/// when started at boot.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Pending {
+    version: String,
+
+    /// Health probe declared in the manifest.
+    #[serde(default)]
+    healthcheck: Option<String>,
+
+    /// Boots (starts of orm) since the update.
+    #[serde(default)]
+    boots: u32,
+
+    /// Whether the update required a reboot, so it's confirmed
+    /// at the next boot at least.
+    #[serde(default)]
+    reboot: bool,
+}
+
+/// Number of boots the updated version has to be confirmed within,
+/// otherwise it's rolled back (`ORM_CONFIRM_BOOTS`, `0` to disable).
+pub fn max_boots() -> u32 {
+    config::parse_or("ORM_CONFIRM_BOOTS", setting!("ORM_CONFIRM_BOOTS"), 0)
+}
+
+fn pending_path(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_pending")
+}
+
+fn load(local_prefix: &Path) -> Result<Option<Pending>, Error> {
+    match fs::read(pending_path(local_prefix)) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(cause) => Err(Error::from(cause)),
+    }
+}
+
+fn save(local_prefix: &Path, pending: &Pending) -> std::io::Result<()> {
+    write_atomic(&pending_path(local_prefix), &serde_json::to_vec(pending)?)
+}
+
+fn clear(local_prefix: &Path) -> std::io::Result<()> {
+    match fs::remove_file(pending_path(local_prefix)) {
+        Err(cause) if cause.kind() != std::io::ErrorKind::NotFound => Err(cause),
+        _ => Ok(()),
+    }
+}
+
+/// Marks the updated version as pending confirmation (if enabled, or rebooted).
+pub fn mark(
+    local_prefix: &Path,
+    version: &str,
+    healthcheck: Option<&str>,
+    reboot: bool,
+) -> std::io::Result<()> {
+    if max_boots() == 0 && !reboot {
+        return Ok(());
+    }
+
+    debug!("Version {} pending confirmation", version);
+
+    save(
+        local_prefix,
+        &Pending {
+            version: version.to_string(),
+            healthcheck: healthcheck.map(str::to_string),
+            boots: 0,
+            reboot,
+        },
+    )
+}
+
+/// Counts a boot for the version pending confirmation (if any),
+/// rolling it back if not confirmed within the maximum boots.
+pub fn boot(app_name: &'static str, local_prefix: &Path, app_dir: &Path) -> Result<(), Error> {
+    let mut pending = match load(local_prefix)? {
+        Some(pending) => pending,
+        None => return Ok(()),
+    };
+
+    let installed = Marker::load(app_dir)?.map(|m| m.version);
+
+    if installed.as_ref() != Some(&pending.version) {
+        debug!("Version {} no longer installed", pending.version);
+
+        return Ok(clear(local_prefix)?);
+    }
+
+    pending.boots += 1;
+
+    let max = match pending.reboot {
+        true => max_boots().max(1),
+        false => max_boots(),
+    };
+
+    if max == 0 || pending.boots <= max {
+        info!(
+            "Version {} pending confirmation (boot {}/{})",
+            pending.version, pending.boots, max
+        );
+
+        return Ok(save(local_prefix, &pending)?);
+    }
+
+    error!(
+        "Version {} not confirmed within {} boots; Rolling back",
+        pending.version, max
+    );
+
+    quarantine::add(
+        &config::state_dir(local_prefix).join(".orm_failed"),
+        &pending.version,
+    )?;
+
+    let restored = super::rollback(app_name, local_prefix, app_dir)?;
+
+    warn!("Rolled back to version {}", restored);
+
+    Ok(clear(local_prefix)?)
+}
+
+/// Confirms the version pending confirmation (if any) once the started application is healthy:
+/// it passes its health probe, if any, otherwise stays alive during the grace period.
+pub async fn confirm<'x>(
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    app: &'x mut process::Application,
+) -> Result<(), Error> {
+    let pending = match load(local_prefix)? {
+        Some(pending) => pending,
+        None => return Ok(()),
+    };
+
+    match health::probe(pending.healthcheck.as_deref(), app_dir) {
+        Some(probe) => health::check(&probe, app_dir, app).await?,
+        None => super::wait_grace(app).await?,
+    }
+
+    info!("Version {} confirmed", pending.version);
+
+    Ok(clear(local_prefix)?)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_boot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let app_dir = tmp.path().join("foo");
+
+        fs::create_dir(&app_dir).unwrap();
+        Marker {
+            version: "2.0.0".to_string(),
+            ..Marker::default()
+        }
+        .save(&app_dir)
+        .unwrap();
+
+        let pending = Pending {
+            version: "2.0.0".to_string(),
+            ..Pending::default()
+        };
+
+        save(tmp.path(), &pending).unwrap();
+
+        std::env::set_var("ORM_CONFIRM_BOOTS", "2");
+
+        boot("foo", tmp.path(), &app_dir).unwrap();
+
+        assert_eq!(load(tmp.path()).unwrap().map(|p| p.boots), Some(1));
+
+        let mut sleep = tokio::process::Command::new("sleep");
+
+        sleep.arg("1");
+
+        let mut app = process::start(&app_dir, vec![("sleep".to_string(), sleep)])
+            .await
+            .unwrap();
+
+        confirm(tmp.path(), &app_dir, &mut app).await.unwrap();
+        process::wait(&mut app).await.unwrap();
+
+        assert_eq!(load(tmp.path()).unwrap(), None);
+
+        // Stale
+        save(
+            tmp.path(),
+            &Pending {
+                version: "1.0.0".to_string(),
+                ..Pending::default()
+            },
+        )
+        .unwrap();
+
+        boot("foo", tmp.path(), &app_dir).unwrap();
+
+        std::env::remove_var("ORM_CONFIRM_BOOTS");
+
+        assert_eq!(load(tmp.path()).unwrap(), None);
+    }
+}
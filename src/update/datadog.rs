@@ -0,0 +1,221 @@
+use std::env::var;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use log::{debug, warn};
+
+use serde_json::{json, Value};
+
+use super::client;
+use super::report::Outcome;
+use crate::error;
+use crate::format_error;
+use crate::logging;
+use error::Error;
+
+/// Compile-time DataDog metrics API URL (e.g. `https://api.datadoghq.eu`)
+const DATADOG_METRICS_URL: Option<&'static str> = option_env!("DATADOG_METRICS_URL");
+
+/// Duration the metrics API has to accept the series.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Metric types of the DataDog API (v2)
+const COUNT: u8 = 1;
+const GAUGE: u8 = 3;
+
+/// Update run, whose metrics are submitted.
+#[derive(Debug)]
+pub struct Run<'x> {
+    pub object_type: &'x str,
+    pub thing_id: &'x str,
+
+    /// Version installed at the end of the run.
+    pub version: Option<&'x str>,
+
+    /// Version the run updated to.
+    pub update_version: Option<&'x str>,
+
+    pub outcome: Outcome,
+
+    /// Duration in seconds of the update run.
+    pub duration: f64,
+
+    /// Size of the downloaded archive (if any).
+    pub downloaded: Option<u64>,
+}
+
+fn metrics_url() -> Option<String> {
+    DATADOG_METRICS_URL
+        .map(|s| s.to_string())
+        .or_else(|| var("DATADOG_METRICS_URL").ok())
+}
+
+/// Whether the metrics are submitted to DataDog (`DATADOG_METRICS_URL`).
+pub fn enabled() -> bool {
+    metrics_url().is_some()
+}
+
+/// Submits the metrics of the update run to the DataDog API: `orm.update.runs` (count, tagged
+/// with the `result`), `orm.update.duration` (seconds) and `orm.update.download_size` (bytes);
+/// A failed submission is only logged.
+pub async fn submit(run: &Run<'_>) {
+    if let Some(url) = metrics_url() {
+        if let Err(cause) = post(&url, run).await {
+            warn!("Fails to submit the metrics to DataDog: {}", cause);
+        }
+    }
+}
+
+async fn post(url: &str, run: &Run<'_>) -> Result<(), Error> {
+    let api_key = logging::datadog_api_key()
+        .ok_or_else(|| format_error!("Missing DATADOG_API_KEY for {}", url))?;
+
+    let endpoint = format!("{}/api/v2/series", url.trim_end_matches('/'));
+    let body = series(
+        run,
+        Utc::now().timestamp(),
+        logging::datadog_tags().as_deref(),
+        var("HOSTNAME").ok().as_deref(),
+    );
+
+    debug!("Submitting {} to {}", body, endpoint);
+
+    client::post_json_with(
+        &endpoint,
+        &[("DD-API-KEY", &api_key)],
+        serde_json::to_vec(&body)?,
+        TIMEOUT,
+    )
+    .await
+}
+
+/// Returns the series of the run, tagged with the `DATADOG_TAGS`, the object type,
+/// the thing ID and the versions.
+fn series(run: &Run<'_>, timestamp: i64, tags: Option<&str>, hostname: Option<&str>) -> Value {
+    let mut tags: Vec<String> = tags
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    tags.push(format!("object_type:{}", run.object_type));
+    tags.push(format!("thing_id:{}", run.thing_id));
+
+    if let Some(version) = run.version {
+        tags.push(format!("version:{}", version));
+    }
+
+    if let Some(version) = run.update_version {
+        tags.push(format!("update_version:{}", version));
+    }
+
+    let metric = |name: &str, kind: u8, value: f64, unit: Option<&str>, tags: &[String]| {
+        let mut metric = json!({
+            "metric": name,
+            "type": kind,
+            "points": [{ "timestamp": timestamp, "value": value }],
+            "tags": tags,
+        });
+
+        if let Some(unit) = unit {
+            metric["unit"] = json!(unit);
+        }
+
+        if let Some(hostname) = hostname {
+            metric["resources"] = json!([{ "name": hostname, "type": "host" }]);
+        }
+
+        metric
+    };
+
+    let mut result_tags = tags.clone();
+
+    result_tags.push(format!(
+        "result:{}",
+        json!(run.outcome).as_str().unwrap_or_default()
+    ));
+
+    let mut series = vec![
+        metric("orm.update.runs", COUNT, 1.0, None, &result_tags),
+        metric(
+            "orm.update.duration",
+            GAUGE,
+            run.duration,
+            Some("second"),
+            &result_tags,
+        ),
+    ];
+
+    if let Some(size) = run.downloaded {
+        series.push(metric(
+            "orm.update.download_size",
+            GAUGE,
+            size as f64,
+            Some("byte"),
+            &tags,
+        ));
+    }
+
+    json!({ "series": series })
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_series() {
+        let run = Run {
+            object_type: "OBJ",
+            thing_id: "dev1",
+            version: Some("1.0.0"),
+            update_version: Some("2.0.0"),
+            outcome: Outcome::RolledBack,
+            duration: 1.5,
+            downloaded: Some(1024),
+        };
+
+        let body = series(&run, 100, Some("env:prod, team:iot"), Some("host1"));
+        let metrics = body["series"].as_array().unwrap();
+
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics[0]["metric"], json!("orm.update.runs"));
+        assert_eq!(
+            metrics[0]["tags"],
+            json!([
+                "env:prod",
+                "team:iot",
+                "object_type:OBJ",
+                "thing_id:dev1",
+                "version:1.0.0",
+                "update_version:2.0.0",
+                "result:rolled_back"
+            ])
+        );
+        assert_eq!(
+            metrics[1]["points"],
+            json!([{ "timestamp": 100, "value": 1.5 }])
+        );
+        assert_eq!(metrics[2]["unit"], json!("byte"));
+        assert_eq!(
+            metrics[2]["resources"],
+            json!([{ "name": "host1", "type": "host" }])
+        );
+
+        let run = Run {
+            version: None,
+            update_version: None,
+            downloaded: None,
+            ..run
+        };
+        let body = series(&run, 100, None, None);
+
+        assert_eq!(body["series"].as_array().map(Vec::len), Some(2));
+        assert!(body["series"][0].get("resources").is_none());
+    }
+}
@@ -0,0 +1,761 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use std::os::unix::fs::FileExt;
+
+use chrono::Utc;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+use log::{debug, info, warn};
+
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, RANGE,
+    RETRY_AFTER,
+};
+use http::uri::{Parts, PathAndQuery};
+
+use tokio::task::JoinSet;
+
+use super::client::HttpsClient;
+use super::coap;
+use super::progress::Progress;
+use super::s3;
+use super::schedule;
+use crate::config;
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Default size (in bytes) above which an archive is downloaded by segments.
+const DEFAULT_SEGMENTED_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Default number of segments concurrently downloaded.
+const DEFAULT_SEGMENTS: u64 = 4;
+
+/// Default maximum size (in bytes) of the fetched content (e.g. manifest), once decoded.
+const DEFAULT_MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Maximum number of attempts to download a single segment.
+const SEGMENT_ATTEMPTS: usize = 3;
+
+/// Location of a manifest or an artifact.
+#[derive(Clone, Debug)]
+pub enum Location {
+    /// Remote resource, fetched by HTTP(S) or from S3.
+    Remote(Uri),
+
+    /// Local file, from a `file://` URL or a plain path
+    /// (e.g. for offline update from an USB stick).
+    Local(PathBuf),
+}
+
+impl Location {
+    pub fn parse(url: &str) -> Result<Location, Error> {
+        if let Some(path) = url.strip_prefix("file://") {
+            Ok(Location::Local(PathBuf::from(path)))
+        } else if !url.contains("://") {
+            Ok(Location::Local(PathBuf::from(url)))
+        } else {
+            url.parse::<Uri>()
+                .map(Location::Remote)
+                .map_err(|err| format_error!("Invalid URL {}: {}", url, err))
+        }
+    }
+
+    /// Returns the location of the named file,
+    /// in the same parent directory/path.
+    pub fn sibling(&self, name: &str) -> Result<Location, Error> {
+        match self {
+            Location::Local(path) => Ok(Location::Local(path.with_file_name(name))),
+
+            Location::Remote(uri) => {
+                let parent_uri = parent_uri(&uri.to_string())?;
+
+                debug!("Parent URL = {:?}", parent_uri);
+
+                Uri::builder()
+                    .scheme(parent_uri.scheme_str().unwrap_or("https"))
+                    .authority(
+                        parent_uri
+                            .authority()
+                            .map(|a| a.as_str())
+                            .unwrap_or_default(),
+                    )
+                    .path_and_query(format!(
+                        "{}/{}",
+                        parent_uri.path().trim_end_matches('/'),
+                        name
+                    ))
+                    .build()
+                    .map(Location::Remote)
+                    .map_err(|err| format_error!("Invalid URL for {}: {}", name, err))
+            }
+        }
+    }
+
+    /// Returns the location with the given parameters appended to its query,
+    /// unless the location doesn't support it (local or S3).
+    pub fn with_query(&self, params: &[(&str, String)]) -> Result<Location, Error> {
+        let uri = match self {
+            Location::Remote(uri) if uri.scheme_str() != Some("s3") => uri,
+            _ => return Ok(self.clone()),
+        };
+
+        let query = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, query_component(value)))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let path_and_query = match uri.query() {
+            Some(q) if !q.is_empty() => format!("{}?{}&{}", uri.path(), q, query),
+            _ => format!("{}?{}", uri.path(), query),
+        };
+
+        let mut parts = uri.clone().into_parts();
+
+        parts.path_and_query = Some(
+            path_and_query
+                .parse::<PathAndQuery>()
+                .map_err(|err| format_error!("Invalid query {}: {}", query, err))?,
+        );
+
+        Ok(Location::Remote(Uri::from_parts(parts)?))
+    }
+}
+
+/// Percent-encodes a query parameter value.
+pub fn query_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl Display for Location {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Remote(uri) => write!(formatter, "{}", uri),
+            Location::Local(path) => write!(formatter, "file://{}", path.display()),
+        }
+    }
+}
+
+/// Returns the parent URI.
+pub(super) fn parent_uri(url: &str) -> Result<Uri, Error> {
+    let uri = url.parse::<Uri>().unwrap();
+    let uri_parts = uri.into_parts();
+
+    if uri_parts.path_and_query.is_none() {
+        return Err(format_error!("Invalid manifest URL: {}", url));
+    }
+
+    let path_and_query = uri_parts.path_and_query.unwrap();
+    let path_segments: Vec<&str> = path_and_query.path().split("/").collect();
+
+    let path_count = path_segments.len();
+
+    if path_count == 0 {
+        return Err(format_error!("Invalid manifest path: {:?}", path_segments));
+    }
+
+    let parent_path: PathAndQuery = path_segments
+        .iter()
+        .take(path_count - 1)
+        .fold("".to_string(), |out, seg| match seg {
+            &"" => out,
+            _ => out + "/" + seg,
+        })
+        .parse()
+        .unwrap();
+
+    let mut parent_parts = Parts::default();
+
+    parent_parts.scheme = uri_parts.scheme;
+    parent_parts.authority = uri_parts.authority;
+    parent_parts.path_and_query = Some(parent_path);
+
+    Uri::from_parts(parent_parts).map_err(Error::from)
+}
+
+/// Fetched content, with the hints of the server.
+#[derive(Debug)]
+pub enum Fetched {
+    /// Content, possibly fresh for the given `max-age` duration.
+    Content(Vec<u8>, Option<Duration>),
+
+    /// Unavailable (e.g. `429` or `503`), to be retried after the given delay.
+    RetryAfter(StatusCode, Duration),
+}
+
+/// Fetches the content at the given location,
+/// considering the `Retry-After` and `Cache-Control` hints from the server.
+pub async fn fetch_with_hints<'x>(
+    client: &'x HttpsClient,
+    location: &'x Location,
+    headers: &'x [(&'static str, String)],
+) -> Result<Fetched, Error> {
+    let uri = match location {
+        Location::Local(path) => return Ok(Fetched::Content(std::fs::read(path)?, None)),
+        Location::Remote(uri) if is_coap(uri) => {
+            return Ok(Fetched::Content(coap::get(uri).await?, None))
+        }
+        Location::Remote(uri) => uri,
+    };
+
+    let mut accepting = vec![(ACCEPT_ENCODING.as_str(), "gzip, deflate".to_string())];
+
+    accepting.extend_from_slice(headers);
+
+    let body = send(client, Method::GET, uri, None, &accepting).await?;
+
+    let status = body.status();
+
+    debug!("Request status for {}: {}", uri, status);
+
+    let header = |name| {
+        body.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        if let Some(delay) =
+            header(RETRY_AFTER).and_then(|v| schedule::parse_retry_after(&v, Utc::now()))
+        {
+            return Ok(Fetched::RetryAfter(status, delay));
+        }
+    }
+
+    if status != 200 {
+        return Err(format_error!(
+            "Fails to fetch {}: status = {} != 200",
+            uri,
+            status
+        ));
+    }
+
+    let max_age = header(CACHE_CONTROL).and_then(|v| schedule::parse_max_age(&v));
+    let encoding = header(CONTENT_ENCODING);
+    let buf = hyper::body::to_bytes(body).await?;
+    let limit = config::parse_or(
+        "ORM_MAX_MANIFEST_SIZE",
+        setting!("ORM_MAX_MANIFEST_SIZE"),
+        DEFAULT_MAX_MANIFEST_SIZE,
+    );
+
+    Ok(Fetched::Content(
+        decode(encoding.as_deref(), &buf, limit)?,
+        max_age,
+    ))
+}
+
+/// Decodes the body according its `Content-Encoding`, failing above `limit` bytes
+/// (e.g. a small compressed body expanding to exhaust the memory).
+fn decode(encoding: Option<&str>, body: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::new();
+
+    match encoding.map(|e| e.trim().to_lowercase()).as_deref() {
+        None | Some("") | Some("identity") => decoded.extend_from_slice(body),
+
+        Some("gzip") | Some("x-gzip") => {
+            GzDecoder::new(body)
+                .take(limit + 1)
+                .read_to_end(&mut decoded)?;
+        }
+
+        Some("deflate") => {
+            // Normally zlib-wrapped, but some servers send raw deflate
+            if ZlibDecoder::new(body)
+                .take(limit + 1)
+                .read_to_end(&mut decoded)
+                .is_err()
+            {
+                decoded.clear();
+                DeflateDecoder::new(body)
+                    .take(limit + 1)
+                    .read_to_end(&mut decoded)?;
+            }
+        }
+
+        Some(other) => {
+            return Err(format_error!("Unsupported content encoding: {}", other));
+        }
+    }
+
+    if decoded.len() as u64 > limit {
+        return Err(format_error!("Content exceeds {} bytes", limit));
+    }
+
+    debug!("Decoded {} bytes from {:?}", decoded.len(), encoding);
+
+    Ok(decoded)
+}
+
+/// Downloads the resource at given location to the target file.
+pub async fn download_to<'x>(
+    client: &'x HttpsClient,
+    location: &'x Location,
+    target: &'x mut File,
+) -> Result<u64, Error> {
+    let uri = match location {
+        Location::Local(path) => {
+            let mut source = File::open(path)?;
+
+            return Ok(std::io::copy(&mut source, target)?);
+        }
+        Location::Remote(uri) if is_coap(uri) => {
+            let payload = coap::get(uri).await?;
+
+            target.write_all(&payload)?;
+
+            return Ok(payload.len() as u64);
+        }
+        Location::Remote(uri) => uri,
+    };
+
+    let threshold = config::parse_or(
+        "ORM_SEGMENTED_DOWNLOAD_THRESHOLD",
+        setting!("ORM_SEGMENTED_DOWNLOAD_THRESHOLD"),
+        DEFAULT_SEGMENTED_THRESHOLD,
+    );
+
+    let segments = config::parse_or(
+        "ORM_DOWNLOAD_SEGMENTS",
+        setting!("ORM_DOWNLOAD_SEGMENTS"),
+        DEFAULT_SEGMENTS,
+    );
+
+    if segments > 1 {
+        match ranged_length(client, uri).await? {
+            Some(length) if length > threshold => {
+                return download_segments(client, uri, length, segments, target).await;
+            }
+            length => debug!("No segmented download for {} (length = {:?})", uri, length),
+        }
+    }
+
+    let mut resp = send(client, Method::GET, uri, None, &[]).await?;
+
+    if !resp.status().is_success() {
+        return Err(format_error!(
+            "Fails to download {}: status = {}",
+            uri,
+            resp.status()
+        ));
+    }
+
+    let length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    let progress = Progress::reported(
+        format!("Downloading {}", uri),
+        "bytes",
+        length,
+        Some("download_progress"),
+    );
+    let mut size = 0;
+
+    while let Some(chunk) = resp.body_mut().data().await {
+        let bytes = chunk?;
+
+        target.write_all(&bytes)?;
+        size += bytes.len() as u64;
+        progress.advance(bytes.len() as u64);
+    }
+
+    Ok(size)
+}
+
+fn is_coap(uri: &Uri) -> bool {
+    matches!(uri.scheme_str(), Some("coap") | Some("coaps"))
+}
+
+/// Sends a request for the given URI, optionally restricted to a byte range,
+/// signing it according the URI scheme (e.g. `s3://bucket/key`).
+async fn send<'x>(
+    client: &'x HttpsClient,
+    method: Method,
+    uri: &'x Uri,
+    range: Option<String>,
+    headers: &'x [(&'static str, String)],
+) -> Result<Response<Body>, Error> {
+    let req = request(client, method, uri, range, headers).await?;
+
+    Ok(client.request(req).await?)
+}
+
+/// Returns the request for the given URI (see `send`).
+async fn request<'x>(
+    client: &'x HttpsClient,
+    method: Method,
+    uri: &'x Uri,
+    range: Option<String>,
+    headers: &'x [(&'static str, String)],
+) -> Result<Request<Body>, Error> {
+    let (builder, range) = match uri.scheme_str() {
+        // Range already signed, and set
+        Some("s3") => (
+            s3::request(client, method, uri, range.as_deref(), None).await?,
+            None,
+        ),
+        _ => (Request::builder().method(method).uri(uri.clone()), range),
+    };
+
+    let req = headers.iter().fold(builder, |req, (name, value)| {
+        req.header(*name, value.as_str())
+    });
+
+    range
+        .iter()
+        .fold(req, |req, r| req.header(RANGE, r.as_str()))
+        .body(Body::empty())
+        .map_err(|err| format_error!("Invalid request for {}: {}", uri, err))
+}
+
+/// Returns the content length of the resource at given URI,
+/// if the server accepts byte ranges for it.
+async fn ranged_length<'x>(client: &'x HttpsClient, uri: &'x Uri) -> Result<Option<u64>, Error> {
+    let resp = send(client, Method::HEAD, uri, None, &[]).await?;
+
+    if resp.status() != StatusCode::OK {
+        return Ok(None);
+    }
+
+    let headers = resp.headers();
+
+    let accept_bytes = headers
+        .get(ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim() == "bytes");
+
+    if !accept_bytes {
+        return Ok(None);
+    }
+
+    Ok(headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok()))
+}
+
+/// Splits the `length` bytes in at most `count` inclusive ranges.
+fn segment_ranges(length: u64, count: u64) -> Vec<(u64, u64)> {
+    let count = count.clamp(1, length.max(1));
+    let size = length.div_ceil(count);
+
+    (0..count)
+        .map(|i| i * size)
+        .take_while(|start| *start < length)
+        .map(|start| (start, (start + size).min(length) - 1))
+        .collect()
+}
+
+/// Concurrently downloads byte ranges of the resource,
+/// each one written at its offset in the target file.
+async fn download_segments<'x>(
+    client: &'x HttpsClient,
+    uri: &'x Uri,
+    length: u64,
+    count: u64,
+    target: &'x mut File,
+) -> Result<u64, Error> {
+    let ranges = segment_ranges(length, count);
+
+    info!(
+        "Downloading {} bytes from {} in {} segments ...",
+        length,
+        uri,
+        ranges.len()
+    );
+
+    target.set_len(length)?;
+
+    let mut tasks = JoinSet::new();
+    let progress = Progress::reported(
+        format!("Downloading {}", uri),
+        "bytes",
+        Some(length),
+        Some("download_progress"),
+    );
+
+    for (start, end) in ranges {
+        let client = client.clone();
+        let uri = uri.clone();
+        let file = target.try_clone()?;
+        let progress = progress.clone();
+
+        tasks.spawn(
+            async move { download_segment(&client, &uri, start, end, &file, &progress).await },
+        );
+    }
+
+    let mut size = 0;
+
+    while let Some(task) = tasks.join_next().await {
+        let downloaded = task
+            .map_err(|err| format_error!("Segment download aborted: {}", err))
+            .and_then(|res| res);
+
+        match downloaded {
+            Ok(downloaded) => size += downloaded,
+            Err(cause) => {
+                // The other segments no longer written to the target file
+                tasks.shutdown().await;
+
+                return Err(cause);
+            }
+        }
+    }
+
+    if size != length {
+        return Err(format_error!(
+            "Incomplete segmented download: {} != {}",
+            size,
+            length
+        ));
+    }
+
+    Ok(size)
+}
+
+/// Downloads the inclusive range `start..=end` to the target file,
+/// resuming from the last written offset on failure.
+async fn download_segment<'x>(
+    client: &'x HttpsClient,
+    uri: &'x Uri,
+    start: u64,
+    end: u64,
+    target: &'x File,
+    progress: &'x Progress,
+) -> Result<u64, Error> {
+    let mut offset = start;
+    let mut attempt = 1;
+
+    loop {
+        match fetch_range(client, uri, &mut offset, end, target, progress).await {
+            Ok(()) => return Ok(end + 1 - start),
+
+            Err(cause) if attempt < SEGMENT_ATTEMPTS => {
+                warn!(
+                    "Fails to download segment {}-{} (attempt #{}); Resuming from {}: {}",
+                    start, end, attempt, offset, cause
+                );
+
+                attempt += 1;
+            }
+
+            Err(cause) => return Err(cause),
+        }
+    }
+}
+
+/// Writes the bytes `offset..=end` of the resource to the target file,
+/// advancing `offset` as the body is received.
+async fn fetch_range<'x>(
+    client: &'x HttpsClient,
+    uri: &'x Uri,
+    offset: &'x mut u64,
+    end: u64,
+    target: &'x File,
+    progress: &'x Progress,
+) -> Result<(), Error> {
+    let range = format!("bytes={}-{}", offset, end);
+    let mut resp = send(client, Method::GET, uri, Some(range), &[]).await?;
+
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(format_error!(
+            "Unexpected status for range {}-{}: {}",
+            offset,
+            end,
+            resp.status()
+        ));
+    }
+
+    while let Some(chunk) = resp.body_mut().data().await {
+        let bytes = chunk?;
+
+        if *offset + bytes.len() as u64 > end + 1 {
+            return Err(format_error!("Range overflow at {}", offset));
+        }
+
+        target.write_all_at(&bytes, *offset)?;
+        *offset += bytes.len() as u64;
+        progress.advance(bytes.len() as u64);
+    }
+
+    if *offset != end + 1 {
+        return Err(format_error!("Truncated range at {} < {}", offset, end + 1));
+    }
+
+    Ok(())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_sibling() {
+        let remote = Location::parse("s3://bucket/path/manifest.yaml")
+            .and_then(|l| l.sibling("foo-1.2.3.tar.gz"))
+            .unwrap();
+
+        assert_eq!(remote.to_string(), "s3://bucket/path/foo-1.2.3.tar.gz");
+
+        let root = Location::parse("http://foo/manifest.yaml")
+            .and_then(|l| l.sibling("foo-1.2.3.tar.gz"))
+            .unwrap();
+
+        assert_eq!(root.to_string(), "http://foo/foo-1.2.3.tar.gz");
+
+        let local = Location::parse("file:///mnt/usb/manifest.yaml")
+            .and_then(|l| l.sibling("foo-1.2.3.tar.gz"))
+            .unwrap();
+
+        assert_eq!(local.to_string(), "file:///mnt/usb/foo-1.2.3.tar.gz");
+
+        let path = Location::parse("/mnt/usb/manifest.yaml").unwrap();
+
+        assert!(matches!(path, Location::Local(_)));
+    }
+
+    #[test]
+    fn test_decode() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+
+        enc.write_all(b"object_type: 'FOO'").unwrap();
+
+        let gzipped = enc.finish().unwrap();
+
+        assert_eq!(
+            decode(Some("gzip"), &gzipped, 1024).unwrap(),
+            b"object_type: 'FOO'".to_vec()
+        );
+
+        assert_eq!(decode(None, b"plain", 1024).unwrap(), b"plain".to_vec());
+
+        assert!(decode(Some("br"), b"...", 1024).is_err());
+
+        // Over the limit
+        assert!(decode(Some("gzip"), &gzipped, 10).is_err());
+        assert!(decode(None, b"plain", 4).is_err());
+
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::best());
+
+        bomb.write_all(&vec![0u8; 16 * 1024 * 1024]).unwrap();
+
+        let bomb = bomb.finish().unwrap();
+
+        assert!(bomb.len() < 64 * 1024);
+        assert!(decode(Some("gzip"), &bomb, DEFAULT_MAX_MANIFEST_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_location_with_query() {
+        let params = [
+            ("thing_id", "foo-1".to_string()),
+            ("version", "1.2.3+build".to_string()),
+        ];
+
+        let remote = Location::parse("https://foo/manifest.yaml?lorem=ipsum")
+            .and_then(|l| l.with_query(&params))
+            .unwrap();
+
+        assert_eq!(
+            remote.to_string(),
+            "https://foo/manifest.yaml?lorem=ipsum&thing_id=foo-1&version=1.2.3%2Bbuild"
+        );
+
+        let s3 = Location::parse("s3://bucket/manifest.yaml")
+            .and_then(|l| l.with_query(&params))
+            .unwrap();
+
+        assert_eq!(s3.to_string(), "s3://bucket/manifest.yaml");
+    }
+
+    #[tokio::test]
+    async fn test_request_range() {
+        std::env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE");
+        std::env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+
+        let client = super::super::client::new_client().unwrap();
+        let range = Some("bytes=0-99".to_string());
+
+        for url in ["s3://bucket/foo.tar.gz", "https://foo/foo.tar.gz"] {
+            let uri = url.parse::<Uri>().unwrap();
+            let req = request(&client, Method::GET, &uri, range.clone(), &[])
+                .await
+                .unwrap();
+
+            assert_eq!(
+                req.headers().get_all(RANGE).iter().collect::<Vec<_>>(),
+                vec!["bytes=0-99"],
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_status() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/foo-1.0.0.tar.gz", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+
+                let _ = stream.read(&mut buf).await.unwrap();
+
+                stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\nConnection: close\r\n\r\nnot found")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = super::super::client::new_client().unwrap();
+        let mut target = tempfile::tempfile().unwrap();
+        let err = download_to(&client, &Location::parse(&url).unwrap(), &mut target)
+            .await
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("status = 404"), "{}", err);
+        assert_eq!(target.metadata().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_segment_ranges() {
+        assert_eq!(segment_ranges(10, 3), vec![(0, 3), (4, 7), (8, 9)]);
+
+        assert_eq!(segment_ranges(2, 4), vec![(0, 0), (1, 1)]);
+
+        assert_eq!(segment_ranges(8, 1), vec![(0, 7)]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use log::debug;
+
+use age::{DecryptError, Decryptor, Identity, IdentityFile};
+
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Header of the [age](https://age-encryption.org/v1) encrypted files.
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+/// Checks whether the file is encrypted with age.
+pub fn is_encrypted(file: &mut File) -> Result<bool, Error> {
+    let mut magic = [0u8; 21];
+
+    file.seek(SeekFrom::Start(0))?;
+
+    let read = file.read(&mut magic)?;
+
+    file.seek(SeekFrom::Start(0))?;
+
+    Ok(&magic[..read] == AGE_MAGIC)
+}
+
+/// Resolves the device identities, from `ORM_AGE_IDENTITY`
+/// or from the file at `ORM_AGE_IDENTITY_FILE`.
+pub fn identities() -> Result<Vec<Box<dyn Identity>>, Error> {
+    let file = match (
+        setting!("ORM_AGE_IDENTITY"),
+        setting!("ORM_AGE_IDENTITY_FILE"),
+    ) {
+        (Some(identity), _) => IdentityFile::from_buffer(identity.as_bytes())?,
+        (None, Some(path)) => IdentityFile::from_file(path)?,
+        (None, None) => {
+            return Err(Error::new(
+                "Encrypted archive, but no identity (ORM_AGE_IDENTITY)".to_string(),
+            ))
+        }
+    };
+
+    Ok(file.into_identities()?)
+}
+
+/// Decrypts the archive to a temporary file, streamed from the downloaded one.
+pub fn decrypt(file: &mut File, identities: &[Box<dyn Identity>]) -> Result<File, Error> {
+    file.seek(SeekFrom::Start(0))?;
+
+    let decryptor = Decryptor::new_buffered(BufReader::new(&*file))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))?;
+
+    let mut decrypted = tempfile::tempfile()?;
+    let size = std::io::copy(&mut reader, &mut decrypted)?;
+
+    decrypted.seek(SeekFrom::Start(0))?;
+
+    debug!("Decrypted archive size = {}", size);
+
+    Ok(decrypted)
+}
+
+impl From<DecryptError> for Error {
+    fn from(derr: DecryptError) -> Error {
+        format_error!("Decryption error: {}", derr)
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use age::x25519;
+
+    #[test]
+    fn test_decrypt() {
+        let identity = x25519::Identity::generate();
+        let recipient = identity.to_public();
+
+        let mut file = tempfile::tempfile().unwrap();
+
+        {
+            let encryptor =
+                age::Encryptor::with_recipients(std::iter::once(&recipient as _)).unwrap();
+
+            let mut writer = encryptor.wrap_output(&mut file).unwrap();
+
+            writer.write_all(b"archive content").unwrap();
+            writer.finish().unwrap();
+        }
+
+        assert!(is_encrypted(&mut file).unwrap());
+
+        let identities: Vec<Box<dyn Identity>> = vec![Box::new(identity)];
+        let mut decrypted = decrypt(&mut file, &identities).unwrap();
+        let mut content = String::new();
+
+        decrypted.read_to_string(&mut content).unwrap();
+
+        assert_eq!(content, "archive content");
+        assert!(!is_encrypted(&mut decrypted).unwrap());
+
+        let others: Vec<Box<dyn Identity>> = vec![Box::new(x25519::Identity::generate())];
+
+        assert!(decrypt(&mut file, &others).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use hyper::Uri;
+
+use tokio::process::Command;
+
+use super::client;
+use crate::config;
+use crate::process;
+use crate::setting;
+
+/// Default duration (in seconds) the updated application has to become healthy.
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// Default duration (in seconds) a smoke test has to pass.
+const DEFAULT_SMOKE_TEST_TIMEOUT: u64 = 30;
+
+/// Interval between the health probes.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Health probe of the updated application.
+#[derive(Debug, PartialEq)]
+pub enum Probe {
+    /// HTTP(S) URL, healthy when a successful status is returned.
+    Url(Uri),
+
+    /// Script of the application (`healthcheck.sh`).
+    Script(PathBuf),
+
+    /// Shell command, executed in the application directory.
+    Command(String),
+}
+
+/// Resolves the probe declared in the manifest,
+/// otherwise the `healthcheck.sh` script of the application (if any).
+pub fn probe(declared: Option<&str>, app_dir: &Path) -> Option<Probe> {
+    match declared {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            url.parse().ok().map(Probe::Url)
+        }
+        Some(command) => Some(Probe::Command(command.to_string())),
+        None => Some(app_dir.join("healthcheck.sh"))
+            .filter(|p| p.is_file())
+            .map(Probe::Script),
+    }
+}
+
+/// Probes the updated application until it's healthy,
+/// failing if it's not within the timeout (`ORM_HEALTHCHECK_TIMEOUT`), or if it exits.
+pub async fn check<'x>(
+    probe: &'x Probe,
+    app_dir: &'x Path,
+    app: &'x mut process::Application,
+) -> std::io::Result<()> {
+    info!("Checking health of updated application: {:?}", probe);
+
+    until_healthy(probe, app_dir, app, "Updated application").await
+}
+
+/// Resolves the probe of a process of the application, its `healthcheck-{name}.sh` script (if any).
+pub fn process_probe(app_dir: &Path, name: &str) -> Option<Probe> {
+    Some(app_dir.join(format!("healthcheck-{}.sh", name)))
+        .filter(|p| p.is_file())
+        .map(Probe::Script)
+}
+
+/// Probes the started process until it's ready, as `check`.
+pub async fn ready<'x>(
+    probe: &'x Probe,
+    app_dir: &'x Path,
+    name: &'x str,
+    app: &'x mut process::Application,
+) -> std::io::Result<()> {
+    info!("Waiting process {} to be ready: {:?}", name, probe);
+
+    until_healthy(probe, app_dir, app, &format!("Process {}", name)).await
+}
+
+async fn until_healthy<'x>(
+    probe: &'x Probe,
+    app_dir: &'x Path,
+    app: &'x mut process::Application,
+    subject: &'x str,
+) -> std::io::Result<()> {
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_HEALTHCHECK_TIMEOUT",
+        setting!("ORM_HEALTHCHECK_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let cause = match run(probe, app_dir).await {
+            Ok(_) => return Ok(()),
+            Err(cause) => cause,
+        };
+
+        if let Some(status) = app.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "{} exited before being healthy ({}): {}",
+                subject, status, cause
+            )));
+        }
+
+        if Instant::now() >= deadline {
+            return Err(std::io::Error::other(format!(
+                "{} not healthy within {:?}: {}",
+                subject, timeout, cause
+            )));
+        }
+
+        debug!("Not healthy yet: {}", cause);
+
+        tokio::time::sleep(INTERVAL).await;
+    }
+}
+
+/// Runs the smoke tests the application ships in its `tests` directory (if any)
+/// against the staged tree (as working directory), before it's promoted:
+/// each executable file, in name order, must succeed within `ORM_SMOKE_TEST_TIMEOUT`.
+pub async fn smoke_test(staged_app: &Path) -> std::io::Result<()> {
+    let tests_dir = staged_app.join("tests");
+
+    if !tests_dir.is_dir() {
+        return Ok(());
+    }
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_SMOKE_TEST_TIMEOUT",
+        setting!("ORM_SMOKE_TEST_TIMEOUT"),
+        DEFAULT_SMOKE_TEST_TIMEOUT,
+    ));
+
+    let mut tests = fs::read_dir(&tests_dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            fs::metadata(path)
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+        .collect::<Vec<PathBuf>>();
+
+    tests.sort();
+
+    for test in tests {
+        info!("Running smoke test {:?} ...", test);
+
+        let mut child = Command::new(&test)
+            .current_dir(staged_app)
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let status = match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => status?,
+            Err(_) => {
+                let _ = child.kill().await;
+
+                return Err(std::io::Error::other(format!(
+                    "Smoke test {:?} not passed within {:?}",
+                    test, timeout
+                )));
+            }
+        };
+
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "Smoke test {:?} failed: {}",
+                test, status
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+async fn run(probe: &Probe, app_dir: &Path) -> Result<(), String> {
+    let status = match probe {
+        Probe::Url(uri) => return get(uri).await,
+        Probe::Script(script) => Command::new(script).current_dir(app_dir).status().await,
+        Probe::Command(command) => {
+            Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .current_dir(app_dir)
+                .status()
+                .await
+        }
+    };
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("Probe failed: {}", status)),
+        Err(cause) => Err(format!("Probe not executed: {}", cause)),
+    }
+}
+
+async fn get(uri: &Uri) -> Result<(), String> {
+    let client = client::new_client().map_err(|err| err.to_string())?;
+
+    let res = tokio::time::timeout(INTERVAL * 5, client.get(uri.clone())).await;
+
+    match res {
+        Ok(Ok(resp)) if resp.status().is_success() => Ok(()),
+        Ok(Ok(resp)) => Err(format!("Probe status: {}", resp.status())),
+        Ok(Err(cause)) => Err(format!("Probe error: {}", cause)),
+        Err(_) => Err("Probe timeout".to_string()),
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        assert_eq!(probe(None, tmp.path()), None);
+        assert!(matches!(
+            probe(Some("http://localhost:8080/health"), tmp.path()),
+            Some(Probe::Url(_))
+        ));
+
+        std::fs::write(tmp.path().join("ready"), "").unwrap();
+
+        let mut sleep = Command::new("sleep");
+
+        sleep.arg("5");
+
+        let mut app = process::start(tmp.path(), vec![("sleep".to_string(), sleep)])
+            .await
+            .unwrap();
+
+        let healthy = Probe::Command("test -f ready".to_string());
+
+        assert!(check(&healthy, tmp.path(), &mut app).await.is_ok());
+
+        process::kill(&mut app).await.unwrap();
+
+        // Exited
+        let unhealthy = Probe::Command("test -f missing".to_string());
+
+        assert!(check(&unhealthy, tmp.path(), &mut app).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_smoke_test() {
+        let tmp = tempfile::tempdir().unwrap();
+        let tests_dir = tmp.path().join("tests");
+
+        assert!(smoke_test(tmp.path()).await.is_ok()); // No tests
+
+        fs::create_dir(&tests_dir).unwrap();
+        fs::write(tmp.path().join("config.yaml"), "").unwrap();
+        fs::write(tests_dir.join("README"), "Not executable").unwrap();
+
+        let script = |name: &str, content: &str| {
+            let path = tests_dir.join(name);
+
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        };
+
+        script("01-config.sh", "#!/bin/sh\ntest -f config.yaml\n");
+
+        assert!(smoke_test(tmp.path()).await.is_ok());
+
+        script("02-fail.sh", "#!/bin/sh\nexit 1\n");
+
+        assert!(smoke_test(tmp.path()).await.is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+
+use log::info;
+
+use crate::config;
+use crate::error;
+use crate::io::write_atomic;
+use error::Error;
+
+fn path(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_hold")
+}
+
+/// Returns since when the updates are held (if so), as RFC 3339 timestamp.
+pub fn since(local_prefix: &Path) -> Option<String> {
+    fs::read_to_string(path(local_prefix))
+        .ok()
+        .map(|since| since.trim().to_string())
+}
+
+/// Holds the updates (persisted across the restarts), until released.
+pub fn hold(local_prefix: &Path) -> Result<(), Error> {
+    if since(local_prefix).is_some() {
+        return Ok(());
+    }
+
+    info!("Holding the updates");
+
+    Ok(write_atomic(
+        &path(local_prefix),
+        Utc::now().to_rfc3339().as_bytes(),
+    )?)
+}
+
+/// Releases the held updates, if any.
+pub fn release(local_prefix: &Path) -> Result<(), Error> {
+    match fs::remove_file(path(local_prefix)) {
+        Ok(_) => {
+            info!("Updates no longer held");
+
+            Ok(())
+        }
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(cause) => Err(Error::from(cause)),
+    }
+}
@@ -0,0 +1,129 @@
+use log::{debug, info};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::manifest;
+use crate::config;
+use crate::error;
+use crate::mqtt;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Job execution, as received from AWS IoT Jobs.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Execution {
+    job_id: String,
+    status: String,
+
+    #[serde(default)]
+    job_document: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct NextResponse {
+    #[serde(default)]
+    execution: Option<Execution>,
+}
+
+/// Pending job of the device, whose document declares the update as a manifest device
+/// (`version`, `sha256`, `format`, ...).
+#[derive(Debug)]
+pub struct Job {
+    pub id: String,
+    pub device: manifest::Device,
+}
+
+/// Whether the updates are triggered by the AWS IoT jobs of the device (`ORM_IOT_JOBS`),
+/// rather than by the manifest.
+pub fn enabled() -> bool {
+    config::parse_or("ORM_IOT_JOBS", setting!("ORM_IOT_JOBS"), false)
+}
+
+/// Returns the next pending job of the device (if any), marked as `IN_PROGRESS`.
+pub async fn next(thing_id: &str) -> Result<Option<Job>, Error> {
+    let topic = format!("$aws/things/{}/jobs/$next/get", thing_id);
+    let response = mqtt::request(thing_id, &topic, serde_json::to_vec(&json!({}))?).await?;
+
+    let execution = match serde_json::from_slice::<NextResponse>(&response)?.execution {
+        Some(execution) => execution,
+        None => return Ok(None),
+    };
+
+    debug!("Job execution: {:?}", execution);
+
+    let device = manifest::Device::from_json(execution.job_document)
+        .map_err(|err| format_error!("Invalid document of job {}: {}", execution.job_id, err))?;
+
+    if execution.status == "QUEUED" {
+        update(thing_id, &execution.job_id, "IN_PROGRESS", None).await?;
+    }
+
+    info!("Job {} for version {}", execution.job_id, device.version);
+
+    Ok(Some(Job {
+        id: execution.job_id,
+        device,
+    }))
+}
+
+/// Updates the status of the job execution
+/// (either `IN_PROGRESS`, `SUCCEEDED` or `FAILED`), with the reason (if any) as status details.
+pub async fn update(
+    thing_id: &str,
+    job_id: &str,
+    status: &str,
+    reason: Option<&str>,
+) -> Result<(), Error> {
+    let topic = format!("$aws/things/{}/jobs/{}/update", thing_id, job_id);
+    let details = match reason {
+        Some(reason) => json!({ "reason": reason }),
+        None => json!({}),
+    };
+
+    info!("Updating job {} as {}", job_id, status);
+
+    let payload = json!({ "status": status, "statusDetails": details });
+
+    mqtt::request(thing_id, &topic, serde_json::to_vec(&payload)?)
+        .await
+        .map(|_| ())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        let response = br#"{
+          "execution": {
+            "jobId": "update-2",
+            "status": "QUEUED",
+            "jobDocument": { "version": "2.0.0", "sha256": "abcd", "reload": true }
+          }
+        }"#;
+
+        let execution = serde_json::from_slice::<NextResponse>(response)
+            .unwrap()
+            .execution
+            .unwrap();
+
+        assert_eq!(execution.job_id, "update-2");
+
+        let device = manifest::Device::from_json(execution.job_document).unwrap();
+
+        assert_eq!(device.version.0, "2.0.0");
+        assert_eq!(device.sha256.as_deref(), Some("abcd"));
+        assert!(device.reload);
+        assert_eq!(device.preserve, vec!["data".to_string()]);
+
+        let none = serde_json::from_slice::<NextResponse>(br#"{"timestamp": 1}"#).unwrap();
+
+        assert!(none.execution.is_none());
+        assert!(manifest::Device::from_json(json!({ "sha256": "abcd" })).is_err());
+    }
+}
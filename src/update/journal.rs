@@ -0,0 +1,319 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+
+use log::{debug, info, warn};
+
+use serde::{Deserialize, Serialize};
+
+use super::manifest::Version;
+use super::marker::Marker;
+use super::slots;
+use crate::config;
+use crate::error;
+use crate::io::write_atomic;
+use error::Error;
+
+/// Step of the update, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Downloaded,
+    Verified,
+    Staged,
+    OldDirRenamed,
+    NewDirInPlace,
+    Started,
+    Committed,
+}
+
+/// Journal of the update in progress (`.orm_journal`),
+/// persisted ahead of each transition so an interrupted update can be repaired.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    pub step: Step,
+    pub version: String,
+
+    /// Directory the previous application is renamed to.
+    #[serde(default)]
+    pub archived: Option<PathBuf>,
+
+    /// Directory the updated application is staged in, next to the current one.
+    #[serde(default)]
+    pub staged: Option<PathBuf>,
+
+    /// Inode of the staged directory, to find the updated application wherever it is.
+    #[serde(default)]
+    pub staged_ino: Option<u64>,
+
+    /// Whether the update is installed in the inactive slot (see `slots`),
+    /// the previous one being kept as is.
+    #[serde(default)]
+    pub slots: bool,
+
+    /// Paths kept from the previous version.
+    #[serde(default)]
+    pub preserve: Vec<String>,
+
+    /// Version marker written once the update is committed
+    /// (none if the staged tree has its own, e.g. restored backup).
+    #[serde(default)]
+    pub marker: Option<Marker>,
+}
+
+impl Journal {
+    pub fn new(version: &Version, preserve: &[String]) -> Journal {
+        Journal {
+            step: Step::Downloaded,
+            version: version.to_string(),
+            archived: None,
+            staged: None,
+            staged_ino: None,
+            slots: false,
+            preserve: preserve.to_vec(),
+            marker: None,
+        }
+    }
+
+    fn path(local_prefix: &Path) -> PathBuf {
+        config::state_dir(local_prefix).join(".orm_journal")
+    }
+
+    fn load(local_prefix: &Path) -> Result<Option<Journal>, Error> {
+        match fs::read(Journal::path(local_prefix)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(cause) => Err(Error::from(cause)),
+        }
+    }
+
+    /// Returns the step of the update in progress (if any).
+    pub fn step(local_prefix: &Path) -> Option<Step> {
+        Journal::load(local_prefix)
+            .ok()
+            .flatten()
+            .map(|journal| journal.step)
+            .filter(|step| *step != Step::Committed)
+    }
+
+    /// Whether an update is in progress (not committed).
+    pub fn pending(local_prefix: &Path) -> Result<bool, Error> {
+        Ok(Journal::load(local_prefix)?
+            .map(|journal| journal.step != Step::Committed)
+            .unwrap_or(false))
+    }
+
+    /// Persists the journal at given step, synced to the storage.
+    pub fn transition(&mut self, local_prefix: &Path, step: Step) -> std::io::Result<()> {
+        self.step = step;
+
+        debug!("Update journal: {:?}", step);
+
+        write_atomic(&Journal::path(local_prefix), &serde_json::to_vec(self)?)
+    }
+
+    /// Restores the previous application directory, wherever the swap was interrupted,
+    /// moving back the preserved paths from the updated one.
+    pub fn rollback(&self, app_dir: &Path) -> std::io::Result<()> {
+        if self.slots {
+            if let (Some(previous), Some(updated)) = (&self.archived, &self.staged) {
+                super::preserve(&self.preserve, updated, previous, false)?;
+                slots::point(app_dir, previous)?;
+            }
+
+            return Ok(());
+        }
+
+        let is_updated = |p: &Path| {
+            fs::symlink_metadata(p)
+                .map(|m| m.is_dir() && Some(m.ino()) == self.staged_ino)
+                .unwrap_or(false)
+        };
+
+        let updated = [Some(app_dir), self.staged.as_deref()]
+            .into_iter()
+            .flatten()
+            .find(|p| is_updated(p));
+
+        let previous = [
+            Some(app_dir),
+            self.archived.as_deref(),
+            self.staged.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|p| p.is_dir() && !is_updated(p))
+        .ok_or_else(|| std::io::Error::other("Previous application directory not found"))?;
+
+        if let Some(updated) = updated {
+            super::preserve(&self.preserve, updated, previous, false)?;
+
+            debug!("Removing updated application directory {:?}", updated);
+
+            fs::remove_dir_all(updated)?;
+        }
+
+        if previous != app_dir {
+            fs::rename(previous, app_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the version marker of the updated application, as installed now.
+    pub fn save_marker(&self, app_dir: &Path) -> std::io::Result<()> {
+        match &self.marker {
+            Some(marker) => Marker {
+                installed_at: Some(Utc::now().to_rfc3339()),
+                ..marker.clone()
+            }
+            .save(app_dir),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the journal, once the update is over.
+    pub fn clear(local_prefix: &Path) -> std::io::Result<()> {
+        match fs::remove_file(Journal::path(local_prefix)) {
+            Err(cause) if cause.kind() != std::io::ErrorKind::NotFound => Err(cause),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Repairs the update interrupted (e.g. by a power loss) according its journal:
+/// rolls back to the previous version if the updated one wasn't started yet,
+/// otherwise completes the update.
+pub fn recover(local_prefix: &Path, app_name: &str) -> Result<(), Error> {
+    let journal = match Journal::load(local_prefix)? {
+        Some(journal) if journal.step != Step::Committed => journal,
+        _ => return Ok(()),
+    };
+
+    let app_dir = local_prefix.join(app_name);
+
+    warn!(
+        "Recovering interrupted update to {} (step = {:?})",
+        journal.version, journal.step
+    );
+
+    let _writable = super::rootfs::writable(local_prefix)?;
+
+    match journal.step {
+        Step::OldDirRenamed | Step::NewDirInPlace => {
+            // The updated application was not started
+            journal.rollback(&app_dir)?;
+
+            info!("Previous application directory restored");
+        }
+
+        Step::Started => {
+            let version = Version(journal.version.clone());
+
+            super::archive_previous(app_name, local_prefix, &journal, &version)?;
+
+            journal.save_marker(&app_dir)?;
+
+            info!("Update to {} completed", version);
+        }
+
+        _ => debug!("Nothing to repair"),
+    }
+
+    Journal::clear(local_prefix)?;
+
+    Ok(())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let local_prefix = tempfile::tempdir().unwrap();
+        let prefix = local_prefix.path();
+        let app_dir = prefix.join("foo");
+        let archived = prefix.join("foo-20260101000000");
+        let staged = prefix.join(".foo.next");
+
+        let mut journal = Journal::new(&Version("2.0.0".to_string()), &["data".to_string()]);
+
+        journal.archived = Some(archived.clone());
+        journal.staged = Some(staged.clone());
+
+        let prepare = |journal: &mut Journal| {
+            fs::create_dir_all(&app_dir).unwrap();
+            fs::write(app_dir.join("run.sh"), "previous").unwrap();
+            fs::create_dir_all(&staged).unwrap();
+            fs::write(staged.join("run.sh"), "updated").unwrap();
+
+            journal.staged_ino = Some(fs::metadata(&staged).unwrap().ino());
+
+            // Preserved data moved to the updated application
+            fs::create_dir_all(staged.join("data")).unwrap();
+            fs::write(staged.join("data/db"), "state").unwrap();
+
+            journal.transition(prefix, Step::OldDirRenamed).unwrap();
+        };
+
+        let check_restored = || {
+            recover(prefix, "foo").unwrap();
+
+            assert_eq!(
+                fs::read_to_string(app_dir.join("run.sh")).unwrap(),
+                "previous"
+            );
+            assert_eq!(
+                fs::read_to_string(app_dir.join("data/db")).unwrap(),
+                "state"
+            );
+            assert!(!staged.exists());
+            assert!(!archived.exists());
+            assert!(Journal::load(prefix).unwrap().is_none());
+        };
+
+        // Power loss before the swap
+        prepare(&mut journal);
+        check_restored();
+
+        // Power loss between the renames of the application directories
+        prepare(&mut journal);
+        fs::rename(&app_dir, &archived).unwrap();
+        check_restored();
+
+        // Power loss after the exchange, before the previous directory is archived
+        prepare(&mut journal);
+        fs::rename(&app_dir, &archived).unwrap();
+        fs::rename(&staged, &app_dir).unwrap();
+        fs::rename(&archived, &staged).unwrap();
+        check_restored();
+
+        // Power loss once the updated application is started
+        fs::rename(&app_dir, &archived).unwrap();
+        fs::create_dir_all(&app_dir).unwrap();
+
+        journal.marker = Some(Marker {
+            version: "2.0.0".to_string(),
+            previous: Some("1.0.0".to_string()),
+            ..Marker::default()
+        });
+        journal.transition(prefix, Step::Started).unwrap();
+
+        std::env::set_var("ORM_BACKUP", "false");
+
+        recover(prefix, "foo").unwrap();
+
+        std::env::remove_var("ORM_BACKUP");
+
+        let marker = Marker::load(&app_dir).unwrap().unwrap();
+
+        assert_eq!(marker.version, "2.0.0");
+        assert!(marker.installed_at.is_some());
+        assert!(!archived.exists());
+    }
+}
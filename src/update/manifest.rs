@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 
 use serde::Deserialize;
@@ -26,12 +27,159 @@ impl PartialEq for Version {
 }
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 pub struct Path(String);
 
+/// Format of the application archive.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+
+    #[serde(rename = "tar")]
+    Tar,
+
+    #[serde(rename = "tar.zst")]
+    TarZst,
+
+    #[serde(rename = "tar.xz")]
+    TarXz,
+
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl Format {
+    /// Returns the file extension of the archive.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::TarGz => "tar.gz",
+            Format::Tar => "tar",
+            Format::TarZst => "tar.zst",
+            Format::TarXz => "tar.xz",
+            Format::Zip => "zip",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Device {
     pub pattern: Pattern,
     pub version: Version,
+
+    /// SHA-256 digest (hexadecimal) of the application archive.
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    /// Size in bytes of the application archive (capping its download from the peers).
+    #[serde(default)]
+    pub size: Option<u64>,
+
+    /// Format of the application archive (default: `tar.gz`).
+    #[serde(default)]
+    pub format: Format,
+
+    /// Directory of the application inside the archive
+    /// (default: the application name; `.` for the archive root).
+    #[serde(default)]
+    pub root: Option<String>,
+
+    /// Name of the artifact metadata (YAML), next to the archive.
+    #[serde(default)]
+    pub metadata: Option<String>,
+
+    /// Name of the chunk index (YAML), next to the manifest,
+    /// to reconstruct the archive from the content addressed chunks.
+    #[serde(default)]
+    pub chunks: Option<String>,
+
+    /// Paths (relative to the application directory) kept from the previous version
+    /// (default: `data`).
+    #[serde(default = "default_preserve")]
+    pub preserve: Vec<String>,
+
+    /// Whether the application archive is encrypted with age (`.age` suffix).
+    #[serde(default)]
+    pub encrypted: bool,
+
+    /// Whether the version is retried even if failed before
+    /// (clearing it from the failed versions).
+    #[serde(default)]
+    pub retry_failed: bool,
+
+    /// Health probe of the updated application, either an HTTP(S) URL or a shell command
+    /// (default: the `healthcheck.sh` script of the application, if any).
+    #[serde(default)]
+    pub healthcheck: Option<String>,
+
+    /// Entrypoint of the application (program and arguments, whitespace separated),
+    /// unless declared in the artifact metadata.
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+
+    /// Whether the update is non-disruptive (e.g. configuration only),
+    /// so the running application is reloaded rather than restarted.
+    #[serde(default)]
+    pub reload: bool,
+
+    /// Whether the update requires a reboot of the device.
+    #[serde(default)]
+    pub reboot: bool,
+
+    /// Level overriding the ones of the log sinks (e.g. `debug`, to troubleshoot the device).
+    #[serde(default)]
+    pub log_level: Option<String>,
+}
+
+impl Device {
+    /// Returns the device settings declared by a JSON document (e.g. job), matching any device.
+    pub fn from_json(mut document: serde_json::Value) -> Result<Device, serde_json::Error> {
+        if let Some(fields) = document.as_object_mut() {
+            fields
+                .entry("pattern")
+                .or_insert(serde_json::Value::from(".*"));
+        }
+
+        serde_json::from_value(document)
+    }
+}
+
+pub fn default_preserve() -> Vec<String> {
+    vec!["data".to_string()]
+}
+
+/// Metadata about the application archive.
+#[derive(Debug, Default, Deserialize)]
+pub struct Metadata {
+    /// SHA-256 digests (hexadecimal) of the archive files, by path.
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+
+    /// Modes and capabilities applied to the extracted files, by path.
+    #[serde(default)]
+    pub permissions: HashMap<String, Permission>,
+
+    /// Entrypoint of the application, as the program (relative to the application directory)
+    /// and its arguments (default: `ORM_ENTRYPOINT`).
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+
+    /// Whether the update requires a reboot of the device.
+    #[serde(default)]
+    pub reboot: bool,
+}
+
+/// Special permissions of an extracted file.
+#[derive(Debug, Deserialize)]
+pub struct Permission {
+    /// Octal mode (e.g. `1777` for a sticky directory).
+    #[serde(default)]
+    pub mode: Option<String>,
+
+    /// File capabilities, as accepted by `setcap` (e.g. `cap_net_bind_service+ep`).
+    #[serde(default)]
+    pub capabilities: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,260 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::error;
+use crate::io::{hex, write_atomic};
+use crate::{format_error, setting};
+use error::Error;
+
+/// Version marker of the application directory (`.orm_version`),
+/// as a JSON document, or just the version for the legacy markers.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Marker {
+    pub version: String,
+
+    /// Time of the installation (RFC 3339).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_at: Option<String>,
+
+    /// URL of the application archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// SHA-256 digest (hexadecimal) of the application archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// Version replaced by the installation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+
+    /// Entrypoint declared in the artifact metadata, as the program and its arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+
+    /// Whether the installation requires a reboot of the device.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reboot: bool,
+
+    /// HMAC-SHA256 (hexadecimal) of the marker, with the device key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac: Option<String>,
+}
+
+/// Whether the markers are authenticated with the device key (`ORM_MARKER_HMAC`),
+/// so a locally modified marker is detected.
+pub fn authenticated() -> bool {
+    config::parse_or("ORM_MARKER_HMAC", setting!("ORM_MARKER_HMAC"), false)
+}
+
+/// Loads the device key (`ORM_MARKER_KEY_FILE`, default: `{ORM_STATE_DIR}/.orm_key`),
+/// generated on the first use.
+pub fn key(local_prefix: &Path) -> std::io::Result<Vec<u8>> {
+    let path = setting!("ORM_MARKER_KEY_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config::state_dir(local_prefix).join(".orm_key"));
+
+    match fs::read(&path) {
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => {
+            info!("Generating marker key {:?}", path);
+
+            let mut key = vec![0; 32];
+
+            openssl::rand::rand_bytes(&mut key).map_err(std::io::Error::other)?;
+
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?;
+
+            file.write_all(&key)?;
+            file.sync_all()?;
+
+            Ok(key)
+        }
+        res => res,
+    }
+}
+
+impl Marker {
+    pub fn parse(content: &str) -> Result<Marker, Error> {
+        let content = content.trim();
+
+        if content.starts_with('{') {
+            Ok(serde_json::from_str(content)?)
+        } else {
+            Ok(Marker {
+                version: content.to_string(),
+                ..Marker::default()
+            })
+        }
+    }
+
+    /// Computes the HMAC of the marker (without its HMAC).
+    fn digest(&self, key: &[u8]) -> std::io::Result<String> {
+        let unsigned = Marker {
+            hmac: None,
+            ..self.clone()
+        };
+
+        let digest = PKey::hmac(key)
+            .and_then(|pkey| {
+                let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+
+                signer.update(&serde_json::to_vec(&unsigned).unwrap_or_default())?;
+                signer.sign_to_vec()
+            })
+            .map_err(std::io::Error::other)?;
+
+        Ok(hex(&digest))
+    }
+
+    /// Checks the marker HMAC with the device key.
+    pub fn verify(&self, key: &[u8]) -> Result<(), Error> {
+        let expected = self.digest(key)?;
+
+        match &self.hmac {
+            Some(hmac)
+                if hmac.len() == expected.len()
+                    && openssl::memcmp::eq(hmac.as_bytes(), expected.as_bytes()) =>
+            {
+                Ok(())
+            }
+            Some(_) => Err(format_error!("Tampered version marker: {}", self.version)),
+            None => Err(format_error!(
+                "Unauthenticated version marker: {}",
+                self.version
+            )),
+        }
+    }
+
+    /// Loads the marker of the application directory, if any.
+    pub fn load(app_dir: &Path) -> Result<Option<Marker>, Error> {
+        match fs::read_to_string(app_dir.join(".orm_version")) {
+            Ok(content) => Marker::parse(&content).map(Some),
+            Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(cause) => Err(Error::from(cause)),
+        }
+    }
+
+    /// Loads the marker of the application directory,
+    /// checking its HMAC if the markers are authenticated.
+    pub fn load_verified(app_dir: &Path) -> Result<Option<Marker>, Error> {
+        match Marker::load(app_dir)? {
+            Some(marker) if authenticated() => {
+                marker.verify(&key(&local_prefix(app_dir))?)?;
+
+                Ok(Some(marker))
+            }
+            loaded => Ok(loaded),
+        }
+    }
+
+    /// Loads the marker deciding what is run from the application directory,
+    /// ignored (i.e. default entrypoint) if it cannot be verified.
+    pub fn load_runnable(app_dir: &Path) -> Option<Marker> {
+        match Marker::load_verified(app_dir) {
+            Ok(marker) => marker,
+            Err(cause) => {
+                warn!(
+                    "Invalid ORM version marker in {:?} (fallback to the default entrypoint): {}",
+                    app_dir, cause
+                );
+
+                None
+            }
+        }
+    }
+
+    /// Writes the marker in the application directory, synced to the storage
+    /// (with its HMAC if the markers are authenticated).
+    pub fn save(&self, app_dir: &Path) -> std::io::Result<()> {
+        let mut marker = Marker {
+            hmac: None,
+            ..self.clone()
+        };
+
+        if authenticated() {
+            marker.hmac = Some(marker.digest(&key(&local_prefix(app_dir))?)?);
+        }
+
+        write_atomic(
+            &app_dir.join(".orm_version"),
+            &serde_json::to_vec_pretty(&marker)?,
+        )
+    }
+}
+
+fn local_prefix(app_dir: &Path) -> PathBuf {
+    app_dir
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Marker::parse("1.2.3\n").unwrap(),
+            Marker {
+                version: "1.2.3".to_string(),
+                ..Marker::default()
+            }
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = Marker {
+            version: "2.0.0".to_string(),
+            installed_at: Some("2026-01-01T00:00:00+00:00".to_string()),
+            previous: Some("1.2.3".to_string()),
+            ..Marker::default()
+        };
+
+        marker.save(tmp.path()).unwrap();
+
+        assert_eq!(Marker::load(tmp.path()).unwrap(), Some(marker));
+        assert!(Marker::parse("{\"previous\": \"1.0.0\"}").is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let tmp = tempfile::tempdir().unwrap();
+        let device_key = key(tmp.path()).unwrap();
+
+        assert_eq!(key(tmp.path()).unwrap(), device_key); // Reloaded
+
+        let mut marker = Marker {
+            version: "2.0.0".to_string(),
+            ..Marker::default()
+        };
+
+        assert!(marker.verify(&device_key).is_err());
+
+        marker.hmac = Some(marker.digest(&device_key).unwrap());
+
+        assert!(marker.verify(&device_key).is_ok());
+        assert!(marker.verify(b"other key").is_err());
+
+        marker.version = "99.0.0".to_string();
+
+        assert!(marker.verify(&device_key).is_err());
+    }
+}
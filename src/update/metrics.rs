@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::warn;
+
+use serde::{Deserialize, Serialize};
+
+use super::report::Outcome;
+use crate::config;
+use crate::error;
+use crate::io::write_atomic;
+use error::Error;
+
+/// Phase (see `trace::traced`) the current update run first failed in.
+static FAILED_PHASE: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Serializes the updates of the counters.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Counters of the update runs (`.orm_metrics`), kept across the restarts.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    /// Runs updating to a new version (or failing to check it).
+    pub attempts: u64,
+
+    /// Runs the new version is installed by.
+    pub successes: u64,
+
+    /// Failed (or rolled back) runs, by failed phase.
+    pub failures: BTreeMap<String, u64>,
+
+    /// Size of the downloaded archives.
+    pub bytes_downloaded: u64,
+
+    pub rollbacks: u64,
+}
+
+fn path(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_metrics")
+}
+
+/// Loads the counters, or returns empty ones if missing or invalid.
+pub fn load(local_prefix: &Path) -> Counters {
+    let path = path(local_prefix);
+
+    if !path.is_file() {
+        return Counters::default();
+    }
+
+    match fs::read(&path)
+        .map_err(Error::from)
+        .and_then(|bytes| serde_json::from_slice::<Counters>(&bytes).map_err(Error::from))
+    {
+        Ok(counters) => counters,
+        Err(cause) => {
+            warn!("Ignoring invalid metrics {:?}: {}", path, cause);
+
+            Counters::default()
+        }
+    }
+}
+
+/// Begins an update run, not failed yet.
+pub fn begin() {
+    if let Ok(mut phase) = FAILED_PHASE.lock() {
+        *phase = None;
+    }
+}
+
+/// Indicates the phase failed, unless an inner one already did.
+pub fn failed(phase: &'static str) {
+    if let Ok(mut failed) = FAILED_PHASE.lock() {
+        failed.get_or_insert(phase);
+    }
+}
+
+/// Records the outcome of the update run (counted as new attempt if `attempt`).
+pub fn record(local_prefix: &Path, outcome: Outcome, attempt: bool) {
+    let phase = FAILED_PHASE
+        .lock()
+        .ok()
+        .and_then(|phase| *phase)
+        .unwrap_or("update");
+
+    update(local_prefix, |counters| {
+        if attempt {
+            counters.attempts += 1;
+        }
+
+        match outcome {
+            Outcome::Updated | Outcome::Rebooting => counters.successes += 1,
+            Outcome::RolledBack => counters.rollbacks += 1,
+            Outcome::NoUpdate | Outcome::Failed => (),
+        }
+
+        if matches!(outcome, Outcome::RolledBack | Outcome::Failed) {
+            *counters.failures.entry(phase.to_string()).or_default() += 1;
+        }
+    })
+}
+
+/// Records the size of a downloaded archive.
+pub fn downloaded(local_prefix: &Path, bytes: u64) {
+    update(local_prefix, |counters| counters.bytes_downloaded += bytes)
+}
+
+/// Updates the saved counters; A failed save is only logged.
+fn update<F: FnOnce(&mut Counters)>(local_prefix: &Path, f: F) {
+    let _lock = LOCK.lock();
+    let mut counters = load(local_prefix);
+
+    f(&mut counters);
+
+    let saved = serde_json::to_vec(&counters)
+        .map_err(Error::from)
+        .and_then(|bytes| Ok(write_atomic(&path(local_prefix), &bytes)?));
+
+    if let Err(cause) = saved {
+        warn!("Fails to save the metrics: {}", cause);
+    }
+}
+
+impl Counters {
+    /// Returns the counters in the Prometheus text format.
+    pub fn exposition(&self) -> String {
+        let mut text = String::new();
+        let mut counter = |name: &str, help: &str, samples: Vec<(String, u64)>| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} counter", name);
+
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{}{} {}", name, labels, value);
+            }
+        };
+
+        counter(
+            "orm_update_attempts_total",
+            "Update runs to a new version.",
+            vec![(String::new(), self.attempts)],
+        );
+        counter(
+            "orm_update_successes_total",
+            "Update runs installing the new version.",
+            vec![(String::new(), self.successes)],
+        );
+        counter(
+            "orm_update_failures_total",
+            "Failed update runs, by phase.",
+            self.failures
+                .iter()
+                .map(|(phase, count)| (format!("{{phase=\"{}\"}}", phase), *count))
+                .collect(),
+        );
+        counter(
+            "orm_update_rollbacks_total",
+            "Update runs rolled back.",
+            vec![(String::new(), self.rollbacks)],
+        );
+        counter(
+            "orm_downloaded_bytes_total",
+            "Size of the downloaded archives.",
+            vec![(String::new(), self.bytes_downloaded)],
+        );
+
+        text
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        begin();
+        failed("download");
+        failed("update");
+        record(tmp.path(), Outcome::Failed, true);
+
+        begin();
+        downloaded(tmp.path(), 1024);
+        record(tmp.path(), Outcome::Updated, true);
+        record(tmp.path(), Outcome::RolledBack, false);
+
+        let counters = load(tmp.path());
+
+        assert_eq!(
+            counters,
+            Counters {
+                attempts: 2,
+                successes: 1,
+                failures: BTreeMap::from([("download".to_string(), 1), ("update".to_string(), 1)]),
+                bytes_downloaded: 1024,
+                rollbacks: 1,
+            }
+        );
+
+        let text = counters.exposition();
+
+        assert!(text.contains("orm_update_attempts_total 2\n"));
+        assert!(text.contains("orm_update_failures_total{phase=\"download\"} 1\n"));
+        assert!(text.contains("# TYPE orm_downloaded_bytes_total counter\n"));
+    }
+}
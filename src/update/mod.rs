@@ -1,42 +1,283 @@
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::str;
 
-use std::io::Write;
-use std::io::{Seek, SeekFrom};
-use std::path::{Path, PathBuf};
+use std::io::{Cursor, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 
 use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 
 use log::{debug, info, warn};
 
-use hyper::body::Buf;
-use hyper::{Client, Uri};
-use hyper_tls::HttpsConnector;
+mod archive;
+mod azure;
+pub mod backup;
+mod canary;
+mod chunks;
+mod client;
+mod coap;
+pub mod confirm;
+mod datadog;
+mod download;
+mod encryption;
+pub mod health;
+pub mod hold;
+mod jobs;
+pub mod journal;
+pub mod manifest;
+pub mod marker;
+pub mod metrics;
+pub mod peer;
+pub mod pending;
+mod progress;
+pub mod prune;
+pub mod quarantine;
+mod reboot;
+mod reload;
+pub mod report;
+pub mod rootfs;
+mod s3;
+pub mod safe_mode;
+mod schedule;
+mod shadow;
+mod signature;
+pub mod slots;
+pub mod status;
+mod storage;
+mod trace;
+mod webhook;
+
+use super::config;
+use super::dbus;
+use super::error;
+use super::io::{exchange, list_file_names, move_path, sha256_hex, sync_tree};
+use super::ipc;
+use super::logging;
+use super::mqtt;
+use super::process;
+use client::HttpsClient;
+use download::{Fetched, Location};
+use error::Error;
+use journal::{Journal, Step};
+use marker::Marker;
+use schedule::Schedule;
+use slots::Layout;
 
-use http::uri::{Parts, PathAndQuery};
+use crate::{format_error, setting};
 
-use flate2::read::{GzDecoder, GzEncoder};
-use flate2::Compression;
-use tar::Archive;
+/// Default name of the script resolving the device ID, at the root of the application directory.
+const DEFAULT_ID_SCRIPT: &str = "id.sh";
 
-pub mod manifest;
+/// Default duration (in seconds) the ID script has to terminate.
+const DEFAULT_ID_TIMEOUT: u64 = 30;
 
-use super::error;
-use super::io::{find_line, list_file_names};
-use error::Error;
+/// Default duration (in seconds) the `pre_stop.sh` script has to terminate.
+const DEFAULT_PRE_STOP_TIMEOUT: u64 = 30;
+
+/// Default duration (in seconds) the `can_update.sh` script has to terminate.
+const DEFAULT_CAN_UPDATE_TIMEOUT: u64 = 30;
+
+/// Whether an update is in progress (until the updated application is committed).
+static UPDATING: AtomicBool = AtomicBool::new(false);
 
-use crate::format_error;
+/// Latest manifest received on the `ORM_MQTT_MANIFEST_TOPIC` subscription (see `subscribe`).
+static PUSHED_MANIFEST: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Delay (in seconds) before retrying to resolve the thing ID to subscribe with.
+const SUBSCRIBE_RETRY_DELAY: u64 = 10;
 
 #[derive(Debug)]
 pub enum ExecutionStatus {
     NoUpdate(String),
     AppTerminated(ExitStatus),
+
+    /// The running application must be restarted to be updated.
+    RestartRequired(String),
+
+    /// The device is rebooting to complete the update.
+    Rebooting,
+
+    /// The updated application failed, so the previous version is restored.
+    RolledBack(String),
+}
+
+/// Update settings of orm, to check for update on request (e.g. from the local API).
+#[derive(Debug, Clone, Copy)]
+pub struct Updater {
+    pub manifest_url: &'static str,
+    pub object_type: &'static str,
+    pub app_name: &'static str,
+}
+
+/// Update in progress, over once dropped.
+struct Updating;
+
+impl Updating {
+    /// Waits for the update in progress (if any) to be over.
+    async fn begin() -> Updating {
+        while UPDATING.swap(true, Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        Updating
+    }
+}
+
+impl Drop for Updating {
+    fn drop(&mut self) {
+        UPDATING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Periodically checks for update while the application runs (`ORM_UPDATE_INTERVAL`, if enabled):
+/// a non-disruptive update is reloaded (see `reload`), otherwise the application is restarted
+/// to be updated.
+pub async fn watch(
+    manifest_url: &'static str,
+    object_type: &'static str,
+    app_name: &'static str,
+    local_prefix: PathBuf,
+) {
+    let interval = config::parse_or("ORM_UPDATE_INTERVAL", setting!("ORM_UPDATE_INTERVAL"), 0);
+
+    if interval == 0 {
+        return;
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+
+        if !process::is_running() || updating() {
+            continue;
+        }
+
+        check(manifest_url, object_type, app_name, &local_prefix).await;
+    }
+}
+
+/// Keeps subscribed to the `ORM_MQTT_MANIFEST_TOPIC` (if defined) while orm runs,
+/// checking for update (see `check`) as soon as a new manifest is pushed.
+pub async fn subscribe(updater: Updater, local_prefix: PathBuf) {
+    let filter = match setting!("ORM_MQTT_MANIFEST_TOPIC") {
+        Some(filter) => filter,
+        None => return,
+    };
+
+    let app_dir = local_prefix.join(updater.app_name);
+
+    // Resolved from the running application (as not installed before the first update)
+    let thing_id = loop {
+        if process::is_running() {
+            match resolve_id(&app_dir) {
+                Ok(thing_id) => break thing_id,
+                Err(cause) => warn!("Fails to resolve the thing ID to subscribe: {}", cause),
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(SUBSCRIBE_RETRY_DELAY)).await;
+    };
+
+    // Distinct client ID, not to be disconnected by the other connections of the thing
+    let options = match mqtt::options(&format!("{}-manifest", thing_id)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            warn!("Missing ORM_MQTT_URL for topic {}", filter);
+
+            return;
+        }
+        Err(cause) => {
+            warn!("Fails to subscribe to the MQTT manifest: {}", cause);
+
+            return;
+        }
+    };
+
+    let topic = mqtt::topic(&filter, &thing_id);
+
+    mqtt::subscribe(options, &topic, |publish| {
+        if !pushed(publish.payload.to_vec(), publish.retain) || !process::is_running() || updating()
+        {
+            return;
+        }
+
+        info!("Manifest pushed on MQTT topic '{}'", publish.topic);
+
+        let local_prefix = local_prefix.clone();
+
+        tokio::spawn(async move {
+            check(
+                updater.manifest_url,
+                updater.object_type,
+                updater.app_name,
+                &local_prefix,
+            )
+            .await
+        });
+    })
+    .await
+}
+
+/// Keeps the manifest received on the subscription, returning whether it's to be checked:
+/// changed, and not the retained one (already checked at startup).
+fn pushed(payload: Vec<u8>, retain: bool) -> bool {
+    let mut latest = PUSHED_MANIFEST.lock().unwrap();
+    let first = latest.is_none();
+    let changed = latest.as_deref() != Some(payload.as_slice());
+
+    *latest = Some(payload);
+
+    changed && !(first && retain)
+}
+
+/// Returns whether an update is in progress.
+pub fn updating() -> bool {
+    UPDATING.load(Ordering::SeqCst)
+}
+
+/// Checks for update while the application runs (see `watch`).
+pub async fn check(
+    manifest_url: &'static str,
+    object_type: &'static str,
+    app_name: &'static str,
+    local_prefix: &Path,
+) {
+    let app_dir = local_prefix.join(app_name);
+
+    let current_version = Marker::load_verified(&app_dir)
+        .ok()
+        .flatten()
+        .and_then(|marker| semver::Version::parse(&marker.version).ok())
+        .unwrap_or(semver::Version::new(0, 0, 0));
+
+    match execute(
+        manifest_url,
+        object_type,
+        app_name,
+        local_prefix,
+        &app_dir,
+        current_version,
+    )
+    .await
+    {
+        Ok(ExecutionStatus::RestartRequired(msg)) => {
+            info!("{}; Restarting the application", msg);
+
+            ipc::request_restart();
+        }
+        Ok(status) => debug!("Update status: {:?}", status),
+        Err(cause) => warn!("Fails to check for update: {}", cause),
+    }
 }
 
-/// Try to update the software.
+/// Try to update the software, reporting the outcome (see `report`)
+/// and tracing its phases (see `trace`).
 pub async fn execute<'x>(
     manifest_url: &'static str,
     object_type: &'static str,
@@ -45,14 +286,110 @@ pub async fn execute<'x>(
     app_dir: &'x Path,
     current_version: semver::Version,
 ) -> Result<ExecutionStatus, Error> {
-    let thing_id = resolve_id(app_dir)?;
+    let run = report::Run::new(local_prefix, object_type, &current_version);
+
+    trace::begin();
+    metrics::begin();
+
+    let status = trace::traced(
+        "update",
+        try_update(
+            manifest_url,
+            object_type,
+            app_name,
+            local_prefix,
+            app_dir,
+            current_version,
+            &run,
+        ),
+    )
+    .await;
+
+    run.finished(app_dir, &status).await;
+    trace::export().await;
+
+    status
+}
+
+async fn try_update<'x>(
+    manifest_url: &'static str,
+    object_type: &'static str,
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    current_version: semver::Version,
+    run: &'x report::Run,
+) -> Result<ExecutionStatus, Error> {
+    let updating = Updating::begin().await;
+    let run_id = logging::new_run_id();
+
+    info!("Update run {}", run_id);
+
+    trace::attribute("orm.current_version", &current_version);
+    logging::enrich("version", &current_version);
+    logging::forget("update_version");
+
+    let thing_id = trace::traced("resolve_id", async { resolve_id(app_dir) }).await?;
 
     debug!("Thing ID = {}", thing_id);
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
+    trace::attribute("orm.thing_id", &thing_id);
+    logging::enrich("thing_id", &thing_id);
+
+    status::lifecycle(
+        "check_started",
+        "info",
+        format!("Checking for update of version {}", current_version),
+    );
+
+    if let Some(until) = Schedule::load(local_prefix).deferred_until(Utc::now()) {
+        return Ok(ExecutionStatus::NoUpdate(format!(
+            "Manifest check deferred by server until {}",
+            until
+        )));
+    }
+
+    if let Some(since) = hold::since(local_prefix) {
+        return Ok(ExecutionStatus::NoUpdate(format!(
+            "Updates held since {}",
+            since
+        )));
+    }
+
+    let client = client::new_client()?;
+
+    let update_settings = if jobs::enabled() {
+        match trace::traced("fetch_job", jobs::next(&thing_id)).await? {
+            Some(job) => {
+                run.executes(&job);
 
-    let update_settings = device_settings(object_type, manifest_url, &thing_id, &client).await?;
+                Some(job.device)
+            }
+            None => return Ok(ExecutionStatus::NoUpdate("No pending job".to_string())),
+        }
+    } else if azure::enabled() {
+        match trace::traced("fetch_twin", azure::desired(&thing_id)).await? {
+            Some(device) => {
+                run.desires(&device.version);
+
+                Some(device)
+            }
+            None => return Ok(ExecutionStatus::NoUpdate("No desired update".to_string())),
+        }
+    } else {
+        trace::traced(
+            "fetch_manifest",
+            device_settings(
+                object_type,
+                manifest_url,
+                &thing_id,
+                &current_version,
+                local_prefix,
+                &client,
+            ),
+        )
+        .await?
+    };
 
     debug!("Update settings = {:?}", update_settings);
 
@@ -62,6 +399,13 @@ pub async fn execute<'x>(
 
     let device = update_settings.unwrap();
 
+    logging::manifest_level(device.log_level.as_deref().and_then(|level| {
+        level
+            .parse()
+            .map_err(|cause| warn!("Invalid log level {}: {}", level, cause))
+            .ok()
+    }));
+
     debug!(
         "Check update version {} against current {}",
         device.version, current_version
@@ -69,6 +413,10 @@ pub async fn execute<'x>(
 
     let new_version = semver::Version::parse(&device.version.0)?;
 
+    run.targets(&device.version);
+    trace::attribute("orm.version", &new_version);
+    logging::enrich("update_version", &new_version);
+
     if new_version <= current_version {
         return Ok(ExecutionStatus::NoUpdate(format!(
             "Application version is already up-to-date: {} < {}",
@@ -76,58 +424,150 @@ pub async fn execute<'x>(
         )));
     }
 
-    let failed_versions_path = local_prefix.join(".orm_failed");
-    let failed_version = find_line(&failed_versions_path, |line| {
-        match semver::Version::parse(line) {
-            Ok(ver) => ver == new_version,
-            Err(_) => false,
+    if safe_mode::refuses(local_prefix, &new_version)? {
+        return Ok(ExecutionStatus::NoUpdate(format!(
+            "Application version {} refused in safe mode",
+            new_version
+        )));
+    }
+
+    let failed_versions_path = config::state_dir(local_prefix).join(".orm_failed");
+    if device.retry_failed {
+        let cleared = quarantine::clear(&failed_versions_path, Some(&new_version))?;
+
+        if cleared > 0 {
+            info!("Failed version {} cleared by the manifest", new_version);
         }
-    })?;
+    }
 
-    debug!("Failed version = {:?}", failed_version);
+    let failures = quarantine::failures(&failed_versions_path, &new_version)?;
 
-    if failed_version.is_some() {
+    debug!("Failures of version {} = {:?}", new_version, failures);
+
+    if let Some(reason) = quarantine::Policy::from_settings().blocks(&failures, Utc::now()) {
         return Ok(ExecutionStatus::NoUpdate(format!(
-            "Application version is a failed one: {}",
-            new_version
+            "Application version is a failed one: {} ({})",
+            new_version, reason
         )));
+    } else if failures.count > 0 {
+        info!(
+            "Retrying failed version {} (attempt {})",
+            new_version,
+            failures.count + 1
+        );
     }
 
-    // --- Archive
+    dbus::update_available(&device.version.0).await;
 
-    let mut ar_file: File = tempfile::tempfile()?;
+    for path in device.preserve.iter().map(Path::new) {
+        let components = path.components();
 
-    let ar_size = download_archive_to(
-        manifest_url,
-        app_name,
-        &device.version,
-        &client,
-        &mut ar_file,
-    )
-    .await?;
+        if !components
+            .clone()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            || !components
+                .clone()
+                .any(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format_error!("Invalid preserved path: {:?}", path));
+        }
+    }
 
-    debug!("Application archive size = {}", ar_size);
+    if let Some(reason) = vetoed(app_dir, &device.version).await {
+        let message = format!(
+            "Update to version {} deferred by application: {}",
+            new_version, reason
+        );
 
-    ar_file.seek(SeekFrom::Start(0))?; // Rewind
+        let event = status::Event::new(app_dir, "update_deferred", "info", message.clone());
+
+        status::report(app_dir, &event).await;
+
+        return Ok(ExecutionStatus::NoUpdate(message));
+    }
+
+    if device.reboot && !reboot::window_open() {
+        return Ok(ExecutionStatus::NoUpdate(format!(
+            "Update to version {} deferred until the maintenance window",
+            new_version
+        )));
+    }
+
+    if process::is_running() && !device.reload {
+        // Checked while the application runs (see `watch`)
+        match pending::Policy::from_settings() {
+            pending::Policy::Immediate => {
+                return Ok(ExecutionStatus::RestartRequired(format!(
+                    "Version {} available",
+                    new_version
+                )))
+            }
+            pending::Policy::NextRestart
+                if pending::version(app_name, local_prefix).as_ref() == Some(&device.version.0) =>
+            {
+                return Ok(ExecutionStatus::NoUpdate(format!(
+                    "Version {} already staged",
+                    new_version
+                )))
+            }
+            pending::Policy::NextRestart => (), // Staged until the next restart
+        }
+    }
+
+    // --- Archive
 
-    let extracted_dir = tempfile::tempdir()?;
+    let (extracted_dir, staged_app, mut journal) =
+        match pending::take(app_name, local_prefix, &device)? {
+            Some(pending) => pending,
+            None => {
+                stage(
+                    manifest_url,
+                    app_name,
+                    local_prefix,
+                    &device,
+                    &client,
+                    &current_version,
+                    run,
+                )
+                .await?
+            }
+        };
     let extracted_path = extracted_dir.path();
 
-    debug!("Checking archive & extracting to {:?}", extracted_path);
+    if reload::applies(&device) {
+        return match reload::apply(local_prefix, app_dir, &staged_app, &journal).await? {
+            true => Ok(ExecutionStatus::NoUpdate(format!(
+                "Application reloaded with version {}",
+                new_version
+            ))),
+            false => Ok(ExecutionStatus::RestartRequired(format!(
+                "Reload of version {} not acknowledged",
+                new_version
+            ))),
+        };
+    }
 
-    let app_prefix = Path::new(app_name);
+    if process::is_running() {
+        pending::keep(app_name, local_prefix, &staged_app, &journal)?;
 
-    extract_archive(&app_prefix, &ar_file, &extracted_path)?;
+        return Ok(ExecutionStatus::NoUpdate(format!(
+            "Version {} staged, to be applied at the next restart",
+            new_version
+        )));
+    }
 
     let status = run_updated(
+        updating,
         app_name,
         local_prefix,
         app_dir,
         &failed_versions_path,
-        &device.version,
-        &extracted_path,
-        &app_prefix,
+        &device,
+        &staged_app,
+        &mut journal,
+        run,
     )
+    .await
     .map_err(|err| {
         if !extracted_path.is_dir() {
             err
@@ -148,21 +588,201 @@ pub async fn execute<'x>(
     Ok(status)
 }
 
-/// Resolve the device/thing ID from the `id.sh` command,
-/// that must be provided inside the application.
-fn resolve_id<'x>(app_dir: &'x Path) -> Result<String, Error> {
-    let cmd_path = app_dir.join("id.sh");
-    let cmd_res = Command::new(&cmd_path).output();
+/// Downloads, verifies then extracts the application archive to a staging directory,
+/// returning it with the path of the staged application and the update journal.
+async fn stage<'x>(
+    manifest_url: &'static str,
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    device: &'x manifest::Device,
+    client: &'x HttpsClient,
+    current_version: &'x semver::Version,
+    run: &'x report::Run,
+) -> Result<(tempfile::TempDir, PathBuf, Journal), Error> {
+    for dir in [
+        config::state_dir(local_prefix),
+        staging_parent(local_prefix),
+    ] {
+        rootfs::check_writable(&dir)?;
+    }
+
+    let mut ar_file: File = tempfile::tempfile()?;
+
+    let ar_size = trace::traced(
+        "download",
+        download_archive_to(
+            manifest_url,
+            app_name,
+            local_prefix,
+            device,
+            client,
+            &mut ar_file,
+        ),
+    )
+    .await?;
+
+    debug!("Application archive size = {}", ar_size);
+
+    run.downloads(ar_size);
+
+    let sha256 = match &device.sha256 {
+        Some(digest) => digest.to_lowercase(),
+        None => {
+            ar_file.seek(SeekFrom::Start(0))?;
+
+            let digest = sha256_hex(&mut ar_file)?;
+
+            ar_file.seek(SeekFrom::Start(0))?; // Rewind
+
+            digest
+        }
+    };
+
+    let mut journal = Journal::new(&device.version, &device.preserve);
+
+    journal.marker = Some(Marker {
+        version: device.version.to_string(),
+        source: Some(
+            Location::parse(manifest_url)?
+                .sibling(&archive_name(app_name, device))?
+                .to_string(),
+        ),
+        sha256: Some(sha256),
+        previous: Some(current_version.to_string()),
+        ..Marker::default()
+    });
 
-    if cmd_res.is_err() {
+    journal.transition(local_prefix, Step::Downloaded)?;
+
+    verify_signature(manifest_url, app_name, device, client, &mut ar_file).await?;
+
+    let decrypted = if encryption::is_encrypted(&mut ar_file)? {
+        info!("Decrypting application archive ...");
+
+        Some(encryption::decrypt(
+            &mut ar_file,
+            &encryption::identities()?,
+        )?)
+    } else if device.encrypted {
         return Err(format_error!(
-            "Fails to execute command {:?}: {}",
-            &cmd_path,
-            cmd_res.unwrap_err()
+            "Application archive {} is not encrypted",
+            archive_name(app_name, device)
         ));
+    } else {
+        None
+    };
+
+    // Shared as downloaded (possibly encrypted), once verified
+    if let Some(sha256) = &device.sha256 {
+        if let Err(cause) = peer::share(
+            local_prefix,
+            app_name,
+            &device.version,
+            sha256,
+            &mut ar_file,
+        ) {
+            warn!("Fails to share archive with peers: {}", cause);
+        }
+    }
+
+    if let Some(decrypted) = decrypted {
+        ar_file = decrypted;
+    }
+
+    journal.transition(local_prefix, Step::Verified)?;
+
+    ar_file.seek(SeekFrom::Start(0))?; // Rewind
+
+    let extracted_dir = staging_dir(app_name, local_prefix)?;
+    let extracted_path = extracted_dir.path();
+
+    debug!("Checking archive & extracting to {:?}", extracted_path);
+
+    let app_prefix = archive::app_root(device.root.as_deref(), app_name)?;
+
+    let (metadata, signed) = fetch_metadata(manifest_url, device, client).await?;
+
+    let declared = match &metadata.entrypoint {
+        Some(_) if !signed => {
+            warn!("Ignoring the entrypoint of the unsigned artifact metadata");
+            None
+        }
+        entrypoint => entrypoint.clone(),
+    };
+
+    let declared = declared.or_else(|| {
+        device
+            .entrypoint
+            .as_ref()
+            .map(|e| e.split_whitespace().map(String::from).collect())
+    });
+
+    if let Some(program) = declared.as_ref().and_then(|e| e.first()) {
+        if !process::contained(program) {
+            return Err(format_error!(
+                "Entrypoint outside of the application directory: {}",
+                program
+            ));
+        }
+    }
+
+    if let Some(marker) = journal.marker.as_mut() {
+        marker.entrypoint = declared;
+        marker.reboot = device.reboot || metadata.reboot;
     }
 
-    let cmd_out = cmd_res?;
+    let entrypoint = process::entrypoint(journal.marker.as_ref());
+
+    trace::traced("extract", async {
+        if storage::enabled() {
+            storage::check(extracted_path, &archive::scan(&ar_file, device.format)?)?;
+        }
+
+        archive::extract(
+            &app_prefix,
+            &ar_file,
+            extracted_path,
+            device.format,
+            archive::Digests::new(&metadata.files),
+            Some(&entrypoint[0]),
+        )?;
+
+        archive::apply_permissions(extracted_path, &metadata.permissions)
+    })
+    .await?;
+
+    journal.transition(local_prefix, Step::Staged)?;
+
+    let staged_app = extracted_path.join(&app_prefix);
+
+    Ok((extracted_dir, staged_app, journal))
+}
+
+/// Returns the name of the script resolving the device ID (`ORM_ID_SCRIPT`, default: `id.sh`).
+fn id_script() -> String {
+    setting!("ORM_ID_SCRIPT").unwrap_or_else(|| DEFAULT_ID_SCRIPT.to_string())
+}
+
+/// Resolve the device/thing ID from the ID script (`id.sh` by default),
+/// that must be provided inside the application (and terminate within `ORM_ID_TIMEOUT`).
+fn resolve_id(app_dir: &Path) -> Result<String, Error> {
+    let cmd_path = app_dir.join(id_script());
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_ID_TIMEOUT",
+        setting!("ORM_ID_TIMEOUT"),
+        DEFAULT_ID_TIMEOUT,
+    ));
+
+    let cmd_out = match process::output_timeout(&mut Command::new(&cmd_path), timeout) {
+        Ok(out) => out,
+        Err(cause) => {
+            return Err(format_error!(
+                "Fails to execute command {:?}: {}",
+                &cmd_path,
+                cause
+            ));
+        }
+    };
     let id_res = str::from_utf8(cmd_out.stdout.as_slice())?;
     let thing_id = id_res.trim().to_string(); // Trim as CLI can output EOL
 
@@ -179,29 +799,27 @@ fn resolve_id<'x>(app_dir: &'x Path) -> Result<String, Error> {
 async fn device_settings<'x>(
     object_type: &'static str,
     manifest_url: &'static str,
-    thing_id: &'x String,
-    client: &'x Client<HttpsConnector<hyper::client::HttpConnector>>,
+    thing_id: &'x str,
+    current_version: &'x semver::Version,
+    local_prefix: &'x Path,
+    client: &'x HttpsClient,
 ) -> Result<Option<manifest::Device>, Error> {
     // --- Manifest
-    info!("Fetching manifest from '{}' ...", manifest_url);
-
-    let body = client.get(Uri::from_static(manifest_url)).await?;
-
-    let status = body.status();
-
-    debug!("Manifest request status: {}", status);
-
-    if status != 200 {
-        return Err(format_error!(
-            "Fails to fetch manifest: status = {} != 200",
-            status
-        ));
-    }
-
-    // ---
+    let bytes = match mqtt_manifest(thing_id).await {
+        Some(payload) => payload,
+        None => {
+            fetch_manifest(
+                object_type,
+                manifest_url,
+                thing_id,
+                current_version,
+                local_prefix,
+                client,
+            )
+            .await?
+        }
+    };
 
-    let buf = hyper::body::to_bytes(body).await?;
-    let bytes = buf.to_vec();
     let utf = bytes.as_slice();
     let yml = str::from_utf8(utf)?;
 
@@ -222,7 +840,7 @@ async fn device_settings<'x>(
     let found = manifest.devices.iter().find(|dev| {
         let manifest::Pattern(p) = &dev.pattern;
 
-        match regex::Regex::new(&p) {
+        match regex::Regex::new(p) {
             Ok(re) => re.is_match(thing_id),
             _ => {
                 warn!("Invalid pattern {}", p);
@@ -231,240 +849,974 @@ async fn device_settings<'x>(
         }
     });
 
-    Ok(found.map(|dev| dev.clone()))
+    Ok(found.cloned())
 }
 
-/// Returns the parent URI.
-fn parent_uri(url: &str) -> Result<Uri, Error> {
-    let uri = url.parse::<Uri>().unwrap();
-    let uri_parts = uri.into_parts();
+/// Fetches the manifest from the server,
+/// unless the previously fetched one is still fresh (`Cache-Control: max-age`).
+async fn fetch_manifest<'x>(
+    object_type: &'static str,
+    manifest_url: &'static str,
+    thing_id: &'x str,
+    current_version: &'x semver::Version,
+    local_prefix: &'x Path,
+    client: &'x HttpsClient,
+) -> Result<Vec<u8>, Error> {
+    let now = Utc::now();
+
+    if let Some(cached) = Schedule::load(local_prefix).cached_manifest(local_prefix, now) {
+        info!("Using cached manifest from '{}'", manifest_url);
 
-    if uri_parts.path_and_query.is_none() {
-        return Err(format_error!("Invalid manifest URL: {}", url));
+        return Ok(cached);
     }
 
-    let path_and_query = uri_parts.path_and_query.unwrap();
-    let path_segments: Vec<&str> = path_and_query.path().split("/").collect();
+    info!("Fetching manifest from '{}' ...", manifest_url);
 
-    let path_count = path_segments.len();
+    let identity = [
+        ("thing_id", thing_id.to_string()),
+        ("version", current_version.to_string()),
+        ("object_type", object_type.to_string()),
+    ];
 
-    if path_count == 0 {
-        return Err(format_error!("Invalid manifest path: {:?}", path_segments));
-    }
+    let location = Location::parse(manifest_url)?;
 
-    let parent_path: PathAndQuery = path_segments
-        .iter()
-        .take(path_count - 1)
-        .fold("".to_string(), |out, seg| match seg {
-            &"" => out,
-            _ => out + "/" + seg,
-        })
-        .parse()
-        .unwrap();
+    let fetched = match setting!("ORM_MANIFEST_IDENTITY").as_deref() {
+        Some("none") => download::fetch_with_hints(client, &location, &[]).await?,
 
-    let mut parent_parts = Parts::default();
+        Some("query") => {
+            download::fetch_with_hints(client, &location.with_query(&identity)?, &[]).await?
+        }
 
-    parent_parts.scheme = uri_parts.scheme;
-    parent_parts.authority = uri_parts.authority;
-    parent_parts.path_and_query = Some(parent_path);
+        _ => {
+            let headers = [
+                ("x-orm-thing-id", identity[0].1.clone()),
+                ("x-orm-version", identity[1].1.clone()),
+                ("x-orm-object-type", identity[2].1.clone()),
+            ];
 
-    Uri::from_parts(parent_parts).map_err(Error::from)
-}
+            download::fetch_with_hints(client, &location, &headers).await?
+        }
+    };
 
-/// Download the application archive to
-async fn download_archive_to<'x>(
-    manifest_url: &'static str,
-    app_name: &'static str,
-    version: &'x manifest::Version,
-    client: &'x Client<HttpsConnector<hyper::client::HttpConnector>>,
-    target: &'x mut File,
-) -> Result<u64, Error> {
-    let parent_uri = parent_uri(manifest_url).unwrap();
+    match fetched {
+        Fetched::Content(body, max_age) => {
+            Schedule::fetched(local_prefix, now, &body, max_age)?;
 
-    debug!("Parent URL = {:?}", parent_uri);
+            Ok(body)
+        }
 
-    let archive_uri = Uri::builder()
-        .scheme(parent_uri.scheme_str().unwrap())
-        .authority(parent_uri.authority().unwrap().as_str())
-        .path_and_query(format!(
-            "{}/{}-{}.tar.gz",
-            parent_uri.path(),
-            app_name,
-            version
-        ))
-        .build()
-        .unwrap();
+        Fetched::RetryAfter(status, delay) => {
+            Schedule::retry_after(local_prefix, now, delay)?;
 
-    debug!("Archive URL = {:?}", archive_uri);
+            Err(format_error!(
+                "Manifest server unavailable (status = {}); Retry after {}s",
+                status,
+                delay.as_secs()
+            ))
+        }
+    }
+}
 
-    let body = client.get(archive_uri).await?;
-    let buf = hyper::body::to_bytes(body).await?;
+/// Receives the manifest as the retained message
+/// of the `ORM_MQTT_MANIFEST_TOPIC`, if defined (unless received on the subscription).
+async fn mqtt_manifest(thing_id: &str) -> Option<Vec<u8>> {
+    let topic = mqtt::topic(&setting!("ORM_MQTT_MANIFEST_TOPIC")?, thing_id);
 
-    debug!(
-        "Downloading application archive to temporary file = {:?}",
-        target
-    );
+    if let Some(pushed) = PUSHED_MANIFEST.lock().unwrap().clone() {
+        return Some(pushed); // Kept up to date by the subscription
+    }
 
-    let size = std::io::copy(&mut buf.reader(), target)?;
+    let received = match mqtt::options(thing_id) {
+        Ok(Some(options)) => mqtt::retained(options, &topic).await,
+        Ok(None) => Err(format_error!("Missing ORM_MQTT_URL for topic {}", topic)),
+        Err(cause) => Err(cause),
+    };
 
-    Ok(size)
+    match received {
+        Ok(payload) => payload,
+        Err(cause) => {
+            warn!(
+                "Fails to receive manifest from MQTT; Fallback to polling: {}",
+                cause
+            );
+
+            None
+        }
+    }
 }
 
-/// Extracts the application archive.
-fn extract_archive<'x>(
-    prefix: &'x Path,
-    ar_file: &'x File,
-    extracted_path: &'x Path,
-) -> Result<usize, Error> {
-    let tar = GzDecoder::new(ar_file);
-    let mut app_archive = Archive::new(tar);
+/// Download the application archive to the target file,
+/// from the LAN peers if possible, otherwise from the origin
+/// (reconstructed from the chunks if the manifest indicates a chunk index);
+/// If the manifest indicates the archive digest, it's verified.
+async fn download_archive_to<'x>(
+    manifest_url: &'static str,
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    device: &'x manifest::Device,
+    client: &'x HttpsClient,
+    target: &'x mut File,
+) -> Result<u64, Error> {
+    let version = &device.version;
+    let sha256 = device.sha256.as_deref();
+
+    if let Some(digest) = sha256 {
+        if let Some(size) =
+            peer::download_from_peers(client, app_name, version, digest, device.size, target)
+                .await?
+        {
+            return Ok(size);
+        }
+    }
 
-    let entries: Vec<PathBuf> = app_archive
-        .entries()?
-        .filter_map(|e| e.ok())
-        .map(|mut entry| -> Result<PathBuf, std::io::Error> {
-            let path = entry.path()?.to_path_buf().to_owned();
-            let extracted_entry = extracted_path.join(&path);
+    let size = match &device.chunks {
+        Some(index) => {
+            let location = Location::parse(manifest_url)?.sibling(index)?;
 
-            debug!("Extracted entry = {:?}", extracted_entry);
+            debug!("Chunk index URL = {}", location);
 
-            entry.unpack(extracted_entry).map(|_| path)
-        })
-        .filter_map(|p| p.ok())
-        .filter(|p| match p.parent() {
-            Some(parent) => parent == prefix && (p.ends_with("run.sh") || p.ends_with("id.sh")),
-            None => false,
-        })
-        .collect();
+            chunks::download_to(client, &location, local_prefix, target).await?
+        }
+        None => {
+            let archive =
+                Location::parse(manifest_url)?.sibling(&archive_name(app_name, device))?;
 
-    let size = entries.len();
+            debug!("Archive URL = {}", archive);
 
-    if size != 2 {
-        return Err(format_error!(
-            "Invalid archive; Missing script(s): {:?}",
-            entries
-        ));
+            debug!(
+                "Downloading application archive to temporary file = {:?}",
+                target
+            );
+
+            download::download_to(client, &archive, target).await?
+        }
+    };
+
+    if let Some(expected) = sha256 {
+        target.seek(SeekFrom::Start(0))?;
+
+        let digest = sha256_hex(target)?;
+
+        if !digest.eq_ignore_ascii_case(expected) {
+            return Err(format_error!(
+                "Archive digest mismatch: {} != {}",
+                digest,
+                expected
+            ));
+        }
     }
 
     Ok(size)
 }
 
+/// Returns the file name of the application archive.
+fn archive_name(app_name: &'static str, device: &manifest::Device) -> String {
+    format!(
+        "{}-{}.{}{}",
+        app_name,
+        device.version,
+        device.format.extension(),
+        if device.encrypted { ".age" } else { "" }
+    )
+}
+
+/// Verifies the detached signature (`.sig`) of the archive,
+/// if a public key is provisioned.
+async fn verify_signature<'x>(
+    manifest_url: &'static str,
+    app_name: &'static str,
+    device: &'x manifest::Device,
+    client: &'x HttpsClient,
+    ar_file: &'x mut File,
+) -> Result<(), Error> {
+    let key = match signature::public_key()? {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+
+    let sig = fetch_signature(manifest_url, &archive_name(app_name, device), client).await?;
+
+    signature::verify(&key, &sig, ar_file)?;
+
+    info!("Archive signature verified");
+
+    Ok(())
+}
+
+/// Fetches the detached signature (`.sig`) of the named artifact, next to the manifest.
+async fn fetch_signature(
+    manifest_url: &'static str,
+    name: &str,
+    client: &HttpsClient,
+) -> Result<String, Error> {
+    let location = Location::parse(manifest_url)?.sibling(&format!("{}.sig", name))?;
+
+    debug!("Signature URL = {}", location);
+
+    match download::fetch_with_hints(client, &location, &[]).await? {
+        Fetched::Content(body, _) => Ok(String::from_utf8_lossy(&body).to_string()),
+        Fetched::RetryAfter(status, _) => Err(format_error!(
+            "Signature of {} unavailable: status = {}",
+            name,
+            status
+        )),
+    }
+}
+
+/// Fetches the artifact metadata, if indicated in the manifest, and whether it's signed;
+/// Its detached signature (`.sig`) is verified if a public key is provisioned,
+/// as it declares the digests, permissions and entrypoint of the application.
+async fn fetch_metadata<'x>(
+    manifest_url: &'static str,
+    device: &'x manifest::Device,
+    client: &'x HttpsClient,
+) -> Result<(manifest::Metadata, bool), Error> {
+    let name = match &device.metadata {
+        Some(name) => name,
+        None => return Ok((manifest::Metadata::default(), false)),
+    };
+
+    let location = Location::parse(manifest_url)?.sibling(name)?;
+
+    debug!("Metadata URL = {}", location);
+
+    let body = match download::fetch_with_hints(client, &location, &[]).await? {
+        Fetched::Content(body, _) => body,
+        Fetched::RetryAfter(status, _) => {
+            return Err(format_error!(
+                "Artifact metadata unavailable: status = {}",
+                status
+            ))
+        }
+    };
+
+    let signed = match signature::public_key()? {
+        Some(key) => {
+            let sig = fetch_signature(manifest_url, name, client).await?;
+
+            signature::verify(&key, &sig, &mut Cursor::new(&body[..]))?;
+
+            info!("Artifact metadata signature verified");
+            true
+        }
+        None => false,
+    };
+
+    Ok((serde_yaml::from_slice(&body)?, signed))
+}
+
+/// Returns the parent directory of the staging ones
+/// (`ORM_STAGING_DIR`, default: the state directory).
+fn staging_parent(local_prefix: &Path) -> PathBuf {
+    setting!("ORM_STAGING_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config::state_dir(local_prefix))
+}
+
+/// Creates the directory the archive is extracted to,
+/// under the state directory unless `ORM_STAGING_DIR` is set
+/// (by default the local prefix, so the application directory is swapped by a same-filesystem rename).
+fn staging_dir(app_name: &'static str, local_prefix: &Path) -> Result<tempfile::TempDir, Error> {
+    let parent = staging_parent(local_prefix);
+    let prefix = format!(".orm_staging-{}-", app_name);
+
+    // Left over by an interrupted update
+    for name in list_file_names(&parent, |n| n.starts_with(&prefix))? {
+        warn!("Removing stale staging directory: {}", name);
+
+        fs::remove_dir_all(parent.join(name))?;
+    }
+
+    Ok(tempfile::Builder::new()
+        .prefix(&prefix)
+        .tempdir_in(&parent)?)
+}
+
+/// Moves the preserved paths (e.g. `data`) between the application directories,
+/// either replacing the ones from the archive in the new directory,
+/// or restoring the ones missing from the previous directory (on revert).
+fn preserve<'x>(
+    preserved: &'x [String],
+    from: &'x Path,
+    to: &'x Path,
+    replace: bool,
+) -> std::io::Result<()> {
+    for path in preserved.iter() {
+        let source = from.join(path);
+        let target = to.join(path);
+
+        if fs::symlink_metadata(&source).is_err() {
+            continue;
+        }
+
+        match fs::symlink_metadata(&target) {
+            Ok(_) if !replace => continue,
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target)?,
+            Ok(_) => fs::remove_file(&target)?,
+            Err(_) => (),
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        debug!("Moving preserved {:?} to {:?}", source, target);
+
+        move_path(&source, &target)?;
+    }
+
+    Ok(())
+}
+
+/// Puts the staged application in place of the current one,
+/// with a single atomic exchange if supported (otherwise two renames),
+/// then moves the previous one to the archive directory.
+fn swap<'x>(app_dir: &'x Path, next_app: &'x Path, archived: &'x Path) -> std::io::Result<()> {
+    match exchange(next_app, app_dir) {
+        Ok(_) => fs::rename(next_app, archived),
+        Err(cause) if matches!(cause.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+            debug!("Atomic exchange not supported: {}", cause);
+
+            fs::rename(app_dir, archived).and_then(|_| fs::rename(next_app, app_dir))
+        }
+        Err(cause) => Err(cause),
+    }
+}
+
+/// Restores the SELinux contexts of the application directory
+/// according the policy (`restorecon`), if `ORM_SELINUX_RESTORECON` is enabled.
+fn relabel(app_dir: &Path) -> std::io::Result<()> {
+    if !config::parse_or(
+        "ORM_SELINUX_RESTORECON",
+        setting!("ORM_SELINUX_RESTORECON"),
+        false,
+    ) {
+        return Ok(());
+    }
+
+    debug!("Restoring SELinux contexts of {:?}", app_dir);
+
+    let status = Command::new("restorecon")
+        .arg("-R")
+        .arg("-F")
+        .arg(app_dir)
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "restorecon failed: {}",
+            status
+        )))
+    }
+}
+
 /// Try to run the updated application.
-fn run_updated<'x>(
+#[allow(clippy::too_many_arguments)]
+async fn run_updated<'x>(
+    updating: Updating,
     app_name: &'static str,
     local_prefix: &'x Path,
     app_dir: &'x Path,
     failed_versions_path: &'x Path,
-    version: &'x manifest::Version,
-    extracted_path: &'x Path,
-    app_prefix: &'x Path,
+    device: &'x manifest::Device,
+    extracted_app: &'x Path,
+    journal: &'x mut Journal,
+    run: &'x report::Run,
 ) -> Result<ExecutionStatus, Error> {
-    let archived_path: PathBuf = {
-        let now: DateTime<Utc> = Utc::now();
-        let ts = now.format("%Y%m%d%H%M%S").to_string();
+    let version = &device.version;
+    let archived_path = archive_path(app_name, local_prefix)?;
+    let reboot = journal.marker.as_ref().is_some_and(|m| m.reboot);
+
+    if reboot && !reboot::window_open() {
+        Journal::clear(local_prefix)?;
+
+        return Ok(ExecutionStatus::NoUpdate(format!(
+            "Update to version {} deferred until the maintenance window",
+            version
+        )));
+    }
+
+    let executed = async {
+        health::smoke_test(extracted_app).await?;
+
+        if canary::enabled() {
+            canary::run(app_dir, extracted_app, device, journal.marker.as_ref()).await?;
+        }
+
+        ipc::staged(app_dir, &version.0).await;
+
+        pre_stop(app_dir).await;
+
+        trace::traced("swap", async {
+            let _writable = rootfs::writable(local_prefix)?;
+
+            promote(
+                app_name,
+                local_prefix,
+                app_dir,
+                extracted_app,
+                &archived_path,
+                journal,
+            )
+        })
+        .await?;
+
+        if reboot {
+            // Not started before the reboot, but verified on the next boot
+            commit(app_name, local_prefix, app_dir, device, journal, true)?;
+
+            return Ok(ExecutionStatus::Rebooting);
+        }
+
+        let started = Instant::now();
+        let mut app = trace::traced("start", async {
+            let commands = process::commands(app_dir, journal.marker.as_ref())?;
+
+            process::start(app_dir, commands).await
+        })
+        .await?;
+        let _monitor = process::monitor::watch(app.id(), app_dir.to_path_buf());
+
+        info!("Successfully started updated {:?} ...", app_dir);
+
+        let healthy = trace::traced("health_check", async {
+            match wait_grace(&mut app).await {
+                Ok(_) => match health::probe(device.healthcheck.as_deref(), app_dir) {
+                    Some(probe) => health::check(&probe, app_dir, &mut app).await,
+                    None => Ok(()),
+                },
+                Err(cause) => Err(cause),
+            }
+        })
+        .await;
+
+        if let Err(cause) = healthy {
+            let _ = process::kill(&mut app).await;
+
+            return Err(cause);
+        }
+
+        commit(app_name, local_prefix, app_dir, device, journal, false)?;
+
+        run.report(app_dir, report::Outcome::Updated, None).await;
+        trace::export().await; // Not to wait for the application
+
+        drop(updating); // Checked again while running
+
+        let status = match process::wait_timeout(&mut app, process::run_timeout()).await? {
+            Some(status) => status,
+            None => {
+                // Already committed, so rolled back from the backup
+                run.quarantine(failed_versions_path, version)?;
+
+                let restored = rollback(app_name, local_prefix, app_dir)
+                    .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+                warn!("Rolled back to version {}", restored);
+
+                return Ok(ExecutionStatus::RolledBack(format!(
+                    "Updated version {} timed out",
+                    version
+                )));
+            }
+        };
+
+        if process::shutdown().is_some()
+            || process::monitor::restart_requested()
+            || ipc::update_requested()
+        {
+            return Ok(ExecutionStatus::AppTerminated(status)); // Not a crash
+        }
+
+        if let Err(cause) = safe_mode::check(app_name, local_prefix, app_dir, started.elapsed()) {
+            warn!("Fails to check crash loop: {}", cause);
+        }
+
+        Ok::<_, std::io::Error>(ExecutionStatus::AppTerminated(status))
+    }
+    .await;
+
+    let status = executed.or_else(|err| {
+        let msg = format!(
+            "Reverts due to failed execution of application from update archive: {}",
+            err
+        );
+
+        warn!("{}", msg);
+
+        // Mark as failed version, unless stopped on shutdown
+        if process::shutdown().is_none() {
+            run.quarantine(failed_versions_path, version)?;
+        }
+
+        // Revert
+        let _writable = rootfs::writable(local_prefix)?;
+
+        journal
+            .rollback(app_dir)
+            .and_then(|_| Journal::clear(local_prefix))
+            .map(|_| ExecutionStatus::RolledBack(msg))
+    })?;
+
+    if let ExecutionStatus::Rebooting = status {
+        if let Err(cause) = reboot::trigger().await {
+            return Ok(ExecutionStatus::NoUpdate(format!(
+                "Version {} installed, but fails to reboot: {}",
+                version, cause
+            )));
+        }
+    }
+
+    Ok(status)
+}
+
+/// Commits the updated application: the previous one is archived, the version marker written,
+/// and the version marked as pending confirmation.
+fn commit<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    device: &'x manifest::Device,
+    journal: &'x mut Journal,
+    reboot: bool,
+) -> std::io::Result<()> {
+    let version = &device.version;
+
+    journal.transition(local_prefix, Step::Started)?;
+
+    {
+        let _writable = rootfs::writable(local_prefix)?;
+
+        archive_previous(app_name, local_prefix, journal, version)?;
+
+        // Add version marker
+        journal.save_marker(app_dir)?;
+        debug!("Current version marker = {}", version);
+    }
+
+    journal.transition(local_prefix, Step::Committed)?;
+
+    confirm::mark(
+        local_prefix,
+        &version.0,
+        device.healthcheck.as_deref(),
+        reboot,
+    )
+}
+
+/// Restores the named backup as the application directory, once checked against its digest
+/// (see `backup::verify`), the current one being archived in turn (as for an update).
+pub fn restore<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    name: &'x str,
+) -> Result<manifest::Version, Error> {
+    if name.contains('/') || !backup::is_backup(app_name, name) {
+        return Err(format_error!("Invalid backup name: {}", name));
+    }
+
+    let path = config::state_dir(local_prefix).join(name);
+
+    backup::verify(&path)?;
+
+    let ar_file = backup::open(local_prefix, &path)?;
+
+    let extracted_dir = staging_dir(app_name, local_prefix)?;
+    let extracted_path = extracted_dir.path();
+    let app_prefix = PathBuf::from(app_name);
+
+    debug!("Checking backup & extracting to {:?}", extracted_path);
+
+    if storage::enabled() {
+        storage::check(
+            extracted_path,
+            &archive::scan(&ar_file, manifest::Format::default())?,
+        )?;
+    }
+
+    archive::extract(
+        &app_prefix,
+        &ar_file,
+        extracted_path,
+        manifest::Format::default(),
+        archive::Digests::new(&HashMap::new()),
+        None,
+    )?;
+
+    let extracted_app = extracted_path.join(&app_prefix);
+
+    let marker = Marker::load(&extracted_app)?;
+
+    // Entrypoint only known from the restored marker
+    if !process::runnable(&extracted_app, marker.as_ref()) {
+        return Err(format_error!(
+            "Invalid backup; Missing entrypoint: {}",
+            process::entrypoint(marker.as_ref())[0]
+        ));
+    }
 
-        local_prefix.join(format!("{}-{}", app_name, ts))
+    let version = match &marker {
+        Some(marker) => manifest::Version(marker.version.clone()),
+        None => {
+            warn!("Missing version marker in backup {}", name);
+
+            manifest::Version("0.0.0".to_string())
+        }
     };
-    let archived_dir = (match archived_path.to_str() {
-        Some(dir) => Ok(dir),
+
+    let mut journal = Journal::new(&version, &manifest::default_preserve());
+
+    // Marker written again (authenticated) once restored
+    journal.marker = marker;
+
+    journal.transition(local_prefix, Step::Staged)?;
+
+    let _writable = rootfs::writable(local_prefix)?;
+    let archived_path = archive_path(app_name, local_prefix)?;
+
+    promote(
+        app_name,
+        local_prefix,
+        app_dir,
+        &extracted_app,
+        &archived_path,
+        &mut journal,
+    )
+    .and_then(|_| {
+        journal.transition(local_prefix, Step::Started)?;
+
+        archive_previous(app_name, local_prefix, &journal, &version)?;
+
+        journal.save_marker(app_dir)?;
+        journal.transition(local_prefix, Step::Committed)
+    })
+    .or_else(|err| {
+        warn!("Reverts restoration of backup {}: {}", name, err);
+
+        journal
+            .rollback(app_dir)
+            .and_then(|_| Journal::clear(local_prefix))
+            .and(Err(err))
+    })?;
+
+    Ok(version)
+}
+
+/// Runs the `can_update.sh` script of the current application (if any), given the new version
+/// as `ORM_UPDATE_VERSION`: the update is vetoed if it fails (e.g. a critical measurement
+/// is in progress), or if it's not terminated within `ORM_CAN_UPDATE_TIMEOUT`,
+/// returning the reason (its output if any); The running application is then asked
+/// (see `ipc::can_update`).
+async fn vetoed(app_dir: &Path, version: &manifest::Version) -> Option<String> {
+    let script = app_dir.join("can_update.sh");
+
+    if !script.is_file() {
+        return ipc::can_update(app_dir, &version.0).await;
+    }
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_CAN_UPDATE_TIMEOUT",
+        setting!("ORM_CAN_UPDATE_TIMEOUT"),
+        DEFAULT_CAN_UPDATE_TIMEOUT,
+    ));
+
+    debug!("Checking the update can be applied with {:?}", script);
+
+    let mut command = Command::new(&script);
+
+    command
+        .current_dir(app_dir)
+        .env("ORM_APP_DIR", app_dir)
+        .env("ORM_UPDATE_VERSION", &version.0);
+
+    let res = tokio::task::spawn_blocking(move || process::output_timeout(&mut command, timeout))
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|res| res);
+
+    match res {
+        Ok(output) if output.status.success() => ipc::can_update(app_dir, &version.0).await,
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+            Some(if stdout.is_empty() {
+                output.status.to_string()
+            } else {
+                stdout
+            })
+        }
+        Err(cause) => Some(cause.to_string()),
+    }
+}
+
+/// Runs the `pre_stop.sh` script of the current application (if any) before it's swapped,
+/// so the running one can drain (e.g. flush its buffers, deregister from the broker)
+/// and exit cleanly; It's killed if not terminated within `ORM_PRE_STOP_TIMEOUT`,
+/// and a failure is only logged.
+async fn pre_stop(app_dir: &Path) {
+    let script = app_dir.join("pre_stop.sh");
+
+    if !script.is_file() {
+        return;
+    }
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_PRE_STOP_TIMEOUT",
+        setting!("ORM_PRE_STOP_TIMEOUT"),
+        DEFAULT_PRE_STOP_TIMEOUT,
+    ));
+
+    info!("Running pre-stop script {:?} ...", script);
+
+    let mut command = Command::new(&script);
+
+    command.current_dir(app_dir).env("ORM_APP_DIR", app_dir);
+
+    let res = tokio::task::spawn_blocking(move || process::output_timeout(&mut command, timeout))
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|res| res);
+
+    match res {
+        Ok(output) if output.status.success() => {
+            debug!(
+                "Pre-stop script output: {}",
+                String::from_utf8_lossy(&output.stdout).trim()
+            )
+        }
+        Ok(output) => warn!(
+            "Pre-stop script failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(cause) => warn!("Fails to run pre-stop script: {}", cause),
+    }
+}
+
+/// Waits the grace period (`ORM_GRACE_PERIOD`), failing if the updated application
+/// (any of its processes) doesn't stay alive meanwhile, before the update is committed.
+async fn wait_grace(app: &mut process::Application) -> std::io::Result<()> {
+    let grace = Duration::from_secs(config::parse_or(
+        "ORM_GRACE_PERIOD",
+        setting!("ORM_GRACE_PERIOD"),
+        0,
+    ));
+
+    if grace.is_zero() {
+        return Ok(());
+    }
+
+    debug!("Waiting the grace period of {:?}", grace);
+
+    let deadline = Instant::now() + grace;
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if let Some(status) = app.try_wait()? {
+            return Err(std::io::Error::other(format!(
+                "Updated application exited during the grace period: {}",
+                status
+            )));
+        }
+
+        tokio::time::sleep(remaining.min(Duration::from_millis(200))).await;
+    }
+
+    Ok(())
+}
+
+/// Rolls back the current version on request (see `rollback`),
+/// marking it as failed so it's not updated to again.
+pub fn revert<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+) -> Result<manifest::Version, Error> {
+    let current = Marker::load(app_dir)?;
+    let restored = rollback(app_name, local_prefix, app_dir)?;
+
+    if let Some(marker) = current {
+        quarantine::add(
+            &config::state_dir(local_prefix).join(".orm_failed"),
+            &marker.version,
+        )?;
+    }
+
+    Ok(restored)
+}
+
+/// Rolls back to the previous version: the inactive slot (see `slots`),
+/// otherwise the newest backup.
+pub fn rollback<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+) -> Result<manifest::Version, Error> {
+    if slots::layout() == Layout::Rename {
+        return match backup::latest(local_prefix, app_name)? {
+            Some(latest) => restore(app_name, local_prefix, app_dir, &latest),
+            None => Err(format_error!("No backup of {} to roll back to", app_name)),
+        };
+    }
+
+    let previous = slots::inactive(app_dir)?;
+
+    if !previous.is_dir() {
+        return Err(format_error!("No previous slot: {:?}", previous));
+    }
+
+    let version = Marker::load(&previous)?
+        .map(|m| manifest::Version(m.version))
+        .unwrap_or_else(|| manifest::Version("0.0.0".to_string()));
+
+    let _writable = rootfs::writable(local_prefix)?;
+    let mut journal = Journal::new(&version, &manifest::default_preserve());
+
+    journal.slots = true;
+    journal.archived = Some(previous);
+    journal.staged = Some(slots::active(app_dir)?);
+    journal.transition(local_prefix, Step::NewDirInPlace)?;
+    journal.rollback(app_dir)?;
+
+    Journal::clear(local_prefix)?;
+
+    Ok(version)
+}
+
+/// Returns the path the previous application directory is renamed to,
+/// unique even for several updates within a second.
+fn archive_path(app_name: &str, local_prefix: &Path) -> Result<PathBuf, Error> {
+    let archived_path = backup::unique_base(local_prefix, app_name, Utc::now())?;
+
+    match archived_path.to_str() {
+        Some(_) => Ok(archived_path),
         None => Err(format_error!(
             "Fails to prepare archive directory path: {:?}",
             archived_path
         )),
-    })?;
+    }
+}
 
-    info!(
-        "Renaming previous application directory to {:?}",
-        archived_dir
-    );
+/// Puts the extracted application in place of the current one:
+/// it's fully staged (and synced) next to it, with the preserved paths,
+/// then swapped, the previous one being moved to the archive path.
+fn promote<'x>(
+    app_name: &'x str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    extracted_app: &'x Path,
+    archived_path: &'x Path,
+    journal: &'x mut Journal,
+) -> std::io::Result<()> {
+    let layout = slots::layout();
+
+    let (next_app, previous) = match layout {
+        Layout::Rename => (
+            local_prefix.join(format!(".{}.next", app_name)),
+            archived_path.to_path_buf(),
+        ),
+        Layout::Slots => {
+            slots::ensure(app_dir)?;
+
+            (slots::inactive(app_dir)?, slots::active(app_dir)?)
+        }
+    };
+
+    if next_app.is_dir() {
+        debug!("Removing stale staged application: {:?}", next_app);
 
-    fs::rename(app_dir, archived_dir)?;
+        fs::remove_dir_all(&next_app)?;
+    }
 
-    let status = fs::rename(extracted_path.join(app_prefix), app_dir)
-        .and_then(|_| {
-            let run_script = app_dir.join("run.sh");
+    move_path(extracted_app, &next_app)?;
+    sync_tree(&next_app)?;
 
-            debug!("Updated run script: {:?}", run_script);
+    journal.slots = layout == Layout::Slots;
+    journal.archived = Some(previous);
+    journal.staged = Some(next_app.clone());
+    journal.staged_ino = Some(fs::symlink_metadata(&next_app)?.ino());
+    journal.transition(local_prefix, Step::OldDirRenamed)?;
 
-            Command::new(run_script).spawn().and_then(|mut child| {
-                info!("Successfully started updated {:?} ...", app_dir);
+    preserve(&journal.preserve, app_dir, &next_app, true)?;
 
-                // List previous archive
-                let previous_archives = list_file_names(local_prefix, |n| {
-                    n.starts_with(app_name) && n.ends_with(".tar.gz")
-                })?;
+    let installed = match layout {
+        Layout::Rename => {
+            info!(
+                "Swapping application directory, previous one archived to {:?}",
+                archived_path
+            );
 
-                // Create archive of the previous application directory
-                let archived_tar = File::create(archived_path.with_extension("tar.gz"))?;
+            swap(app_dir, &next_app, archived_path)?;
 
-                let enc = GzEncoder::new(&archived_tar, Compression::best());
-                let mut tar = tar::Builder::new(enc);
+            app_dir
+        }
+        Layout::Slots => {
+            info!("Switching application directory to {:?}", next_app);
 
-                tar.append_dir_all(app_prefix, &archived_dir)?;
+            slots::point(app_dir, &next_app)?;
 
-                fs::remove_dir_all(archived_dir)?;
+            &next_app
+        }
+    };
 
-                debug!(
-                    "Previous application directory archived as {:?}",
-                    archived_tar
-                );
+    journal.transition(local_prefix, Step::NewDirInPlace)?;
 
-                // Clean archives
-                for ar in previous_archives.iter() {
-                    debug!("Cleaning previous archive: {}", ar);
+    relabel(installed)
+}
 
-                    fs::remove_file(local_prefix.join(ar))?
-                }
+/// Archives the previous application directory (if backup is enabled), then removes it;
+/// With the slots layout, it's kept as the inactive slot.
+fn archive_previous<'x>(
+    app_name: &'x str,
+    local_prefix: &'x Path,
+    journal: &'x Journal,
+    version: &'x manifest::Version,
+) -> std::io::Result<()> {
+    let archived_path = match journal.archived.as_deref().filter(|p| p.is_dir()) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
 
-                // Add version marker and wait termination
-                let mut version_marker = File::create(app_dir.join(".orm_version"))?;
+    if journal.slots {
+        return backup::record(local_prefix, archived_path, version, None);
+    }
 
-                write!(&mut version_marker, "{}", version)?;
-                debug!("Current version marker = {}", version);
+    let archived_tar = if backup::enabled() {
+        let base =
+            config::state_dir(local_prefix).join(archived_path.file_name().unwrap_or_default());
+        let path = backup::create(app_name, archived_path, &base)?;
 
-                child.wait().map(ExecutionStatus::AppTerminated)
-            })
-        })
-        .or_else(|err| {
-            let msg = format!(
-                "Reverts due to failed execution of application from update archive: {}",
-                err
-            );
+        debug!("Previous application directory archived as {:?}", path);
 
-            warn!("{}", msg);
+        backup::upload(local_prefix, app_name, &path);
 
-            // Mark as failed version
-            let mut failed_versions = fs::OpenOptions::new()
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(failed_versions_path)?;
+        Some(path)
+    } else {
+        info!("Removing previous application directory without backup");
 
-            debug!("Failed version: {:?}", failed_versions);
-            writeln!(failed_versions, "{}", version)?;
+        None
+    };
 
-            // Revert
-            let before_revert = {
-                if app_dir.is_dir() {
-                    fs::remove_dir_all(app_dir)
-                } else {
-                    Ok(())
-                }
-            };
+    backup::record(
+        local_prefix,
+        archived_path,
+        version,
+        archived_tar.as_deref(),
+    )?;
 
-            before_revert
-                .and_then(|_| fs::rename(archived_dir, app_dir))
-                .map(|_| ExecutionStatus::NoUpdate(msg))
-        })?;
+    fs::remove_dir_all(archived_path)?;
 
-    Ok(status)
+    // Clean archives
+    backup::retain(local_prefix, app_name)
 }
 
 // --- Tests
 
 #[cfg(test)]
 mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::download::parent_uri;
     use super::*;
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -479,4 +1831,15 @@ mod tests {
 
         assert_eq!(parent2.to_string(), "https://foo/bar".to_string());
     }
+
+    #[test]
+    fn test_pushed() {
+        assert!(!pushed(b"v1".to_vec(), true)); // Retained at subscription
+        assert!(!pushed(b"v1".to_vec(), true)); // Unchanged once reconnected
+        assert!(pushed(b"v2".to_vec(), false));
+        assert!(!pushed(b"v2".to_vec(), false));
+        assert!(pushed(b"v3".to_vec(), true)); // Changed while disconnected
+
+        assert_eq!(PUSHED_MANIFEST.lock().unwrap().as_deref(), Some(&b"v3"[..]));
+    }
 }
@@ -0,0 +1,409 @@
+use std::convert::Infallible;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode, Uri};
+
+use super::client::HttpsClient;
+use super::manifest;
+use crate::config;
+use crate::error;
+use crate::io::{list_file_names, sha256_hex};
+use crate::{format_error, setting};
+use error::Error;
+
+/// Number of archives kept in the peer cache, for each application.
+const CACHE_RETENTION: usize = 2;
+
+/// Default timeout (in seconds) downloading from a peer.
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// Default maximum size (in bytes) of an archive downloaded from a peer,
+/// if not indicated in the manifest.
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Size of the chunks served to the peers.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns the directory of the archives shared with the LAN peers.
+pub fn cache_dir(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_cache")
+}
+
+fn cache_name(app_name: &str, version: &str, sha256: &str) -> String {
+    format!("{}-{}-{}", app_name, version, sha256.to_lowercase())
+}
+
+/// Tries to download the archive from the LAN peers listed in `ORM_PEERS`,
+/// checked against its SHA-256 digest, and capped to its size (if known,
+/// otherwise `ORM_PEER_MAX_SIZE`).
+///
+/// Returns the size of the archive if downloaded from a peer.
+pub async fn download_from_peers<'x>(
+    client: &'x HttpsClient,
+    app_name: &'static str,
+    version: &'x manifest::Version,
+    sha256: &'x str,
+    size: Option<u64>,
+    target: &'x mut File,
+) -> Result<Option<u64>, Error> {
+    let peers = match setting!("ORM_PEERS") {
+        Some(peers) => peers,
+        None => return Ok(None),
+    };
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_PEER_TIMEOUT",
+        setting!("ORM_PEER_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    let max_size = size.unwrap_or_else(|| {
+        config::parse_or(
+            "ORM_PEER_MAX_SIZE",
+            setting!("ORM_PEER_MAX_SIZE"),
+            DEFAULT_MAX_SIZE,
+        )
+    });
+
+    for peer in peers.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let url = format!(
+            "{}/{}/{}/{}",
+            peer.trim_end_matches('/'),
+            app_name,
+            version,
+            sha256
+        );
+
+        let downloaded = tokio::time::timeout(
+            timeout,
+            download_from(client, &url, sha256, max_size, target),
+        )
+        .await
+        .unwrap_or_else(|_| Err(format_error!("Timeout after {}s", timeout.as_secs())));
+
+        match downloaded {
+            Ok(size) => {
+                info!("Application archive downloaded from peer {}", peer);
+
+                return Ok(Some(size));
+            }
+            Err(cause) => {
+                debug!("Fails to download archive from peer {}: {}", peer, cause);
+
+                target.set_len(0)?;
+                target.seek(SeekFrom::Start(0))?;
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+async fn download_from<'x>(
+    client: &'x HttpsClient,
+    url: &'x str,
+    sha256: &'x str,
+    max_size: u64,
+    target: &'x mut File,
+) -> Result<u64, Error> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|err| format_error!("Invalid peer URL {}: {}", url, err))?;
+
+    let mut resp = client.get(uri).await?;
+
+    if resp.status() != StatusCode::OK {
+        return Err(format_error!("Peer status = {}", resp.status()));
+    }
+
+    let declared = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if declared.is_some_and(|length| length > max_size) {
+        return Err(format_error!(
+            "Peer archive exceeds {} bytes: {:?}",
+            max_size,
+            declared
+        ));
+    }
+
+    let mut size = 0;
+
+    while let Some(chunk) = resp.body_mut().data().await {
+        let bytes = chunk?;
+
+        size += bytes.len() as u64;
+
+        if size > max_size {
+            return Err(format_error!("Peer archive exceeds {} bytes", max_size));
+        }
+
+        std::io::Write::write_all(target, &bytes)?;
+    }
+
+    target.seek(SeekFrom::Start(0))?;
+
+    let digest = sha256_hex(target)?;
+
+    if !digest.eq_ignore_ascii_case(sha256) {
+        return Err(format_error!("Peer archive digest mismatch: {}", digest));
+    }
+
+    Ok(size)
+}
+
+/// Shares the verified archive with the LAN peers,
+/// if serving them is enabled (`ORM_PEER_LISTEN`).
+pub fn share<'x>(
+    local_prefix: &'x Path,
+    app_name: &'static str,
+    version: &'x manifest::Version,
+    sha256: &'x str,
+    ar_file: &'x mut File,
+) -> Result<(), Error> {
+    if setting!("ORM_PEER_LISTEN").is_none() {
+        return Ok(());
+    }
+
+    let dir = cache_dir(local_prefix);
+
+    fs::create_dir_all(&dir)?;
+
+    let name = cache_name(app_name, &version.to_string(), sha256);
+    let partial = dir.join(format!(".{}", name));
+
+    ar_file.seek(SeekFrom::Start(0))?;
+    std::io::copy(ar_file, &mut File::create(&partial)?)?;
+    ar_file.seek(SeekFrom::Start(0))?;
+
+    fs::rename(&partial, dir.join(&name))?;
+
+    debug!("Archive shared with peers as {}", name);
+
+    // Retention
+    let prefix = format!("{}-", app_name);
+    let mut cached = list_file_names(&dir, |n| n.starts_with(&prefix) && n != &name)?;
+
+    cached.sort_by_key(|n| fs::metadata(dir.join(n)).and_then(|m| m.modified()).ok());
+
+    let obsolete = cached.len().saturating_sub(CACHE_RETENTION - 1);
+
+    for n in cached.iter().take(obsolete) {
+        debug!("Removing obsolete archive from peer cache: {}", n);
+
+        fs::remove_file(dir.join(n))?;
+    }
+
+    Ok(())
+}
+
+/// Serves the cached archives to the LAN peers, on the `ORM_PEER_LISTEN` address,
+/// as `GET /{app_name}/{version}/{sha256}`.
+pub async fn serve(local_prefix: PathBuf) -> Result<(), Error> {
+    let listen = match setting!("ORM_PEER_LISTEN") {
+        Some(listen) => listen,
+        None => return Ok(()),
+    };
+
+    let addr = listen
+        .parse::<SocketAddr>()
+        .map_err(|err| format_error!("Invalid peer listen address {}: {}", listen, err))?;
+
+    let dir = cache_dir(&local_prefix);
+
+    let make_svc = make_service_fn(move |_conn| {
+        let dir = dir.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let dir = dir.clone();
+
+                async move { Ok::<_, Infallible>(respond(&dir, req)) }
+            }))
+        }
+    });
+
+    info!("Serving cached archives to peers on {}", addr);
+
+    Server::try_bind(&addr)?
+        .serve(make_svc)
+        .await
+        .map_err(Error::from)
+}
+
+fn respond(dir: &Path, req: Request<Body>) -> Response<Body> {
+    let segments: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+
+    let status = |code: StatusCode| {
+        let mut resp = Response::new(Body::empty());
+
+        *resp.status_mut() = code;
+
+        resp
+    };
+
+    let valid = |s: &&str| {
+        !s.is_empty()
+            && !s.starts_with('.')
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+    };
+
+    if req.method() != hyper::Method::GET
+        || segments.len() != 3
+        || !segments.iter().all(valid)
+        || segments[2].len() != 64
+        || !segments[2].chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return status(StatusCode::BAD_REQUEST);
+    }
+
+    let path = dir.join(cache_name(segments[0], segments[1], segments[2]));
+
+    let mut file = match File::open(&path) {
+        Ok(f) => f,
+        Err(_) => return status(StatusCode::NOT_FOUND),
+    };
+
+    debug!("Serving {:?} to peer", path);
+
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender
+                        .send_data(hyper::body::Bytes::copy_from_slice(&buf[..n]))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(cause) => {
+                    warn!("Fails to read cached archive {:?}: {}", path, cause);
+
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    Response::new(body)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[tokio::test]
+    async fn test_respond() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        fs::write(tmp.path().join(cache_name("foo", "1.0.0", SHA256)), "hello").unwrap();
+        fs::write(tmp.path().join("secret"), "secret").unwrap();
+
+        let get = |path: String| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+
+            respond(tmp.path(), req)
+        };
+
+        let resp = get(format!("/foo/1.0.0/{}", SHA256));
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&hyper::body::to_bytes(resp).await.unwrap()[..], b"hello");
+
+        assert_eq!(
+            get(format!("/foo/2.0.0/{}", SHA256)).status(),
+            StatusCode::NOT_FOUND
+        );
+
+        for path in [
+            "/secret".to_string(),
+            "/foo/1.0.0".to_string(),
+            format!("/foo/1.0.0/{}/x", SHA256),
+            format!("/../1.0.0/{}", SHA256),
+            format!("/foo/../{}", SHA256),
+            format!("/.orm/1.0.0/{}", SHA256),
+            format!("/foo/1.0.0%2F..%2F/{}", SHA256),
+            "/foo/1.0.0/..".to_string(),
+            "/foo/1.0.0/2cf24dba".to_string(),
+            format!("/foo/1.0.0/{}", SHA256.replace('2', "z")),
+        ] {
+            assert_eq!(
+                get(path.clone()).status(),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                path
+            );
+        }
+
+        let post = Request::post(format!("/foo/1.0.0/{}", SHA256))
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(respond(tmp.path(), post).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_download_capped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/foo/1.0.0/{}",
+            listener.local_addr().unwrap(),
+            SHA256
+        );
+
+        tokio::spawn(async move {
+            // Declared length, then close delimited body
+            for head in ["Content-Length: 5\r\n", "Connection: close\r\n"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+
+                let _ = stream.read(&mut buf).await.unwrap();
+
+                stream
+                    .write_all(format!("HTTP/1.1 200 OK\r\n{}\r\nhello", head).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = crate::update::client::new_client().unwrap();
+        let mut target = tempfile::tempfile().unwrap();
+
+        for _ in 0..2 {
+            let err = download_from(&client, &url, SHA256, 4, &mut target)
+                .await
+                .unwrap_err()
+                .to_string();
+
+            assert!(err.contains("exceeds 4 bytes"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{info, warn};
+
+use super::journal::{Journal, Step};
+use super::manifest;
+use super::marker::Marker;
+use crate::config;
+use crate::error;
+use crate::setting;
+use error::Error;
+
+/// Policy applying the update found while the application runs (`ORM_UPDATE_POLICY`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The application is restarted to be updated.
+    #[default]
+    Immediate,
+
+    /// The update is staged, then applied the next time the application is restarted
+    /// (once exited on its own, or orm restarted).
+    NextRestart,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Policy, String> {
+        match repr {
+            "immediate" => Ok(Policy::Immediate),
+            "next-restart" => Ok(Policy::NextRestart),
+            _ => Err(format!("Unsupported update policy: {}", repr)),
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_settings() -> Policy {
+        config::parse_or(
+            "ORM_UPDATE_POLICY",
+            setting!("ORM_UPDATE_POLICY"),
+            Policy::default(),
+        )
+    }
+}
+
+/// Directory the pending update is staged in, with its version marker.
+fn path(app_name: &str, local_prefix: &Path) -> PathBuf {
+    super::staging_parent(local_prefix).join(format!(".orm_pending-{}", app_name))
+}
+
+/// Returns whether an update is pending.
+pub fn exists(app_name: &str, local_prefix: &Path) -> bool {
+    path(app_name, local_prefix).is_dir()
+}
+
+/// Returns the version of the pending update, if any.
+pub fn version(app_name: &str, local_prefix: &Path) -> Option<String> {
+    Marker::load(&path(app_name, local_prefix))
+        .ok()
+        .flatten()
+        .map(|marker| marker.version)
+}
+
+/// Keeps the staged application as the pending update, until the next restart.
+pub fn keep(
+    app_name: &str,
+    local_prefix: &Path,
+    staged_app: &Path,
+    journal: &Journal,
+) -> std::io::Result<()> {
+    let pending = path(app_name, local_prefix);
+
+    if pending.is_dir() {
+        fs::remove_dir_all(&pending)?; // Superseded
+    }
+
+    if let Some(marker) = &journal.marker {
+        marker.save(staged_app)?;
+    }
+
+    fs::rename(staged_app, &pending)?;
+
+    info!(
+        "Update to {} staged until the next restart of the application",
+        journal.version
+    );
+
+    Journal::clear(local_prefix)
+}
+
+/// Takes the pending update of the device version (if any) into a new staging directory,
+/// returning it with the path of the staged application and the update journal;
+/// A pending update of another version is discarded.
+pub fn take(
+    app_name: &'static str,
+    local_prefix: &Path,
+    device: &manifest::Device,
+) -> Result<Option<(tempfile::TempDir, PathBuf, Journal)>, Error> {
+    let pending = path(app_name, local_prefix);
+
+    if !pending.is_dir() {
+        return Ok(None);
+    }
+
+    let marker = match Marker::load(&pending)? {
+        Some(marker) if marker.version == device.version.0 => marker,
+        marker => {
+            warn!(
+                "Discarding the pending update to {}",
+                marker.map(|m| m.version).unwrap_or_default()
+            );
+
+            fs::remove_dir_all(&pending)?;
+
+            return Ok(None);
+        }
+    };
+
+    info!("Applying the pending update to {}", marker.version);
+
+    let extracted_dir = super::staging_dir(app_name, local_prefix)?;
+    let staged_app = extracted_dir.path().join(app_name);
+
+    fs::rename(&pending, &staged_app)?;
+
+    let mut journal = Journal::new(&device.version, &device.preserve);
+
+    journal.marker = Some(marker);
+    journal.transition(local_prefix, Step::Staged)?;
+
+    Ok(Some((extracted_dir, staged_app, journal)))
+}
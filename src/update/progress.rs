@@ -0,0 +1,130 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use super::status;
+use crate::config;
+use crate::setting;
+
+/// Default interval (in seconds) between the progress logs.
+const DEFAULT_INTERVAL: u64 = 10;
+
+/// Progress of a long operation (download, extraction),
+/// periodically logged so it's observably alive;
+/// Can be shared by concurrent tasks.
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    label: String,
+    unit: &'static str,
+    total: Option<u64>,
+    done: AtomicU64,
+    interval: Duration,
+    logged_at: Mutex<Instant>,
+
+    /// Lifecycle event the logged progress is also reported as.
+    event: Option<&'static str>,
+}
+
+impl Progress {
+    pub fn new(label: String, unit: &'static str, total: Option<u64>) -> Progress {
+        Progress::reported(label, unit, total, None)
+    }
+
+    /// Progress whose logs are also reported as lifecycle event (see `status::lifecycle`).
+    pub fn reported(
+        label: String,
+        unit: &'static str,
+        total: Option<u64>,
+        event: Option<&'static str>,
+    ) -> Progress {
+        let interval = config::parse_or(
+            "ORM_PROGRESS_INTERVAL",
+            setting!("ORM_PROGRESS_INTERVAL"),
+            DEFAULT_INTERVAL,
+        );
+
+        Progress {
+            inner: Arc::new(Inner {
+                label,
+                unit,
+                total,
+                done: AtomicU64::new(0),
+                interval: Duration::from_secs(interval),
+                logged_at: Mutex::new(Instant::now()),
+                event,
+            }),
+        }
+    }
+
+    /// Accounts for `n` more units done,
+    /// logging the progress if the interval is elapsed.
+    pub fn advance(&self, n: u64) {
+        let inner = &self.inner;
+        let done = inner.done.fetch_add(n, Ordering::Relaxed) + n;
+
+        if inner.interval.is_zero() {
+            return;
+        }
+
+        if let Ok(mut logged_at) = inner.logged_at.try_lock() {
+            if logged_at.elapsed() < inner.interval {
+                return;
+            }
+
+            *logged_at = Instant::now();
+
+            let report = inner.report(done);
+
+            info!("{}", report);
+
+            if let Some(event) = inner.event {
+                status::lifecycle(event, "info", report);
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn report(&self, done: u64) -> String {
+        match self.total {
+            Some(total) if total > 0 => format!(
+                "{}: {}/{} {} ({}%)",
+                self.label,
+                done,
+                total,
+                self.unit,
+                done * 100 / total
+            ),
+            _ => format!("{}: {} {}", self.label, done, self.unit),
+        }
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let download = Progress::new("Downloading".to_string(), "bytes", Some(200));
+
+        download.advance(50);
+
+        assert_eq!(
+            download.inner.report(50),
+            "Downloading: 50/200 bytes (25%)".to_string()
+        );
+
+        let extract = Progress::new("Extracting".to_string(), "entries", None);
+
+        assert_eq!(extract.inner.report(3), "Extracting: 3 entries".to_string());
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use super::backup;
+use super::chunks;
+use super::journal::Journal;
+use super::peer;
+use super::rootfs;
+use crate::config;
+use crate::error;
+use crate::io::{disk_usage, list_file_names};
+use crate::setting;
+use error::Error;
+
+/// Disk budget in bytes of the local prefix (`ORM_PRUNE_BUDGET`), if any.
+pub fn budget() -> Option<u64> {
+    Some(config::parse_or(
+        "ORM_PRUNE_BUDGET",
+        setting!("ORM_PRUNE_BUDGET"),
+        0u64,
+    ))
+    .filter(|b| *b > 0)
+}
+
+/// Removes the leftovers of the interrupted updates
+/// (staging and stale previous application directories, partial files),
+/// then the backups, oldest first, until the local prefix is under the budget (if any);
+/// Returns the freed bytes.
+pub fn prune(local_prefix: &Path, app_name: &str, budget: Option<u64>) -> Result<u64, Error> {
+    let mut freed = 0;
+
+    if Journal::pending(local_prefix)? {
+        warn!("Update in progress, leftovers are not pruned");
+    } else {
+        let state_dir = config::state_dir(local_prefix);
+        let staging = format!(".orm_staging-{}-", app_name);
+        let next = format!(".{}.next", app_name);
+
+        let mut leftovers: Vec<PathBuf> = Vec::new();
+        let mut list = |dir: &Path, filter: &dyn Fn(&String) -> bool| -> Result<(), Error> {
+            if dir.is_dir() {
+                leftovers.extend(list_file_names(dir, filter)?.iter().map(|n| dir.join(n)));
+            }
+
+            Ok(())
+        };
+
+        // Staging and previous application directories, partially written files
+        list(&super::staging_parent(local_prefix), &|n| {
+            n.starts_with(&staging)
+        })?;
+        list(local_prefix, &|n| {
+            *n == next || is_archived_dir(app_name, n) || is_partial(n)
+        })?;
+
+        if state_dir != local_prefix {
+            list(&state_dir, &|n| is_partial(n))?;
+        }
+
+        // Partially downloaded chunks and archives
+        list(&chunks::store_dir(local_prefix), &|n| n.starts_with('.'))?;
+        list(&peer::cache_dir(local_prefix), &|n| n.starts_with('.'))?;
+
+        // On the application partition
+        let _writable = match leftovers
+            .iter()
+            .any(|p| p.starts_with(local_prefix) && !p.starts_with(&state_dir))
+        {
+            true => rootfs::writable(local_prefix)?,
+            false => None,
+        };
+
+        for path in leftovers {
+            freed += remove(&path)?;
+        }
+    }
+
+    if let Some(budget) = budget {
+        let state_dir = config::state_dir(local_prefix);
+        let mut usage = disk_usage(local_prefix)?;
+
+        if !state_dir.starts_with(local_prefix) {
+            usage += disk_usage(&state_dir)?;
+        }
+
+        let mut backups = list_file_names(&state_dir, |n| backup::is_backup(app_name, n))?;
+
+        debug!("Local prefix usage = {} bytes (budget {})", usage, budget);
+
+        backups.sort_by_key(|n| backup::order_key(n)); // Oldest first
+
+        for name in backups {
+            if usage <= budget {
+                break;
+            }
+
+            let size = remove(&state_dir.join(name))?;
+
+            usage = usage.saturating_sub(size);
+            freed += size;
+        }
+
+        if usage > budget {
+            warn!(
+                "Local prefix still over the budget: {} > {} bytes",
+                usage, budget
+            );
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Checks whether the file name is a partially written file (see `write_atomic`).
+fn is_partial(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".partial")
+}
+
+/// Checks whether the file name is a previous application directory (`{app}-{timestamp}[-{n}]`).
+fn is_archived_dir(app_name: &str, name: &str) -> bool {
+    let is_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+    name.strip_prefix(app_name)
+        .and_then(|n| n.strip_prefix('-'))
+        .map(|n| n.split_once('-').unwrap_or((n, "0")))
+        .map(|(ts, n)| ts.len() == 14 && is_digits(ts) && is_digits(n))
+        .unwrap_or(false)
+}
+
+fn remove(path: &Path) -> Result<u64, Error> {
+    let size = disk_usage(path)?;
+
+    info!("Pruning {:?} ({} bytes)", path, size);
+
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+
+    Ok(size)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let local_prefix = tempfile::tempdir().unwrap();
+        let prefix = local_prefix.path();
+        let write = |name: &str, size: usize| {
+            let path = prefix.join(name);
+
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, vec![0; size]).unwrap();
+        };
+
+        write("foo/run.sh", 100);
+        write(".orm_staging-foo-a1b2c3/foo/run.sh", 100);
+        write(".foo.next/run.sh", 100);
+        write("foo-20260101000000/run.sh", 100);
+        write("foo-20260101000000-1/run.sh", 100);
+        write("foo-20260101000000.tar.zst", 300);
+        write("foo-20260201000000.tar.zst", 200);
+        write(".foo-20260301000000.tar.zst.partial", 10);
+        write(".orm_chunks/.0123abcd", 20);
+        write(".orm_chunks/0123abcd", 20);
+        write(".orm_cache/.foo-2.0.0-0123abcd.tar.gz", 30);
+
+        assert_eq!(prune(prefix, "foo", None).unwrap(), 460);
+        assert!(prefix.join(".orm_chunks/0123abcd").exists());
+        assert!(prefix.join("foo-20260101000000.tar.zst").exists());
+
+        assert_eq!(prune(prefix, "foo", Some(420)).unwrap(), 300);
+        assert!(!prefix.join("foo-20260101000000.tar.zst").exists());
+        assert!(prefix.join("foo-20260201000000.tar.zst").exists());
+        assert!(prefix.join("foo/run.sh").exists());
+    }
+}
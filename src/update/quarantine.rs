@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use log::debug;
+
+use crate::config;
+use crate::io::{append_line, write_atomic};
+use crate::logging;
+use crate::setting;
+
+/// Default delay (in seconds) before a failed version is retried.
+const DEFAULT_COOLDOWN: u64 = 3600;
+
+/// Failures of a version, recorded in the quarantine (`.orm_failed`).
+#[derive(Debug, PartialEq)]
+pub struct Failures {
+    pub count: u32,
+
+    /// Time of the last failure (unknown for the legacy records).
+    pub last: Option<DateTime<Utc>>,
+}
+
+/// Retry policy of the failed versions.
+#[derive(Debug)]
+pub struct Policy {
+    /// Maximum number of attempts for a version (`ORM_RETRY_MAX_ATTEMPTS`);
+    /// The default `1` never retries a failed version.
+    pub max_attempts: u32,
+
+    /// Delay after a failure before the version is retried (`ORM_RETRY_COOLDOWN`).
+    pub cooldown: Duration,
+}
+
+impl Policy {
+    pub fn from_settings() -> Policy {
+        Policy {
+            max_attempts: config::parse_or(
+                "ORM_RETRY_MAX_ATTEMPTS",
+                setting!("ORM_RETRY_MAX_ATTEMPTS"),
+                1,
+            ),
+            cooldown: Duration::from_secs(config::parse_or(
+                "ORM_RETRY_COOLDOWN",
+                setting!("ORM_RETRY_COOLDOWN"),
+                DEFAULT_COOLDOWN,
+            )),
+        }
+    }
+
+    /// Returns why the version is still quarantined (if so) at given time.
+    pub fn blocks(&self, failures: &Failures, now: DateTime<Utc>) -> Option<String> {
+        if failures.count == 0 {
+            return None;
+        }
+
+        if failures.count >= self.max_attempts {
+            return Some(format!("failed {} time(s)", failures.count));
+        }
+
+        let retry_at = failures.last.and_then(|last| {
+            chrono::Duration::from_std(self.cooldown)
+                .ok()
+                .map(|d| last + d)
+        });
+
+        match retry_at {
+            Some(at) if at > now => Some(format!("failed, retry after {}", at.to_rfc3339())),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the failures recorded for the version,
+/// as `{version}\t{timestamp}\t{run ID}` lines (or just `{version}` for the legacy ones).
+pub fn failures(path: &Path, version: &semver::Version) -> std::io::Result<Failures> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(cause) => return Err(cause),
+    };
+
+    let mut failures = Failures {
+        count: 0,
+        last: None,
+    };
+
+    for line in content.lines() {
+        let mut fields = line.split('\t');
+
+        match fields.next().map(semver::Version::parse) {
+            Some(Ok(ver)) if ver == *version => {
+                failures.count += 1;
+
+                let at = fields
+                    .next()
+                    .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                    .map(|ts| ts.with_timezone(&Utc));
+
+                failures.last = at.max(failures.last);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Records a failure of the version, in the current update run.
+pub fn add(path: &Path, version: &str) -> std::io::Result<()> {
+    debug!("Failed version: {}", version);
+
+    append_line(
+        path,
+        &format!(
+            "{}\t{}\t{}",
+            version,
+            Utc::now().to_rfc3339(),
+            logging::run_id()
+        ),
+    )
+}
+
+/// Removes the failures of the version (or all of them),
+/// returning the number of removed records.
+pub fn clear(path: &Path, version: Option<&semver::Version>) -> std::io::Result<usize> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(cause) => return Err(cause),
+    };
+
+    let (removed, kept): (Vec<&str>, Vec<&str>) = content.lines().partition(|line| {
+        let recorded = line.split('\t').next().map(semver::Version::parse);
+
+        match (version, recorded) {
+            (None, _) => true,
+            (Some(v), Some(Ok(ver))) => ver == *v,
+            _ => false,
+        }
+    });
+
+    if !removed.is_empty() {
+        let lines: String = kept.iter().map(|l| format!("{}\n", l)).collect();
+
+        write_atomic(path, lines.as_bytes())?;
+    }
+
+    Ok(removed.len())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".orm_failed");
+        let version = semver::Version::parse("2.0.0").unwrap();
+
+        fs::write(&path, "2.0.0\n1.0.0\n").unwrap(); // Legacy record
+        add(&path, "2.0.0").unwrap();
+
+        let failed = failures(&path, &version).unwrap();
+
+        assert_eq!(failed.count, 2);
+
+        let now = Utc::now();
+        let policy = Policy {
+            max_attempts: 3,
+            cooldown: Duration::from_secs(600),
+        };
+
+        assert!(policy.blocks(&failed, now).is_some()); // Cooling down
+        assert_eq!(
+            policy.blocks(&failed, now + chrono::Duration::seconds(601)),
+            None
+        );
+
+        let exhausted = Policy {
+            max_attempts: 2,
+            cooldown: Duration::from_secs(0),
+        };
+
+        assert!(exhausted.blocks(&failed, now).is_some());
+        assert_eq!(
+            exhausted.blocks(
+                &failures(&path, &semver::Version::new(3, 0, 0)).unwrap(),
+                now
+            ),
+            None
+        );
+
+        assert_eq!(clear(&path, Some(&version)).unwrap(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1.0.0\n");
+        assert_eq!(clear(&path, None).unwrap(), 1);
+        assert_eq!(failures(&path, &version).unwrap().count, 0);
+    }
+}
@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use chrono::{Local, NaiveTime};
+
+use log::{info, warn};
+
+use tokio::process::Command;
+
+use crate::setting;
+
+/// Default command rebooting the device.
+const DEFAULT_COMMAND: &str = "reboot";
+
+/// Daily maintenance window (local time) the device can be rebooted within,
+/// possibly over midnight (e.g. `23:00-02:00`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Window, String> {
+        let (start, end) = repr
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid window (HH:MM-HH:MM): {}", repr))?;
+
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|err| format!("Invalid time '{}': {}", t, err))
+        };
+
+        Ok(Window {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Whether the device can be rebooted now, within the maintenance window
+/// (`ORM_REBOOT_WINDOW`, if any).
+pub fn window_open() -> bool {
+    let repr = match setting!("ORM_REBOOT_WINDOW") {
+        Some(repr) => repr,
+        None => return true,
+    };
+
+    match repr.parse::<Window>() {
+        Ok(window) => window.contains(Local::now().time()),
+        Err(cause) => {
+            warn!("Invalid setting ORM_REBOOT_WINDOW = {}: {}", repr, cause);
+
+            true
+        }
+    }
+}
+
+/// Reboots the device with `ORM_REBOOT_COMMAND` (default: `reboot`).
+pub async fn trigger() -> std::io::Result<()> {
+    let command = setting!("ORM_REBOOT_COMMAND").unwrap_or_else(|| DEFAULT_COMMAND.to_string());
+
+    info!("Rebooting the device: {}", command);
+
+    let status = Command::new("sh").arg("-c").arg(&command).status().await?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "Reboot command failed: {}",
+            status
+        )))
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let at = |t: &str| NaiveTime::parse_from_str(t, "%H:%M").unwrap();
+        let night: Window = "23:00-02:00".parse().unwrap();
+
+        assert!(night.contains(at("23:30")));
+        assert!(night.contains(at("01:59")));
+        assert!(!night.contains(at("02:00")));
+        assert!(!night.contains(at("12:00")));
+
+        let day: Window = "02:00 - 04:30".parse().unwrap();
+
+        assert!(day.contains(at("03:00")));
+        assert!(!day.contains(at("05:00")));
+
+        assert!("02:00".parse::<Window>().is_err());
+        assert!("25:00-02:00".parse::<Window>().is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use super::journal::Journal;
+use super::manifest;
+use super::rootfs;
+use crate::config;
+use crate::io::{move_path, sync_tree};
+use crate::ipc;
+use crate::process;
+use crate::setting;
+
+/// Default duration (in seconds) the application has to acknowledge its reload.
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Signal requesting the application to reload (`ORM_RELOAD_SIGNAL`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Signal(i32);
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Signal, String> {
+        if let Ok(signal) = repr.parse::<i32>() {
+            return Ok(Signal(signal));
+        }
+
+        match repr.trim_start_matches("SIG") {
+            "HUP" => Ok(Signal(libc::SIGHUP)),
+            "USR1" => Ok(Signal(libc::SIGUSR1)),
+            "USR2" => Ok(Signal(libc::SIGUSR2)),
+            _ => Err(format!("Unsupported signal: {}", repr)),
+        }
+    }
+}
+
+/// Whether the update is applied by reloading the running application,
+/// as flagged non-disruptive by the manifest (`reload`).
+pub fn applies(device: &manifest::Device) -> bool {
+    device.reload && process::is_running()
+}
+
+/// Places the files of the staged application over the running one (except the preserved paths),
+/// then sends it `ORM_RELOAD_SIGNAL` (default: `SIGHUP`), returning whether it acknowledged
+/// the reload (`reloaded` on the control socket) within `ORM_RELOAD_TIMEOUT`.
+pub async fn apply(
+    local_prefix: &Path,
+    app_dir: &Path,
+    staged_app: &Path,
+    journal: &Journal,
+) -> std::io::Result<bool> {
+    ipc::staged(app_dir, &journal.version).await;
+
+    {
+        let _writable = rootfs::writable(local_prefix)?;
+
+        info!("Placing the files of version {}", journal.version);
+
+        place(staged_app, app_dir, Path::new(""), &journal.preserve)?;
+        sync_tree(app_dir)?;
+
+        journal.save_marker(app_dir)?;
+        Journal::clear(local_prefix)?;
+
+        super::relabel(app_dir)?;
+    }
+
+    let signal = config::parse_or(
+        "ORM_RELOAD_SIGNAL",
+        setting!("ORM_RELOAD_SIGNAL"),
+        Signal(libc::SIGHUP),
+    );
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_RELOAD_TIMEOUT",
+        setting!("ORM_RELOAD_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    ipc::take_reloaded(); // Stale
+
+    info!("Reloading the application with signal {}", signal.0);
+
+    process::signal_all(signal.0);
+
+    let started = Instant::now();
+
+    while started.elapsed() < timeout {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        if ipc::take_reloaded() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Moves the staged files over the current ones (the directories being merged),
+/// skipping the preserved paths.
+fn place(from: &Path, to: &Path, relative: &Path, preserved: &[String]) -> std::io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+
+        if preserved.iter().any(|p| path.starts_with(p)) {
+            debug!("Keeping preserved {:?}", path);
+            continue;
+        }
+
+        let target = to.join(entry.file_name());
+        let existing = fs::symlink_metadata(&target).ok();
+
+        if entry.file_type()?.is_dir() {
+            if existing.as_ref().is_some_and(|m| m.is_dir()) {
+                place(&entry.path(), &target, &path, preserved)?;
+                continue;
+            }
+        } else if !existing.as_ref().is_some_and(|m| m.is_dir()) {
+            move_path(&entry.path(), &target)?; // Replaced at once if renamed
+            continue;
+        }
+
+        match existing {
+            Some(metadata) if metadata.is_dir() => fs::remove_dir_all(&target)?,
+            Some(_) => fs::remove_file(&target)?,
+            None => (),
+        }
+
+        move_path(&entry.path(), &target)?;
+    }
+
+    Ok(())
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_place() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (staged, app_dir) = (tmp.path().join("staged"), tmp.path().join("app"));
+
+        for dir in [&staged, &app_dir] {
+            fs::create_dir_all(dir.join("conf")).unwrap();
+            fs::create_dir_all(dir.join("data")).unwrap();
+            fs::write(dir.join("conf/app.yaml"), dir.to_str().unwrap()).unwrap();
+            fs::write(dir.join("data/state"), dir.to_str().unwrap()).unwrap();
+        }
+
+        fs::write(staged.join("conf/new.yaml"), "new").unwrap();
+        fs::write(app_dir.join("obsolete"), "old").unwrap();
+
+        place(&staged, &app_dir, Path::new(""), &["data".to_string()]).unwrap();
+
+        let read = |path: &str| fs::read_to_string(app_dir.join(path)).unwrap();
+
+        assert_eq!(read("conf/app.yaml"), staged.to_str().unwrap());
+        assert_eq!(read("conf/new.yaml"), "new");
+        assert_eq!(read("data/state"), app_dir.to_str().unwrap());
+        assert_eq!(read("obsolete"), "old");
+    }
+
+    #[test]
+    fn test_signal() {
+        assert_eq!("HUP".parse(), Ok(Signal(libc::SIGHUP)));
+        assert_eq!("SIGUSR1".parse(), Ok(Signal(libc::SIGUSR1)));
+        assert_eq!("12".parse(), Ok(Signal(12)));
+        assert!("FOO".parse::<Signal>().is_err());
+    }
+}
@@ -0,0 +1,481 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use log::{debug, warn};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::azure;
+use super::client;
+use super::datadog;
+use super::jobs;
+use super::manifest;
+use super::marker::Marker;
+use super::metrics;
+use super::quarantine;
+use super::shadow;
+use super::status;
+use super::webhook;
+use super::ExecutionStatus;
+use crate::config;
+use crate::dbus;
+use crate::error;
+use crate::logging;
+use crate::outbox::{self, Outbox};
+use crate::setting;
+use error::Error;
+
+/// Default duration (in seconds) the report has to be accepted.
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Kind of the reports queued in the outbox.
+const KIND: &str = "report";
+
+/// Result of an update run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The updated application is committed (or reloaded).
+    Updated,
+
+    /// The update is installed, completed by rebooting the device.
+    Rebooting,
+
+    /// Nothing installed (e.g. up-to-date, deferred or staged).
+    NoUpdate,
+
+    /// The updated application failed, so the previous one is restored.
+    RolledBack,
+
+    Failed,
+}
+
+/// Status document of an update run, posted to the reporting endpoint.
+#[derive(Debug, Serialize)]
+struct Document<'x> {
+    thing_id: String,
+    previous_version: &'x str,
+
+    /// Version installed at the end of the run.
+    version: Option<String>,
+
+    result: Outcome,
+
+    /// Reason of a failure, or why nothing is installed.
+    reason: Option<&'x str>,
+
+    /// Duration in seconds of the update run.
+    duration: f64,
+
+    run_id: String,
+    timestamp: String,
+}
+
+/// Update run, whose outcome is reported to `ORM_REPORT_URL` (if any),
+/// and to the AWS IoT job or Azure device twin declaring its update (if any).
+#[derive(Debug)]
+pub struct Run {
+    local_prefix: PathBuf,
+    object_type: &'static str,
+    previous_version: String,
+    started: Instant,
+
+    /// Whether the run is already counted as update attempt (see `metrics::record`).
+    attempted: AtomicBool,
+
+    /// ID of the executed job.
+    job: Mutex<Option<String>>,
+
+    /// Version declared by the job or device twin.
+    desired: Mutex<Option<String>>,
+
+    /// Version the run updates to.
+    target: Mutex<Option<String>>,
+
+    /// Why the target version is quarantined by the run (if so).
+    quarantined: Mutex<Option<String>>,
+
+    /// Size of the archive downloaded by the run (if any).
+    downloaded: Mutex<Option<u64>>,
+}
+
+impl Run {
+    pub fn new(
+        local_prefix: &Path,
+        object_type: &'static str,
+        previous_version: &semver::Version,
+    ) -> Run {
+        Run {
+            local_prefix: local_prefix.to_path_buf(),
+            object_type,
+            previous_version: previous_version.to_string(),
+            started: Instant::now(),
+            attempted: AtomicBool::new(false),
+            job: Mutex::new(None),
+            desired: Mutex::new(None),
+            target: Mutex::new(None),
+            quarantined: Mutex::new(None),
+            downloaded: Mutex::new(None),
+        }
+    }
+
+    /// Indicates the size of the archive downloaded by the run (see `metrics::downloaded`).
+    pub fn downloads(&self, bytes: u64) {
+        metrics::downloaded(&self.local_prefix, bytes);
+
+        if let Ok(mut downloaded) = self.downloaded.lock() {
+            *downloaded = Some(bytes);
+        }
+    }
+
+    /// Indicates the job executed by the run.
+    pub fn executes(&self, job: &jobs::Job) {
+        if let Ok(mut executed) = self.job.lock() {
+            *executed = Some(job.id.clone());
+        }
+
+        self.desires(&job.device.version);
+    }
+
+    /// Indicates the version desired by the backend (e.g. device twin).
+    pub fn desires(&self, version: &manifest::Version) {
+        if let Ok(mut desired) = self.desired.lock() {
+            *desired = Some(version.0.clone());
+        }
+    }
+
+    /// Indicates the version the run updates to.
+    pub fn targets(&self, version: &manifest::Version) {
+        if let Ok(mut target) = self.target.lock() {
+            *target = Some(version.0.clone());
+        }
+    }
+
+    /// Records a failure of the version in the quarantine (see `quarantine::add`),
+    /// noting whether it's no longer retried.
+    pub fn quarantine(&self, path: &Path, version: &manifest::Version) -> std::io::Result<()> {
+        quarantine::add(path, &version.0)?;
+
+        let policy = quarantine::Policy::from_settings();
+        let failures = match semver::Version::parse(&version.0) {
+            Ok(version) => quarantine::failures(path, &version)?,
+            Err(_) => return Ok(()),
+        };
+
+        if failures.count >= policy.max_attempts {
+            if let Ok(mut quarantined) = self.quarantined.lock() {
+                *quarantined = policy.blocks(&failures, Utc::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports the outcome of the run according its status (and the installed version);
+    /// Once the updated application is terminated, it's already reported as `updated`
+    /// when committed.
+    pub async fn finished(&self, app_dir: &Path, status: &Result<ExecutionStatus, Error>) {
+        let (outcome, reason) = match status {
+            Ok(ExecutionStatus::AppTerminated(_)) => return,
+            Ok(ExecutionStatus::NoUpdate(msg)) | Ok(ExecutionStatus::RestartRequired(msg)) => {
+                let installed = Marker::load(app_dir).ok().flatten().map(|m| m.version);
+
+                // e.g. reloaded
+                match installed.is_some_and(|v| v != self.previous_version) {
+                    true => (Outcome::Updated, Some(msg.clone())),
+                    false => (Outcome::NoUpdate, Some(msg.clone())),
+                }
+            }
+            Ok(ExecutionStatus::RolledBack(msg)) => (Outcome::RolledBack, Some(msg.clone())),
+            Ok(ExecutionStatus::Rebooting) => (Outcome::Rebooting, None),
+            Err(cause) => (Outcome::Failed, Some(cause.to_string())),
+        };
+
+        self.report(app_dir, outcome, reason.as_deref()).await
+    }
+
+    /// Reports the outcome of the run: the lifecycle event is reported (if any, see
+    /// `status::lifecycle`), the metrics recorded (see `metrics::record`) and submitted
+    /// to DataDog (see `datadog::submit`), the status document is posted to `ORM_REPORT_URL`,
+    /// the software state is updated in the `ORM_SHADOW_NAME` shadow (or the Azure device twin),
+    /// the executed job (if any) is updated accordingly, and the webhook notified
+    /// (each if enabled);
+    /// A failed report is only logged.
+    pub async fn report(&self, app_dir: &Path, outcome: Outcome, reason: Option<&str>) {
+        let version = Marker::load(app_dir).ok().flatten().map(|m| m.version);
+        let lifecycle = match outcome {
+            Outcome::Updated | Outcome::Rebooting => Some(("update_applied", "info")),
+            Outcome::RolledBack => Some(("rollback", "warning")),
+            Outcome::Failed => Some(("update_failed", "critical")),
+            Outcome::NoUpdate => None,
+        };
+
+        if outcome != Outcome::NoUpdate {
+            let attempt = !self.attempted.swap(true, Ordering::SeqCst);
+
+            metrics::record(&self.local_prefix, outcome, attempt);
+
+            self.submit(app_dir, outcome).await;
+        }
+
+        if let (Outcome::Updated | Outcome::Rebooting, Some(version)) = (outcome, &version) {
+            dbus::update_applied(version).await;
+        }
+
+        if let Some((event, severity)) = lifecycle {
+            let message = match reason {
+                Some(reason) => reason.to_string(),
+                None => format!("Version {}", version.unwrap_or_default()),
+            };
+
+            status::lifecycle(event, severity, message);
+        }
+
+        if let Some(url) = setting!("ORM_REPORT_URL") {
+            if let Err(cause) = self.post(app_dir, &url, outcome, reason).await {
+                warn!("Fails to report the update outcome to {}: {}", url, cause);
+            }
+        }
+
+        if let Some(name) = shadow::name() {
+            if let Err(cause) = self.update_shadow(app_dir, &name, outcome, reason).await {
+                warn!("Fails to update shadow {}: {}", name, cause);
+            }
+        }
+
+        if azure::enabled() {
+            let mut reported = self.state(app_dir, outcome, reason);
+            let desired = self.desired.lock().ok().and_then(|d| d.clone());
+
+            reported["compliant"] =
+                json!(desired.is_some() && reported["version"] == json!(desired));
+
+            let updated = match super::resolve_id(app_dir) {
+                Ok(device_id) => azure::report(&device_id, reported).await,
+                Err(cause) => Err(cause),
+            };
+
+            if let Err(cause) = updated {
+                warn!("Fails to update the device twin: {}", cause);
+            }
+        }
+
+        if let Err(cause) = self.update_job(app_dir, outcome, reason).await {
+            warn!("Fails to update the job: {}", cause);
+        }
+
+        self.notify(app_dir, outcome, reason).await
+    }
+
+    /// Notifies the webhook the update is applied or rolled back,
+    /// then the updated version quarantined (if so).
+    async fn notify(&self, app_dir: &Path, outcome: Outcome, reason: Option<&str>) {
+        let event = match outcome {
+            Outcome::Updated | Outcome::Rebooting => Some("update_applied"),
+            Outcome::RolledBack => Some("update_rolled_back"),
+            Outcome::NoUpdate | Outcome::Failed => None,
+        };
+
+        let thing_id = match super::resolve_id(app_dir) {
+            Ok(thing_id) => thing_id,
+            Err(cause) => {
+                warn!("Fails to notify the webhook: {}", cause);
+                return;
+            }
+        };
+
+        let version = Marker::load(app_dir).ok().flatten().map(|m| m.version);
+        let target = self.target.lock().ok().and_then(|t| t.clone());
+        let quarantined = self.quarantined.lock().ok().and_then(|mut q| q.take());
+
+        let notification = |event, reason| webhook::Notification {
+            event,
+            thing_id: &thing_id,
+            previous_version: &self.previous_version,
+            version: version.as_deref(),
+            update_version: target.as_deref(),
+            reason,
+        };
+
+        if let Some(event) = event {
+            webhook::notify(&notification(event, reason)).await;
+        }
+
+        if let Some(quarantined) = quarantined.as_deref() {
+            webhook::notify(&notification("version_quarantined", Some(quarantined))).await;
+        }
+    }
+
+    /// Submits the metrics of the run to DataDog.
+    async fn submit(&self, app_dir: &Path, outcome: Outcome) {
+        if !datadog::enabled() {
+            return;
+        }
+
+        let thing_id = match super::resolve_id(app_dir) {
+            Ok(thing_id) => thing_id,
+            Err(cause) => {
+                warn!("Fails to submit the metrics: {}", cause);
+                return;
+            }
+        };
+
+        let version = Marker::load(app_dir).ok().flatten().map(|m| m.version);
+        let target = self.target.lock().ok().and_then(|t| t.clone());
+
+        datadog::submit(&datadog::Run {
+            object_type: self.object_type,
+            thing_id: &thing_id,
+            version: version.as_deref(),
+            update_version: target.as_deref(),
+            outcome,
+            duration: self.started.elapsed().as_secs_f64(),
+            downloaded: self.downloaded.lock().ok().and_then(|mut d| d.take()),
+        })
+        .await
+    }
+
+    async fn update_shadow(
+        &self,
+        app_dir: &Path,
+        name: &str,
+        outcome: Outcome,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let reported = self.state(app_dir, outcome, reason);
+
+        shadow::update(&super::resolve_id(app_dir)?, name, reported).await
+    }
+
+    /// Returns the software state: installed version and update state,
+    /// with the reason as last error (kept until the next failure).
+    fn state(&self, app_dir: &Path, outcome: Outcome, reason: Option<&str>) -> Value {
+        let mut state = json!({
+            "version": Marker::load(app_dir).ok().flatten().map(|m| m.version),
+            "update_state": outcome,
+            "run_id": logging::run_id(),
+            "timestamp": Utc::now().to_rfc3339(),
+        });
+
+        if matches!(outcome, Outcome::RolledBack | Outcome::Failed) {
+            state["last_error"] = json!(reason);
+        }
+
+        state
+    }
+
+    /// Updates the status of the executed job: `SUCCEEDED` once its version is installed,
+    /// or `FAILED`.
+    async fn update_job(
+        &self,
+        app_dir: &Path,
+        outcome: Outcome,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let job_id = match self.job.lock().ok().and_then(|job| job.clone()) {
+            Some(job_id) => job_id,
+            None => return Ok(()),
+        };
+
+        let version = self.desired.lock().ok().and_then(|d| d.clone());
+
+        let installed = Marker::load(app_dir).ok().flatten().map(|m| m.version);
+        let status = match outcome {
+            Outcome::Updated | Outcome::Rebooting => "SUCCEEDED",
+            Outcome::NoUpdate if installed.is_some() && installed == version => "SUCCEEDED",
+            Outcome::NoUpdate => return Ok(()), // Still pending
+            Outcome::RolledBack | Outcome::Failed => "FAILED",
+        };
+
+        jobs::update(&super::resolve_id(app_dir)?, &job_id, status, reason).await
+    }
+
+    async fn post(
+        &self,
+        app_dir: &Path,
+        url: &str,
+        outcome: Outcome,
+        reason: Option<&str>,
+    ) -> Result<(), Error> {
+        let document = Document {
+            thing_id: super::resolve_id(app_dir)?,
+            previous_version: &self.previous_version,
+            version: Marker::load(app_dir).ok().flatten().map(|m| m.version),
+            result: outcome,
+            reason,
+            duration: self.started.elapsed().as_secs_f64(),
+            run_id: logging::run_id(),
+            timestamp: Utc::now().to_rfc3339(),
+        };
+
+        debug!("Reporting {:?} to {}", document, url);
+
+        let outbox = match Outbox::from_settings(&self.local_prefix) {
+            Some(outbox) => outbox,
+            None => return client::post_json(url, serde_json::to_vec(&document)?, timeout()).await,
+        };
+
+        let sent = match forward_queued(&outbox).await {
+            Ok(()) => client::post_json(url, serde_json::to_vec(&document)?, timeout()).await,
+            Err(cause) => Err(cause),
+        };
+
+        if let Err(cause) = sent {
+            warn!(
+                "Fails to report the update outcome to {} (queued): {}",
+                url, cause
+            );
+
+            let queued = json!({ "url": url, "document": document });
+
+            outbox.push(KIND, &serde_json::to_vec(&queued)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn timeout() -> Duration {
+    Duration::from_secs(config::parse_or(
+        "ORM_REPORT_TIMEOUT",
+        setting!("ORM_REPORT_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ))
+}
+
+/// Periodically forwards the reports queued in the outbox (if enabled) while offline
+/// (see `outbox::retry_interval`).
+pub async fn forward(local_prefix: PathBuf) {
+    let outbox = match Outbox::from_settings(&local_prefix) {
+        Some(outbox) => outbox,
+        None => return,
+    };
+
+    loop {
+        if let Err(cause) = forward_queued(&outbox).await {
+            debug!("Fails to forward the queued reports: {}", cause);
+        }
+
+        tokio::time::sleep(outbox::retry_interval()).await;
+    }
+}
+
+/// Posts the queued reports, oldest first, until one fails.
+async fn forward_queued(outbox: &Outbox) -> Result<(), Error> {
+    while let Some(entry) = outbox.next(KIND)? {
+        let queued: Value = serde_json::from_slice(&entry.payload)?;
+        let url = queued["url"].as_str().unwrap_or_default();
+
+        debug!("Forwarding the queued report to {}", url);
+
+        client::post_json(url, serde_json::to_vec(&queued["document"])?, timeout()).await?;
+        outbox.remove(entry)?;
+    }
+
+    Ok(())
+}
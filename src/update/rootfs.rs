@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use log::{debug, info, warn};
+
+use crate::config;
+use crate::setting;
+
+/// Number of the active `Writable` guards.
+static WRITABLE: Mutex<usize> = Mutex::new(0);
+
+/// Whether the partition of the application is read-only at runtime (`ORM_READ_ONLY`),
+/// so it's remounted read-write only to install the update.
+pub fn read_only() -> bool {
+    config::parse_or("ORM_READ_ONLY", setting!("ORM_READ_ONLY"), false)
+}
+
+/// Partition of the application remounted read-write, until dropped.
+#[derive(Debug)]
+pub struct Writable {
+    mount_point: PathBuf,
+}
+
+/// Remounts the partition of the local prefix read-write, if read-only at runtime
+/// (nested guards sharing the same remount).
+pub fn writable(local_prefix: &Path) -> std::io::Result<Option<Writable>> {
+    if !read_only() {
+        return Ok(None);
+    }
+
+    let mount_point = mount_point(local_prefix)?;
+    let mut count = WRITABLE
+        .lock()
+        .map_err(|_| std::io::Error::other("Poisoned lock"))?;
+
+    if *count == 0 {
+        info!("Remounting {:?} read-write", mount_point);
+
+        remount(&mount_point, "rw")?;
+    }
+
+    *count += 1;
+
+    Ok(Some(Writable { mount_point }))
+}
+
+impl Drop for Writable {
+    fn drop(&mut self) {
+        let mut count = match WRITABLE.lock() {
+            Ok(count) => count,
+            Err(_) => return,
+        };
+
+        *count = count.saturating_sub(1);
+
+        if *count > 0 {
+            return;
+        }
+
+        info!("Remounting {:?} read-only", self.mount_point);
+
+        if let Err(cause) = remount(&self.mount_point, "ro") {
+            warn!(
+                "Fails to remount {:?} read-only: {}",
+                self.mount_point, cause
+            );
+        }
+    }
+}
+
+/// Remounts the partition either with the `ORM_REMOUNT_HOOK` command
+/// (given the mode, `rw` or `ro`, and the mount point), or with `mount -o remount`.
+fn remount(mount_point: &Path, mode: &str) -> std::io::Result<()> {
+    let status = match setting!("ORM_REMOUNT_HOOK") {
+        Some(hook) => Command::new(hook).arg(mode).arg(mount_point).status()?,
+        None => Command::new("mount")
+            .arg("-o")
+            .arg(format!("remount,{}", mode))
+            .arg(mount_point)
+            .status()?,
+    };
+
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "Fails to remount {:?} ({}): {}",
+            mount_point, mode, status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Resolves the mount point of the application partition
+/// (`ORM_READ_ONLY_MOUNT`, default: the one containing the local prefix).
+fn mount_point(local_prefix: &Path) -> std::io::Result<PathBuf> {
+    if let Some(path) = setting!("ORM_READ_ONLY_MOUNT") {
+        return Ok(PathBuf::from(path));
+    }
+
+    let prefix = fs::canonicalize(local_prefix)?;
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+
+    let found = mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|dir| PathBuf::from(dir.replace("\\040", " ")))
+        .filter(|dir| prefix.starts_with(dir))
+        .max_by_key(|dir| dir.components().count())
+        .unwrap_or_else(|| PathBuf::from("/"));
+
+    debug!("Mount point of {:?} = {:?}", local_prefix, found);
+
+    Ok(found)
+}
+
+/// Checks the directory is writable, creating (then removing) a probe file.
+pub fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(".orm_writable");
+
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| std::io::Error::new(err.kind(), format!("{:?} not writable: {}", dir, err)))
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_writable() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        assert!(check_writable(tmp.path()).is_ok());
+        assert!(!tmp.path().join(".orm_writable").exists());
+        assert!(check_writable(&tmp.path().join("missing")).is_err());
+    }
+}
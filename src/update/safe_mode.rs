@@ -0,0 +1,244 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, error, warn};
+
+use serde::{Deserialize, Serialize};
+
+use super::marker::Marker;
+use super::quarantine;
+use crate::config;
+use crate::error;
+use crate::io::write_atomic;
+use crate::setting;
+use error::Error;
+
+/// Default window (in seconds) an exit of the updated application is considered as a crash.
+const DEFAULT_WINDOW: u64 = 60;
+
+/// Runs of the installed version (`.orm_runs`), to detect a crash loop.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Runs {
+    version: String,
+
+    /// Consecutive exits within the crash window.
+    crashes: u32,
+
+    /// Whether the version once ran longer than the window (no longer tracked).
+    stable: bool,
+}
+
+/// Crash loop policy (`ORM_CRASH_LOOP_STARTS`, `ORM_CRASH_WINDOW`).
+#[derive(Debug)]
+pub struct Policy {
+    /// Consecutive crashes after which the update is rolled back (`0` to disable).
+    pub max_starts: u32,
+    pub window: Duration,
+}
+
+impl Policy {
+    pub fn from_settings() -> Policy {
+        Policy {
+            max_starts: config::parse_or(
+                "ORM_CRASH_LOOP_STARTS",
+                setting!("ORM_CRASH_LOOP_STARTS"),
+                0,
+            ),
+            window: Duration::from_secs(config::parse_or(
+                "ORM_CRASH_WINDOW",
+                setting!("ORM_CRASH_WINDOW"),
+                DEFAULT_WINDOW,
+            )),
+        }
+    }
+}
+
+fn runs_path(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_runs")
+}
+
+fn safe_mode_path(local_prefix: &Path) -> PathBuf {
+    config::state_dir(local_prefix).join(".orm_safe_mode")
+}
+
+/// Records a run of the updated version, returning whether it's crash looping.
+fn record(
+    local_prefix: &Path,
+    policy: &Policy,
+    version: &str,
+    uptime: Duration,
+) -> Result<bool, Error> {
+    let path = runs_path(local_prefix);
+
+    let mut runs = match fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice::<Runs>(&bytes).unwrap_or_default(),
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => Runs::default(),
+        Err(cause) => return Err(Error::from(cause)),
+    };
+
+    if runs.version != version {
+        runs = Runs {
+            version: version.to_string(),
+            ..Runs::default()
+        };
+    }
+
+    if runs.stable {
+        return Ok(false);
+    }
+
+    if uptime < policy.window {
+        runs.crashes += 1;
+    } else {
+        runs.stable = true;
+    }
+
+    debug!("Runs of version {} = {:?}", version, runs);
+
+    write_atomic(&path, &serde_json::to_vec(&runs)?)?;
+
+    Ok(runs.crashes >= policy.max_starts)
+}
+
+/// Checks the run of the installed application (terminated after the given uptime):
+/// if the updated version is crash looping, it's marked as failed and rolled back,
+/// entering the safe mode where the updates to this version are refused.
+pub fn check<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    uptime: Duration,
+) -> Result<(), Error> {
+    let policy = Policy::from_settings();
+
+    if policy.max_starts == 0 {
+        return Ok(());
+    }
+
+    let marker = match Marker::load(app_dir)? {
+        Some(marker) if marker.previous.is_some() => marker, // Installed by an update
+        _ => return Ok(()),
+    };
+
+    if !record(local_prefix, &policy, &marker.version, uptime)? {
+        return Ok(());
+    }
+
+    error!(
+        "Version {} crashed in the first {:?} of {} consecutive runs; Entering safe mode",
+        marker.version, policy.window, policy.max_starts
+    );
+
+    enter(app_name, local_prefix, app_dir, &marker.version)
+}
+
+/// Rolls back the supervised application crash looping (`ORM_RESTART_LOOP_ROLLBACK`),
+/// if installed by an update, returning whether it's rolled back.
+pub fn crash_loop<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+) -> Result<bool, Error> {
+    if !config::parse_or(
+        "ORM_RESTART_LOOP_ROLLBACK",
+        setting!("ORM_RESTART_LOOP_ROLLBACK"),
+        false,
+    ) {
+        return Ok(false);
+    }
+
+    let marker = match Marker::load(app_dir)? {
+        Some(marker) if marker.previous.is_some() => marker,
+        _ => return Ok(false),
+    };
+
+    enter(app_name, local_prefix, app_dir, &marker.version)?;
+
+    Ok(true)
+}
+
+/// Enters the safe mode: the version is marked as failed and rolled back.
+fn enter<'x>(
+    app_name: &'static str,
+    local_prefix: &'x Path,
+    app_dir: &'x Path,
+    version: &'x str,
+) -> Result<(), Error> {
+    quarantine::add(
+        &config::state_dir(local_prefix).join(".orm_failed"),
+        version,
+    )?;
+    write_atomic(&safe_mode_path(local_prefix), version.as_bytes())?;
+
+    let restored = super::rollback(app_name, local_prefix, app_dir)?;
+
+    warn!("Rolled back to version {}", restored);
+
+    // Not tracked, so it's not rolled back in turn
+    let runs = Runs {
+        version: restored.to_string(),
+        crashes: 0,
+        stable: true,
+    };
+
+    write_atomic(&runs_path(local_prefix), &serde_json::to_vec(&runs)?)?;
+
+    Ok(())
+}
+
+/// Checks whether the update to the version is refused by the safe mode,
+/// which is left once the manifest declares a different version.
+pub fn refuses(local_prefix: &Path, version: &semver::Version) -> Result<bool, Error> {
+    let path = safe_mode_path(local_prefix);
+
+    let refused = match fs::read_to_string(&path) {
+        Ok(content) => content.trim().to_string(),
+        Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(cause) => return Err(Error::from(cause)),
+    };
+
+    if semver::Version::parse(&refused).ok().as_ref() == Some(version) {
+        return Ok(true);
+    }
+
+    warn!("Leaving safe mode (version {} refused)", refused);
+
+    fs::remove_file(&path)?;
+
+    Ok(false)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let tmp = tempfile::tempdir().unwrap();
+        let policy = Policy {
+            max_starts: 2,
+            window: Duration::from_secs(60),
+        };
+        let crash = Duration::from_secs(1);
+
+        assert!(!record(tmp.path(), &policy, "2.0.0", crash).unwrap());
+        assert!(record(tmp.path(), &policy, "2.0.0", crash).unwrap());
+
+        // Once stable, no longer tracked
+        assert!(!record(tmp.path(), &policy, "3.0.0", crash).unwrap());
+        assert!(!record(tmp.path(), &policy, "3.0.0", Duration::from_secs(90)).unwrap());
+        assert!(!record(tmp.path(), &policy, "3.0.0", crash).unwrap());
+        assert!(!record(tmp.path(), &policy, "3.0.0", crash).unwrap());
+
+        let version = semver::Version::new(2, 0, 0);
+
+        write_atomic(&safe_mode_path(tmp.path()), b"2.0.0").unwrap();
+
+        assert!(refuses(tmp.path(), &version).unwrap());
+        assert!(!refuses(tmp.path(), &semver::Version::new(2, 0, 1)).unwrap());
+        assert!(!refuses(tmp.path(), &version).unwrap()); // Left
+    }
+}
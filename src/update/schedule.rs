@@ -0,0 +1,191 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use log::{debug, warn};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::error;
+use error::Error;
+
+/// Scheduling hints from the manifest server,
+/// persisted across the runs in `.orm_schedule`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Schedule {
+    /// Timestamp (seconds) before which the manifest must not be requested
+    /// (from `Retry-After`).
+    retry_after: Option<i64>,
+
+    /// Timestamp (seconds) until which the cached manifest is fresh
+    /// (from `Cache-Control: max-age`).
+    manifest_expires: Option<i64>,
+}
+
+impl Schedule {
+    fn path(local_prefix: &Path) -> PathBuf {
+        config::state_dir(local_prefix).join(".orm_schedule")
+    }
+
+    fn cache_path(local_prefix: &Path) -> PathBuf {
+        config::state_dir(local_prefix).join(".orm_manifest")
+    }
+
+    /// Loads the schedule, or returns an empty one if missing or invalid.
+    pub fn load(local_prefix: &Path) -> Schedule {
+        let path = Schedule::path(local_prefix);
+
+        if !path.is_file() {
+            return Schedule::default();
+        }
+
+        match fs::read(&path)
+            .map_err(Error::from)
+            .and_then(|bytes| serde_json::from_slice::<Schedule>(&bytes).map_err(Error::from))
+        {
+            Ok(schedule) => schedule,
+            Err(cause) => {
+                warn!("Ignoring invalid schedule {:?}: {}", path, cause);
+
+                Schedule::default()
+            }
+        }
+    }
+
+    fn save(&self, local_prefix: &Path) -> Result<(), Error> {
+        fs::write(Schedule::path(local_prefix), serde_json::to_vec(self)?)?;
+
+        Ok(())
+    }
+
+    /// Returns the time before which the manifest must not be requested,
+    /// if still in the future.
+    pub fn deferred_until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.retry_after
+            .filter(|ts| *ts > now.timestamp())
+            .map(|ts| Utc.timestamp_opt(ts, 0).unwrap())
+    }
+
+    /// Returns the cached manifest, if still fresh.
+    pub fn cached_manifest(&self, local_prefix: &Path, now: DateTime<Utc>) -> Option<Vec<u8>> {
+        let expires = self.manifest_expires.filter(|ts| *ts > now.timestamp())?;
+
+        debug!(
+            "Cached manifest fresh until {}",
+            Utc.timestamp_opt(expires, 0).unwrap()
+        );
+
+        fs::read(Schedule::cache_path(local_prefix)).ok()
+    }
+
+    /// Records that the server asked to retry after the given delay.
+    pub fn retry_after(
+        local_prefix: &Path,
+        now: DateTime<Utc>,
+        delay: Duration,
+    ) -> Result<(), Error> {
+        let mut schedule = Schedule::load(local_prefix);
+
+        schedule.retry_after = Some(now.timestamp() + delay.as_secs() as i64);
+
+        schedule.save(local_prefix)
+    }
+
+    /// Records the successfully fetched manifest,
+    /// cached if the server indicated a `max-age`.
+    pub fn fetched(
+        local_prefix: &Path,
+        now: DateTime<Utc>,
+        manifest: &[u8],
+        max_age: Option<Duration>,
+    ) -> Result<(), Error> {
+        let schedule = Schedule {
+            retry_after: None,
+            manifest_expires: max_age.map(|age| now.timestamp() + age.as_secs() as i64),
+        };
+
+        let cache_path = Schedule::cache_path(local_prefix);
+
+        if schedule.manifest_expires.is_some() {
+            fs::write(&cache_path, manifest)?;
+        } else if cache_path.is_file() {
+            fs::remove_file(&cache_path)?;
+        }
+
+        schedule.save(local_prefix)
+    }
+}
+
+/// Parses a `Retry-After` header value, either as delay in seconds
+/// or as HTTP date.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| (date.timestamp() - now.timestamp()).max(0) as u64)
+        .map(Duration::from_secs)
+}
+
+/// Parses the `max-age` from a `Cache-Control` header value,
+/// unless the response must not be cached.
+pub fn parse_max_age(value: &str) -> Option<Duration> {
+    let directives: Vec<String> = value.split(',').map(|d| d.trim().to_lowercase()).collect();
+
+    if directives
+        .iter()
+        .any(|d| d == "no-store" || d == "no-cache")
+    {
+        return None;
+    }
+
+    directives
+        .iter()
+        .find_map(|d| d.strip_prefix("max-age="))
+        .and_then(|secs| secs.trim_matches('"').parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 0, 0).unwrap();
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(28 * 60))
+        );
+
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_parse_max_age() {
+        assert_eq!(
+            parse_max_age("public, max-age=3600"),
+            Some(Duration::from_secs(3600))
+        );
+
+        assert_eq!(parse_max_age("no-cache, max-age=3600"), None);
+        assert_eq!(parse_max_age("max-age=0"), None);
+        assert_eq!(parse_max_age("private"), None);
+    }
+}
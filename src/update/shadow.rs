@@ -0,0 +1,27 @@
+use log::debug;
+
+use serde_json::{json, Value};
+
+use crate::error;
+use crate::mqtt;
+use crate::setting;
+use error::Error;
+
+/// Returns the name of the AWS IoT shadow the software state is reported in
+/// (`ORM_SHADOW_NAME`), if enabled.
+pub fn name() -> Option<String> {
+    setting!("ORM_SHADOW_NAME")
+}
+
+/// Updates the reported state of the named shadow of the thing
+/// (the omitted properties being kept).
+pub async fn update(thing_id: &str, name: &str, reported: Value) -> Result<(), Error> {
+    let topic = format!("$aws/things/{}/shadow/name/{}/update", thing_id, name);
+    let payload = json!({ "state": { "reported": reported } });
+
+    debug!("Updating shadow {}: {}", name, payload);
+
+    mqtt::request(thing_id, &topic, serde_json::to_vec(&payload)?)
+        .await
+        .map(|_| ())
+}
@@ -0,0 +1,208 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use log::debug;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use blake2::{Blake2b512, Digest};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Public key to verify the [minisign](https://jedisct1.github.io/minisign/) signatures.
+pub struct PublicKey {
+    key_id: [u8; 8],
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// Parses the public key, either the base64 line or the whole `minisign.pub` content.
+    pub fn parse(repr: &str) -> Result<PublicKey, Error> {
+        let line = repr
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty() && !l.starts_with("untrusted comment:"))
+            .ok_or_else(|| Error::new("Empty public key".to_string()))?;
+
+        let bytes = decode(line)?;
+
+        if bytes.len() != 42 || &bytes[..2] != b"Ed" {
+            return Err(format_error!("Invalid minisign public key: {}", line));
+        }
+
+        let mut key_id = [0u8; 8];
+        let mut key = [0u8; 32];
+
+        key_id.copy_from_slice(&bytes[2..10]);
+        key.copy_from_slice(&bytes[10..42]);
+
+        Ok(PublicKey {
+            key_id,
+            key: VerifyingKey::from_bytes(&key)
+                .map_err(|err| format_error!("Invalid public key: {}", err))?,
+        })
+    }
+}
+
+/// Resolves the public key from `ORM_SIGNATURE_PUBLIC_KEY`,
+/// or from the file at `ORM_SIGNATURE_PUBLIC_KEY_FILE`.
+pub fn public_key() -> Result<Option<PublicKey>, Error> {
+    let repr = match setting!("ORM_SIGNATURE_PUBLIC_KEY") {
+        Some(key) => key,
+        None => match setting!("ORM_SIGNATURE_PUBLIC_KEY_FILE") {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => return Ok(None),
+        },
+    };
+
+    PublicKey::parse(&repr).map(Some)
+}
+
+/// Verifies the minisign signature of the file (or content),
+/// either prehashed (`ED`, BLAKE2b-512) or legacy (`Ed`).
+pub fn verify<R: Read + Seek>(key: &PublicKey, signature: &str, file: &mut R) -> Result<(), Error> {
+    let mut lines = signature.lines().map(|l| l.trim_end_matches('\r'));
+
+    let (sig_line, trusted_comment, global_line) =
+        match (lines.next(), lines.next(), lines.next(), lines.next()) {
+            (Some(c), Some(s), Some(t), Some(g)) if c.starts_with("untrusted comment:") => {
+                match t.strip_prefix("trusted comment: ") {
+                    Some(comment) => (s, comment, g),
+                    None => return Err(Error::new("Missing trusted comment".to_string())),
+                }
+            }
+            _ => return Err(Error::new("Invalid minisign signature".to_string())),
+        };
+
+    let sig_bytes = decode(sig_line)?;
+
+    if sig_bytes.len() != 74 {
+        return Err(format_error!(
+            "Invalid minisign signature length: {}",
+            sig_bytes.len()
+        ));
+    }
+
+    if sig_bytes[2..10] != key.key_id {
+        return Err(format_error!(
+            "Signature key ID {} doesn't match the public key {}",
+            crate::io::hex(&sig_bytes[2..10]),
+            crate::io::hex(&key.key_id)
+        ));
+    }
+
+    let sig = signature_from(&sig_bytes[10..74])?;
+
+    file.seek(SeekFrom::Start(0))?;
+
+    let verified = match &sig_bytes[..2] {
+        b"ED" => {
+            let mut hasher = Blake2b512::new();
+
+            std::io::copy(file, &mut hasher)?;
+
+            key.key.verify_strict(&hasher.finalize(), &sig)
+        }
+        b"Ed" => {
+            let mut content = Vec::new();
+
+            file.read_to_end(&mut content)?;
+
+            key.key.verify_strict(&content, &sig)
+        }
+        alg => {
+            return Err(format_error!(
+                "Unsupported signature algorithm: {:?}",
+                String::from_utf8_lossy(alg)
+            ))
+        }
+    };
+
+    file.seek(SeekFrom::Start(0))?;
+
+    verified.map_err(|err| format_error!("Invalid signature: {}", err))?;
+
+    // Global signature, covering the trusted comment
+    let global = signature_from(&decode(global_line)?)?;
+    let mut signed = sig_bytes[10..74].to_vec();
+
+    signed.extend_from_slice(trusted_comment.as_bytes());
+
+    key.key
+        .verify_strict(&signed, &global)
+        .map_err(|err| format_error!("Invalid trusted comment signature: {}", err))?;
+
+    debug!("Signature verified ({})", trusted_comment);
+
+    Ok(())
+}
+
+fn signature_from(bytes: &[u8]) -> Result<Signature, Error> {
+    Signature::from_slice(bytes).map_err(|err| format_error!("Invalid signature: {}", err))
+}
+
+fn decode(repr: &str) -> Result<Vec<u8>, Error> {
+    BASE64
+        .decode(repr.trim())
+        .map_err(|err| format_error!("Invalid base64 {}: {}", repr, err))
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    #[test]
+    fn test_verify() {
+        let signing = SigningKey::from_bytes(&[7u8; 32]);
+        let key_id = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let mut pk = b"Ed".to_vec();
+        pk.extend_from_slice(&key_id);
+        pk.extend_from_slice(signing.verifying_key().as_bytes());
+
+        let key = PublicKey::parse(&format!(
+            "untrusted comment: minisign public key\n{}\n",
+            BASE64.encode(&pk)
+        ))
+        .unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"archive content").unwrap();
+
+        let sign = |content: &[u8]| {
+            let sig = signing.sign(&Blake2b512::digest(content)).to_bytes();
+
+            let mut sig_bytes = b"ED".to_vec();
+            sig_bytes.extend_from_slice(&key_id);
+            sig_bytes.extend_from_slice(&sig);
+
+            let comment = "timestamp:0\tfile:foo-1.0.0.tar.gz";
+            let mut global = sig.to_vec();
+            global.extend_from_slice(comment.as_bytes());
+
+            format!(
+                "untrusted comment: signature\n{}\ntrusted comment: {}\n{}\n",
+                BASE64.encode(&sig_bytes),
+                comment,
+                BASE64.encode(signing.sign(&global).to_bytes())
+            )
+        };
+
+        assert!(verify(&key, &sign(b"archive content"), &mut file).is_ok());
+        assert!(verify(&key, &sign(b"tampered content"), &mut file).is_err());
+
+        let mut content = std::io::Cursor::new(b"archive content");
+
+        assert!(verify(&key, &sign(b"archive content"), &mut content).is_ok());
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::info;
+
+use super::rootfs;
+use crate::config;
+use crate::setting;
+
+/// Layout of the application directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// The application directory is swapped with the updated one.
+    #[default]
+    Rename,
+
+    /// The application directory is a symlink to either the `slot_a` or `slot_b` directory,
+    /// flipped to the inactive one where the update is installed.
+    Slots,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(repr: &str) -> Result<Layout, String> {
+        match repr {
+            "rename" => Ok(Layout::Rename),
+            "slots" => Ok(Layout::Slots),
+            _ => Err(format!("Unsupported layout: {}", repr)),
+        }
+    }
+}
+
+/// Returns the layout of the application directory (`ORM_LAYOUT`).
+pub fn layout() -> Layout {
+    config::parse_or("ORM_LAYOUT", setting!("ORM_LAYOUT"), Layout::default())
+}
+
+fn slot(app_dir: &Path, name: &str) -> PathBuf {
+    let app_name = app_dir.file_name().unwrap_or_default().to_string_lossy();
+
+    app_dir.with_file_name(format!("{}.{}", app_name, name))
+}
+
+/// Converts the application directory to the slots layout, if not yet:
+/// it's moved to the `slot_a` one, the symlink taking its place.
+pub fn ensure(app_dir: &Path) -> std::io::Result<()> {
+    let link = temporary_link(app_dir);
+    let _writable;
+
+    match fs::symlink_metadata(app_dir) {
+        Ok(metadata) if metadata.file_type().is_symlink() => return Ok(()),
+        Ok(_) => {
+            let slot_a = slot(app_dir, "slot_a");
+
+            info!("Converting {:?} to the slots layout", app_dir);
+
+            _writable = rootfs::writable(app_dir.parent().unwrap_or(Path::new(".")))?;
+
+            fs::rename(app_dir, &slot_a)?;
+            create_link(&link, &slot_a)?;
+        }
+        Err(cause) if cause.kind() != std::io::ErrorKind::NotFound => return Err(cause),
+        Err(_) if fs::symlink_metadata(&link).is_err() => return Ok(()),
+        Err(_) => (), // Interrupted conversion
+    }
+
+    fs::rename(&link, app_dir)?;
+
+    sync_parent(app_dir)
+}
+
+/// Returns the active slot, the application directory links to.
+pub fn active(app_dir: &Path) -> std::io::Result<PathBuf> {
+    let target = fs::read_link(app_dir)?;
+
+    Ok(match app_dir.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    })
+}
+
+/// Returns the inactive slot, to install the update into.
+pub fn inactive(app_dir: &Path) -> std::io::Result<PathBuf> {
+    let slot_a = slot(app_dir, "slot_a");
+
+    if active(app_dir)? == slot_a {
+        Ok(slot(app_dir, "slot_b"))
+    } else {
+        Ok(slot_a)
+    }
+}
+
+/// Atomically links the application directory to the given slot.
+pub fn point(app_dir: &Path, slot: &Path) -> std::io::Result<()> {
+    let link = temporary_link(app_dir);
+
+    create_link(&link, slot)?;
+    fs::rename(&link, app_dir)?;
+
+    sync_parent(app_dir)
+}
+
+fn temporary_link(app_dir: &Path) -> PathBuf {
+    let app_name = app_dir.file_name().unwrap_or_default().to_string_lossy();
+
+    app_dir.with_file_name(format!(".{}.link", app_name))
+}
+
+fn create_link(link: &Path, slot: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(link).is_ok() {
+        fs::remove_file(link)?;
+    }
+
+    // Relative to the local prefix
+    symlink(slot.file_name().unwrap_or_default(), link)
+}
+
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) => fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point() {
+        let tmp = tempfile::tempdir().unwrap();
+        let app_dir = tmp.path().join("foo");
+
+        fs::create_dir(&app_dir).unwrap();
+        fs::write(app_dir.join("run.sh"), "1.0.0").unwrap();
+
+        ensure(&app_dir).unwrap();
+        ensure(&app_dir).unwrap(); // Already converted
+
+        let slot_a = tmp.path().join("foo.slot_a");
+
+        assert_eq!(active(&app_dir).unwrap(), slot_a);
+        assert_eq!(fs::read_to_string(app_dir.join("run.sh")).unwrap(), "1.0.0");
+
+        let slot_b = inactive(&app_dir).unwrap();
+
+        assert_eq!(slot_b, tmp.path().join("foo.slot_b"));
+
+        fs::create_dir(&slot_b).unwrap();
+        fs::write(slot_b.join("run.sh"), "2.0.0").unwrap();
+
+        point(&app_dir, &slot_b).unwrap();
+
+        assert_eq!(fs::read_to_string(app_dir.join("run.sh")).unwrap(), "2.0.0");
+        assert_eq!(inactive(&app_dir).unwrap(), slot_a);
+        assert_eq!(fs::read_link(&app_dir).unwrap(), Path::new("foo.slot_b"));
+    }
+}
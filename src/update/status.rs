@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use chrono::Utc;
+
+use log::{debug, warn};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::marker::Marker;
+use crate::config;
+use crate::error;
+use crate::logging;
+use crate::mqtt;
+use crate::process;
+use crate::process::monitor::Usage;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Application directory the lifecycle events are reported for, once enabled.
+static LIFECYCLE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Queue of the lifecycle events, published in order by a single task (see `publish_queued`).
+static QUEUE: OnceLock<UnboundedSender<Event>> = OnceLock::new();
+
+/// Status event of the device, reported to the backend.
+#[derive(Debug, Serialize)]
+pub struct Event {
+    event: &'static str,
+
+    /// Either `info`, `warning` or `critical`.
+    severity: &'static str,
+
+    message: String,
+
+    /// Installed version of the application.
+    version: Option<String>,
+
+    run_id: String,
+    timestamp: String,
+
+    /// Resource usage of the application, for a `usage` event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+impl Event {
+    pub fn new(
+        app_dir: &Path,
+        event: &'static str,
+        severity: &'static str,
+        message: String,
+    ) -> Event {
+        Event {
+            event,
+            severity,
+            message,
+            version: Marker::load(app_dir).ok().flatten().map(|m| m.version),
+            run_id: logging::run_id(),
+            timestamp: Utc::now().to_rfc3339(),
+            usage: None,
+        }
+    }
+
+    pub fn with_usage(self, usage: Usage) -> Event {
+        Event {
+            usage: Some(usage),
+            ..self
+        }
+    }
+}
+
+/// Reports the event on the `ORM_MQTT_STATUS_TOPIC`, if defined;
+/// A failed report is only logged.
+pub async fn report(app_dir: &Path, event: &Event) {
+    let topic = match setting!("ORM_MQTT_STATUS_TOPIC") {
+        Some(topic) => topic,
+        None => return,
+    };
+
+    if let Err(cause) = publish(app_dir, &topic, event).await {
+        warn!("Fails to report the {} event: {}", event.event, cause);
+    }
+}
+
+/// Returns the current status of the updater, as JSON document: current `version`,
+/// staged `pending_version`, whether the application is `running`, whether an update is
+/// in progress (`updating`) and its `update_step` (see `journal::Step`), since when
+/// the updates are `held` (if so), the ID of the last update run (`run_id`),
+/// and the `metrics` of the update runs (see `metrics::Counters`).
+pub fn current(app_name: &str, local_prefix: &Path) -> Value {
+    json!({
+        "version": Marker::load(&local_prefix.join(app_name)).ok().flatten().map(|m| m.version),
+        "pending_version": super::pending::version(app_name, local_prefix),
+        "running": process::is_running(),
+        "updating": super::updating(),
+        "update_step": super::journal::Journal::step(local_prefix),
+        "held": super::hold::since(local_prefix),
+        "run_id": Some(logging::run_id()).filter(|id| !id.is_empty()),
+        "metrics": super::metrics::load(local_prefix),
+    })
+}
+
+/// Enables the lifecycle events of the application (if `ORM_MQTT_LIFECYCLE`):
+/// update check started, download progress, update applied, rollback, application crashed.
+pub fn enable_lifecycle(app_dir: &Path) {
+    if !config::parse_or("ORM_MQTT_LIFECYCLE", setting!("ORM_MQTT_LIFECYCLE"), false)
+        || LIFECYCLE.set(app_dir.to_path_buf()).is_err()
+    {
+        return;
+    }
+
+    let (sender, receiver) = unbounded_channel();
+
+    let _ = QUEUE.set(sender);
+
+    tokio::spawn(publish_queued(app_dir.to_path_buf(), receiver));
+}
+
+/// Queues the lifecycle event (if enabled), not waiting for it to be reported.
+pub fn lifecycle(event: &'static str, severity: &'static str, message: String) {
+    if let (Some(app_dir), Some(queue)) = (LIFECYCLE.get(), QUEUE.get()) {
+        let _ = queue.send(Event::new(app_dir, event, severity, message));
+    }
+}
+
+/// Reports the queued events on the `ORM_MQTT_STATUS_TOPIC` (if defined),
+/// over a single connection (opened for the first one).
+async fn publish_queued(app_dir: PathBuf, mut queue: UnboundedReceiver<Event>) {
+    let topic = match setting!("ORM_MQTT_STATUS_TOPIC") {
+        Some(topic) => topic,
+        None => return,
+    };
+
+    let mut connection: Option<(mqtt::Publisher, String)> = None;
+
+    while let Some(event) = queue.recv().await {
+        if connection.is_none() {
+            connection = match connect(&app_dir, &topic) {
+                Ok(connected) => Some(connected),
+                Err(cause) => {
+                    warn!("Fails to report the {} event: {}", event.event, cause);
+                    continue;
+                }
+            };
+        }
+
+        if let Some((publisher, topic)) = &connection {
+            debug!("Reporting {:?} on MQTT topic '{}'", event, topic);
+
+            let published = match serde_json::to_vec(&event) {
+                Ok(payload) => publisher.publish(topic, payload).await,
+                Err(cause) => Err(Error::from(cause)),
+            };
+
+            if let Err(cause) = published {
+                warn!("Fails to report the {} event: {}", event.event, cause);
+            }
+        }
+    }
+}
+
+/// Connects the publisher of the lifecycle events, returning it with the resolved topic.
+fn connect(app_dir: &Path, topic: &str) -> Result<(mqtt::Publisher, String), Error> {
+    let thing_id = super::resolve_id(app_dir)?;
+    let topic = mqtt::topic(topic, &thing_id);
+
+    // Distinct client ID, not to disconnect the other connections of the thing
+    match mqtt::options(&format!("{}-status", thing_id))? {
+        Some(options) => Ok((mqtt::Publisher::connect(options), topic)),
+        None => Err(format_error!("Missing ORM_MQTT_URL for topic {}", topic)),
+    }
+}
+
+async fn publish(app_dir: &Path, topic: &str, event: &Event) -> Result<(), Error> {
+    let thing_id = super::resolve_id(app_dir)?;
+    let topic = mqtt::topic(topic, &thing_id);
+
+    let options = match mqtt::options(&thing_id)? {
+        Some(options) => options,
+        None => return Err(format_error!("Missing ORM_MQTT_URL for topic {}", topic)),
+    };
+
+    debug!("Reporting {:?} on MQTT topic '{}'", event, topic);
+
+    mqtt::publish(options, &topic, serde_json::to_vec(event)?).await
+}
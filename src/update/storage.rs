@@ -0,0 +1,113 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use log::debug;
+
+use crate::config;
+use crate::error;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Storage required to extract an archive.
+#[derive(Debug)]
+pub struct Usage {
+    pub entries: u64,
+    pub size: u64,
+}
+
+/// Storage available on a filesystem.
+#[derive(Debug)]
+struct Available {
+    block_size: u64,
+    bytes: u64,
+
+    /// Free inodes, if the filesystem has a fixed inode table.
+    inodes: Option<u64>,
+}
+
+/// Whether the storage is checked before extraction (`ORM_STORAGE_CHECK`).
+pub fn enabled() -> bool {
+    config::parse_or("ORM_STORAGE_CHECK", setting!("ORM_STORAGE_CHECK"), true)
+}
+
+/// Checks the filesystem at given path has enough free space and inodes.
+pub fn check(path: &Path, usage: &Usage) -> Result<(), Error> {
+    let available = available(path)?;
+
+    debug!(
+        "Storage required = {:?}, available = {:?}",
+        usage, available
+    );
+
+    // Each entry wastes at most a block (or takes one for a directory)
+    let bytes = usage.size + usage.entries * available.block_size;
+
+    if bytes > available.bytes {
+        return Err(format_error!(
+            "Not enough space on {:?}: {} bytes required, {} available",
+            path,
+            bytes,
+            available.bytes
+        ));
+    }
+
+    match available.inodes {
+        Some(inodes) if usage.entries > inodes => Err(format_error!(
+            "Not enough inodes on {:?}: {} required, {} available",
+            path,
+            usage.entries,
+            inodes
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn available(path: &Path) -> Result<Available, Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| format_error!("Invalid path {:?}: {}", path, err))?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::from(std::io::Error::last_os_error()));
+    }
+
+    let block_size = stat.f_frsize as u64;
+
+    Ok(Available {
+        block_size,
+        bytes: stat.f_bavail as u64 * block_size,
+        inodes: if stat.f_files == 0 {
+            None
+        } else {
+            Some(stat.f_favail as u64)
+        },
+    })
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        let small = Usage {
+            entries: 3,
+            size: 1024,
+        };
+
+        assert!(check(tmp.path(), &small).is_ok());
+
+        let huge = Usage {
+            entries: 3,
+            size: u64::MAX / 2,
+        };
+
+        assert!(check(tmp.path(), &huge).is_err());
+    }
+}
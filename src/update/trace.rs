@@ -0,0 +1,310 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use serde_json::{json, Value};
+
+use super::client;
+use super::metrics;
+use crate::config;
+use crate::io::hex;
+use crate::logging;
+use crate::setting;
+
+/// Default duration (in seconds) the collector has to accept the spans.
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Default `service.name` of the spans.
+const DEFAULT_SERVICE_NAME: &str = "orm";
+
+/// Trace of the current update run, if exported (`ORM_OTLP_ENDPOINT`).
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct Trace {
+    id: [u8; 16],
+    spans: Vec<Span>,
+
+    /// Indexes of the spans in progress, the current one last.
+    stack: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct Span {
+    id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: &'static str,
+    start: u128,
+    end: Option<u128>,
+    attributes: Vec<(&'static str, String)>,
+
+    /// Error message, if failed.
+    error: Option<String>,
+}
+
+/// Begins the trace of an update run, if the spans are exported to an OTLP collector.
+pub fn begin() {
+    if setting!("ORM_OTLP_ENDPOINT").is_none() {
+        return;
+    }
+
+    let mut id = [0u8; 16];
+
+    openssl::rand::rand_bytes(&mut id).unwrap_or_default();
+
+    if let Ok(mut trace) = TRACE.lock() {
+        *trace = Some(Trace {
+            id,
+            spans: Vec::new(),
+            stack: Vec::new(),
+        });
+    }
+}
+
+/// Runs the phase as a span (child of the current one), failed if it results in an error
+/// (see `metrics::failed`).
+pub async fn traced<T, E, F>(name: &'static str, phase: F) -> Result<T, E>
+where
+    E: Display,
+    F: Future<Output = Result<T, E>>,
+{
+    let index = enter(name);
+    let result = phase.await;
+
+    if result.is_err() {
+        metrics::failed(name);
+    }
+
+    if let Some(index) = index {
+        exit(index, result.as_ref().err().map(|err| err.to_string()));
+    }
+
+    result
+}
+
+/// Sets the attribute of the current span.
+pub fn attribute<V: ToString>(key: &'static str, value: V) {
+    with_trace(|trace| {
+        if let Some(&index) = trace.stack.last() {
+            trace.spans[index].attributes.push((key, value.to_string()));
+        }
+    });
+}
+
+/// Exports the spans of the trace (if any) to the OTLP collector (`ORM_OTLP_ENDPOINT`),
+/// the ones still in progress ended; A failed export is only logged.
+pub async fn export() {
+    let mut trace = match TRACE.lock().ok().and_then(|mut trace| trace.take()) {
+        Some(trace) => trace,
+        None => return,
+    };
+
+    // e.g. the update run once the updated application is committed
+    for index in trace.stack.drain(..) {
+        trace.spans[index].end = Some(now());
+    }
+
+    let endpoint = match setting!("ORM_OTLP_ENDPOINT") {
+        Some(endpoint) if endpoint.ends_with("/v1/traces") => endpoint,
+        Some(endpoint) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        None => return,
+    };
+
+    let service_name =
+        setting!("ORM_OTLP_SERVICE_NAME").unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_OTLP_TIMEOUT",
+        setting!("ORM_OTLP_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    debug!("Exporting {} span(s) to {}", trace.spans.len(), endpoint);
+
+    let body = match serde_json::to_vec(&trace.request(&service_name)) {
+        Ok(body) => body,
+        Err(cause) => {
+            warn!("Fails to encode the spans: {}", cause);
+            return;
+        }
+    };
+
+    if let Err(cause) = client::post_json(&endpoint, body, timeout).await {
+        warn!("Fails to export the spans to {}: {}", endpoint, cause);
+    }
+}
+
+fn with_trace<T, F: FnOnce(&mut Trace) -> T>(f: F) -> Option<T> {
+    TRACE
+        .lock()
+        .ok()
+        .and_then(|mut trace| trace.as_mut().map(f))
+}
+
+/// Starts the span, returning its index (if traced).
+fn enter(name: &'static str) -> Option<usize> {
+    with_trace(|trace| {
+        let mut id = [0u8; 8];
+
+        openssl::rand::rand_bytes(&mut id).unwrap_or_default();
+
+        let parent = trace.stack.last().map(|&i| trace.spans[i].id);
+
+        trace.spans.push(Span {
+            id,
+            parent,
+            name,
+            start: now(),
+            end: None,
+            attributes: Vec::new(),
+            error: None,
+        });
+
+        let index = trace.spans.len() - 1;
+
+        trace.stack.push(index);
+
+        index
+    })
+}
+
+fn exit(index: usize, error: Option<String>) {
+    with_trace(|trace| {
+        if let Some(span) = trace.spans.get_mut(index) {
+            span.end = Some(now());
+            span.error = error;
+        }
+
+        trace.stack.retain(|&i| i != index);
+    });
+}
+
+/// Returns the current time, in nanoseconds since the Unix epoch.
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+impl Trace {
+    /// Returns the OTLP/HTTP (JSON) export request of the ended spans.
+    fn request(&self, service_name: &str) -> Value {
+        let spans = self
+            .spans
+            .iter()
+            .filter_map(|span| span.end.map(|end| (span, end)))
+            .map(|(span, end)| {
+                let mut attributes = span.attributes.clone();
+
+                if span.parent.is_none() {
+                    attributes.push(("orm.run_id", logging::run_id()));
+                }
+
+                let mut otlp = json!({
+                    "traceId": hex(&self.id),
+                    "spanId": hex(&span.id),
+                    "name": span.name,
+                    "kind": 1, // Internal
+                    "startTimeUnixNano": span.start.to_string(),
+                    "endTimeUnixNano": end.to_string(),
+                    "attributes": attributes
+                        .iter()
+                        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                        .collect::<Vec<_>>(),
+                    "status": match &span.error {
+                        Some(message) => json!({ "code": 2, "message": message }),
+                        None => json!({ "code": 1 }),
+                    },
+                });
+
+                if let Some(parent) = span.parent {
+                    otlp["parentSpanId"] = json!(hex(&parent));
+                }
+
+                otlp
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": service_name }
+                    }]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "orm" },
+                    "spans": spans
+                }]
+            }]
+        })
+    }
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let trace = Trace {
+            id: [1; 16],
+            spans: vec![
+                Span {
+                    id: [2; 8],
+                    parent: None,
+                    name: "update",
+                    start: 10,
+                    end: Some(30),
+                    attributes: vec![("orm.version", "2.0.0".to_string())],
+                    error: None,
+                },
+                Span {
+                    id: [3; 8],
+                    parent: Some([2; 8]),
+                    name: "download",
+                    start: 15,
+                    end: Some(20),
+                    attributes: Vec::new(),
+                    error: Some("Status = 404".to_string()),
+                },
+                Span {
+                    id: [4; 8],
+                    parent: Some([2; 8]),
+                    name: "extract",
+                    start: 20,
+                    end: None,
+                    attributes: Vec::new(),
+                    error: None,
+                },
+            ],
+            stack: Vec::new(),
+        };
+
+        let request = trace.request("orm");
+        let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+
+        assert_eq!(spans.as_array().map(Vec::len), Some(2));
+        assert_eq!(spans[0]["traceId"], json!("01".repeat(16)));
+        assert_eq!(spans[0]["status"], json!({ "code": 1 }));
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!(
+            spans[0]["attributes"][0],
+            json!({ "key": "orm.version", "value": { "stringValue": "2.0.0" } })
+        );
+
+        assert_eq!(spans[1]["parentSpanId"], json!("02".repeat(8)));
+        assert_eq!(spans[1]["startTimeUnixNano"], json!("15"));
+        assert_eq!(
+            spans[1]["status"],
+            json!({ "code": 2, "message": "Status = 404" })
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use chrono::Utc;
+
+use log::{debug, warn};
+
+use serde::Serialize;
+use serde_json::json;
+
+use super::client;
+use crate::config;
+use crate::error;
+use crate::logging;
+use crate::{format_error, setting};
+use error::Error;
+
+/// Default duration (in seconds) the webhook has to accept the notification.
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Default events notified to the webhook.
+const DEFAULT_EVENTS: &str = "update_applied,update_rolled_back,version_quarantined";
+
+/// Notification of an update event, posted to the webhook.
+#[derive(Debug, Serialize)]
+pub struct Notification<'x> {
+    /// Either `update_applied`, `update_rolled_back` or `version_quarantined`.
+    pub event: &'static str,
+
+    pub thing_id: &'x str,
+
+    /// Version installed before the update run.
+    pub previous_version: &'x str,
+
+    /// Version installed at the end of the run.
+    pub version: Option<&'x str>,
+
+    /// Version the run updated to (either applied, rolled back or quarantined).
+    pub update_version: Option<&'x str>,
+
+    pub reason: Option<&'x str>,
+}
+
+/// Format of the webhook payload (`ORM_WEBHOOK_FORMAT`).
+#[derive(Debug, PartialEq)]
+enum Format {
+    /// The notification as JSON document (or the rendered `ORM_WEBHOOK_TEMPLATE`).
+    Json,
+
+    /// Slack-compatible message, whose text is the rendered `ORM_WEBHOOK_TEMPLATE`.
+    Slack,
+}
+
+/// Notifies the event to the `ORM_WEBHOOK_URL` (if defined and the event is one of the
+/// `ORM_WEBHOOK_EVENTS`); A failed notification is only logged.
+pub async fn notify(notification: &Notification<'_>) {
+    let url = match setting!("ORM_WEBHOOK_URL") {
+        Some(url) => url,
+        None => return,
+    };
+
+    let events = setting!("ORM_WEBHOOK_EVENTS").unwrap_or_else(|| DEFAULT_EVENTS.to_string());
+
+    if !events.split(',').any(|e| e.trim() == notification.event) {
+        return;
+    }
+
+    if let Err(cause) = post(&url, notification).await {
+        warn!(
+            "Fails to notify the {} event to the webhook: {}",
+            notification.event, cause
+        );
+    }
+}
+
+async fn post(url: &str, notification: &Notification<'_>) -> Result<(), Error> {
+    let format = match setting!("ORM_WEBHOOK_FORMAT").as_deref() {
+        None | Some("json") => Format::Json,
+        Some("slack") => Format::Slack,
+        Some(other) => return Err(format_error!("Unsupported webhook format: {}", other)),
+    };
+
+    let body = payload(&format, setting!("ORM_WEBHOOK_TEMPLATE"), notification)?;
+
+    debug!("Notifying {} to {}", String::from_utf8_lossy(&body), url);
+
+    let timeout = Duration::from_secs(config::parse_or(
+        "ORM_WEBHOOK_TIMEOUT",
+        setting!("ORM_WEBHOOK_TIMEOUT"),
+        DEFAULT_TIMEOUT,
+    ));
+
+    client::post_json(url, body, timeout).await
+}
+
+/// Returns the payload of the notification, in the format (rendering the template if any).
+fn payload(
+    format: &Format,
+    template: Option<String>,
+    notification: &Notification<'_>,
+) -> Result<Vec<u8>, Error> {
+    match (format, template) {
+        (Format::Json, None) => {
+            let mut document = serde_json::to_value(notification)?;
+
+            document["run_id"] = json!(logging::run_id());
+            document["timestamp"] = json!(Utc::now().to_rfc3339());
+
+            Ok(serde_json::to_vec(&document)?)
+        }
+        (Format::Json, Some(template)) => Ok(render(&template, notification, true).into_bytes()),
+        (Format::Slack, template) => {
+            let template = template.unwrap_or_else(|| default_text(notification.event).to_string());
+            let text = render(&template, notification, false);
+
+            Ok(serde_json::to_vec(&json!({ "text": text }))?)
+        }
+    }
+}
+
+/// Returns the default Slack text of the event.
+fn default_text(event: &str) -> &'static str {
+    match event {
+        "update_applied" => "{thing_id}: updated from {previous_version} to {version}",
+        "update_rolled_back" => {
+            "{thing_id}: update to {update_version} rolled back to {version} ({reason})"
+        }
+        _ => "{thing_id}: version {update_version} quarantined ({reason})",
+    }
+}
+
+/// Renders the template, replacing the `{event}`, `{thing_id}`, `{previous_version}`,
+/// `{version}`, `{update_version}` and `{reason}` placeholders (escaped for JSON if so).
+fn render(template: &str, notification: &Notification<'_>, escaped: bool) -> String {
+    let value = |v: &str| match escaped {
+        true => {
+            let quoted = json!(v).to_string();
+
+            quoted[1..quoted.len() - 1].to_string()
+        }
+        false => v.to_string(),
+    };
+
+    [
+        ("{event}", Some(notification.event)),
+        ("{thing_id}", Some(notification.thing_id)),
+        ("{previous_version}", Some(notification.previous_version)),
+        ("{version}", notification.version),
+        ("{update_version}", notification.update_version),
+        ("{reason}", notification.reason),
+    ]
+    .iter()
+    .fold(template.to_string(), |rendered, (placeholder, v)| {
+        rendered.replace(placeholder, &value(v.unwrap_or_default()))
+    })
+}
+
+// --- Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let notification = Notification {
+            event: "update_rolled_back",
+            thing_id: "dev1",
+            previous_version: "1.0.0",
+            version: Some("1.0.0"),
+            update_version: Some("2.0.0"),
+            reason: Some("exited \"1\""),
+        };
+
+        let slack = payload(&Format::Slack, None, &notification).unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&slack).unwrap(),
+            json!({ "text": "dev1: update to 2.0.0 rolled back to 1.0.0 (exited \"1\")" })
+        );
+
+        let template = r#"{"device": "{thing_id}", "msg": "{event}: {reason}"}"#.to_string();
+        let custom = payload(&Format::Json, Some(template), &notification).unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&custom).unwrap(),
+            json!({ "device": "dev1", "msg": "update_rolled_back: exited \"1\"" })
+        );
+
+        let document = payload(&Format::Json, None, &notification).unwrap();
+        let document = serde_json::from_slice::<serde_json::Value>(&document).unwrap();
+
+        assert_eq!(document["update_version"], json!("2.0.0"));
+        assert_eq!(document["thing_id"], json!("dev1"));
+    }
+}